@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+
+use crate::vm::is_symbol;
+
+/// Instructions the ROM holds, at addresses 0 to 32767.
+const ROM_SIZE: usize = 0x8000;
+
+/// Where variables are allocated from.
+const FIRST_VARIABLE_ADDRESS: u16 = 16;
+
+/// Symbols every Hack program has.
+const PREDEFINED_SYMBOLS: [(&str, u16); 7] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+/// Assemble Hack assembly into `.hack` binary text, one 16 bit instruction
+/// per line, like the nand2tetris Assembler.
+///
+/// Labels `(XXX)` are resolved to the address of the next instruction; any
+/// other symbol is a variable, allocated from RAM 16 in order of first use.
+/// Every malformed line is returned as `line: message`.
+pub fn assemble(asm: &str) -> Result<String, Vec<String>> {
+    let mut symbols = predefined_symbols();
+    let mut errors = vec![];
+
+    // first pass: labels, and the instructions with their line numbers
+    let mut instructions = vec![];
+    for (i, line) in asm.lines().enumerate() {
+        let line_number = i + 1;
+        // whitespace is ignored everywhere, as in `D = M + 1`
+        let code = line.split("//").next().unwrap();
+        let code = code.split_whitespace().collect::<String>();
+        if code.is_empty() {
+            continue;
+        }
+
+        if let Some(label) = code.strip_prefix('(') {
+            match label.strip_suffix(')') {
+                Some(label) if is_symbol(label) => {
+                    if symbols.contains_key(label) {
+                        errors.push((line_number, format!("`{label}` is already defined")));
+                    } else if instructions.len() >= ROM_SIZE {
+                        errors.push((line_number, format!("`{label}` is past the end of the ROM")));
+                    } else {
+                        symbols.insert(label.to_string(), instructions.len() as u16);
+                    }
+                }
+                _ => errors.push((line_number, format!("bad label `{code}`"))),
+            }
+        } else {
+            instructions.push((line_number, code));
+        }
+    }
+
+    if let Some(&(line_number, _)) = instructions.get(ROM_SIZE) {
+        errors.push((
+            line_number,
+            format!("more than {ROM_SIZE} instructions don't fit in the ROM"),
+        ));
+    }
+
+    // second pass: encode, allocating variables
+    let mut next_variable = FIRST_VARIABLE_ADDRESS;
+    let mut buf = String::new();
+    for (line_number, code) in instructions {
+        let word = if let Some(value) = code.strip_prefix('@') {
+            if let Ok(address) = value.parse::<u16>() {
+                if address > 0x7fff {
+                    errors.push((line_number, format!("`{value}` is more than 32767")));
+                }
+                address
+            } else if is_symbol(value) {
+                *symbols.entry(value.to_string()).or_insert_with(|| {
+                    next_variable += 1;
+                    next_variable - 1
+                })
+            } else {
+                errors.push((line_number, format!("bad address `{value}`")));
+                continue;
+            }
+        } else {
+            match encode_c_instruction(&code) {
+                Ok(word) => word,
+                Err(error) => {
+                    errors.push((line_number, error));
+                    continue;
+                }
+            }
+        };
+        buf += &format!("{word:016b}\n");
+    }
+
+    if errors.is_empty() {
+        Ok(buf)
+    } else {
+        errors.sort_by_key(|(line_number, _)| *line_number);
+        Err(errors
+            .into_iter()
+            .map(|(line_number, error)| format!("{line_number}: {error}"))
+            .collect())
+    }
+}
+
+fn predefined_symbols() -> HashMap<String, u16> {
+    let mut symbols = PREDEFINED_SYMBOLS
+        .iter()
+        .map(|&(name, address)| (name.to_string(), address))
+        .collect::<HashMap<_, _>>();
+    for i in 0..16 {
+        symbols.insert(format!("R{i}"), i);
+    }
+    symbols
+}
+
+/// `dest=comp;jump`, with `dest=` and `;jump` optional.
+fn encode_c_instruction(code: &str) -> Result<u16, String> {
+    let (dest, rest) = code.split_once('=').unwrap_or(("", code));
+    let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+
+    let mut dest_bits = 0;
+    for register in dest.chars() {
+        let bit = match register {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => return Err(format!("bad dest `{dest}`")),
+        };
+        if dest_bits & bit != 0 {
+            return Err(format!("bad dest `{dest}`"));
+        }
+        dest_bits |= bit;
+    }
+    if code.contains('=') && dest.is_empty() {
+        return Err("empty dest".to_string());
+    }
+
+    let Some(comp_bits) = comp_bits(comp) else {
+        return Err(format!("bad comp `{comp}`"));
+    };
+
+    let jump_bits = match jump {
+        "" => 0b000,
+        "JGT" => 0b001,
+        "JEQ" => 0b010,
+        "JGE" => 0b011,
+        "JLT" => 0b100,
+        "JNE" => 0b101,
+        "JLE" => 0b110,
+        "JMP" => 0b111,
+        _ => return Err(format!("bad jump `{jump}`")),
+    };
+
+    Ok(0b111 << 13 | comp_bits << 6 | dest_bits << 3 | jump_bits)
+}
+
+/// The `a` bit and the six `c` bits of a computation.
+fn comp_bits(comp: &str) -> Option<u16> {
+    // `M` computations are the `A` ones with the `a` bit set
+    let (comp, a) = if comp.contains('M') {
+        (comp.replace('M', "A"), 1)
+    } else {
+        (comp.to_string(), 0)
+    };
+    let c = match comp.as_str() {
+        "0" => 0b101010,
+        "1" => 0b111111,
+        "-1" => 0b111010,
+        "D" => 0b001100,
+        "A" => 0b110000,
+        "!D" => 0b001101,
+        "!A" => 0b110001,
+        "-D" => 0b001111,
+        "-A" => 0b110011,
+        "D+1" => 0b011111,
+        "A+1" => 0b110111,
+        "D-1" => 0b001110,
+        "A-1" => 0b110010,
+        "D+A" | "A+D" => 0b000010,
+        "D-A" => 0b010011,
+        "A-D" => 0b000111,
+        "D&A" | "A&D" => 0b000000,
+        "D|A" | "A|D" => 0b010101,
+        _ => return None,
+    };
+    // `AM` in a computation is not a thing
+    if a == 1 && comp.matches('A').count() > 1 {
+        return None;
+    }
+    Some(a << 6 | c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        // `Add.asm` of nand2tetris project 6
+        assert_eq!(
+            assemble(
+                "// Computes R0 = 2 + 3  (R0 refers to RAM[0])
+
+@2
+D=A
+@3
+D=D+A
+@0
+M=D
+"
+            ),
+            Ok("0000000000000010
+1110110000010000
+0000000000000011
+1110000010010000
+0000000000000000
+1110001100001000
+"
+            .to_string())
+        );
+    }
+
+    #[test]
+    fn test_assemble_symbols() {
+        assert_eq!(
+            assemble(
+                "   @R1
+   D = M      // a comment
+(LOOP)
+   @sum
+   M=D+M
+   @i
+   M=M+1
+   @sum
+   D;JGT
+   @LOOP
+   0;JMP
+   @SCREEN
+   AMD=M-1
+   @KBD
+"
+            ),
+            Ok([
+                "0000000000000001",
+                "1111110000010000",
+                // (LOOP) is 2, `sum` is 16, `i` is 17
+                "0000000000010000",
+                "1111000010001000",
+                "0000000000010001",
+                "1111110111001000",
+                "0000000000010000",
+                "1110001100000001",
+                "0000000000000010",
+                "1110101010000111",
+                "0100000000000000",
+                "1111110010111000",
+                "0110000000000000",
+                "",
+            ]
+            .join("\n"))
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble(
+                "(LOOP)
+(LOOP)
+@32768
+@1x
+D=D*A
+X=D
+0;JUMP
+(R0)
+"
+            ),
+            Err(vec![
+                "2: `LOOP` is already defined".to_string(),
+                "3: `32768` is more than 32767".to_string(),
+                "4: bad address `1x`".to_string(),
+                "5: bad comp `D*A`".to_string(),
+                "6: bad dest `X`".to_string(),
+                "7: bad jump `JUMP`".to_string(),
+                "8: `R0` is already defined".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_assemble_rom_size() {
+        let full_rom = "D=A\n".repeat(ROM_SIZE);
+        assert!(assemble(&format!("(START)\n{full_rom}")).is_ok());
+        assert_eq!(
+            assemble(&format!("{full_rom}(END)\nD=A\n")),
+            Err(vec![
+                "32769: `END` is past the end of the ROM".to_string(),
+                "32770: more than 32768 instructions don't fit in the ROM".to_string(),
+            ])
+        );
+    }
+}
@@ -0,0 +1,255 @@
+/// Lines shown around the first difference.
+const CONTEXT: usize = 2;
+
+/// Compare an output with its reference the way the nand2tetris
+/// TextComparer does: whitespace is ignored, within lines and as empty
+/// lines. The first differing line is reported, with the lines around it in
+/// both texts, named after `output_name` and `reference_name`.
+pub fn compare(
+    output: &str,
+    output_name: &str,
+    reference: &str,
+    reference_name: &str,
+) -> Result<(), String> {
+    let output_lines = significant_lines(output);
+    let reference_lines = significant_lines(reference);
+
+    let mut i = 0;
+    loop {
+        match (output_lines.get(i), reference_lines.get(i)) {
+            (None, None) => return Ok(()),
+            (Some(a), Some(b)) if a.1 == b.1 => i += 1,
+            _ => break,
+        }
+    }
+
+    let mut buf = String::new();
+    for (name, text, lines) in [
+        (reference_name, reference, &reference_lines),
+        (output_name, output, &output_lines),
+    ] {
+        match lines.get(i) {
+            Some(&(line_number, _)) => {
+                buf += &format!("{name}:{line_number}:\n");
+                buf += &context(text, line_number);
+            }
+            None => buf += &format!("{name}: ends here\n"),
+        }
+    }
+    Err(buf)
+}
+
+/// (line number, line without whitespace) of every line that isn't blank.
+fn significant_lines(text: &str) -> Vec<(usize, String)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.split_whitespace().collect::<String>()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect()
+}
+
+/// The lines of `text` around `line_number`, that one marked with `>`.
+fn context(text: &str, line_number: usize) -> String {
+    let lines = text.lines().collect::<Vec<_>>();
+    let first = line_number.saturating_sub(CONTEXT).max(1);
+    let last = (line_number + CONTEXT).min(lines.len());
+    let mut buf = String::new();
+    for n in first..=last {
+        let marker = if n == line_number { '>' } else { ' ' };
+        buf += &format!("{marker} {n:>5} | {}\n", lines[n - 1].trim_end());
+    }
+    buf
+}
+
+/// The AST output without the `<info>` annotations of its identifiers,
+/// which the reference files don't have.
+pub fn strip_info(xml: &str) -> String {
+    let mut buf = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find("<info>") {
+        buf += &rest[..start];
+        rest = match rest[start..].find("</info>") {
+            Some(end) => &rest[start + end + "</info>".len()..],
+            None => "",
+        };
+    }
+    buf + rest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let reference =
+            "<tokens>\n<keyword> class </keyword>\n<identifier> Main </identifier>\n</tokens>\n";
+        assert_eq!(
+            compare(
+                "\n<tokens>\n  <keyword>class</keyword>\n\n<identifier>Main</identifier>\n</tokens>",
+                "Main.my-token.xml",
+                reference,
+                "MainT.xml",
+            ),
+            Ok(())
+        );
+
+        assert_eq!(
+            compare(
+                "<tokens>\n<keyword>class</keyword>\n<identifier>Mian</identifier>\n</tokens>\n",
+                "out",
+                reference,
+                "ref",
+            ),
+            Err("ref:3:
+      1 | <tokens>
+      2 | <keyword> class </keyword>
+>     3 | <identifier> Main </identifier>
+      4 | </tokens>
+out:3:
+      1 | <tokens>
+      2 | <keyword>class</keyword>
+>     3 | <identifier>Mian</identifier>
+      4 | </tokens>
+"
+            .to_string())
+        );
+
+        // a missing line is where the shorter text ends
+        assert_eq!(
+            compare("push constant 1\n", "out", "push constant 1\nadd\n", "ref"),
+            Err("ref:2:
+      1 | push constant 1
+>     2 | add
+out: ends here
+"
+            .to_string())
+        );
+    }
+
+    #[test]
+    fn test_strip_info() {
+        assert_eq!(
+            strip_info(
+                "<class>
+    <identifier>Main<info>(name: Main, usage: delcare
+        className)</info></identifier>
+    <identifier>x<info>(name: x)</info></identifier>
+</class>"
+            ),
+            "<class>
+    <identifier>Main</identifier>
+    <identifier>x</identifier>
+</class>"
+        );
+    }
+
+    #[test]
+    fn test_compare_ast() -> std::io::Result<()> {
+        use crate::{compilation_engine::CompileOptions, test_file::TestFile};
+
+        // an operator chain is one expression, as in the reference files
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    function void main() {")?;
+        test_file.add_line("        var Array a;")?;
+        test_file.add_line("        let a[0] = -1;")?;
+        test_file.add_line("        do Main.f(a[0] - 1 - 2);")?;
+        test_file.add_line("        return;")?;
+        test_file.add_line("    }")?;
+        test_file.add_line("}")?;
+        let ast = test_file.compile(CompileOptions::default())?.ast;
+
+        let reference = "\
+<class>
+  <keyword> class </keyword>
+  <identifier> Main </identifier>
+  <symbol> { </symbol>
+  <subroutineDec>
+    <keyword> function </keyword>
+    <keyword> void </keyword>
+    <identifier> main </identifier>
+    <symbol> ( </symbol>
+    <parameterList>
+    </parameterList>
+    <symbol> ) </symbol>
+    <subroutineBody>
+      <symbol> { </symbol>
+      <varDec>
+        <keyword> var </keyword>
+        <identifier> Array </identifier>
+        <identifier> a </identifier>
+        <symbol> ; </symbol>
+      </varDec>
+      <statements>
+        <letStatement>
+          <keyword> let </keyword>
+          <identifier> a </identifier>
+          <symbol> [ </symbol>
+          <expression>
+            <term>
+              <integerConstant> 0 </integerConstant>
+            </term>
+          </expression>
+          <symbol> ] </symbol>
+          <symbol> = </symbol>
+          <expression>
+            <term>
+              <symbol> - </symbol>
+              <term>
+                <integerConstant> 1 </integerConstant>
+              </term>
+            </term>
+          </expression>
+          <symbol> ; </symbol>
+        </letStatement>
+        <doStatement>
+          <keyword> do </keyword>
+          <identifier> Main </identifier>
+          <symbol> . </symbol>
+          <identifier> f </identifier>
+          <symbol> ( </symbol>
+          <expressionList>
+            <expression>
+              <term>
+                <identifier> a </identifier>
+                <symbol> [ </symbol>
+                <expression>
+                  <term>
+                    <integerConstant> 0 </integerConstant>
+                  </term>
+                </expression>
+                <symbol> ] </symbol>
+              </term>
+              <symbol> - </symbol>
+              <term>
+                <integerConstant> 1 </integerConstant>
+              </term>
+              <symbol> - </symbol>
+              <term>
+                <integerConstant> 2 </integerConstant>
+              </term>
+            </expression>
+          </expressionList>
+          <symbol> ) </symbol>
+          <symbol> ; </symbol>
+        </doStatement>
+        <returnStatement>
+          <keyword> return </keyword>
+          <symbol> ; </symbol>
+        </returnStatement>
+      </statements>
+      <symbol> } </symbol>
+    </subroutineBody>
+  </subroutineDec>
+  <symbol> } </symbol>
+</class>
+";
+        assert_eq!(
+            compare(&strip_info(&ast), "output", reference, "Main.xml"),
+            Ok(())
+        );
+        Ok(())
+    }
+}
@@ -1,114 +1,320 @@
 use std::{
+    fmt,
     fs::{File, OpenOptions},
-    io::{self, Write},
+    io::{self, BufWriter, Write},
     path::Path,
 };
 
-use crate::{symbol_table::SymbolTable, vm_writer::VmWriter, *};
+use crate::{
+    debug_info::{self, ClassDebugInfo, SubroutineDebugInfo},
+    doc::{self, ClassDoc, SubroutineDoc, VarDoc},
+    optimizer,
+    symbol_table::SymbolTable,
+    vm::{self, ArithmeticOp, Segment, VmInstruction},
+    vm_writer::VmWriter,
+    *,
+};
+
+/// An error in the compiled source, reported with the line it was found on.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+/// Why a `compile_*` step stopped early: the output could not be written, or
+/// a syntax error was recorded and parsing resumes at the next statement or
+/// class member.
+#[derive(Debug)]
+pub enum CompileError {
+    Io(io::Error),
+    Syntax,
+}
+
+impl From<io::Error> for CompileError {
+    fn from(err: io::Error) -> Self {
+        CompileError::Io(err)
+    }
+}
+
+impl From<CompileError> for io::Error {
+    fn from(err: CompileError) -> Self {
+        match err {
+            CompileError::Io(err) => err,
+            CompileError::Syntax => io::Error::new(io::ErrorKind::InvalidData, "syntax error"),
+        }
+    }
+}
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+/// How the VM code is generated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CompileOptions {
+    /// run the `optimizer` passes on every function
+    pub optimize: bool,
+    /// `&` and `|` in `if` and `while` conditions skip their right-hand side
+    /// when the left-hand side decides the result
+    pub short_circuit: bool,
+    /// build each distinct string literal once and reuse it
+    pub pool_strings: bool,
+}
+
+/// A run of the same `&` or `|` operator in a short-circuit condition.
+struct Run {
+    op: char,
+    /// the first instruction of the run
+    start: usize,
+    /// where a `|` run jumps when an operand is true
+    or_true: Option<String>,
+}
 
-pub struct CompilationEngine {
-    output_ast_file: File,
+pub struct CompilationEngine<W: Write = BufWriter<File>> {
+    output_ast_file: Option<File>,
     _output_ast_test_string: String,
     tokenizer: Tokenizer,
-    vm_writer: vm_writer::VmWriter,
+    vm_writer: VmWriter<W>,
     class_symbol_table: SymbolTable,
     subroutine_symbol_table: SymbolTable,
     class_name: String,
     current_subroutine_type: Option<String>,
     statement_id: i32,
+    class_doc: ClassDoc,
+    debug_info: ClassDebugInfo,
+    diagnostics: Vec<Diagnostic>,
+    // number of `{` eaten and not closed yet
+    brace_depth: usize,
+    options: CompileOptions,
 }
 
 impl CompilationEngine {
+    /// Write the VM code next to the source, in a `.my-vm.vm` file.
+    #[cfg(test)]
     pub fn new(filepath: &Path) -> io::Result<Self> {
-        // output file settings
-        let Some(filename) = filepath.file_name().unwrap().to_str() else {
-            panic!();
-        };
-        let file_basename = &filename[..(filename.len() - (JACK_FILE_EXTENSION.len() + 1))];
-        // ast file
-        let output_ast_filename = file_basename.to_string() + "." + OUTPUT_AST_FILE_EXTENSION;
-        let mut output_ast_file_path = filepath.parent().unwrap().to_path_buf();
-        output_ast_file_path.extend([output_ast_filename].iter());
+        let mut output_vm_file_path = filepath.to_path_buf();
+        output_vm_file_path.set_extension(OUTPUT_VM_FILE_EXTENSION);
+        let vm_output = BufWriter::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output_vm_file_path)?,
+        );
+
+        CompilationEngine::with_vm_output(filepath, vm_output)
+    }
+}
+
+impl CompilationEngine<io::Sink> {
+    /// Only check the source: neither the AST nor the VM code is written.
+    pub fn without_output(filepath: &Path) -> io::Result<Self> {
+        CompilationEngine::with_outputs(filepath, None, io::sink())
+    }
+}
+
+impl<W: Write> CompilationEngine<W> {
+    /// Write the VM code to `vm_output`, and the AST next to the source, in a
+    /// `.my-ast.xml` file.
+    pub fn with_vm_output(filepath: &Path, vm_output: W) -> io::Result<Self> {
+        let mut output_ast_file_path = filepath.to_path_buf();
+        output_ast_file_path.set_extension(OUTPUT_AST_FILE_EXTENSION);
         let output_ast_file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(output_ast_file_path)?;
-        // vm file
-        let output_vm_filename = file_basename.to_string() + "." + OUTPUT_VM_FILE_EXTENSION;
-        let mut output_vm_file_path = filepath.parent().unwrap().to_path_buf();
-        output_vm_file_path.extend([output_vm_filename].iter());
 
+        CompilationEngine::with_outputs(filepath, Some(output_ast_file), vm_output)
+    }
+
+    fn with_outputs(
+        filepath: &Path,
+        output_ast_file: Option<File>,
+        vm_output: W,
+    ) -> io::Result<Self> {
+        let Some(filename) = filepath.file_name().unwrap().to_str() else {
+            panic!();
+        };
         let tokenizer = Tokenizer::new(filepath)?;
 
         let engine = Self {
             output_ast_file,
             _output_ast_test_string: String::new(),
             tokenizer,
-            vm_writer: VmWriter::new(output_vm_file_path.as_path())?,
+            vm_writer: VmWriter::new(vm_output),
             class_symbol_table: SymbolTable::new(),
             subroutine_symbol_table: SymbolTable::new(),
             class_name: filename[0..(filename.len() - 5)].to_string(),
             current_subroutine_type: None,
             statement_id: 0,
+            class_doc: ClassDoc::default(),
+            debug_info: ClassDebugInfo {
+                file: filepath.to_path_buf(),
+                ..ClassDebugInfo::default()
+            },
+            diagnostics: vec![],
+            brace_depth: 0,
+            options: CompileOptions::default(),
         };
 
         Ok(engine)
     }
 
+    pub fn set_options(&mut self, options: CompileOptions) {
+        self.options = options;
+    }
+
+    /// Declarations and doc comments of the compiled class.
+    pub fn class_doc(&self) -> &ClassDoc {
+        &self.class_doc
+    }
+
+    /// The AST written so far, as in the `.my-ast.xml` file.
+    #[cfg(test)]
+    pub fn ast(&self) -> &str {
+        &self._output_ast_test_string
+    }
+
+    /// Source lines and variable names of the compiled class.
+    pub fn debug_info(&self) -> &ClassDebugInfo {
+        &self.debug_info
+    }
+
+    /// VM code of the compiled functions, in order.
+    pub fn functions(&self) -> &[Vec<VmInstruction>] {
+        self.vm_writer.functions()
+    }
+
+    /// Every error found in the source, in order.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Move to the next token. Past the end of the file, the current token
+    /// stays `None`.
     fn _get_next_token(&mut self) -> io::Result<()> {
-        if !self.tokenizer.has_more_tokens() {
-            report_syntax_error("need more token");
-        }
-        self.tokenizer.advance()?;
-        Ok(())
+        self.tokenizer.advance()
     }
 
+    /// Compile the whole file. Lexical and syntax errors don't stop
+    /// compiling; they are collected in `diagnostics`.
     pub fn compile_class(&mut self) -> io::Result<()> {
+        match self._compile_class() {
+            Err(CompileError::Syntax) => {}
+            result => result?,
+        }
+        self.diagnostics
+            .extend(self.tokenizer.errors().iter().cloned());
+        self.diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+        if self.options.optimize {
+            for function in self.vm_writer.functions_mut() {
+                optimizer::optimize(function);
+            }
+        }
+        self._collect_debug_info();
+        self.vm_writer.finish()
+    }
+
+    fn _collect_debug_info(&mut self) {
+        self.debug_info.class_name = self.class_name.clone();
+        let is_pooled_string = |variable: &debug_info::Variable| variable.name.starts_with('"');
+        self.debug_info.statics =
+            debug_info::variables(&self.class_symbol_table, symbol_table::Kind::Static);
+        self.debug_info.statics.retain(|v| !is_pooled_string(v));
+        self.debug_info.fields =
+            debug_info::variables(&self.class_symbol_table, symbol_table::Kind::Field);
+
+        // the optimizer doesn't keep track of lines
+        if self.options.optimize {
+            return;
+        }
+        let functions = self.vm_writer.functions();
+        let positions = self.vm_writer.lines().iter().zip(self.vm_writer.columns());
+        for (function, (lines, columns)) in functions.iter().zip(positions) {
+            let name = vm::function_name(function);
+            if let Some(subroutine) = self
+                .debug_info
+                .subroutines
+                .iter_mut()
+                .find(|subroutine| subroutine.name == name)
+            {
+                subroutine.lines = lines.clone();
+                subroutine.columns = columns.clone();
+            }
+        }
+    }
+
+    fn _compile_class(&mut self) -> CompileResult<()> {
         // open class tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_CLASS))?;
-        if self.tokenizer.token_type() != Some(TokenType::Keyword) {
-            report_syntax_error("need a keyword");
-        }
-        if self.tokenizer.keyword() != "class" {
-            report_syntax_error("need class");
+        self.class_doc.doc = doc::doc_text(self.tokenizer.comments());
+        if self.tokenizer.token_type() != Some(TokenType::Keyword)
+            || self.tokenizer.keyword() != "class"
+        {
+            return self._error("`class`");
         }
         self.print_to_ast(&format!("<{0}>{1}</{0}>\n", XML_TAG_KEYWORD, XML_TAG_CLASS))?;
         self._get_next_token()?; // eat `class`
 
         // className
-        self.class_name = self.tokenizer.identifier();
+        self.class_name = self._expect_identifier()?;
+        self.class_doc.name = self.class_name.clone();
         self._eat_identifier("delcare className")?; // eat `className`
 
         // `{`
-        self._eat_symbol()?;
-
-        // classVarDec*
-        while self.tokenizer.token_type() == Some(TokenType::Keyword)
-            && (self.tokenizer.keyword() == "static" || self.tokenizer.keyword() == "field")
-        {
-            self.compile_class_var_dec()?;
-        }
+        self._eat_symbol('{')?;
 
-        // subroutineDec*
-        while self.tokenizer.token_type() == Some(TokenType::Keyword)
-            && (self.tokenizer.keyword() == "constructor"
-                || self.tokenizer.keyword() == "function"
-                || self.tokenizer.keyword() == "method")
-        {
-            self.compile_subroutine_dec()?;
+        // classVarDec* subroutineDec*
+        let mut seen_subroutine = false;
+        loop {
+            let result = match self.tokenizer.token_type() {
+                Some(TokenType::Keyword)
+                    if Self::_is_class_var_keyword(&self.tokenizer.keyword()) =>
+                {
+                    if seen_subroutine {
+                        self._report("`static` and `field` must come before subroutines");
+                    }
+                    self.compile_class_var_dec()
+                }
+                Some(TokenType::Keyword)
+                    if Self::_is_subroutine_keyword(&self.tokenizer.keyword()) =>
+                {
+                    seen_subroutine = true;
+                    self.compile_subroutine_dec()
+                }
+                Some(TokenType::Symbol) if self.tokenizer.symbol() == '}' => break,
+                None => break,
+                _ => self._error("a class member"),
+            };
+            match result {
+                Err(CompileError::Syntax) => self._synchronize_member()?,
+                result => result?,
+            }
         }
 
         // `}`
-        self._eat_symbol()?;
+        self._eat_symbol('}')?;
+
+        // nothing after the class
+        if self.tokenizer.has_more_tokens() {
+            return self._error("end of file");
+        }
 
         // close class tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_CLASS))?;
         Ok(())
     }
 
-    pub fn compile_class_var_dec(&mut self) -> io::Result<()> {
+    pub fn compile_class_var_dec(&mut self) -> CompileResult<()> {
         // open classVarDec tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_CLASS_VAR_DEC))?;
+        let var_doc = doc::doc_text(self.tokenizer.comments());
 
         // `static` or `field`
         let var_kind = match self.tokenizer.keyword().as_str() {
@@ -122,8 +328,10 @@ impl CompilationEngine {
         let var_type = self.compile_type()?;
 
         // varName
+        let var_name = self._expect_identifier()?;
         self.class_symbol_table
-            .define(&self.tokenizer.identifier(), &var_type, var_kind);
+            .define(&var_name, &var_type, var_kind);
+        self._document_var(var_kind, &var_type, &var_doc);
         self._eat_identifier("delcare varName in class")?;
 
         // (`,` varName)*
@@ -131,30 +339,36 @@ impl CompilationEngine {
             && self.tokenizer.symbol() == ','
         {
             // `,`
-            self._eat_symbol()?;
+            self._eat_symbol(',')?;
 
             // varName
+            let var_name = self._expect_identifier()?;
             self.class_symbol_table
-                .define(&self.tokenizer.identifier(), &var_type, var_kind);
+                .define(&var_name, &var_type, var_kind);
+            self._document_var(var_kind, &var_type, &var_doc);
             self._eat_identifier("delcare varName in class")?;
         }
 
         // `;`
-        self._eat_symbol()?;
+        self._eat_symbol(';')?;
 
         // close classVarDec tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_CLASS_VAR_DEC))?;
         Ok(())
     }
 
-    pub fn compile_subroutine_dec(&mut self) -> io::Result<()> {
+    pub fn compile_subroutine_dec(&mut self) -> CompileResult<()> {
         // reset subroutine symbol table
         self.subroutine_symbol_table.reset();
         self.current_subroutine_type = None;
         self.statement_id = 0;
+        let position = (self.tokenizer.line(), self.tokenizer.column());
+        // the prologue before the first statement has no line
+        self.vm_writer.set_position(0, 0);
 
         // open subroutineDec tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_SUBROUTINE_DEC))?;
+        let subroutine_doc = doc::doc_text(self.tokenizer.comments());
 
         // `constructor` `function` or `method`
         let subroutine_type = self.tokenizer.keyword();
@@ -162,7 +376,7 @@ impl CompilationEngine {
         self._eat_keyword()?;
 
         // handle method
-        if self.current_subroutine_type == Some("mothod".to_string()) {
+        if self.current_subroutine_type == Some("method".to_string()) {
             self.subroutine_symbol_table
                 .define("this", &self.class_name, symbol_table::Kind::Arg);
         }
@@ -180,26 +394,54 @@ impl CompilationEngine {
         }
 
         // subroutineName
-        let subroutine_name = self.tokenizer.identifier();
-        self._eat_identifier(&format!(
+        let subroutine_name = self._eat_identifier(&format!(
             "delcare subroutineName in class, return type({})",
             return_type
         ))?;
 
         // `(`
-        self._eat_symbol()?;
+        self._eat_symbol('(')?;
 
         // parameterList
         self.compile_parameter_list()?;
 
         // `)`
-        self._eat_symbol()?;
+        self._eat_symbol(')')?;
+
+        // document the signature
+        let parameters = self
+            .subroutine_symbol_table
+            .names_of(symbol_table::Kind::Arg)
+            .into_iter()
+            .filter(|name| name != "this")
+            .map(|name| {
+                let type_ = self.subroutine_symbol_table.type_of(&name).unwrap();
+                (name, type_)
+            })
+            .collect();
+        self.class_doc.subroutines.push(SubroutineDoc {
+            subroutine_type: self.current_subroutine_type.clone().unwrap(),
+            return_type: return_type.clone(),
+            name: subroutine_name.clone(),
+            parameters,
+            doc: subroutine_doc,
+        });
 
         // vm function info
         let vm_fn_name = format!("{}.{}", self.class_name, subroutine_name);
 
         // subroutineBody
-        self.compile_subroutine_body(&vm_fn_name, &return_type)?;
+        let result = self.compile_subroutine_body(&vm_fn_name);
+        self.debug_info.subroutines.push(SubroutineDebugInfo {
+            name: vm_fn_name,
+            subroutine_type: self.current_subroutine_type.clone().unwrap(),
+            args: debug_info::variables(&self.subroutine_symbol_table, symbol_table::Kind::Arg),
+            locals: debug_info::variables(&self.subroutine_symbol_table, symbol_table::Kind::Var),
+            position,
+            lines: vec![],
+            columns: vec![],
+        });
+        result?;
 
         // close subroutineDec tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_SUBROUTINE_DEC))?;
@@ -207,7 +449,7 @@ impl CompilationEngine {
         Ok(())
     }
 
-    pub fn compile_parameter_list(&mut self) -> io::Result<()> {
+    pub fn compile_parameter_list(&mut self) -> CompileResult<()> {
         // open parameterList tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_PARAMETER_LIST))?;
 
@@ -221,11 +463,9 @@ impl CompilationEngine {
                 let type_ = self.compile_type()?;
 
                 // varName
-                self.subroutine_symbol_table.define(
-                    &self.tokenizer.identifier(),
-                    &type_,
-                    symbol_table::Kind::Arg,
-                );
+                let var_name = self._expect_identifier()?;
+                self.subroutine_symbol_table
+                    .define(&var_name, &type_, symbol_table::Kind::Arg);
                 self._eat_identifier("delcare varName(arg) in parameterList")?;
 
                 if self.tokenizer.token_type() != Some(TokenType::Symbol)
@@ -236,7 +476,7 @@ impl CompilationEngine {
                 }
 
                 // `,`
-                self._eat_symbol()?;
+                self._eat_symbol(',')?;
             }
         }
 
@@ -246,58 +486,58 @@ impl CompilationEngine {
         Ok(())
     }
 
-    pub fn compile_subroutine_body(
-        &mut self,
-        subroutine_name: &str,
-        subroutine_return_type: &str,
-    ) -> io::Result<()> {
+    pub fn compile_subroutine_body(&mut self, subroutine_name: &str) -> CompileResult<()> {
         // open subroutineBody tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_SUBROUTINE_BODY))?;
 
         // `{`
-        self._eat_symbol()?;
+        self._eat_symbol('{')?;
 
         // varDec*
+        let depth = self.brace_depth;
         while self.tokenizer.token_type() == Some(TokenType::Keyword)
             && self.tokenizer.keyword() == "var"
         {
-            self.compile_var_dec()?;
+            match self.compile_var_dec() {
+                Err(CompileError::Syntax) => self._synchronize_statement(depth)?,
+                result => result?,
+            }
         }
 
         // code gen
         // function xxx.yyy nVars
-        self.vm_writer.writeFunction(
+        self.vm_writer.write_function(
             subroutine_name,
             self.subroutine_symbol_table
                 .var_count(symbol_table::Kind::Var),
-        )?;
+        );
 
         if self.current_subroutine_type == Some("method".to_string()) {
-            self.vm_writer.writePush("argument", 0)?;
-            self.vm_writer.writePop("pointer", 0)?;
+            self.vm_writer.write_push(Segment::Argument, 0);
+            self.vm_writer.write_pop(Segment::Pointer, 0);
         }
 
         if self.current_subroutine_type == Some("constructor".to_string()) {
-            self.vm_writer.writePush(
-                "constant",
+            self.vm_writer.write_push(
+                Segment::Constant,
                 self.class_symbol_table.var_count(symbol_table::Kind::Field),
-            )?;
-            self.vm_writer.writeCall("Memory.alloc", 1)?;
-            self.vm_writer.writePop("pointer", 0)?;
+            );
+            self.vm_writer.write_call("Memory.alloc", 1);
+            self.vm_writer.write_pop(Segment::Pointer, 0);
         }
 
         // statements
         self.compile_statements()?;
 
         // `}`
-        self._eat_symbol()?;
+        self._eat_symbol('}')?;
 
         // close subroutineBody tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_SUBROUTINE_BODY))?;
         Ok(())
     }
 
-    pub fn compile_var_dec(&mut self) -> io::Result<()> {
+    pub fn compile_var_dec(&mut self) -> CompileResult<()> {
         // open varDec tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_VAR_DEC))?;
 
@@ -310,11 +550,9 @@ impl CompilationEngine {
         // varName (`,` varName)*
         loop {
             // varName
-            self.subroutine_symbol_table.define(
-                &self.tokenizer.identifier(),
-                &type_,
-                symbol_table::Kind::Var,
-            );
+            let var_name = self._expect_identifier()?;
+            self.subroutine_symbol_table
+                .define(&var_name, &type_, symbol_table::Kind::Var);
             self._eat_identifier("delcare varName in subroutine")?;
 
             if self.tokenizer.token_type() != Some(TokenType::Symbol)
@@ -325,39 +563,56 @@ impl CompilationEngine {
             }
 
             // `,`
-            self._eat_symbol()?;
+            self._eat_symbol(',')?;
         }
 
         // `;`
-        self._eat_symbol()?;
+        self._eat_symbol(';')?;
 
         // close varDec tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_VAR_DEC))?;
         Ok(())
     }
 
-    pub fn compile_statements(&mut self) -> io::Result<()> {
+    pub fn compile_statements(&mut self) -> CompileResult<()> {
         // open statements tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_STATEMENTS))?;
 
         // statement*
-        while self.tokenizer.token_type() == Some(TokenType::Keyword) {
-            match self.tokenizer.keyword().as_str() {
-                "let" => self.compile_let()?,
-                "if" => self.compile_if()?,
-                "while" => self.compile_while()?,
-                "do" => self.compile_do()?,
-                "return" => self.compile_return()?,
-                _ => report_syntax_error("unknow statement"),
+        let depth = self.brace_depth;
+        let position = self.vm_writer.position();
+        loop {
+            self.vm_writer
+                .set_position(self.tokenizer.line(), self.tokenizer.column());
+            let result = match self.tokenizer.token_type() {
+                Some(TokenType::Keyword) => match self.tokenizer.keyword().as_str() {
+                    "let" => self.compile_let(),
+                    "if" => self.compile_if(),
+                    "while" => self.compile_while(),
+                    "do" => self.compile_do(),
+                    "return" => self.compile_return(),
+                    keyword if Self::_is_subroutine_keyword(keyword) => break,
+                    _ => self._error("a statement"),
+                },
+                Some(TokenType::Symbol) if self.tokenizer.symbol() == '}' => break,
+                None => break,
+                _ => self._error("a statement"),
+            };
+            match result {
+                Err(CompileError::Syntax) => self._synchronize_statement(depth)?,
+                result => result?,
             }
         }
+        // code after a block, like the jump back of a `while`, belongs to
+        // the statement the block is in
+        self.vm_writer.set_position(position.0, position.1);
 
         // close statements tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_STATEMENTS))?;
         Ok(())
     }
 
-    pub fn compile_let(&mut self) -> io::Result<()> {
+    pub fn compile_let(&mut self) -> CompileResult<()> {
         // open letStatement tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_STATEMENT_LET))?;
 
@@ -365,8 +620,7 @@ impl CompilationEngine {
         self._eat_keyword()?;
 
         // varName
-        let left = self.tokenizer.identifier();
-        self._eat_identifier("use in let statement")?;
+        let left = self._eat_identifier("use in let statement")?;
 
         let mut is_array = false;
 
@@ -374,80 +628,72 @@ impl CompilationEngine {
         if self.tokenizer.token_type() == Some(TokenType::Symbol) && self.tokenizer.symbol() == '['
         {
             // `[`
-            self._eat_symbol()?;
+            self._eat_symbol('[')?;
 
             is_array = true;
 
             let mut kind = self.subroutine_symbol_table.kind_of(&left);
             let mut index = self.subroutine_symbol_table.index_of(&left);
 
-            if kind == None {
+            if kind.is_none() {
                 kind = self.class_symbol_table.kind_of(&left);
                 index = self.class_symbol_table.index_of(&left);
             }
 
             if let Some(k) = kind {
-                let segment = match k {
-                    symbol_table::Kind::Static => "static",
-                    symbol_table::Kind::Field => "this",
-                    symbol_table::Kind::Arg => "argument",
-                    symbol_table::Kind::Var => "local",
-                };
-                self.vm_writer.writePush(segment, index.unwrap())?;
+                self.vm_writer.write_push(k.into(), index.unwrap());
             } else {
-                panic!();
+                self._report(&format!("undefined variable `{}`", left));
             }
 
             // expression
             self.compile_expression()?;
 
-            self.vm_writer.writeArithmetic("add")?;
+            self.vm_writer.write_arithmetic(ArithmeticOp::Add);
 
             // `]`
-            self._eat_symbol()?;
+            self._eat_symbol(']')?;
         }
 
         // `=`
-        self._eat_symbol()?;
+        self._eat_symbol('=')?;
 
         // expression
         self.compile_expression()?;
 
         if is_array {
-            self.vm_writer.writePop("temp", 0)?;
-            self.vm_writer.writePop("pointer", 1)?;
-            self.vm_writer.writePush("temp", 0)?;
-            self.vm_writer.writePop("that", 0)?;
+            self.vm_writer.write_pop(Segment::Temp, 0);
+            self.vm_writer.write_pop(Segment::Pointer, 1);
+            self.vm_writer.write_push(Segment::Temp, 0);
+            self.vm_writer.write_pop(Segment::That, 0);
         } else {
             // code gen: let left = xxx;
             let mut segment = self.subroutine_symbol_table.kind_of(&left);
             let mut index = self.subroutine_symbol_table.index_of(&left);
-            if segment == None {
+            if segment.is_none() {
                 segment = self.class_symbol_table.kind_of(&left);
                 index = self.class_symbol_table.index_of(&left);
             }
 
             if let Some(seg) = segment {
-                let seg_str = match seg {
-                    symbol_table::Kind::Static => "static",
-                    symbol_table::Kind::Field => "this",
-                    symbol_table::Kind::Arg => "argument",
-                    symbol_table::Kind::Var => "local",
-                };
-                self.vm_writer.writePop(seg_str, index.unwrap())?;
+                self.vm_writer.write_pop(seg.into(), index.unwrap());
+            } else {
+                self._report(&format!("undefined variable `{}`", left));
             }
         }
 
         // `;`
-        self._eat_symbol()?;
+        self._eat_symbol(';')?;
 
         // close letStatement tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_STATEMENT_LET))?;
         Ok(())
     }
 
-    pub fn compile_if(&mut self) -> io::Result<()> {
+    pub fn compile_if(&mut self) -> CompileResult<()> {
         self.statement_id += 1;
+        let else_label = format!("else_{}", self.statement_id);
+        let end_label = format!("end_{}", self.statement_id);
         // open ifStatement tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_STATEMENT_IF))?;
 
@@ -455,31 +701,25 @@ impl CompilationEngine {
         self._eat_keyword()?;
 
         // `(`
-        self._eat_symbol()?;
+        self._eat_symbol('(')?;
 
-        // expression
-        self.compile_expression()?;
+        // expression, jump to else when false
+        self.compile_condition(&else_label)?;
 
         // `)`
-        self._eat_symbol()?;
+        self._eat_symbol(')')?;
 
         // `{`
-        self._eat_symbol()?;
-
-        // code gen
-        self.vm_writer.writeArithmetic("not")?;
-        let else_label = format!("else_{}", self.statement_id);
-        let end_label = format!("end_{}", self.statement_id);
-        self.vm_writer.writeIf(&else_label)?;
+        self._eat_symbol('{')?;
 
         // statements
         self.compile_statements()?;
 
-        self.vm_writer.writeGoto(&end_label)?;
-        self.vm_writer.writeLabel(&else_label)?;
+        self.vm_writer.write_goto(&end_label);
+        self.vm_writer.write_label(&else_label);
 
         // `}`
-        self._eat_symbol()?;
+        self._eat_symbol('}')?;
 
         // optional `else`
         if self.tokenizer.token_type() == Some(TokenType::Keyword)
@@ -489,23 +729,23 @@ impl CompilationEngine {
             self._eat_keyword()?;
 
             // `{`
-            self._eat_symbol()?;
+            self._eat_symbol('{')?;
 
             // statements
             self.compile_statements()?;
 
             // `}`
-            self._eat_symbol()?;
+            self._eat_symbol('}')?;
         }
 
-        self.vm_writer.writeLabel(&end_label)?;
+        self.vm_writer.write_label(&end_label);
 
         // close ifStatement tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_STATEMENT_IF))?;
         Ok(())
     }
 
-    pub fn compile_while(&mut self) -> io::Result<()> {
+    pub fn compile_while(&mut self) -> CompileResult<()> {
         self.statement_id += 1;
         // open whileStatement tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_STATEMENT_WHILE))?;
@@ -514,39 +754,36 @@ impl CompilationEngine {
         self._eat_keyword()?;
 
         let while_start_label = format!("while_start_{}", self.statement_id);
-        self.vm_writer.writeLabel(&while_start_label)?;
+        let end_label = format!("while_end_{}", self.statement_id);
+        self.vm_writer.write_label(&while_start_label);
 
         // `(`
-        self._eat_symbol()?;
-
-        // expression
-        self.compile_expression()?;
+        self._eat_symbol('(')?;
 
-        self.vm_writer.writeArithmetic("not")?;
-        let end_label = format!("while_end_{}", self.statement_id);
-        self.vm_writer.writeIf(&end_label)?;
+        // expression, leave the loop when false
+        self.compile_condition(&end_label)?;
 
         // `)`
-        self._eat_symbol()?;
+        self._eat_symbol(')')?;
 
         // `{`
-        self._eat_symbol()?;
+        self._eat_symbol('{')?;
 
         // statements
         self.compile_statements()?;
 
         // `}`
-        self._eat_symbol()?;
+        self._eat_symbol('}')?;
 
-        self.vm_writer.writeGoto(&while_start_label)?;
-        self.vm_writer.writeLabel(&end_label)?;
+        self.vm_writer.write_goto(&while_start_label);
+        self.vm_writer.write_label(&end_label);
 
         // close whileStatement tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_STATEMENT_WHILE))?;
         Ok(())
     }
 
-    pub fn compile_do(&mut self) -> io::Result<()> {
+    pub fn compile_do(&mut self) -> CompileResult<()> {
         // open doStatement tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_STATEMENT_DO))?;
 
@@ -555,108 +792,128 @@ impl CompilationEngine {
 
         // subroutineCall
         self.compile_subroutine_call()?;
+        // the returned value is not used
+        self.vm_writer.write_pop(Segment::Temp, 0);
 
         // `;`
-        self._eat_symbol()?;
+        self._eat_symbol(';')?;
 
         // close doStatement tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_STATEMENT_DO))?;
         Ok(())
     }
 
-    pub fn compile_return(&mut self) -> io::Result<()> {
+    pub fn compile_return(&mut self) -> CompileResult<()> {
         // open returnStatement tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_STATEMENT_RETURN))?;
 
         // `return`
         self._eat_keyword()?;
 
-        // expression?
-        if self.tokenizer.token_type() != Some(TokenType::Symbol)
-            || self.tokenizer.token_type() == Some(TokenType::Symbol)
-                && self.tokenizer.symbol() != ';'
-        {
-            self.compile_expression()?;
-        } else {
+        // expression?, none before the `;`, or before a `}` that misses it
+        let ends_statement = match self.tokenizer.token_type() {
+            Some(TokenType::Symbol) => matches!(self.tokenizer.symbol(), ';' | '}'),
+            token_type => token_type.is_none(),
+        };
+        if ends_statement {
             // return void
-            self.vm_writer.writePush("constant", 0)?;
+            self.vm_writer.write_push(Segment::Constant, 0);
+        } else {
+            self.compile_expression()?;
         }
 
         // code gen
-        self.vm_writer.writeReturn()?;
+        self.vm_writer.write_return();
 
         // `;`
-        self._eat_symbol()?;
+        self._eat_symbol(';')?;
 
         // close returnStatement tag
         self.print_to_ast(&format!("</{}>\n", XML_TAG_STATEMENT_RETURN))?;
         Ok(())
     }
 
-    pub fn compile_expression(&mut self) -> io::Result<()> {
+    pub fn compile_expression(&mut self) -> CompileResult<()> {
         // open expression tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_EXPRESSION))?;
 
-        // // term (op term)*
-        // loop {
-        //     // term
-        //     self.compile_term()?;
-
-        //     if self.tokenizer.token_type() != Some(TokenType::Symbol)
-        //         || self.tokenizer.token_type() == Some(TokenType::Symbol)
-        //             && !CompilationEngine::_is_op(self.tokenizer.symbol())
-        //     {
-        //         break;
-        //     }
-
-        //     // op
-        //     self._eat_symbol()?;
-        // }
-
-        // left term
+        // term (op term)*, applied from left to right
         self.compile_term()?;
+        while let Some(op) = self._current_op() {
+            self._eat_symbol(op)?;
+            self.compile_term()?;
 
-        if self.tokenizer.token_type() == Some(TokenType::Symbol)
-            && CompilationEngine::_is_op(self.tokenizer.symbol())
-        {
-            // op
-            let op = self.tokenizer.symbol();
-            self._eat_symbol()?;
+            // code gen
+            self._write_op(op);
+        }
+
+        // close expression tag
+        self.print_to_ast(&format!("</{}>\n", XML_TAG_EXPRESSION))?;
+        Ok(())
+    }
 
-            // right term
+    /// Compile the condition of an `if` or `while`, jumping to `false_label`
+    /// when it is false. In short-circuit mode, a `&` or `|` at the top level
+    /// of the condition skips its right-hand side once the left-hand side
+    /// decides the result; its operands are taken as booleans.
+    fn compile_condition(&mut self, false_label: &str) -> CompileResult<()> {
+        if !self.options.short_circuit {
             self.compile_expression()?;
+            self.vm_writer.write_arithmetic(ArithmeticOp::Not);
+            self.vm_writer.write_if(false_label);
+            return Ok(());
+        }
 
-            // code gen
+        // open expression tag
+        self.print_to_ast(&format!("<{}>\n", XML_TAG_EXPRESSION))?;
+
+        // Operators apply from left to right, so a run of `&` jumps to
+        // `false_label` as soon as an operand is false, and a run of `|` to
+        // an `or_true` label as soon as one is true, as long as the run
+        // goes on to the end of the condition. A run followed by another
+        // operator gives its value to that operator instead.
+        self.compile_term()?;
+        let mut run: Option<Run> = None;
+        while let Some(op) = self._current_op() {
+            self._eat_symbol(op)?;
+            if let Some(ended) = run.take_if(|run| run.op != op) {
+                self._end_run(ended, false_label);
+            }
             match op {
-                '+' => {
-                    self.vm_writer.writeArithmetic("add")?;
-                }
-                '-' => {
-                    self.vm_writer.writeArithmetic("sub")?;
-                }
-                '*' => {
-                    self.vm_writer.writeCall("Math.multiply", 2)?;
-                }
-                '/' => {
-                    self.vm_writer.writeCall("Math.divide", 2)?;
-                }
-                '&' => {
-                    self.vm_writer.writeArithmetic("and")?;
-                }
-                '|' => {
-                    self.vm_writer.writeArithmetic("or")?;
-                }
-                '<' => {
-                    self.vm_writer.writeArithmetic("lt")?;
+                '&' | '|' => {
+                    let run = run.get_or_insert_with(|| Run {
+                        op,
+                        start: self.vm_writer.instruction_count(),
+                        or_true: None,
+                    });
+                    if op == '&' {
+                        // false & ... is false
+                        self.vm_writer.write_arithmetic(ArithmeticOp::Not);
+                        self.vm_writer.write_if(false_label);
+                    } else {
+                        // true | ... is true
+                        let or_true = run.or_true.get_or_insert_with(|| {
+                            self.statement_id += 1;
+                            format!("or_true_{}", self.statement_id)
+                        });
+                        self.vm_writer.write_if(or_true);
+                    }
+                    self.compile_term()?;
                 }
-                '>' => {
-                    self.vm_writer.writeArithmetic("gt")?;
+                _ => {
+                    self.compile_term()?;
+                    self._write_op(op);
                 }
-                '=' => {
-                    self.vm_writer.writeArithmetic("eq")?;
-                }
-                _ => panic!(),
-            };
+            }
+        }
+        self.vm_writer.write_arithmetic(ArithmeticOp::Not);
+        self.vm_writer.write_if(false_label);
+        if let Some(Run {
+            or_true: Some(or_true),
+            ..
+        }) = run
+        {
+            self.vm_writer.write_label(&or_true);
         }
 
         // close expression tag
@@ -664,25 +921,94 @@ impl CompilationEngine {
         Ok(())
     }
 
-    pub fn compile_term(&mut self) -> io::Result<()> {
+    /// Put the value of a run of `&` or `|` in a condition on the stack,
+    /// where its last operand is: the operands before it jumped out of the
+    /// run when they decided its value.
+    fn _end_run(&mut self, run: Run, false_label: &str) {
+        self.statement_id += 1;
+        let (decided, value) = match run.or_true {
+            Some(or_true) => (or_true, true),
+            None => {
+                // the jumps of the run weren't to the end of the condition
+                let and_false = format!("and_false_{}", self.statement_id);
+                self.vm_writer.retarget(run.start, false_label, &and_false);
+                (and_false, false)
+            }
+        };
+        let kind = if value { "or" } else { "and" };
+        let end = format!("{kind}_end_{}", self.statement_id);
+        self.vm_writer.write_goto(&end);
+        self.vm_writer.write_label(&decided);
+        self.vm_writer.write_push(Segment::Constant, 0);
+        if value {
+            self.vm_writer.write_arithmetic(ArithmeticOp::Not);
+        }
+        self.vm_writer.write_label(&end);
+    }
+
+    fn _write_string(&mut self, string: &str) {
+        self.vm_writer
+            .write_push(Segment::Constant, string.len() as u16);
+        self.vm_writer.write_call("String.new", 1);
+        for c in string.chars() {
+            self.vm_writer.write_push(Segment::Constant, c as u16);
+            self.vm_writer.write_call("String.appendChar", 2);
+        }
+    }
+
+    /// Every distinct literal of the class is kept in a static of its own,
+    /// named by the quoted literal so no variable can clash with it. The
+    /// static is 0 until the string is built on first use.
+    fn _write_pooled_string(&mut self, string: &str) {
+        let name = format!("\"{string}\"");
+        if self.class_symbol_table.kind_of(&name).is_none() {
+            self.class_symbol_table
+                .define(&name, "String", symbol_table::Kind::Static);
+        }
+        let index = self.class_symbol_table.index_of(&name).unwrap();
+
+        self.statement_id += 1;
+        let ready_label = format!("string_ready_{}", self.statement_id);
+        self.vm_writer.write_push(Segment::Static, index);
+        self.vm_writer.write_if(&ready_label);
+        self._write_string(string);
+        self.vm_writer.write_pop(Segment::Static, index);
+        self.vm_writer.write_label(&ready_label);
+        self.vm_writer.write_push(Segment::Static, index);
+    }
+
+    fn _write_op(&mut self, op: char) {
+        match op {
+            '+' => self.vm_writer.write_arithmetic(ArithmeticOp::Add),
+            '-' => self.vm_writer.write_arithmetic(ArithmeticOp::Sub),
+            '*' => self.vm_writer.write_call("Math.multiply", 2),
+            '/' => self.vm_writer.write_call("Math.divide", 2),
+            '&' => self.vm_writer.write_arithmetic(ArithmeticOp::And),
+            '|' => self.vm_writer.write_arithmetic(ArithmeticOp::Or),
+            '<' => self.vm_writer.write_arithmetic(ArithmeticOp::Lt),
+            '>' => self.vm_writer.write_arithmetic(ArithmeticOp::Gt),
+            '=' => self.vm_writer.write_arithmetic(ArithmeticOp::Eq),
+            _ => panic!(),
+        }
+    }
+
+    pub fn compile_term(&mut self) -> CompileResult<()> {
         // open term tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_TERM))?;
 
         // integer const
         if self.tokenizer.token_type() == Some(TokenType::IntConst) {
             let const_ = self.tokenizer.int_const();
-            self.vm_writer.writePush("constant", const_ as i32)?;
+            self.vm_writer.write_push(Segment::Constant, const_ as u16);
             self._eat_int_const()?;
         }
         // | string const
         else if self.tokenizer.token_type() == Some(TokenType::StringConst) {
             let string = self.tokenizer.string_const();
-            let len = string.len();
-            self.vm_writer.writePush("constant", len as i32)?;
-            self.vm_writer.writeCall("String.new", 1)?;
-            for c in string.chars() {
-                self.vm_writer.writePush("constant", c as i32)?;
-                self.vm_writer.writeCall("String.appendChar", 2)?;
+            if self.options.pool_strings {
+                self._write_pooled_string(&string);
+            } else {
+                self._write_string(&string);
             }
 
             self._eat_string_const()?;
@@ -696,17 +1022,17 @@ impl CompilationEngine {
         {
             match self.tokenizer.keyword().as_str() {
                 "true" => {
-                    self.vm_writer.writePush("constant", 1)?;
-                    self.vm_writer.writeArithmetic("neg")?;
+                    self.vm_writer.write_push(Segment::Constant, 1);
+                    self.vm_writer.write_arithmetic(ArithmeticOp::Neg);
                 }
                 "false" => {
-                    self.vm_writer.writePush("constant", 0)?;
+                    self.vm_writer.write_push(Segment::Constant, 0);
                 }
                 "null" => {
-                    self.vm_writer.writePush("constant", 0)?;
+                    self.vm_writer.write_push(Segment::Constant, 0);
                 }
                 "this" => {
-                    self.vm_writer.writePush("pointer", 0)?;
+                    self.vm_writer.write_push(Segment::Pointer, 0);
                 }
                 _ => panic!(),
             }
@@ -717,28 +1043,28 @@ impl CompilationEngine {
             && self.tokenizer.symbol() == '('
         {
             // `(`
-            self._eat_symbol()?;
+            self._eat_symbol('(')?;
             // expression
             self.compile_expression()?;
             // `)`
-            self._eat_symbol()?;
+            self._eat_symbol(')')?;
         }
         // | unaryOp term
         else if self.tokenizer.token_type() == Some(TokenType::Symbol)
-            && CompilationEngine::_is_unary_op(self.tokenizer.symbol())
+            && Self::_is_unary_op(self.tokenizer.symbol())
         {
             // unaryOp
             let op = self.tokenizer.symbol();
-            self._eat_symbol()?;
+            self._eat_symbol(op)?;
             // term
             self.compile_term()?;
             // code gen
             match op {
                 '-' => {
-                    self.vm_writer.writeArithmetic("neg")?;
+                    self.vm_writer.write_arithmetic(ArithmeticOp::Neg);
                 }
                 '~' => {
-                    self.vm_writer.writeArithmetic("not")?;
+                    self.vm_writer.write_arithmetic(ArithmeticOp::Not);
                 }
                 _ => panic!(),
             }
@@ -746,8 +1072,8 @@ impl CompilationEngine {
         // look ahead two token
         else if self.tokenizer.token_type() == Some(TokenType::Identifier) {
             // first token: varName | className | subroutineName
-            let first_identifier = self.tokenizer.identifier();
-            self._eat_identifier("use in term varName|className|subroutineName")?;
+            let first_identifier =
+                self._eat_identifier("use in term varName|className|subroutineName")?;
 
             // look ahead 2nd token
             // varName[expression]
@@ -755,91 +1081,76 @@ impl CompilationEngine {
                 && self.tokenizer.symbol() == '['
             {
                 // `[`
-                self._eat_symbol()?;
+                self._eat_symbol('[')?;
 
                 let mut kind = self.subroutine_symbol_table.kind_of(&first_identifier);
-                let mut type_ = self.subroutine_symbol_table.type_of(&first_identifier);
                 let mut index = self.subroutine_symbol_table.index_of(&first_identifier);
 
-                if kind == None {
+                if kind.is_none() {
                     kind = self.class_symbol_table.kind_of(&first_identifier);
-                    type_ = self.class_symbol_table.type_of(&first_identifier);
                     index = self.class_symbol_table.index_of(&first_identifier);
                 }
 
                 if let Some(k) = kind {
-                    let segment = match k {
-                        symbol_table::Kind::Static => "static",
-                        symbol_table::Kind::Field => "this",
-                        symbol_table::Kind::Arg => "argument",
-                        symbol_table::Kind::Var => "local",
-                    };
-                    self.vm_writer.writePush(segment, index.unwrap())?;
+                    self.vm_writer.write_push(k.into(), index.unwrap());
                 } else {
-                    panic!();
+                    self._report(&format!("undefined variable `{}`", first_identifier));
                 }
 
                 // expression
                 self.compile_expression()?;
 
-                self.vm_writer.writeArithmetic("add")?;
-                self.vm_writer.writePop("pointer", 1)?;
-                self.vm_writer.writePush("that", 0)?;
+                self.vm_writer.write_arithmetic(ArithmeticOp::Add);
+                self.vm_writer.write_pop(Segment::Pointer, 1);
+                self.vm_writer.write_push(Segment::That, 0);
 
                 // `]`
-                self._eat_symbol()?;
+                self._eat_symbol(']')?;
             }
             // subroutineName(expressionList)
             else if self.tokenizer.token_type() == Some(TokenType::Symbol)
                 && self.tokenizer.symbol() == '('
             {
                 // `(`
-                self._eat_symbol()?;
+                self._eat_symbol('(')?;
 
-                self.vm_writer.writePush("pointer", 0)?;
+                self.vm_writer.write_push(Segment::Pointer, 0);
 
                 // expressionList
                 let n_args = self.compile_expression_list()?;
 
                 // code gen: fn()
                 let method_name = format!("{}.{}", &self.class_name, &first_identifier);
-                self.vm_writer.writeCall(&method_name, n_args + 1)?;
+                self.vm_writer.write_call(&method_name, n_args + 1);
 
                 // `)`
-                self._eat_symbol()?;
+                self._eat_symbol(')')?;
             }
             // (className | varName).subroutineName(expressionList)
             else if self.tokenizer.token_type() == Some(TokenType::Symbol)
                 && self.tokenizer.symbol() == '.'
             {
                 // `.`
-                self._eat_symbol()?;
+                self._eat_symbol('.')?;
 
                 // subroutineName
-                let second_identifier = self.tokenizer.identifier();
-                self._eat_identifier("use in xxx.subroutineName")?;
+                let second_identifier = self._eat_identifier("use in xxx.subroutineName")?;
 
                 // `(`
-                self._eat_symbol()?;
+                self._eat_symbol('(')?;
 
                 let mut kind = self.subroutine_symbol_table.kind_of(&first_identifier);
                 let mut type_ = self.subroutine_symbol_table.type_of(&first_identifier);
                 let mut index = self.subroutine_symbol_table.index_of(&first_identifier);
 
-                if kind == None {
+                if kind.is_none() {
                     kind = self.class_symbol_table.kind_of(&first_identifier);
                     type_ = self.class_symbol_table.type_of(&first_identifier);
                     index = self.class_symbol_table.index_of(&first_identifier);
                 }
 
                 if let Some(k) = kind {
-                    let segment = match k {
-                        symbol_table::Kind::Static => "static",
-                        symbol_table::Kind::Field => "this",
-                        symbol_table::Kind::Arg => "argument",
-                        symbol_table::Kind::Var => "local",
-                    };
-                    self.vm_writer.writePush(segment, index.unwrap())?;
+                    self.vm_writer.write_push(k.into(), index.unwrap());
                 }
 
                 // expressionList
@@ -848,35 +1159,31 @@ impl CompilationEngine {
                 let mut fn_name = format!("{}.{}", first_identifier, second_identifier);
                 if kind.is_some() {
                     fn_name = format!("{}.{}", type_.unwrap(), second_identifier);
-                    self.vm_writer.writeCall(&fn_name, n_args + 1)?;
+                    self.vm_writer.write_call(&fn_name, n_args + 1);
                 } else {
-                    self.vm_writer.writeCall(&fn_name, n_args)?;
+                    self.vm_writer.write_call(&fn_name, n_args);
                 }
 
                 // `)`
-                self._eat_symbol()?;
+                self._eat_symbol(')')?;
             } else {
                 // code gen
                 let mut kind = self.subroutine_symbol_table.kind_of(&first_identifier);
                 let mut index = self.subroutine_symbol_table.index_of(&first_identifier);
 
-                if kind == None {
+                if kind.is_none() {
                     kind = self.class_symbol_table.kind_of(&first_identifier);
                     index = self.class_symbol_table.index_of(&first_identifier);
                 }
 
                 if let Some(k) = kind {
-                    let segment = match k {
-                        symbol_table::Kind::Static => "static",
-                        symbol_table::Kind::Field => "this",
-                        symbol_table::Kind::Arg => "argument",
-                        symbol_table::Kind::Var => "local",
-                    };
-                    self.vm_writer.writePush(segment, index.unwrap())?;
+                    self.vm_writer.write_push(k.into(), index.unwrap());
+                } else {
+                    self._report(&format!("undefined variable `{}`", first_identifier));
                 }
             }
         } else {
-            report_syntax_error("bad term");
+            return self._error("an expression");
         }
 
         // close term tag
@@ -884,7 +1191,7 @@ impl CompilationEngine {
         Ok(())
     }
 
-    pub fn compile_expression_list(&mut self) -> io::Result<i32> {
+    pub fn compile_expression_list(&mut self) -> CompileResult<u16> {
         // open expressionList tag
         self.print_to_ast(&format!("<{}>\n", XML_TAG_EXPRESSION_LIST))?;
 
@@ -909,7 +1216,7 @@ impl CompilationEngine {
                 }
 
                 // `,`
-                self._eat_symbol()?;
+                self._eat_symbol(',')?;
             }
         }
 
@@ -918,8 +1225,8 @@ impl CompilationEngine {
         Ok(count)
     }
 
-    fn compile_type(&mut self) -> io::Result<String> {
-        let mut the_type = String::new();
+    fn compile_type(&mut self) -> CompileResult<String> {
+        let the_type;
         if self.tokenizer.token_type() == Some(TokenType::Keyword) {
             // `int` `char` or `boolean`
             the_type = self.tokenizer.keyword();
@@ -929,62 +1236,58 @@ impl CompilationEngine {
             the_type = self.tokenizer.identifier();
             self._eat_identifier("use as a user define type")?;
         } else {
-            report_syntax_error("");
+            return self._error("a type");
         }
-        Ok(the_type.to_string())
+        Ok(the_type)
     }
 
-    fn compile_subroutine_call(&mut self) -> io::Result<()> {
+    fn compile_subroutine_call(&mut self) -> CompileResult<()> {
         // subroutineName or (className | varName)
-        let first_identifier = self.tokenizer.identifier();
-        self._eat_identifier(
+        let first_identifier = self._eat_identifier(
             "use as subroutineName or (className | varName) in a subroutine call",
         )?;
 
         // look ahead 2nd token
-        if self.tokenizer.symbol() == '(' {
+        if self.tokenizer.token_type() == Some(TokenType::Symbol) && self.tokenizer.symbol() == '('
+        {
             // `(`
-            self._eat_symbol()?;
-            self.vm_writer.writePush("pointer", 0)?;
+            self._eat_symbol('(')?;
+            self.vm_writer.write_push(Segment::Pointer, 0);
 
             // expressionList
             let n_args = self.compile_expression_list()?;
 
             // code gen
             let method_name = format!("{}.{}", &self.class_name, &first_identifier);
-            self.vm_writer.writeCall(&method_name, n_args + 1)?;
+            self.vm_writer.write_call(&method_name, n_args + 1);
 
             // `)`
-            self._eat_symbol()?;
-        } else if self.tokenizer.symbol() == '.' {
+            self._eat_symbol(')')?;
+        } else if self.tokenizer.token_type() == Some(TokenType::Symbol)
+            && self.tokenizer.symbol() == '.'
+        {
             // `.`
-            self._eat_symbol()?;
+            self._eat_symbol('.')?;
 
             // subroutineName
-            let second_identifier = self.tokenizer.identifier();
-            self._eat_identifier("use as a xxx.subroutineName in a subroutine call")?;
+            let second_identifier =
+                self._eat_identifier("use as a xxx.subroutineName in a subroutine call")?;
 
             // `(`
-            self._eat_symbol()?;
+            self._eat_symbol('(')?;
 
             let mut kind = self.subroutine_symbol_table.kind_of(&first_identifier);
             let mut type_ = self.subroutine_symbol_table.type_of(&first_identifier);
             let mut index = self.subroutine_symbol_table.index_of(&first_identifier);
 
-            if kind == None {
+            if kind.is_none() {
                 kind = self.class_symbol_table.kind_of(&first_identifier);
                 type_ = self.class_symbol_table.type_of(&first_identifier);
                 index = self.class_symbol_table.index_of(&first_identifier);
             }
 
             if let Some(k) = kind {
-                let segment = match k {
-                    symbol_table::Kind::Static => "static",
-                    symbol_table::Kind::Field => "this",
-                    symbol_table::Kind::Arg => "argument",
-                    symbol_table::Kind::Var => "local",
-                };
-                self.vm_writer.writePush(segment, index.unwrap())?;
+                self.vm_writer.write_push(k.into(), index.unwrap());
             }
 
             // expressionList
@@ -993,36 +1296,52 @@ impl CompilationEngine {
             let mut fn_name = format!("{}.{}", first_identifier, second_identifier);
             if kind.is_some() {
                 fn_name = format!("{}.{}", type_.unwrap(), second_identifier);
-                self.vm_writer.writeCall(&fn_name, n_args + 1)?;
+                self.vm_writer.write_call(&fn_name, n_args + 1);
             } else {
-                self.vm_writer.writeCall(&fn_name, n_args)?;
+                self.vm_writer.write_call(&fn_name, n_args);
             }
 
             // `)`
-            self._eat_symbol()?;
+            self._eat_symbol(')')?;
         } else {
-            report_syntax_error("syntax error")
+            return self._error("`(` or `.`");
         }
         Ok(())
     }
 
+    fn _document_var(&mut self, kind: symbol_table::Kind, type_: &str, doc: &Option<String>) {
+        self.class_doc.vars.push(VarDoc {
+            kind,
+            type_: type_.to_string(),
+            name: self.tokenizer.identifier(),
+            doc: doc.clone(),
+        });
+    }
+
     fn print_to_ast(&mut self, s: &str) -> io::Result<()> {
         self._output_ast_test_string += s;
-        self.output_ast_file.write_all(s.as_bytes())?;
+        if let Some(output_ast_file) = &mut self.output_ast_file {
+            output_ast_file.write_all(s.as_bytes())?;
+        }
         Ok(())
     }
 
-    fn _eat_keyword(&mut self) -> io::Result<()> {
-        self.print_to_ast(&format!(
-            "<{0}>{1}</{0}>\n",
-            XML_TAG_KEYWORD,
-            self.tokenizer.keyword()
-        ))?;
+    fn _eat_keyword(&mut self) -> CompileResult<String> {
+        if self.tokenizer.token_type() != Some(TokenType::Keyword) {
+            return self._error("a keyword");
+        }
+        let keyword = self.tokenizer.keyword();
+        self.print_to_ast(&format!("<{0}>{1}</{0}>\n", XML_TAG_KEYWORD, keyword))?;
         self._get_next_token()?;
-        Ok(())
+        Ok(keyword)
     }
 
-    fn _eat_symbol(&mut self) -> io::Result<()> {
+    fn _eat_symbol(&mut self, expected: char) -> CompileResult<()> {
+        if self.tokenizer.token_type() != Some(TokenType::Symbol)
+            || self.tokenizer.symbol() != expected
+        {
+            return self._error(&format!("`{}`", expected));
+        }
         let symbol = self.tokenizer.symbol();
         let mut symbol_str = symbol.to_string();
 
@@ -1032,6 +1351,10 @@ impl CompilationEngine {
             symbol_str = "&gt;".to_string();
         } else if symbol == '&' {
             symbol_str = "&amp;".to_string();
+        } else if symbol == '{' {
+            self.brace_depth += 1;
+        } else if symbol == '}' {
+            self.brace_depth -= 1;
         }
 
         self.print_to_ast(&format!("<{0}>{1}</{0}>\n", XML_TAG_SYMBOL, symbol_str))?;
@@ -1039,36 +1362,45 @@ impl CompilationEngine {
         Ok(())
     }
 
-    fn _eat_identifier(&mut self, usage: &str) -> io::Result<()> {
-        let name = self.tokenizer.identifier();
+    /// The current token's name if it is an identifier, without eating it.
+    fn _expect_identifier(&mut self) -> CompileResult<String> {
+        if self.tokenizer.token_type() != Some(TokenType::Identifier) {
+            return self._error("an identifier");
+        }
+        Ok(self.tokenizer.identifier())
+    }
+
+    fn _eat_identifier(&mut self, usage: &str) -> CompileResult<String> {
+        let name = self._expect_identifier()?;
         let mut kind = self.subroutine_symbol_table.kind_of(&name);
-        if kind == None {
+        if kind.is_none() {
             kind = self.class_symbol_table.kind_of(&name);
         }
         let mut index = self.subroutine_symbol_table.index_of(&name);
-        if index == None {
+        if index.is_none() {
             index = self.class_symbol_table.index_of(&name);
         }
         let mut type_ = self.subroutine_symbol_table.type_of(&name);
-        if type_ == None {
+        if type_.is_none() {
             type_ = self.class_symbol_table.type_of(&name);
         }
 
+        let info = format!(
+            "(name: {name}, kind: {kind:?}, type: {type_:?}, index: {index:?}, usage: {usage})"
+        );
         self.print_to_ast(&format!(
             "<{0}>{1}<{2}>{3}</{2}></{0}>\n",
             XML_TAG_IDENTIFIER,
             self.tokenizer.identifier(),
             "info",
-            format!(
-                "(name: {name}, kind: {kind:?}, type: {type_:?}, index: {index:?}, usage: {usage})"
-            )
+            info
         ))?;
         self._get_next_token()?;
 
-        Ok(())
+        Ok(name)
     }
 
-    fn _eat_int_const(&mut self) -> io::Result<()> {
+    fn _eat_int_const(&mut self) -> CompileResult<()> {
         self.print_to_ast(&format!(
             "<{0}>{1}</{0}>\n",
             XML_TAG_INT_CONST,
@@ -1079,7 +1411,7 @@ impl CompilationEngine {
         Ok(())
     }
 
-    fn _eat_string_const(&mut self) -> io::Result<()> {
+    fn _eat_string_const(&mut self) -> CompileResult<()> {
         self.print_to_ast(&format!(
             "<{0}>{1}</{0}>\n",
             XML_TAG_STRING_CONST,
@@ -1090,6 +1422,111 @@ impl CompilationEngine {
         Ok(())
     }
 
+    /// Record a diagnostic at the current token and keep compiling.
+    fn _report(&mut self, message: &str) {
+        self.diagnostics.push(Diagnostic {
+            line: self.tokenizer.line(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Record "expected ..., found ..." at the current token and unwind to
+    /// the nearest recovery point.
+    fn _error<T>(&mut self, expected: &str) -> CompileResult<T> {
+        let found = self.tokenizer.describe_token();
+        self._report(&format!("expected {}, found {}", expected, found));
+        Err(CompileError::Syntax)
+    }
+
+    /// Panic-mode recovery inside a block at `depth`: skip past the `;`
+    /// ending the broken statement, or up to the next statement keyword or
+    /// the `}` closing the block. Nested `{ ... }` are skipped as a whole.
+    fn _synchronize_statement(&mut self, depth: usize) -> io::Result<()> {
+        loop {
+            match self.tokenizer.token_type() {
+                None => return Ok(()),
+                Some(TokenType::Symbol) => match self.tokenizer.symbol() {
+                    ';' if self.brace_depth <= depth => {
+                        self._get_next_token()?;
+                        return Ok(());
+                    }
+                    '}' if self.brace_depth <= depth => return Ok(()),
+                    '}' => self.brace_depth -= 1,
+                    '{' => self.brace_depth += 1,
+                    _ => {}
+                },
+                Some(TokenType::Keyword) => {
+                    let keyword = self.tokenizer.keyword();
+                    if Self::_is_subroutine_keyword(&keyword)
+                        || (self.brace_depth <= depth && Self::_is_statement_keyword(&keyword))
+                    {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+            self._get_next_token()?;
+        }
+    }
+
+    /// Panic-mode recovery in the class body: skip to the next `static`,
+    /// `field`, `constructor`, `function` or `method`, past the `}` closing
+    /// the broken subroutine, or up to the `}` closing the class.
+    fn _synchronize_member(&mut self) -> io::Result<()> {
+        loop {
+            match self.tokenizer.token_type() {
+                None => return Ok(()),
+                Some(TokenType::Keyword) => {
+                    let keyword = self.tokenizer.keyword();
+                    if Self::_is_class_var_keyword(&keyword)
+                        || Self::_is_subroutine_keyword(&keyword)
+                    {
+                        self.brace_depth = 1;
+                        return Ok(());
+                    }
+                }
+                Some(TokenType::Symbol) => match self.tokenizer.symbol() {
+                    '}' if self.brace_depth <= 1 => return Ok(()),
+                    '}' => {
+                        self.brace_depth -= 1;
+                        self._get_next_token()?;
+                        if self.brace_depth == 1 {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    '{' => self.brace_depth += 1,
+                    _ => {}
+                },
+                _ => {}
+            }
+            self._get_next_token()?;
+        }
+    }
+
+    fn _is_class_var_keyword(keyword: &str) -> bool {
+        matches!(keyword, "static" | "field")
+    }
+
+    fn _is_subroutine_keyword(keyword: &str) -> bool {
+        matches!(keyword, "constructor" | "function" | "method")
+    }
+
+    fn _is_statement_keyword(keyword: &str) -> bool {
+        matches!(keyword, "let" | "if" | "while" | "do" | "return")
+    }
+
+    /// The binary operator at the current token, if any.
+    fn _current_op(&self) -> Option<char> {
+        if self.tokenizer.token_type() == Some(TokenType::Symbol)
+            && Self::_is_op(self.tokenizer.symbol())
+        {
+            Some(self.tokenizer.symbol())
+        } else {
+            None
+        }
+    }
+
     fn _is_op(op: char) -> bool {
         matches!(op, '+' | '-' | '*' | '/' | '&' | '|' | '<' | '>' | '=')
     }
@@ -1117,7 +1554,7 @@ mod tests {
             engine._output_ast_test_string,
             "<class>
 <keyword>class</keyword>
-<identifier>Main</identifier>
+<identifier>Main<info>(name: Main, kind: None, type: None, index: None, usage: delcare className)</info></identifier>
 <symbol>{</symbol>
 <symbol>}</symbol>
 </class>
@@ -1140,12 +1577,12 @@ mod tests {
             engine._output_ast_test_string,
             "<class>
 <keyword>class</keyword>
-<identifier>Main</identifier>
+<identifier>Main<info>(name: Main, kind: None, type: None, index: None, usage: delcare className)</info></identifier>
 <symbol>{</symbol>
 <classVarDec>
 <keyword>static</keyword>
 <keyword>boolean</keyword>
-<identifier>test</identifier>
+<identifier>test<info>(name: test, kind: Some(Static), type: Some(\"boolean\"), index: Some(0), usage: delcare varName in class)</info></identifier>
 <symbol>;</symbol>
 </classVarDec>
 <symbol>}</symbol>
@@ -1174,18 +1611,18 @@ mod tests {
             engine._output_ast_test_string,
             "<class>
 <keyword>class</keyword>
-<identifier>Main</identifier>
+<identifier>Main<info>(name: Main, kind: None, type: None, index: None, usage: delcare className)</info></identifier>
 <symbol>{</symbol>
 <classVarDec>
 <keyword>static</keyword>
 <keyword>boolean</keyword>
-<identifier>test1</identifier>
+<identifier>test1<info>(name: test1, kind: Some(Static), type: Some(\"boolean\"), index: Some(0), usage: delcare varName in class)</info></identifier>
 <symbol>;</symbol>
 </classVarDec>
 <classVarDec>
 <keyword>field</keyword>
 <keyword>boolean</keyword>
-<identifier>test2</identifier>
+<identifier>test2<info>(name: test2, kind: Some(Field), type: Some(\"boolean\"), index: Some(0), usage: delcare varName in class)</info></identifier>
 <symbol>;</symbol>
 </classVarDec>
 <symbol>}</symbol>
@@ -1210,7 +1647,7 @@ mod tests {
             "<classVarDec>
 <keyword>static</keyword>
 <keyword>boolean</keyword>
-<identifier>test</identifier>
+<identifier>test<info>(name: test, kind: Some(Static), type: Some(\"boolean\"), index: Some(0), usage: delcare varName in class)</info></identifier>
 <symbol>;</symbol>
 </classVarDec>
 "
@@ -1232,13 +1669,13 @@ mod tests {
             engine._output_ast_test_string,
             "<parameterList>
 <keyword>int</keyword>
-<identifier>a</identifier>
+<identifier>a<info>(name: a, kind: Some(Arg), type: Some(\"int\"), index: Some(0), usage: delcare varName(arg) in parameterList)</info></identifier>
 <symbol>,</symbol>
 <keyword>boolean</keyword>
-<identifier>b</identifier>
+<identifier>b<info>(name: b, kind: Some(Arg), type: Some(\"boolean\"), index: Some(1), usage: delcare varName(arg) in parameterList)</info></identifier>
 <symbol>,</symbol>
 <keyword>char</keyword>
-<identifier>c</identifier>
+<identifier>c<info>(name: c, kind: Some(Arg), type: Some(\"char\"), index: Some(2), usage: delcare varName(arg) in parameterList)</info></identifier>
 </parameterList>
 "
         );
@@ -1279,9 +1716,9 @@ mod tests {
             "<varDec>
 <keyword>var</keyword>
 <keyword>int</keyword>
-<identifier>game</identifier>
+<identifier>game<info>(name: game, kind: Some(Var), type: Some(\"int\"), index: Some(0), usage: delcare varName in subroutine)</info></identifier>
 <symbol>,</symbol>
-<identifier>game2</identifier>
+<identifier>game2<info>(name: game2, kind: Some(Var), type: Some(\"int\"), index: Some(1), usage: delcare varName in subroutine)</info></identifier>
 <symbol>;</symbol>
 </varDec>
 "
@@ -1303,7 +1740,7 @@ mod tests {
         )?;
 
         let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
-        // engine.compile_subroutine_body()?;
+        engine.compile_subroutine_body("Main.main")?;
 
         assert_eq!(
             engine._output_ast_test_string,
@@ -1311,26 +1748,26 @@ mod tests {
 <symbol>{</symbol>
 <varDec>
 <keyword>var</keyword>
-<identifier>SquareGame</identifier>
-<identifier>game</identifier>
+<identifier>SquareGame<info>(name: SquareGame, kind: None, type: None, index: None, usage: use as a user define type)</info></identifier>
+<identifier>game<info>(name: game, kind: Some(Var), type: Some(\"SquareGame\"), index: Some(0), usage: delcare varName in subroutine)</info></identifier>
 <symbol>;</symbol>
 </varDec>
 <varDec>
 <keyword>var</keyword>
 <keyword>int</keyword>
-<identifier>x</identifier>
+<identifier>x<info>(name: x, kind: Some(Var), type: Some(\"int\"), index: Some(1), usage: delcare varName in subroutine)</info></identifier>
 <symbol>,</symbol>
-<identifier>y</identifier>
+<identifier>y<info>(name: y, kind: Some(Var), type: Some(\"int\"), index: Some(2), usage: delcare varName in subroutine)</info></identifier>
 <symbol>;</symbol>
 </varDec>
 <varDec>
 <keyword>var</keyword>
 <keyword>char</keyword>
-<identifier>a</identifier>
+<identifier>a<info>(name: a, kind: Some(Var), type: Some(\"char\"), index: Some(3), usage: delcare varName in subroutine)</info></identifier>
 <symbol>,</symbol>
-<identifier>b</identifier>
+<identifier>b<info>(name: b, kind: Some(Var), type: Some(\"char\"), index: Some(4), usage: delcare varName in subroutine)</info></identifier>
 <symbol>,</symbol>
-<identifier>c</identifier>
+<identifier>c<info>(name: c, kind: Some(Var), type: Some(\"char\"), index: Some(5), usage: delcare varName in subroutine)</info></identifier>
 <symbol>;</symbol>
 </varDec>
 <statements>
@@ -1361,7 +1798,7 @@ mod tests {
             "<subroutineDec>
 <keyword>function</keyword>
 <keyword>void</keyword>
-<identifier>main</identifier>
+<identifier>main<info>(name: main, kind: None, type: None, index: None, usage: delcare subroutineName in class, return type(void))</info></identifier>
 <symbol>(</symbol>
 <parameterList>
 </parameterList>
@@ -1370,8 +1807,8 @@ mod tests {
 <symbol>{</symbol>
 <varDec>
 <keyword>var</keyword>
-<identifier>SquareGame</identifier>
-<identifier>game</identifier>
+<identifier>SquareGame<info>(name: SquareGame, kind: None, type: None, index: None, usage: use as a user define type)</info></identifier>
+<identifier>game<info>(name: game, kind: Some(Var), type: Some(\"SquareGame\"), index: Some(0), usage: delcare varName in subroutine)</info></identifier>
 <symbol>;</symbol>
 </varDec>
 <statements>
@@ -1384,4 +1821,432 @@ mod tests {
 
         Ok(())
     }
+
+    fn messages(engine: &CompilationEngine) -> Vec<String> {
+        engine.diagnostics().iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn test_wrong_symbol_is_reported() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    function void main() {")?;
+        test_file.add_line("        do Output.println() }")?;
+        test_file.add_line("    function void f() { return }")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+
+        assert_eq!(
+            messages(&engine),
+            vec!["3: expected `;`, found `}`", "4: expected `;`, found `}`"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_every_syntax_error() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    function void main() {")?;
+        test_file.add_line("        var int x")?;
+        test_file.add_line("        let x = ;")?;
+        test_file.add_line("        while (x) { let x = x + ; }")?;
+        test_file.add_line("        do Output.printInt(x)")?;
+        test_file.add_line("        return;")?;
+        test_file.add_line("    }")?;
+        test_file.add_line("    method void f( { return; }")?;
+        test_file.add_line("    function int g() { if (1 { return 2; } return 1; }")?;
+        test_file.add_line("    field int y;")?;
+        test_file.add_line("}")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+
+        assert_eq!(
+            messages(&engine),
+            vec![
+                "4: expected `;`, found `let`",
+                "4: expected an expression, found `;`",
+                "5: expected an expression, found `;`",
+                "7: expected `;`, found `return`",
+                "9: expected a type, found `{`",
+                "10: expected `)`, found `{`",
+                "11: `static` and `field` must come before subroutines",
+                "13: expected end of file, found `}`",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_lexical_errors() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    function void main() {")?;
+        test_file.add_line("        do Output.printString(\"abc);")?;
+        test_file.add_line("        do Output.printInt(1 # 2);")?;
+        test_file.add_line("        return;")?;
+        test_file.add_line("    }")?;
+        test_file.add_line("} /* never closed")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+
+        assert_eq!(
+            messages(&engine),
+            vec![
+                "3: string doesn't end on its line",
+                "4: expected `)`, found `do`",
+                "4: expected `)`, found `2`",
+                "4: unexpected character `#`",
+                "7: comment doesn't end",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_undefined_variable() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main { function int main() { let a[1] = b; return c; } }")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+
+        assert_eq!(
+            messages(&engine),
+            vec![
+                "1: undefined variable `a`",
+                "1: undefined variable `b`",
+                "1: undefined variable `c`",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_instructions() -> io::Result<()> {
+        use crate::vm::{ArithmeticOp::*, Segment::*, VmInstruction::*};
+
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Point {")?;
+        test_file.add_line("    field int x;")?;
+        test_file.add_line("    method int neg() { return -x; }")?;
+        test_file.add_line("    function void main() { do Output.printInt(1 + 2); return; }")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+
+        assert_eq!(
+            engine.functions(),
+            [
+                vec![
+                    Function("Point.neg".to_string(), 0),
+                    Push(Argument, 0),
+                    Pop(Pointer, 0),
+                    Push(This, 0),
+                    Arithmetic(Neg),
+                    Return,
+                ],
+                vec![
+                    Function("Point.main".to_string(), 0),
+                    Push(Constant, 1),
+                    Push(Constant, 2),
+                    Arithmetic(Add),
+                    Call("Output.printInt".to_string(), 1),
+                    // `do` drops the returned value
+                    Pop(Temp, 0),
+                    Push(Constant, 0),
+                    Return,
+                ],
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_output_in_memory() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main { function int one() { return 1; } }")?;
+
+        let mut out = vec![];
+        let mut engine = CompilationEngine::with_vm_output(Path::new(&test_file.path), &mut out)?;
+        engine.compile_class()?;
+        drop(engine);
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "function Main.one 0\npush constant 1\nreturn\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_output() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main { function int one() { return 1; } }")?;
+
+        let path = Path::new(&test_file.path);
+        let mut engine = CompilationEngine::without_output(path)?;
+        engine.compile_class()?;
+        assert_eq!(engine.functions().len(), 1);
+
+        let files: Vec<_> = fs::read_dir(path.parent().unwrap())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(files, ["test.test"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimize() -> io::Result<()> {
+        use crate::vm::{Segment::*, VmInstruction::*};
+
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    function int main() {")?;
+        test_file.add_line("        var int x;")?;
+        test_file.add_line("        let x = 2 * 8 + 1;")?;
+        test_file.add_line("        while (false) { let x = x + 1; }")?;
+        test_file.add_line("        return -(-x) + 0;")?;
+        test_file.add_line("    }")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.set_options(CompileOptions {
+            optimize: true,
+            ..Default::default()
+        });
+        engine.compile_class()?;
+
+        assert_eq!(
+            engine.functions(),
+            [vec![
+                Function("Main.main".to_string(), 1),
+                Push(Constant, 17),
+                Pop(Local, 0),
+                Push(Local, 0),
+                Return,
+            ]]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_circuit() -> io::Result<()> {
+        use crate::vm::{ArithmeticOp::*, Segment::*, VmInstruction::*};
+
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    function void f(Array a, int i, int n) {")?;
+        test_file.add_line("        while ((i < n) & (a[i] = 0)) { let i = i + 1; }")?;
+        test_file.add_line("        if ((i = 0) | (i > n)) { let n = 0; }")?;
+        test_file.add_line("        return;")?;
+        test_file.add_line("    }")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+        let ast = engine._output_ast_test_string.clone();
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.set_options(CompileOptions {
+            short_circuit: true,
+            ..Default::default()
+        });
+        engine.compile_class()?;
+        assert_eq!(engine._output_ast_test_string, ast);
+
+        let label = |name: &str| Label(name.to_string());
+        let if_goto = |name: &str| IfGoto(name.to_string());
+        let goto = |name: &str| Goto(name.to_string());
+        assert_eq!(
+            engine.functions(),
+            [vec![
+                Function("Main.f".to_string(), 0),
+                label("while_start_1"),
+                Push(Argument, 1),
+                Push(Argument, 2),
+                Arithmetic(Lt),
+                // a[i] is only read when i < n
+                Arithmetic(Not),
+                if_goto("while_end_1"),
+                Push(Argument, 0),
+                Push(Argument, 1),
+                Arithmetic(Add),
+                Pop(Pointer, 1),
+                Push(That, 0),
+                Push(Constant, 0),
+                Arithmetic(Eq),
+                Arithmetic(Not),
+                if_goto("while_end_1"),
+                Push(Argument, 1),
+                Push(Constant, 1),
+                Arithmetic(Add),
+                Pop(Argument, 1),
+                goto("while_start_1"),
+                label("while_end_1"),
+                Push(Argument, 1),
+                Push(Constant, 0),
+                Arithmetic(Eq),
+                // i > n is only computed when i != 0
+                if_goto("or_true_3"),
+                Push(Argument, 1),
+                Push(Argument, 2),
+                Arithmetic(Gt),
+                Arithmetic(Not),
+                if_goto("else_2"),
+                label("or_true_3"),
+                Push(Constant, 0),
+                Pop(Argument, 2),
+                goto("end_2"),
+                label("else_2"),
+                label("end_2"),
+                Push(Constant, 0),
+                Return,
+            ]]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_strings() -> io::Result<()> {
+        use crate::vm::{Segment::*, VmInstruction::*};
+
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    static int count;")?;
+        test_file.add_line("    function void main() {")?;
+        test_file.add_line("        do Output.printString(\"ab\");")?;
+        test_file.add_line("        do Output.printString(\"\");")?;
+        test_file.add_line("        do Output.printString(\"ab\");")?;
+        test_file.add_line("        return;")?;
+        test_file.add_line("    }")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.set_options(CompileOptions {
+            pool_strings: true,
+            ..Default::default()
+        });
+        engine.compile_class()?;
+
+        let pooled = |index: u16, id: u16, string: &str| {
+            let label = format!("string_ready_{id}");
+            let mut code = vec![Push(Static, index), IfGoto(label.clone())];
+            code.push(Push(Constant, string.len() as u16));
+            code.push(Call("String.new".to_string(), 1));
+            for c in string.chars() {
+                code.push(Push(Constant, c as u16));
+                code.push(Call("String.appendChar".to_string(), 2));
+            }
+            code.extend([Pop(Static, index), Label(label), Push(Static, index)]);
+            code.push(Call("Output.printString".to_string(), 1));
+            code.push(Pop(Temp, 0));
+            code
+        };
+        assert_eq!(
+            engine.functions(),
+            [[
+                vec![Function("Main.main".to_string(), 0)],
+                // `count` is static 0
+                pooled(1, 1, "ab"),
+                pooled(2, 2, ""),
+                pooled(1, 3, "ab"),
+                vec![Push(Constant, 0), Return],
+            ]
+            .concat()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_info() -> io::Result<()> {
+        use crate::debug_info::Variable;
+
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    field int x;")?;
+        test_file.add_line("    method int get(boolean b) {")?;
+        test_file.add_line("        var char c;")?;
+        test_file.add_line("        if (b) {")?;
+        test_file.add_line("            let c = 1;")?;
+        test_file.add_line("        }")?;
+        test_file.add_line("        return x;")?;
+        test_file.add_line("    }")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+
+        let variable = |name: &str, type_: &str, index: u16| Variable {
+            name: name.to_string(),
+            type_: type_.to_string(),
+            index,
+        };
+        let debug_info = engine.debug_info();
+        assert_eq!(debug_info.class_name, "Main");
+        assert_eq!(debug_info.file_name(), "test.test");
+        assert_eq!(debug_info.fields, [variable("x", "int", 0)]);
+        let subroutine = &debug_info.subroutines[0];
+        assert_eq!(subroutine.name, "Main.get");
+        // a method gets its object as argument 0
+        assert_eq!(
+            subroutine.args,
+            [variable("this", "Main", 0), variable("b", "boolean", 1)]
+        );
+        assert_eq!(subroutine.locals, [variable("c", "char", 0)]);
+        // the prologue, the condition, `let`, the end of the `if`, `return`
+        assert_eq!(subroutine.position, (3, 5));
+        assert_eq!(subroutine.lines, [0, 0, 0, 5, 5, 5, 6, 6, 5, 5, 5, 8, 8]);
+        assert_eq!(
+            subroutine.columns,
+            [0, 0, 0, 9, 9, 9, 13, 13, 9, 9, 9, 9, 9]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_ast_file() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main { function int f() { return 1 + 2 + 3; } }")?;
+        CompilationEngine::new(Path::new(&test_file.path))?.compile_class()?;
+
+        // a shorter AST leaves nothing of the longer one behind
+        test_file.clear()?;
+        test_file.add_line("class Main {}")?;
+        let mut engine = CompilationEngine::new(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+        let ast_path = Path::new(&test_file.path).with_extension(OUTPUT_AST_FILE_EXTENSION);
+        assert_eq!(fs::read_to_string(ast_path)?, engine.ast());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+
+use crate::symbol_table::{Kind, SymbolTable};
+
+/// What a debugger needs to know about a compiled class, collected by the
+/// compilation engine: the source lines of its VM code, and the Jack names
+/// of the variables behind each segment.
+#[derive(PartialEq, Eq, Debug, Default, Clone)]
+pub struct ClassDebugInfo {
+    pub file: PathBuf,
+    pub class_name: String,
+    pub statics: Vec<Variable>,
+    pub fields: Vec<Variable>,
+    pub subroutines: Vec<SubroutineDebugInfo>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SubroutineDebugInfo {
+    /// VM function name, `Class.subroutine`
+    pub name: String,
+    /// `constructor`, `function` or `method`
+    pub subroutine_type: String,
+    pub args: Vec<Variable>,
+    pub locals: Vec<Variable>,
+    /// (line, column) of the declaration
+    pub position: (usize, usize),
+    /// source line of each VM instruction of the function, `0` before the
+    /// first statement, empty when the code was optimized
+    pub lines: Vec<usize>,
+    /// source column of each VM instruction, like `lines`
+    pub columns: Vec<usize>,
+}
+
+/// A variable and its index in its segment.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub type_: String,
+    pub index: u16,
+}
+
+impl ClassDebugInfo {
+    /// Name of the source file, like `Main.jack`.
+    pub fn file_name(&self) -> String {
+        self.file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// The variables of a kind in `table`, in index order.
+pub fn variables(table: &SymbolTable, kind: Kind) -> Vec<Variable> {
+    table
+        .names_of(kind)
+        .into_iter()
+        .map(|name| Variable {
+            type_: table.type_of(&name).unwrap(),
+            index: table.index_of(&name).unwrap(),
+            name,
+        })
+        .collect()
+}
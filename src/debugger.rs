@@ -0,0 +1,611 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use crate::{
+    debug_info::{ClassDebugInfo, SubroutineDebugInfo, Variable},
+    emulator::{self, Emulator, Exit},
+    screen,
+};
+
+const HELP: &str = "\
+break <Class.subroutine>   stop when the subroutine is called
+break <File.jack:line>     stop at the statements of a line
+delete <n>                 remove breakpoint n
+info breakpoints           list the breakpoints
+continue, run              run until a breakpoint or the end
+step                       run to the next statement, into calls
+next                       run to the next statement, over calls
+finish                     run until the subroutine returns
+print <name>               show a variable of the current subroutine
+info locals                show every variable in scope
+backtrace                  show the subroutines being run
+screen <image>             write the screen to a .png or .ppm image
+quit                       stop debugging";
+
+/// Where the program should stop.
+#[derive(PartialEq, Eq, Debug, Clone)]
+enum Breakpoint {
+    /// the first statement of a subroutine, by VM function name
+    Function(String),
+    /// the statements on a line of a file, like `Main.jack`
+    Line(String, usize),
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Function(name) => write!(f, "{name}"),
+            Breakpoint::Line(file, line) => write!(f, "{file}:{line}"),
+        }
+    }
+}
+
+/// The statement the innermost subroutine is at.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct Location {
+    /// number of subroutines being run
+    depth: usize,
+    /// (class, subroutine) index in `Debugger::classes`
+    subroutine: (usize, usize),
+    line: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Resume {
+    Continue,
+    Step,
+    Next,
+    Finish,
+}
+
+/// A source-level debugger: runs a program compiled with debug info on the
+/// emulator, stopping at Jack statements, and shows its variables by their
+/// Jack names.
+pub struct Debugger {
+    emulator: Emulator,
+    classes: Vec<ClassDebugInfo>,
+    /// VM function name -> (class, subroutine) index in `classes`
+    subroutines: HashMap<String, (usize, usize)>,
+    /// lines of each class source, read when the debugger starts so they
+    /// match the compiled code
+    sources: Vec<Vec<String>>,
+    /// `None` where a breakpoint was deleted, so the others keep their
+    /// numbers
+    breakpoints: Vec<Option<Breakpoint>>,
+    /// how the program ended, once it did
+    exit: Option<String>,
+}
+
+impl Debugger {
+    pub fn new(emulator: Emulator, classes: Vec<ClassDebugInfo>) -> Self {
+        let mut subroutines = HashMap::new();
+        for (i, class) in classes.iter().enumerate() {
+            for (j, subroutine) in class.subroutines.iter().enumerate() {
+                subroutines.insert(subroutine.name.clone(), (i, j));
+            }
+        }
+        let sources = classes
+            .iter()
+            .map(|class| {
+                fs::read_to_string(&class.file)
+                    .map(|source| source.lines().map(str::to_string).collect())
+                    .unwrap_or_default()
+            })
+            .collect();
+        Debugger {
+            emulator,
+            classes,
+            subroutines,
+            sources,
+            breakpoints: vec![],
+            exit: None,
+        }
+    }
+
+    /// Read commands from `input` until `quit` or the end of it, writing
+    /// what they show to `out`.
+    pub fn run(&mut self, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "stopped before `Sys.init`, `help` lists the commands")?;
+        loop {
+            write!(out, "(jdb) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words[..] {
+                [] => {}
+                ["quit" | "q"] => return Ok(()),
+                ["help" | "h"] => writeln!(out, "{HELP}")?,
+                ["break" | "b", location] => match self.parse_breakpoint(location) {
+                    Ok(breakpoint) => {
+                        writeln!(
+                            out,
+                            "breakpoint {} at {breakpoint}",
+                            self.breakpoints.len() + 1
+                        )?;
+                        self.breakpoints.push(Some(breakpoint));
+                    }
+                    Err(error) => writeln!(out, "{error}")?,
+                },
+                ["delete" | "d", n] => {
+                    let breakpoint = n
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|n| self.breakpoints.get_mut(n.checked_sub(1)?));
+                    match breakpoint {
+                        Some(breakpoint @ Some(_)) => *breakpoint = None,
+                        _ => writeln!(out, "no breakpoint `{n}`")?,
+                    }
+                }
+                ["info", "breakpoints"] => {
+                    for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+                        if let Some(breakpoint) = breakpoint {
+                            writeln!(out, "{} {breakpoint}", i + 1)?;
+                        }
+                    }
+                }
+                ["continue" | "c" | "run" | "r"] => self.resume(Resume::Continue, out)?,
+                ["step" | "s"] => self.resume(Resume::Step, out)?,
+                ["next" | "n"] => self.resume(Resume::Next, out)?,
+                ["finish"] => self.resume(Resume::Finish, out)?,
+                ["print" | "p", name] => match self.variable(name) {
+                    Some(value) => writeln!(out, "{name} = {value}")?,
+                    None if self.location().is_none() => writeln!(out, "not in a subroutine")?,
+                    None => writeln!(out, "no variable `{name}` here")?,
+                },
+                ["info", "locals"] => self.print_variables(out)?,
+                ["backtrace" | "bt"] => self.print_backtrace(out)?,
+                ["screen", path] => {
+                    match screen::write_snapshot(Path::new(path), self.emulator.ram()) {
+                        Ok(()) => writeln!(out, "screen written to {path}")?,
+                        Err(error) => writeln!(out, "{error}")?,
+                    }
+                }
+                _ => writeln!(out, "unknown command `{}`, try `help`", line.trim())?,
+            }
+        }
+    }
+
+    fn parse_breakpoint(&self, location: &str) -> Result<Breakpoint, String> {
+        if let Some((file, line)) = location.split_once(':') {
+            let Ok(line) = line.parse::<usize>() else {
+                return Err(format!("`{line}` is not a line number"));
+            };
+            let Some(class) = self.classes.iter().find(|class| class.file_name() == file) else {
+                return Err(format!("no file `{file}` in the program"));
+            };
+            if line == 0
+                || !class
+                    .subroutines
+                    .iter()
+                    .any(|subroutine| subroutine.lines.contains(&line))
+            {
+                return Err(format!("no code at {file}:{line}"));
+            }
+            Ok(Breakpoint::Line(file.to_string(), line))
+        } else if self.subroutines.contains_key(location) {
+            Ok(Breakpoint::Function(location.to_string()))
+        } else {
+            Err(format!("no subroutine `{location}` in the program"))
+        }
+    }
+
+    fn resume(&mut self, resume: Resume, out: &mut dyn Write) -> io::Result<()> {
+        if let Some(exit) = &self.exit {
+            return writeln!(out, "the program is not running: {exit}");
+        }
+
+        let start = self.location();
+        let mut previous = start;
+        loop {
+            let exit = match self.emulator.step() {
+                Ok(None) => None,
+                Ok(Some(Exit::Halt)) => Some("halted".to_string()),
+                Ok(Some(Exit::Error(code))) => Some(format!("stopped with ERR{code}")),
+                Err(error) => Some(format!("failed: {error}")),
+            };
+            if let Some(exit) = exit {
+                self.flush_program_output()?;
+                writeln!(out, "program {exit}")?;
+                self.exit = Some(exit);
+                return Ok(());
+            }
+
+            let location = self.location();
+            if let Some(location) = location.filter(|location| Some(*location) != previous) {
+                let stop = match (resume, start) {
+                    (Resume::Continue, _) => false,
+                    (_, None) => true,
+                    (Resume::Step, Some(start)) => location != start,
+                    (Resume::Next, Some(start)) => {
+                        location.depth <= start.depth && location != start
+                    }
+                    (Resume::Finish, Some(start)) => location.depth < start.depth,
+                };
+                let breakpoint = self.breakpoints.iter().position(|breakpoint| {
+                    breakpoint
+                        .as_ref()
+                        .is_some_and(|breakpoint| self.hits(breakpoint, location, previous))
+                });
+                if stop || breakpoint.is_some() {
+                    self.flush_program_output()?;
+                    if let Some(i) = breakpoint {
+                        let breakpoint = self.breakpoints[i].as_ref().unwrap();
+                        writeln!(out, "breakpoint {}, {breakpoint}", i + 1)?;
+                    }
+                    return self.print_location(location, out);
+                }
+            }
+            previous = location;
+        }
+    }
+
+    fn hits(
+        &self,
+        breakpoint: &Breakpoint,
+        location: Location,
+        previous: Option<Location>,
+    ) -> bool {
+        let (class, subroutine) = self.info(location);
+        match breakpoint {
+            // the first statement, not one after a call returned
+            Breakpoint::Function(name) => {
+                subroutine.name == *name
+                    && previous.is_none_or(|previous| previous.depth < location.depth)
+            }
+            Breakpoint::Line(file, line) => location.line == *line && class.file_name() == *file,
+        }
+    }
+
+    fn flush_program_output(&mut self) -> io::Result<()> {
+        self.emulator.flush().map_err(io::Error::other)
+    }
+
+    /// The statement being run, unless the innermost function has no debug
+    /// info or is between statements, like in its prologue.
+    fn location(&self) -> Option<Location> {
+        let (function, index) = self.emulator.position()?;
+        let &subroutine = self.subroutines.get(function)?;
+        let (class, info) = (subroutine.0, &self.classes[subroutine.0]);
+        let line = *info.subroutines[subroutine.1].lines.get(index)?;
+        (line > 0).then_some(Location {
+            depth: self.emulator.depth(),
+            subroutine: (class, subroutine.1),
+            line,
+        })
+    }
+
+    fn info(&self, location: Location) -> (&ClassDebugInfo, &SubroutineDebugInfo) {
+        let class = &self.classes[location.subroutine.0];
+        (class, &class.subroutines[location.subroutine.1])
+    }
+
+    fn print_location(&self, location: Location, out: &mut dyn Write) -> io::Result<()> {
+        let (class, subroutine) = self.info(location);
+        let text = self.sources[location.subroutine.0]
+            .get(location.line - 1)
+            .map_or("", |text| text.trim());
+        writeln!(
+            out,
+            "{} at {}:{}: {text}",
+            subroutine.name,
+            class.file_name(),
+            location.line
+        )
+    }
+
+    fn print_backtrace(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (i, (function, index)) in self.emulator.backtrace().into_iter().enumerate() {
+            let line = self
+                .subroutines
+                .get(function)
+                .and_then(|&(class, subroutine)| {
+                    let line = *self.classes[class].subroutines[subroutine]
+                        .lines
+                        .get(index)?;
+                    (line > 0).then(|| format!(" at {}:{line}", self.classes[class].file_name()))
+                });
+            writeln!(out, "#{i} {function}{}", line.unwrap_or_default())?;
+        }
+        Ok(())
+    }
+
+    /// Every variable the current subroutine sees, as declared in Jack.
+    fn print_variables(&self, out: &mut dyn Write) -> io::Result<()> {
+        let Some(location) = self.location() else {
+            return writeln!(out, "not in a subroutine");
+        };
+        let (class, subroutine) = self.info(location);
+        let ram = self.emulator.ram();
+        let groups = [
+            ("argument", &subroutine.args, Some(ram[emulator::ARG])),
+            ("var", &subroutine.locals, Some(ram[emulator::LCL])),
+            ("field", &class.fields, self.this()),
+            ("static", &class.statics, None),
+        ];
+        for (kind, variables, base) in groups {
+            for variable in variables {
+                if kind == "field" && base.is_none() {
+                    break;
+                }
+                let value = self.value(&class.class_name, variable, base);
+                writeln!(out, "{kind} {} {} = {value}", variable.type_, variable.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The value of a variable the current subroutine sees, by name.
+    fn variable(&self, name: &str) -> Option<String> {
+        let (class, subroutine) = self.info(self.location()?);
+        let ram = self.emulator.ram();
+        let find =
+            |variables: &'_ [Variable]| variables.iter().position(|variable| variable.name == name);
+        let (variable, base) = if let Some(i) = find(&subroutine.locals) {
+            (&subroutine.locals[i], Some(ram[emulator::LCL]))
+        } else if let Some(i) = find(&subroutine.args) {
+            (&subroutine.args[i], Some(ram[emulator::ARG]))
+        } else if let (Some(i), Some(this)) = (find(&class.fields), self.this()) {
+            (&class.fields[i], Some(this))
+        } else if let Some(i) = find(&class.statics) {
+            (&class.statics[i], None)
+        } else {
+            return None;
+        };
+        Some(self.value(&class.class_name, variable, base))
+    }
+
+    /// The object a method or constructor works on.
+    fn this(&self) -> Option<u16> {
+        let (_, subroutine) = self.info(self.location()?);
+        (subroutine.subroutine_type != "function").then(|| self.emulator.ram()[emulator::THIS])
+    }
+
+    /// The value of a variable at `base + index`, or of a static of
+    /// `class_name` when there is no base, shown as its type.
+    fn value(&self, class_name: &str, variable: &Variable, base: Option<u16>) -> String {
+        let ram = self.emulator.ram();
+        let address = match base {
+            Some(base) => Some(base.wrapping_add(variable.index)),
+            // a static the program never uses is never set either
+            None => self.emulator.static_address(class_name, variable.index),
+        };
+        let value = address.map_or(0, |address| ram[address as usize % ram.len()]);
+        format_value(ram, &variable.type_, value)
+    }
+}
+
+fn format_value(ram: &[u16], type_: &str, value: u16) -> String {
+    match type_ {
+        "int" => (value as i16).to_string(),
+        "boolean" => match value {
+            0 => "false".to_string(),
+            u16::MAX => "true".to_string(),
+            value => (value as i16).to_string(),
+        },
+        "char" => match char::from_u32(value as u32) {
+            Some(c) if c.is_ascii_graphic() || c == ' ' => format!("'{c}'"),
+            _ => value.to_string(),
+        },
+        _ if value == 0 => "null".to_string(),
+        "String" => match string_text(ram, value as usize) {
+            Some(text) => format!("{text:?}"),
+            None => format!("String@{value}"),
+        },
+        _ => format!("{type_}@{value}"),
+    }
+}
+
+/// The text of a Jack string, laid out as `[max length, length, chars..]`.
+fn string_text(ram: &[u16], string: usize) -> Option<String> {
+    let length = *ram.get(string + 1)? as usize;
+    let chars = ram.get(string + 2..string + 2 + length)?;
+    Some(
+        chars
+            .iter()
+            .map(|&c| char::from_u32(c as u32).unwrap_or('?'))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::test_file::{self, TempDir};
+
+    /// Debug a `Main` class with the given commands, returning what the
+    /// debugger shows.
+    fn debug(source: &str, commands: &str) -> io::Result<String> {
+        let linked = test_file::compile_and_link(source, "")?;
+        let mut debugger = Debugger::new(linked.emulator, vec![linked.debug_info]);
+        let mut out = vec![];
+        debugger.run(&mut commands.as_bytes(), &mut out)?;
+        Ok(String::from_utf8(out).unwrap().replace("(jdb) ", "> "))
+    }
+
+    const SOURCE: &str = "class Main {
+    static boolean done;
+    function void main() {
+        var int i, sum;
+        var String s;
+        let s = \"hi\";
+        while (i < 3) {
+            let sum = sum + Main.twice(i);
+            let i = i + 1;
+        }
+        let done = true;
+        return;
+    }
+    function int twice(int n) {
+        var char c;
+        let c = 65;
+        return n + n;
+    }
+}";
+
+    #[test]
+    fn test_breakpoints() -> io::Result<()> {
+        let out = debug(
+            SOURCE,
+            "break Main.twice
+break test.test:11
+break Main.thrice
+break test.test:2
+continue
+backtrace
+info locals
+finish
+print sum
+delete 1
+info breakpoints
+continue
+print done
+print sum
+print n
+continue
+continue
+",
+        )?;
+        assert_eq!(
+            out,
+            "stopped before `Sys.init`, `help` lists the commands
+> breakpoint 1 at Main.twice
+> breakpoint 2 at test.test:11
+> no subroutine `Main.thrice` in the program
+> no code at test.test:2
+> breakpoint 1, Main.twice
+Main.twice at test.test:16: let c = 65;
+> #0 Main.twice at test.test:16
+#1 Main.main at test.test:8
+#2 Sys.init
+> argument int n = 0
+var char c = 0
+static boolean done = false
+> Main.main at test.test:8: let sum = sum + Main.twice(i);
+> sum = 0
+> > 2 test.test:11
+> breakpoint 2, test.test:11
+Main.main at test.test:11: let done = true;
+> done = false
+> sum = 6
+> no variable `n` here
+> program halted
+> the program is not running: halted
+> 
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_step() -> io::Result<()> {
+        let out = debug(
+            SOURCE,
+            "print i
+step
+next
+next
+print s
+next
+step
+step
+print c
+next
+next
+next
+",
+        )?;
+        assert_eq!(
+            out,
+            "stopped before `Sys.init`, `help` lists the commands
+> not in a subroutine
+> Main.main at test.test:6: let s = \"hi\";
+> Main.main at test.test:7: while (i < 3) {
+> Main.main at test.test:8: let sum = sum + Main.twice(i);
+> s = \"hi\"
+> Main.main at test.test:9: let i = i + 1;
+> Main.main at test.test:7: while (i < 3) {
+> Main.main at test.test:8: let sum = sum + Main.twice(i);
+> no variable `c` here
+> Main.main at test.test:9: let i = i + 1;
+> Main.main at test.test:7: while (i < 3) {
+> Main.main at test.test:8: let sum = sum + Main.twice(i);
+> 
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_screen() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let during = dir.path().join("during.ppm");
+        let after = dir.path().join("after.ppm");
+        let out = debug(
+            "class Main {
+    function void main() {
+        do Screen.drawRectangle(0, 0, 10, 10);
+        do Screen.drawLine(100, 100, 200, 100);
+        return;
+    }
+}",
+            &format!(
+                "break test.test:4
+continue
+screen {}
+continue
+screen {}
+screen screen.txt
+",
+                during.display(),
+                after.display()
+            ),
+        )?;
+        assert_eq!(
+            out,
+            format!(
+                "stopped before `Sys.init`, `help` lists the commands
+> breakpoint 1 at test.test:4
+> breakpoint 1, test.test:4
+Main.main at test.test:4: do Screen.drawLine(100, 100, 200, 100);
+> screen written to {}
+> program halted
+> screen written to {}
+> `screen.txt` is not a .png or .ppm file
+> 
+",
+                during.display(),
+                after.display()
+            )
+        );
+
+        // the line is drawn after the snapshot taken at the breakpoint
+        let during = fs::read(during)?;
+        let after = fs::read(after)?;
+        assert_ne!(during, after);
+        assert_ne!(during, screen::to_ppm(&[0; screen::KBD + 1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_value() {
+        let mut ram = vec![0; 8];
+        ram[2..7].copy_from_slice(&[5, 2, b'o' as u16, b'k' as u16, 0]);
+        assert_eq!(format_value(&ram, "int", 65535), "-1");
+        assert_eq!(format_value(&ram, "boolean", 65535), "true");
+        assert_eq!(format_value(&ram, "char", 65), "'A'");
+        assert_eq!(format_value(&ram, "char", 128), "128");
+        assert_eq!(format_value(&ram, "String", 2), "\"ok\"");
+        assert_eq!(format_value(&ram, "String", 0), "null");
+        assert_eq!(format_value(&ram, "Array", 2048), "Array@2048");
+    }
+}
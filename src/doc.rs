@@ -0,0 +1,375 @@
+use crate::{symbol_table::Kind, tokenizer::Comment};
+
+/// API documentation of a class, collected by the compilation engine from
+/// its declarations and the `/** ... */` comments in front of them.
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct ClassDoc {
+    pub name: String,
+    pub doc: Option<String>,
+    pub vars: Vec<VarDoc>,
+    pub subroutines: Vec<SubroutineDoc>,
+}
+
+/// A `static` or `field` variable.
+#[derive(PartialEq, Eq, Debug)]
+pub struct VarDoc {
+    pub kind: Kind,
+    pub type_: String,
+    pub name: String,
+    pub doc: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct SubroutineDoc {
+    /// `constructor`, `function` or `method`
+    pub subroutine_type: String,
+    pub return_type: String,
+    pub name: String,
+    /// (name, type) pairs in declaration order
+    pub parameters: Vec<(String, String)>,
+    pub doc: Option<String>,
+}
+
+impl SubroutineDoc {
+    pub fn signature(&self) -> String {
+        let parameters = self
+            .parameters
+            .iter()
+            .map(|(name, type_)| format!("{type_} {name}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "{} {} {}({})",
+            self.subroutine_type, self.return_type, self.name, parameters
+        )
+    }
+}
+
+/// The text of the last doc comment in `comments`, without the comment
+/// markers and the leading `*` of each line.
+pub fn doc_text(comments: &[Comment]) -> Option<String> {
+    let body = comments
+        .iter()
+        .rev()
+        .filter(|c| c.is_doc_comment())
+        .find_map(|c| c.text.strip_prefix("/**")?.strip_suffix("*/"))?;
+    let lines = body
+        .lines()
+        .map(|line| {
+            let line = line.trim();
+            line.strip_prefix('*').unwrap_or(line).trim()
+        })
+        .collect::<Vec<_>>();
+    Some(lines.join("\n").trim().to_string())
+}
+
+const SUBROUTINE_SECTIONS: [(&str, &str); 3] = [
+    ("constructor", "Constructors"),
+    ("method", "Methods"),
+    ("function", "Functions"),
+];
+
+pub fn render_markdown(class: &ClassDoc) -> String {
+    let mut buf = format!("# class {}\n", class.name);
+    if let Some(doc) = &class.doc {
+        buf += &format!("\n{doc}\n");
+    }
+
+    if !class.vars.is_empty() {
+        buf += "\n## Fields\n\n";
+        for var in &class.vars {
+            buf += &format!("- `{} {} {}`", kind_keyword(var.kind), var.type_, var.name);
+            if let Some(doc) = &var.doc {
+                buf += &format!(": {}", doc.replace('\n', " "));
+            }
+            buf += "\n";
+        }
+    }
+
+    for (subroutine_type, title) in SUBROUTINE_SECTIONS {
+        let subroutines = class
+            .subroutines
+            .iter()
+            .filter(|s| s.subroutine_type == subroutine_type)
+            .collect::<Vec<_>>();
+        if subroutines.is_empty() {
+            continue;
+        }
+
+        buf += &format!("\n## {title}\n");
+        for subroutine in subroutines {
+            buf += &format!("\n### {}\n\n", subroutine.name);
+            buf += &format!("```\n{}\n```\n", subroutine.signature());
+            if let Some(doc) = &subroutine.doc {
+                buf += &format!("\n{doc}\n");
+            }
+            if !subroutine.parameters.is_empty() {
+                buf += "\nParameters:\n\n";
+                for (name, type_) in &subroutine.parameters {
+                    buf += &format!("- `{name}`: `{type_}`\n");
+                }
+            }
+            buf += &format!("\nReturns: `{}`\n", subroutine.return_type);
+        }
+    }
+
+    buf
+}
+
+pub fn render_html(class: &ClassDoc) -> String {
+    let mut buf = String::new();
+    buf += "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n";
+    buf += &format!("<title>class {}</title>\n", escape(&class.name));
+    buf += "</head>\n<body>\n";
+    buf += &format!("<h1>class {}</h1>\n", escape(&class.name));
+    if let Some(doc) = &class.doc {
+        buf += &format!("<p>{}</p>\n", escape(doc));
+    }
+
+    if !class.vars.is_empty() {
+        buf += "<h2>Fields</h2>\n<ul>\n";
+        for var in &class.vars {
+            buf += &format!(
+                "<li><code>{} {} {}</code>",
+                kind_keyword(var.kind),
+                escape(&var.type_),
+                escape(&var.name)
+            );
+            if let Some(doc) = &var.doc {
+                buf += &format!(": {}", escape(doc));
+            }
+            buf += "</li>\n";
+        }
+        buf += "</ul>\n";
+    }
+
+    for (subroutine_type, title) in SUBROUTINE_SECTIONS {
+        let subroutines = class
+            .subroutines
+            .iter()
+            .filter(|s| s.subroutine_type == subroutine_type)
+            .collect::<Vec<_>>();
+        if subroutines.is_empty() {
+            continue;
+        }
+
+        buf += &format!("<h2>{title}</h2>\n");
+        for subroutine in subroutines {
+            buf += &format!("<h3 id=\"{0}\">{0}</h3>\n", escape(&subroutine.name));
+            buf += &format!("<pre>{}</pre>\n", escape(&subroutine.signature()));
+            if let Some(doc) = &subroutine.doc {
+                buf += &format!("<p>{}</p>\n", escape(doc));
+            }
+            if !subroutine.parameters.is_empty() {
+                buf += "<p>Parameters:</p>\n<ul>\n";
+                for (name, type_) in &subroutine.parameters {
+                    buf += &format!(
+                        "<li><code>{}</code>: <code>{}</code></li>\n",
+                        escape(name),
+                        escape(type_)
+                    );
+                }
+                buf += "</ul>\n";
+            }
+            buf += &format!(
+                "<p>Returns: <code>{}</code></p>\n",
+                escape(&subroutine.return_type)
+            );
+        }
+    }
+
+    buf += "</body>\n</html>\n";
+    buf
+}
+
+fn kind_keyword(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Static => "static",
+        Kind::Field => "field",
+        Kind::Arg => "argument",
+        Kind::Var => "var",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::Path};
+
+    use super::*;
+    use crate::{compilation_engine::CompilationEngine, test_file::*};
+
+    #[test]
+    fn test_doc_text() {
+        let comments = [
+            Comment {
+                text: "/** old */".to_string(),
+                line: 1,
+            },
+            Comment {
+                text: "/** Draws the square.\n     *  Fast. */".to_string(),
+                line: 2,
+            },
+            Comment {
+                text: "// not a doc comment".to_string(),
+                line: 4,
+            },
+        ];
+        assert_eq!(
+            doc_text(&comments),
+            Some("Draws the square.\nFast.".to_string())
+        );
+        assert_eq!(doc_text(&comments[2..]), None);
+    }
+
+    #[test]
+    fn test_unterminated_doc_comment() -> io::Result<()> {
+        let comments = [Comment {
+            text: "/**".to_string(),
+            line: 1,
+        }];
+        assert_eq!(doc_text(&comments), None);
+
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("/**")?;
+
+        let mut engine = CompilationEngine::without_output(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+        let messages = engine
+            .diagnostics()
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>();
+        assert!(messages.contains(&"1: comment doesn't end".to_string()));
+        assert_eq!(engine.class_doc().doc, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_doc() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("/** A square. */")?;
+        test_file.add_line("class Square {")?;
+        test_file.add_line("    /** Position. */")?;
+        test_file.add_line("    field int x, y;")?;
+        test_file.add_line("    static boolean flag;")?;
+        test_file.add_line("    /** Makes a square.")?;
+        test_file.add_line("     *  Size in pixels. */")?;
+        test_file.add_line("    constructor Square new(int ax, int size) { return this; }")?;
+        test_file.add_line("    // not documented")?;
+        test_file.add_line("    method void draw() { return; }")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::without_output(Path::new(&test_file.path))?;
+        engine.compile_class()?;
+        let class = engine.class_doc();
+
+        assert_eq!(class.name, "Square");
+        assert_eq!(class.doc, Some("A square.".to_string()));
+        assert_eq!(
+            class.vars,
+            vec![
+                VarDoc {
+                    kind: Kind::Field,
+                    type_: "int".to_string(),
+                    name: "x".to_string(),
+                    doc: Some("Position.".to_string())
+                },
+                VarDoc {
+                    kind: Kind::Field,
+                    type_: "int".to_string(),
+                    name: "y".to_string(),
+                    doc: Some("Position.".to_string())
+                },
+                VarDoc {
+                    kind: Kind::Static,
+                    type_: "boolean".to_string(),
+                    name: "flag".to_string(),
+                    doc: None
+                },
+            ]
+        );
+        assert_eq!(
+            class.subroutines,
+            vec![
+                SubroutineDoc {
+                    subroutine_type: "constructor".to_string(),
+                    return_type: "Square".to_string(),
+                    name: "new".to_string(),
+                    parameters: vec![
+                        ("ax".to_string(), "int".to_string()),
+                        ("size".to_string(), "int".to_string())
+                    ],
+                    doc: Some("Makes a square.\nSize in pixels.".to_string())
+                },
+                SubroutineDoc {
+                    subroutine_type: "method".to_string(),
+                    return_type: "void".to_string(),
+                    name: "draw".to_string(),
+                    parameters: vec![],
+                    doc: None
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        let class = ClassDoc {
+            name: "Math".to_string(),
+            doc: Some("Math operations.".to_string()),
+            vars: vec![],
+            subroutines: vec![SubroutineDoc {
+                subroutine_type: "function".to_string(),
+                return_type: "int".to_string(),
+                name: "max".to_string(),
+                parameters: vec![
+                    ("a".to_string(), "int".to_string()),
+                    ("b".to_string(), "int".to_string()),
+                ],
+                doc: Some("Returns the greater number.".to_string()),
+            }],
+        };
+
+        assert_eq!(
+            render_markdown(&class),
+            "# class Math
+
+Math operations.
+
+## Functions
+
+### max
+
+```
+function int max(int a, int b)
+```
+
+Returns the greater number.
+
+Parameters:
+
+- `a`: `int`
+- `b`: `int`
+
+Returns: `int`
+"
+        );
+
+        let html = render_html(&class);
+        assert!(html.contains("<h1>class Math</h1>"));
+        assert!(html.contains("<pre>function int max(int a, int b)</pre>"));
+        assert!(html.contains("<li><code>a</code>: <code>int</code></li>"));
+    }
+}
@@ -0,0 +1,680 @@
+use std::collections::HashMap;
+
+use crate::{
+    os::{Os, OsFunction, OsReturn},
+    profiler::{Profile, Profiler},
+    vm::{self, ArithmeticOp, Segment, VmInstruction},
+};
+
+pub const RAM_SIZE: usize = 32768;
+
+const SP: usize = 0;
+pub const LCL: usize = 1;
+pub const ARG: usize = 2;
+pub const THIS: usize = 3;
+const THAT: usize = 4;
+const TEMP: usize = 5;
+const FIRST_STATIC_ADDRESS: u16 = 16;
+const LAST_STATIC_ADDRESS: u16 = 255;
+const STACK_START: u16 = 256;
+const STACK_END: u16 = 2048;
+
+/// Why a program stopped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exit {
+    /// `Sys.halt`, the end of `Sys.init`, or a `goto` to itself
+    Halt,
+    /// `Sys.error`, or an OS function called with a bad argument
+    Error(i16),
+}
+
+/// A VM instruction with its label, static and function names resolved.
+#[derive(Clone, Copy, Debug)]
+enum Instruction {
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Arithmetic(ArithmeticOp),
+    Label,
+    Goto(usize),
+    IfGoto(usize),
+    /// a `goto` to itself: the way a program halts
+    Halt,
+    Call(Callee, u16),
+    Function(u16),
+    Return,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Callee {
+    /// index into `Emulator::functions`
+    Vm(usize),
+    Os(OsFunction),
+}
+
+struct Function {
+    name: String,
+    start: usize,
+}
+
+/// Runs linked VM code with Hack semantics: 16 bit words wrapping around,
+/// the standard memory map and call frames. OS functions the program
+/// doesn't define are run natively by [`Os`].
+///
+/// The instructions are numbered like the VM code they are loaded from, one
+/// per instruction, labels included.
+pub struct Emulator {
+    ram: Vec<u16>,
+    code: Vec<Instruction>,
+    functions: Vec<Function>,
+    /// static `index` of a class -> address
+    statics: HashMap<(String, u16), u16>,
+    pc: usize,
+    /// functions being run, `Sys.init` first
+    call_stack: Vec<usize>,
+    steps: u64,
+    os: Os,
+    profiler: Option<Profiler>,
+}
+
+impl Emulator {
+    /// Load a program linked with [`crate::linker::link`], calling `Sys.init`
+    /// like the Hack bootstrap does.
+    pub fn new(program: &[Vec<VmInstruction>], os: Os) -> Result<Self, String> {
+        let mut emulator = Emulator {
+            ram: vec![0; RAM_SIZE],
+            code: vec![],
+            functions: vec![],
+            statics: HashMap::new(),
+            pc: 0,
+            call_stack: vec![],
+            steps: 0,
+            os,
+            profiler: None,
+        };
+        emulator.load(program)?;
+
+        let Some(sys_init) = emulator.function_index("Sys.init") else {
+            return Err("`Sys.init` is missing".to_string());
+        };
+        emulator.ram[SP] = STACK_START;
+        // `Sys.init` returns to the end of the code, which halts
+        emulator.call(sys_init, 0, emulator.code.len())?;
+        Ok(emulator)
+    }
+
+    fn load(&mut self, program: &[Vec<VmInstruction>]) -> Result<(), String> {
+        for function in program {
+            self.functions.push(Function {
+                name: vm::function_name(function).to_string(),
+                start: self.code.len(),
+            });
+            self.code
+                .extend(function.iter().map(|_| Instruction::Label));
+        }
+        if self.code.len() >= u16::MAX as usize {
+            return Err("the program is too long".to_string());
+        }
+
+        let mut next_static = FIRST_STATIC_ADDRESS;
+        for (function_index, function) in program.iter().enumerate() {
+            let start = self.functions[function_index].start;
+            let name = &self.functions[function_index].name;
+            let class_name = name.split('.').next().unwrap().to_string();
+
+            let mut labels = HashMap::new();
+            for (i, instruction) in function.iter().enumerate() {
+                if let VmInstruction::Label(label) = instruction {
+                    labels.insert(label.as_str(), start + i);
+                }
+            }
+            let label = |label: &String| {
+                labels
+                    .get(label.as_str())
+                    .copied()
+                    .ok_or_else(|| format!("{name}: label `{label}` is not defined"))
+            };
+
+            for (i, instruction) in function.iter().enumerate() {
+                self.code[start + i] = match instruction {
+                    VmInstruction::Push(Segment::Static, index)
+                    | VmInstruction::Pop(Segment::Static, index) => {
+                        let key = (class_name.clone(), *index);
+                        let address = match self.statics.get(&key) {
+                            Some(address) => *address,
+                            None if next_static > LAST_STATIC_ADDRESS => {
+                                return Err(format!("{name}: too many static variables"));
+                            }
+                            None => {
+                                self.statics.insert(key, next_static);
+                                next_static += 1;
+                                next_static - 1
+                            }
+                        };
+                        match instruction {
+                            VmInstruction::Push(..) => Instruction::Push(Segment::Static, address),
+                            _ => Instruction::Pop(Segment::Static, address),
+                        }
+                    }
+                    VmInstruction::Push(segment, index) => Instruction::Push(*segment, *index),
+                    VmInstruction::Pop(segment, index) => Instruction::Pop(*segment, *index),
+                    VmInstruction::Arithmetic(op) => Instruction::Arithmetic(*op),
+                    VmInstruction::Label(_) => Instruction::Label,
+                    VmInstruction::Goto(target) => {
+                        let target = label(target)?;
+                        // only labels between the target and the `goto`
+                        if target <= start + i
+                            && (target..start + i)
+                                .all(|pc| matches!(function[pc - start], VmInstruction::Label(_)))
+                        {
+                            Instruction::Halt
+                        } else {
+                            Instruction::Goto(target)
+                        }
+                    }
+                    VmInstruction::IfGoto(target) => Instruction::IfGoto(label(target)?),
+                    VmInstruction::Call(callee, n_args) => {
+                        let callee = if let Some(index) = self.function_index(callee) {
+                            Callee::Vm(index)
+                        } else if let Some(os_function) = OsFunction::from_name(callee) {
+                            if os_function.arity() != *n_args {
+                                return Err(format!(
+                                    "{name}: `{callee}` takes {} argument(s), not {n_args}",
+                                    os_function.arity()
+                                ));
+                            }
+                            Callee::Os(os_function)
+                        } else {
+                            return Err(format!("{name}: `{callee}` is not defined"));
+                        };
+                        Instruction::Call(callee, *n_args)
+                    }
+                    VmInstruction::Function(_, n_vars) => Instruction::Function(*n_vars),
+                    VmInstruction::Return => Instruction::Return,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Run until the program stops.
+    pub fn run(&mut self) -> Result<Exit, String> {
+        loop {
+            if let Some(exit) = self.step()? {
+                self.os.flush().map_err(|error| error.to_string())?;
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Run one instruction, returning why the program stopped, if it did.
+    pub fn step(&mut self) -> Result<Option<Exit>, String> {
+        let Some(&instruction) = self.code.get(self.pc) else {
+            return Ok(Some(Exit::Halt));
+        };
+        self.os.tick(self.steps, &mut self.ram);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.instruction(self.pc);
+        }
+        self.steps += 1;
+        self.pc += 1;
+
+        match instruction {
+            Instruction::Push(segment, index) => {
+                let value = match segment {
+                    Segment::Constant => index,
+                    _ => self.ram[self.address(segment, index)?],
+                };
+                self.push(value)?;
+            }
+            Instruction::Pop(segment, index) => {
+                if segment == Segment::Constant {
+                    return Err(self.fault("can't pop to `constant`"));
+                }
+                let value = self.pop()?;
+                let address = self.address(segment, index)?;
+                self.ram[address] = value;
+            }
+            Instruction::Arithmetic(op) => self.arithmetic(op)?,
+            Instruction::Label => {}
+            Instruction::Goto(target) => self.pc = target,
+            Instruction::IfGoto(target) => {
+                if self.pop()? != 0 {
+                    self.pc = target;
+                }
+            }
+            Instruction::Halt => {
+                self.pc -= 1;
+                return Ok(Some(Exit::Halt));
+            }
+            Instruction::Call(Callee::Vm(index), n_args) => self.call(index, n_args, self.pc)?,
+            Instruction::Call(Callee::Os(function), n_args) => {
+                let sp = self.ram[SP] as usize;
+                let Some(args_start) = sp.checked_sub(n_args as usize) else {
+                    return Err(self.fault("stack underflow"));
+                };
+                let args = self.ram[args_start..sp].to_vec();
+                self.ram[SP] = args_start as u16;
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.os_call(function);
+                }
+                match self.os.call(function, &args, &mut self.ram) {
+                    Ok(OsReturn::Value(value)) => self.push(value)?,
+                    Ok(OsReturn::Exit(exit)) => return Ok(Some(exit)),
+                    Err(error) => return Err(self.fault(&format!("{}: {error}", function))),
+                }
+            }
+            Instruction::Function(n_vars) => {
+                for _ in 0..n_vars {
+                    self.push(0)?;
+                }
+            }
+            Instruction::Return => self.return_()?,
+        }
+        Ok(None)
+    }
+
+    fn call(&mut self, index: usize, n_args: u16, return_address: usize) -> Result<(), String> {
+        self.push(return_address as u16)?;
+        for pointer in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[pointer])?;
+        }
+        let sp = self.ram[SP];
+        self.ram[ARG] = sp.wrapping_sub(n_args + 5);
+        self.ram[LCL] = sp;
+        self.pc = self.functions[index].start;
+        self.call_stack.push(index);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.call(index);
+        }
+        Ok(())
+    }
+
+    fn return_(&mut self) -> Result<(), String> {
+        let frame = self.ram[LCL] as usize;
+        if frame < 5 {
+            return Err(self.fault("return without a frame"));
+        }
+        // the saved frame is the 5 words below LCL
+        self.ram_address(frame - 1)?;
+        let return_address = self.ram[frame - 5];
+        let return_value = self.pop()?;
+        let arg = self.ram_address(self.ram[ARG] as usize)?;
+        self.ram[arg] = return_value;
+        self.ram[SP] = arg as u16 + 1;
+        for (i, pointer) in [THAT, THIS, ARG, LCL].into_iter().enumerate() {
+            self.ram[pointer] = self.ram[frame - 1 - i];
+        }
+        self.pc = return_address as usize;
+        self.call_stack.pop();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.return_();
+        }
+        Ok(())
+    }
+
+    fn arithmetic(&mut self, op: ArithmeticOp) -> Result<(), String> {
+        let y = self.pop()?;
+        let value = match op {
+            ArithmeticOp::Neg => y.wrapping_neg(),
+            ArithmeticOp::Not => !y,
+            _ => {
+                let x = self.pop()?;
+                match op {
+                    ArithmeticOp::Add => x.wrapping_add(y),
+                    ArithmeticOp::Sub => x.wrapping_sub(y),
+                    ArithmeticOp::And => x & y,
+                    ArithmeticOp::Or => x | y,
+                    ArithmeticOp::Eq => truth(x == y),
+                    ArithmeticOp::Gt => truth((x as i16) > (y as i16)),
+                    ArithmeticOp::Lt => truth((x as i16) < (y as i16)),
+                    ArithmeticOp::Neg | ArithmeticOp::Not => unreachable!(),
+                }
+            }
+        };
+        self.push(value)
+    }
+
+    /// RAM address of a segment entry.
+    fn address(&self, segment: Segment, index: u16) -> Result<usize, String> {
+        let address = match segment {
+            Segment::Local => self.ram[LCL].wrapping_add(index),
+            Segment::Argument => self.ram[ARG].wrapping_add(index),
+            Segment::This => self.ram[THIS].wrapping_add(index),
+            Segment::That => self.ram[THAT].wrapping_add(index),
+            Segment::Pointer if index < 2 => THIS as u16 + index,
+            Segment::Temp if index < 8 => TEMP as u16 + index,
+            // resolved when loading
+            Segment::Static => index,
+            _ => return Err(self.fault(&format!("`{segment} {index}` is out of range"))),
+        };
+        self.ram_address(address as usize)
+    }
+
+    /// `address`, if it is in the RAM.
+    fn ram_address(&self, address: usize) -> Result<usize, String> {
+        if address >= RAM_SIZE {
+            return Err(self.fault(&format!("address {address} is out of the RAM")));
+        }
+        Ok(address)
+    }
+
+    fn push(&mut self, value: u16) -> Result<(), String> {
+        let sp = self.ram[SP];
+        if sp >= STACK_END {
+            return Err(self.fault("stack overflow"));
+        }
+        self.ram[sp as usize] = value;
+        self.ram[SP] = sp + 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u16, String> {
+        let sp = self.ram[SP];
+        if sp <= STACK_START {
+            return Err(self.fault("stack underflow"));
+        }
+        self.ram[SP] = sp - 1;
+        Ok(self.ram[sp as usize - 1])
+    }
+
+    fn fault(&self, message: &str) -> String {
+        match self.call_stack.last() {
+            Some(&index) => format!("{}: {message}", self.functions[index].name),
+            None => message.to_string(),
+        }
+    }
+
+    fn function_index(&self, name: &str) -> Option<usize> {
+        self.functions
+            .iter()
+            .position(|function| function.name == name)
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+
+    /// The RAM, to set a program's input before it runs.
+    #[cfg(test)]
+    pub fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.ram
+    }
+
+    /// Count what the program does from now on, for [`Emulator::profile`].
+    pub fn enable_profiler(&mut self) {
+        let mut profiler = Profiler::new(self.code.len(), self.functions.len());
+        // the functions already running, like `Sys.init` when loaded
+        for &index in &self.call_stack {
+            profiler.call(index);
+        }
+        self.profiler = Some(profiler);
+    }
+
+    /// What the program did since [`Emulator::enable_profiler`].
+    pub fn profile(&self) -> Option<Profile> {
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| (function.name.clone(), function.start))
+            .collect();
+        Some(self.profiler.as_ref()?.profile(functions))
+    }
+
+    /// Write out what the program printed so far.
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.os.flush().map_err(|error| error.to_string())
+    }
+
+    /// The function being run and the index in it of the instruction it
+    /// runs next.
+    pub fn position(&self) -> Option<(&str, usize)> {
+        let function = &self.functions[*self.call_stack.last()?];
+        Some((function.name.as_str(), self.pc - function.start))
+    }
+
+    /// How many functions are being run.
+    pub fn depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    /// The functions being run, innermost first, each with the index in the
+    /// function of the instruction it runs next, or of the `call` it waits
+    /// on. Empty once `Sys.init` has returned.
+    pub fn backtrace(&self) -> Vec<(&str, usize)> {
+        let mut frames = vec![];
+        let mut pc = self.pc;
+        let mut lcl = self.ram[LCL] as usize;
+        for &index in self.call_stack.iter().rev() {
+            let function = &self.functions[index];
+            frames.push((function.name.as_str(), pc.saturating_sub(function.start)));
+            if lcl < 5 {
+                break;
+            }
+            // the caller continues after its `call`
+            pc = (self.ram[lcl - 5] as usize).saturating_sub(1);
+            lcl = self.ram[lcl - 4] as usize;
+        }
+        frames
+    }
+
+    /// Address of static `index` of a class, if the program uses it.
+    pub fn static_address(&self, class_name: &str, index: u16) -> Option<u16> {
+        self.statics.get(&(class_name.to_string(), index)).copied()
+    }
+}
+
+fn truth(value: bool) -> u16 {
+    if value {
+        u16::MAX
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{keyboard::Keyboard, linker, os::Os, screen, test_file, vm::VmInstruction::*};
+
+    /// Compile a `Main` class, link it and run it with the keyboard
+    /// `script`, returning the emulator and the text output.
+    fn run(source: &str, script: &str) -> io::Result<(Emulator, Exit, String)> {
+        let mut linked = test_file::compile_and_link(source, script)?;
+        let exit = linked.emulator.run().unwrap();
+        Ok((linked.emulator, exit, linked.output.text()))
+    }
+
+    #[test]
+    fn test_run() -> io::Result<()> {
+        let (emulator, exit, output) = run(
+            "class Main {
+                static int result;
+                function void main() {
+                    var Array a;
+                    var int i, sum;
+                    let a = Array.new(10);
+                    while (i < 10) {
+                        let a[i] = i * i;
+                        let i = i + 1;
+                    }
+                    let i = 0;
+                    while (i < 10) {
+                        let sum = sum + a[i];
+                        let i = i + 1;
+                    }
+                    let result = sum - 300;
+                    do Output.printString(\"sum: \");
+                    do Output.printInt(result);
+                    do Output.println();
+                    return;
+                }
+            }",
+            "",
+        )?;
+
+        assert_eq!(exit, Exit::Halt);
+        assert_eq!(output, "sum: -15\n");
+        let result = emulator.static_address("Main", 0).unwrap();
+        assert_eq!(emulator.ram()[result as usize] as i16, -15);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_keyboard() -> io::Result<()> {
+        let (_, _, output) = run(
+            &std::fs::read_to_string("test_files/Main.jack")?,
+            r#"type "3\n10\n20\n-3\n""#,
+        )?;
+        assert_eq!(
+            output,
+            "How many numbers? Enter a number: Enter a number: Enter a number: The average is 9"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_error() -> io::Result<()> {
+        let (_, exit, output) = run(
+            "class Main { function void main() { do Output.printInt(1 / 0); return; } }",
+            "",
+        )?;
+        assert_eq!(exit, Exit::Error(3));
+        assert_eq!(output, "ERR3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_timed_keys() -> io::Result<()> {
+        let (emulator, _, _) = run(
+            "class Main {
+                static int key, frames, released;
+                function void main() {
+                    while (Keyboard.keyPressed() = 0) {}
+                    let key = Keyboard.keyPressed();
+                    while (~(Keyboard.keyPressed() = 0)) {}
+                    let released = 1;
+                    while (Keyboard.keyPressed() = 0) {
+                        do Sys.wait(10);
+                        let frames = frames + 1;
+                    }
+                    return;
+                }
+            }",
+            "at 1000 press up
+            at 2000 release
+            frame 7 press space",
+        )?;
+        let ram = emulator.ram();
+        let statics = ["key", "frames", "released"]
+            .iter()
+            .enumerate()
+            .map(|(i, _)| ram[emulator.static_address("Main", i as u16).unwrap() as usize])
+            .collect::<Vec<_>>();
+
+        assert_eq!(statics, [131, 7, 1]);
+        assert_eq!(ram[screen::KBD], 32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_screen() -> io::Result<()> {
+        let (emulator, _, output) = run(
+            "class Main {
+                function void main() {
+                    do Screen.drawRectangle(0, 100, 15, 101);
+                    do Screen.setColor(false);
+                    do Screen.drawPixel(3, 100);
+                    do Screen.setColor(true);
+                    do Screen.drawLine(20, 0, 20, 9);
+                    do Output.printString(\"Hi\");
+                    return;
+                }
+            }",
+            "",
+        )?;
+        let ram = emulator.ram();
+
+        assert_eq!(output, "Hi");
+        assert_eq!(ram[screen::SCREEN + 100 * 32], !(1 << 3));
+        assert_eq!(ram[screen::SCREEN + 101 * 32], u16::MAX);
+        // 'H' and 'i' share the first word of each text line
+        assert_eq!(ram[screen::SCREEN + 4 * 32] & 0xff, 63);
+        assert_eq!(ram[screen::SCREEN + 4 * 32] >> 8, 12);
+        assert!((0..10).all(|y| screen::pixel(ram, 20, y)));
+        assert!(!screen::pixel(ram, 20, 10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_semantics() {
+        // 16 bit wraparound and signed comparisons
+        let program = linker::link(
+            [vec![vec![
+                Function("Main.main".to_string(), 0),
+                Push(Segment::Constant, 32767),
+                Push(Segment::Constant, 1),
+                Arithmetic(ArithmeticOp::Add),
+                Pop(Segment::Temp, 2),
+                Push(Segment::Temp, 2),
+                Push(Segment::Constant, 0),
+                Arithmetic(ArithmeticOp::Lt),
+                Pop(Segment::Temp, 3),
+                Push(Segment::Constant, 0),
+                Return,
+            ]]],
+            true,
+        )
+        .unwrap();
+        let os = Os::new(Keyboard::new(Box::new(io::empty())), Box::new(io::sink()));
+        let mut emulator = Emulator::new(&program, os).unwrap();
+
+        assert_eq!(emulator.run(), Ok(Exit::Halt));
+        assert_eq!(emulator.ram()[TEMP + 2..TEMP + 4], [0x8000, u16::MAX]);
+        // the stack is back to `Sys.init`'s frame, at its halt loop
+        assert_eq!(emulator.ram()[SP], STACK_START + 5);
+
+        let program = linker::link(
+            [vec![vec![
+                Function("Main.main".to_string(), 0),
+                Push(Segment::Constant, 1),
+                Pop(Segment::Pointer, 2),
+                Return,
+            ]]],
+            true,
+        )
+        .unwrap();
+        let os = Os::new(Keyboard::new(Box::new(io::empty())), Box::new(io::sink()));
+        assert_eq!(
+            Emulator::new(&program, os).unwrap().run(),
+            Err("Main.main: `pointer 2` is out of range".to_string())
+        );
+    }
+
+    #[test]
+    fn test_return_out_of_ram() {
+        // `that 0` is LCL and `that 1` is ARG
+        for (pointer, address) in [(0, 65534), (1, 65535)] {
+            let program = linker::link(
+                [vec![vec![
+                    Function("Main.main".to_string(), 0),
+                    Push(Segment::Constant, 1),
+                    Pop(Segment::Pointer, 1),
+                    Push(Segment::Constant, 0),
+                    Arithmetic(ArithmeticOp::Not),
+                    Pop(Segment::That, pointer),
+                    Push(Segment::Constant, 0),
+                    Return,
+                ]]],
+                true,
+            )
+            .unwrap();
+            let os = Os::new(Keyboard::new(Box::new(io::empty())), Box::new(io::sink()));
+            assert_eq!(
+                Emulator::new(&program, os).unwrap().run(),
+                Err(format!("Main.main: address {address} is out of the RAM"))
+            );
+        }
+    }
+}
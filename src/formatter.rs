@@ -0,0 +1,905 @@
+use std::{io, path::Path};
+
+use crate::*;
+
+const INDENT: &str = "    ";
+
+/// How a variable declaration naming several variables is laid out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VarStyle {
+    /// `var int i, sum;` becomes `var int i;` and `var int sum;`
+    Split,
+    /// `var int i, sum;` stays on one line
+    Grouped,
+}
+
+/// Re-emits a Jack class in canonical layout: 4-space indentation, one
+/// statement per line, spaces around binary operators, `} else {` on one
+/// line and a blank line between subroutines. Comments are kept where they
+/// were: trailing comments stay at the end of their line, other comments get
+/// a line of their own at the current indentation. A single blank line is
+/// kept wherever the source had one or more.
+///
+/// Formatting is idempotent: formatting the output again gives the same text.
+pub struct Formatter {
+    tokenizer: Tokenizer,
+    var_style: VarStyle,
+    output: String,
+    // current output line, without indentation
+    line: String,
+    line_indent: usize,
+    indent: usize,
+    // a statement broken over several lines is indented one more level
+    continuation: bool,
+    // the next token is written without a leading space
+    glue: bool,
+    // source line of the last written token or comment
+    last_line: usize,
+    // how many of `tokenizer.comments()` are already written
+    comments_taken: usize,
+}
+
+impl Formatter {
+    pub fn new(filepath: &Path, var_style: VarStyle) -> io::Result<Self> {
+        let tokenizer = Tokenizer::new(filepath)?;
+
+        Ok(Self {
+            tokenizer,
+            var_style,
+            output: String::new(),
+            line: String::new(),
+            line_indent: 0,
+            indent: 0,
+            continuation: false,
+            glue: false,
+            last_line: 0,
+            comments_taken: 0,
+        })
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    fn _get_next_token(&mut self) -> io::Result<()> {
+        self.last_line = self.tokenizer.line();
+        self.tokenizer.advance()?;
+        self.comments_taken = 0;
+        Ok(())
+    }
+
+    pub fn format_class(&mut self) -> io::Result<()> {
+        // `class`
+        self._eat_keyword()?;
+
+        // className
+        self._eat_identifier()?;
+
+        // `{`
+        self._eat_symbol()?;
+        self._end_line();
+        self.indent += 1;
+
+        let mut first_member = true;
+
+        // classVarDec*
+        while self.tokenizer.token_type() == Some(TokenType::Keyword)
+            && (self.tokenizer.keyword() == "static" || self.tokenizer.keyword() == "field")
+        {
+            self.format_var_dec()?;
+            first_member = false;
+        }
+
+        // subroutineDec*
+        while self.tokenizer.token_type() == Some(TokenType::Keyword)
+            && (self.tokenizer.keyword() == "constructor"
+                || self.tokenizer.keyword() == "function"
+                || self.tokenizer.keyword() == "method")
+        {
+            if !first_member {
+                self._blank_line();
+            }
+            self.format_subroutine_dec()?;
+            first_member = false;
+        }
+
+        // `}`
+        self._close_brace()?;
+        self._end_line();
+
+        // comments after the class
+        self._flush_comments();
+        self._newline();
+        Ok(())
+    }
+
+    /// `var`, `static` and `field` declarations.
+    pub fn format_var_dec(&mut self) -> io::Result<()> {
+        // `var` `static` or `field`
+        let keyword = self.tokenizer.keyword();
+        self._eat_keyword()?;
+
+        // type
+        let type_ = self.format_type()?;
+        let declaration = format!("{keyword} {type_}");
+        // end of the first line of a split declaration, if nothing trails it
+        let mut first_line_end = None;
+        let mut split = false;
+
+        // varName (`,` varName)*
+        loop {
+            // varName
+            self._eat_identifier()?;
+
+            if self.tokenizer.token_type() != Some(TokenType::Symbol)
+                || self.tokenizer.symbol() != ','
+            {
+                break;
+            }
+
+            // `,`
+            match self.var_style {
+                VarStyle::Grouped => self._eat_symbol()?,
+                VarStyle::Split => {
+                    self._skip_token()?;
+                    self._write_token(";", false);
+                    self._end_line();
+                    if !split && self.output.ends_with(";\n") {
+                        first_line_end = Some(self.output.len() - 1);
+                    }
+                    split = true;
+                    self._flush_comments();
+                    self._write_token(&declaration, true);
+                }
+            }
+        }
+
+        // `;`
+        self._eat_symbol()?;
+
+        // a comment after the whole declaration stays on its first line
+        if let Some(end) = first_line_end {
+            while let Some(comment) = self.tokenizer.comments().get(self.comments_taken) {
+                if comment.line != self.last_line || comment.text.contains('\n') {
+                    break;
+                }
+                let comment = comment.clone();
+                self.comments_taken += 1;
+                self.output.insert_str(end, &format!(" {}", comment.text));
+                self.last_line = comment.end_line();
+            }
+        }
+        self._end_line();
+        Ok(())
+    }
+
+    pub fn format_subroutine_dec(&mut self) -> io::Result<()> {
+        // `constructor` `function` or `method`
+        self._eat_keyword()?;
+
+        // void | type
+        self.format_type()?;
+
+        // subroutineName
+        self._eat_identifier()?;
+
+        // `(`
+        self.glue = true;
+        self._eat_symbol()?;
+
+        // parameterList
+        self.format_parameter_list()?;
+
+        // `)`
+        self._eat_symbol()?;
+
+        // subroutineBody
+        self.format_subroutine_body()?;
+        Ok(())
+    }
+
+    pub fn format_parameter_list(&mut self) -> io::Result<()> {
+        // empty
+        if self.tokenizer.token_type() == Some(TokenType::Symbol) && self.tokenizer.symbol() == ')'
+        {
+            return Ok(());
+        }
+
+        loop {
+            // type
+            self.format_type()?;
+
+            // varName
+            self._eat_identifier()?;
+
+            if self.tokenizer.token_type() != Some(TokenType::Symbol)
+                || self.tokenizer.symbol() != ','
+            {
+                break;
+            }
+
+            // `,`
+            self._eat_symbol()?;
+        }
+        Ok(())
+    }
+
+    pub fn format_subroutine_body(&mut self) -> io::Result<()> {
+        // `{`
+        self._eat_symbol()?;
+        self._end_line();
+        self.indent += 1;
+
+        // varDec*
+        while self.tokenizer.token_type() == Some(TokenType::Keyword)
+            && self.tokenizer.keyword() == "var"
+        {
+            self.format_var_dec()?;
+        }
+
+        // statements
+        self.format_statements()?;
+
+        // `}`
+        self._close_brace()?;
+        self._end_line();
+        Ok(())
+    }
+
+    pub fn format_statements(&mut self) -> io::Result<()> {
+        // statement*
+        while self.tokenizer.token_type() == Some(TokenType::Keyword) {
+            match self.tokenizer.keyword().as_str() {
+                "let" => self.format_let()?,
+                "if" => self.format_if()?,
+                "while" => self.format_while()?,
+                "do" => self.format_do()?,
+                "return" => self.format_return()?,
+                _ => return self._error("a statement"),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn format_let(&mut self) -> io::Result<()> {
+        // `let`
+        self._eat_keyword()?;
+
+        // varName
+        self._eat_identifier()?;
+
+        // (`[` expression `]`)?
+        if self.tokenizer.token_type() == Some(TokenType::Symbol) && self.tokenizer.symbol() == '['
+        {
+            self._eat_symbol()?;
+            self.format_expression()?;
+            self._eat_symbol()?;
+        }
+
+        // `=`
+        self._eat_symbol()?;
+
+        // expression
+        self.format_expression()?;
+
+        // `;`
+        self._eat_symbol()?;
+        self._end_line();
+        Ok(())
+    }
+
+    pub fn format_if(&mut self) -> io::Result<()> {
+        // `if`
+        self._eat_keyword()?;
+
+        // `(` expression `)`
+        self._eat_symbol()?;
+        self.format_expression()?;
+        self._eat_symbol()?;
+
+        // `{` statements `}`
+        self._open_brace()?;
+        self.format_statements()?;
+        self._close_brace()?;
+
+        // optional `else`
+        if self.tokenizer.token_type() == Some(TokenType::Keyword)
+            && self.tokenizer.keyword() == "else"
+        {
+            // `else`
+            self._eat_keyword()?;
+
+            // `{` statements `}`
+            self._open_brace()?;
+            self.format_statements()?;
+            self._close_brace()?;
+        }
+
+        self._end_line();
+        Ok(())
+    }
+
+    pub fn format_while(&mut self) -> io::Result<()> {
+        // `while`
+        self._eat_keyword()?;
+
+        // `(` expression `)`
+        self._eat_symbol()?;
+        self.format_expression()?;
+        self._eat_symbol()?;
+
+        // `{` statements `}`
+        self._open_brace()?;
+        self.format_statements()?;
+        self._close_brace()?;
+
+        self._end_line();
+        Ok(())
+    }
+
+    pub fn format_do(&mut self) -> io::Result<()> {
+        // `do`
+        self._eat_keyword()?;
+
+        // subroutineCall
+        self.format_subroutine_call()?;
+
+        // `;`
+        self._eat_symbol()?;
+        self._end_line();
+        Ok(())
+    }
+
+    pub fn format_return(&mut self) -> io::Result<()> {
+        // `return`
+        self._eat_keyword()?;
+
+        // expression?
+        if self.tokenizer.token_type() != Some(TokenType::Symbol) || self.tokenizer.symbol() != ';'
+        {
+            self.format_expression()?;
+        }
+
+        // `;`
+        self._eat_symbol()?;
+        self._end_line();
+        Ok(())
+    }
+
+    pub fn format_expression(&mut self) -> io::Result<()> {
+        // term (op term)*
+        loop {
+            self.format_term()?;
+
+            if self.tokenizer.token_type() != Some(TokenType::Symbol)
+                || !Formatter::_is_op(self.tokenizer.symbol())
+            {
+                break;
+            }
+
+            // op
+            self._eat_symbol()?;
+        }
+        Ok(())
+    }
+
+    pub fn format_term(&mut self) -> io::Result<()> {
+        match self.tokenizer.token_type() {
+            // integer const
+            Some(TokenType::IntConst) => self._eat_int_const()?,
+            // | string const
+            Some(TokenType::StringConst) => self._eat_string_const()?,
+            // | keyword const
+            Some(TokenType::Keyword) => self._eat_keyword()?,
+            // | (expression)
+            Some(TokenType::Symbol) if self.tokenizer.symbol() == '(' => {
+                self._eat_symbol()?;
+                self.format_expression()?;
+                self._eat_symbol()?;
+            }
+            // | unaryOp term
+            Some(TokenType::Symbol) if Formatter::_is_unary_op(self.tokenizer.symbol()) => {
+                self._eat_symbol()?;
+                self.glue = true;
+                self.format_term()?;
+            }
+            // | varName | varName[expression] | subroutineCall
+            Some(TokenType::Identifier) => {
+                self._eat_identifier()?;
+
+                if self.tokenizer.token_type() == Some(TokenType::Symbol) {
+                    match self.tokenizer.symbol() {
+                        '[' => {
+                            self._eat_symbol()?;
+                            self.format_expression()?;
+                            self._eat_symbol()?;
+                        }
+                        '(' | '.' => self._format_call_rest()?,
+                        _ => {}
+                    }
+                }
+            }
+            _ => return self._error("an expression"),
+        }
+        Ok(())
+    }
+
+    pub fn format_expression_list(&mut self) -> io::Result<()> {
+        // total optional
+        if self.tokenizer.token_type() == Some(TokenType::Symbol) && self.tokenizer.symbol() == ')'
+        {
+            return Ok(());
+        }
+
+        // expression (`,` expression)*
+        loop {
+            self.format_expression()?;
+
+            if self.tokenizer.token_type() != Some(TokenType::Symbol)
+                || self.tokenizer.symbol() != ','
+            {
+                break;
+            }
+
+            // `,`
+            self._eat_symbol()?;
+        }
+        Ok(())
+    }
+
+    fn format_type(&mut self) -> io::Result<String> {
+        let the_type;
+        if self.tokenizer.token_type() == Some(TokenType::Keyword) {
+            // `int` `char` `boolean` or `void`
+            the_type = self.tokenizer.keyword();
+            self._eat_keyword()?;
+        } else if self.tokenizer.token_type() == Some(TokenType::Identifier) {
+            // className
+            the_type = self.tokenizer.identifier();
+            self._eat_identifier()?;
+        } else {
+            return self._error("a type");
+        }
+        Ok(the_type)
+    }
+
+    fn format_subroutine_call(&mut self) -> io::Result<()> {
+        // subroutineName or (className | varName)
+        self._eat_identifier()?;
+        self._format_call_rest()
+    }
+
+    /// The part of a subroutine call after its first identifier:
+    /// `(expressionList)` or `.subroutineName(expressionList)`.
+    fn _format_call_rest(&mut self) -> io::Result<()> {
+        if self.tokenizer.token_type() == Some(TokenType::Symbol) && self.tokenizer.symbol() == '.'
+        {
+            // `.` subroutineName
+            self._eat_symbol()?;
+            self._eat_identifier()?;
+        }
+
+        // `(`
+        self.glue = true;
+        self._eat_symbol()?;
+
+        // expressionList
+        self.format_expression_list()?;
+
+        // `)`
+        self._eat_symbol()?;
+        Ok(())
+    }
+
+    fn _open_brace(&mut self) -> io::Result<()> {
+        self._eat_symbol()?;
+        self._end_line();
+        self.indent += 1;
+        Ok(())
+    }
+
+    fn _close_brace(&mut self) -> io::Result<()> {
+        // comments before `}` belong to the block
+        self._flush_comments();
+        self.indent -= 1;
+        self._eat_symbol()
+    }
+
+    fn _eat_keyword(&mut self) -> io::Result<()> {
+        if self.tokenizer.token_type() != Some(TokenType::Keyword) {
+            return self._error("a keyword");
+        }
+        let keyword = self.tokenizer.keyword();
+        self._eat(&keyword, true)
+    }
+
+    fn _eat_symbol(&mut self) -> io::Result<()> {
+        if self.tokenizer.token_type() != Some(TokenType::Symbol) {
+            return self._error("a symbol");
+        }
+        let symbol = self.tokenizer.symbol();
+        let space_before = !matches!(symbol, ')' | ']' | '[' | '.' | ',' | ';');
+        self._eat(&symbol.to_string(), space_before)?;
+        self.glue = matches!(symbol, '(' | '[' | '.' | '~');
+        Ok(())
+    }
+
+    fn _eat_identifier(&mut self) -> io::Result<()> {
+        if self.tokenizer.token_type() != Some(TokenType::Identifier) {
+            return self._error("an identifier");
+        }
+        let identifier = self.tokenizer.identifier();
+        self._eat(&identifier, true)
+    }
+
+    fn _eat_int_const(&mut self) -> io::Result<()> {
+        if self.tokenizer.token_type() != Some(TokenType::IntConst) {
+            return self._error("an integer constant");
+        }
+        let int_const = self.tokenizer.int_const().to_string();
+        self._eat(&int_const, true)
+    }
+
+    fn _eat_string_const(&mut self) -> io::Result<()> {
+        if self.tokenizer.token_type() != Some(TokenType::StringConst) {
+            return self._error("a string constant");
+        }
+        let string_const = format!("\"{}\"", self.tokenizer.string_const());
+        self._eat(&string_const, true)
+    }
+
+    fn _eat(&mut self, text: &str, space_before: bool) -> io::Result<()> {
+        self._flush_comments();
+        if self.line.is_empty() && text != "}" {
+            self._blank_line_if_gap(self.tokenizer.line());
+        }
+        self._write_token(text, space_before);
+        self._get_next_token()
+    }
+
+    /// "line: expected ..., found ..." at the current token. The formatter
+    /// only runs on classes that compile, so it gives up at the first error.
+    fn _error<T>(&self, expected: &str) -> io::Result<T> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}: expected {}, found {}",
+                self.tokenizer.line(),
+                expected,
+                self.tokenizer.describe_token()
+            ),
+        ))
+    }
+
+    /// Consume the current token without writing it.
+    fn _skip_token(&mut self) -> io::Result<()> {
+        self._flush_comments();
+        self._get_next_token()
+    }
+
+    fn _write_token(&mut self, text: &str, space_before: bool) {
+        if self.line.is_empty() {
+            self.line_indent = self.indent + self.continuation as usize;
+        } else if space_before && !self.glue {
+            self.line.push(' ');
+        }
+        self.line += text;
+        self.glue = false;
+    }
+
+    /// Write the comments in front of the current token.
+    fn _flush_comments(&mut self) {
+        let comments = self.tokenizer.comments()[self.comments_taken..].to_vec();
+        self.comments_taken += comments.len();
+
+        for comment in comments {
+            // trailing comment
+            if !self.line.is_empty() && comment.line == self.last_line {
+                self._write_token(&comment.text, true);
+                self.last_line = comment.end_line();
+                if comment.is_line_comment() {
+                    self._break_line();
+                }
+                continue;
+            }
+
+            // comment on its own line
+            if !self.line.is_empty() {
+                self._break_line();
+            }
+            self._blank_line_if_gap(comment.line);
+            let indent = INDENT.repeat(self.indent + self.continuation as usize);
+            let reindent = comment
+                .text
+                .lines()
+                .skip(1)
+                .all(|line| line.trim_start().starts_with('*'));
+            for (i, line) in comment.text.lines().enumerate() {
+                if i == 0 {
+                    self.output += &indent;
+                    self.output += line.trim_start();
+                } else if reindent {
+                    self.output += &indent;
+                    self.output += " ";
+                    self.output += line.trim_start();
+                } else {
+                    self.output += line;
+                }
+                self.output.push('\n');
+            }
+            self.last_line = comment.end_line();
+        }
+    }
+
+    /// End the current line at a statement or block boundary, keeping any
+    /// trailing comments on it.
+    fn _end_line(&mut self) {
+        while let Some(comment) = self.tokenizer.comments().get(self.comments_taken) {
+            if self.line.is_empty() || comment.line != self.last_line {
+                break;
+            }
+            let comment = comment.clone();
+            self.comments_taken += 1;
+            self._write_token(&comment.text, true);
+            self.last_line = comment.end_line();
+        }
+        self._newline();
+        self.continuation = false;
+    }
+
+    /// Break a statement in the middle, continuing on the next line.
+    fn _break_line(&mut self) {
+        self._newline();
+        self.continuation = true;
+    }
+
+    fn _newline(&mut self) {
+        if !self.line.is_empty() {
+            self.output += &INDENT.repeat(self.line_indent);
+            self.output += self.line.trim_end();
+            self.output.push('\n');
+            self.line.clear();
+        }
+        self.glue = false;
+    }
+
+    fn _blank_line_if_gap(&mut self, line: usize) {
+        if line > self.last_line + 1 {
+            self._blank_line();
+        }
+    }
+
+    fn _blank_line(&mut self) {
+        if !self.output.is_empty()
+            && !self.output.ends_with("\n\n")
+            && !self.output.ends_with("{\n")
+        {
+            self.output.push('\n');
+        }
+    }
+
+    fn _is_op(op: char) -> bool {
+        matches!(op, '+' | '-' | '*' | '/' | '&' | '|' | '<' | '>' | '=')
+    }
+
+    fn _is_unary_op(op: char) -> bool {
+        matches!(op, '-' | '~')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_file::*;
+
+    fn format(test_file: &TestFile, var_style: VarStyle) -> io::Result<String> {
+        let mut formatter = Formatter::new(Path::new(&test_file.path), var_style)?;
+        formatter.format_class()?;
+        Ok(formatter.output().to_string())
+    }
+
+    #[test]
+    fn test_format_layout() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line(
+            "class Main{field int x;
+function void main(){var int i;let i=-(1+2)*x[3];
+if(~(i<0)){do Output.printInt( i );}else{let x[i]=Foo.bar(1,i);}
+while(i>0){let i=i-1;}return;}
+method int get(){return x;}}",
+        )?;
+
+        assert_eq!(
+            format(&test_file, VarStyle::Split)?,
+            "class Main {
+    field int x;
+
+    function void main() {
+        var int i;
+        let i = -(1 + 2) * x[3];
+        if (~(i < 0)) {
+            do Output.printInt(i);
+        } else {
+            let x[i] = Foo.bar(1, i);
+        }
+        while (i > 0) {
+            let i = i - 1;
+        }
+        return;
+    }
+
+    method int get() {
+        return x;
+    }
+}
+"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_var_style() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line(
+            "class Main { static int a, b; function void main() { var char c, d; return; } }",
+        )?;
+
+        assert_eq!(
+            format(&test_file, VarStyle::Split)?,
+            "class Main {
+    static int a;
+    static int b;
+
+    function void main() {
+        var char c;
+        var char d;
+        return;
+    }
+}
+"
+        );
+        assert_eq!(
+            format(&test_file, VarStyle::Grouped)?,
+            "class Main {
+    static int a, b;
+
+    function void main() {
+        var char c, d;
+        return;
+    }
+}
+"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_comments() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("// header")?;
+        test_file.add_line("")?;
+        test_file.add_line("class Main { // the class")?;
+        test_file.add_line("      /** Entry point.")?;
+        test_file.add_line("          * Does nothing. */")?;
+        test_file.add_line("  function void main() {")?;
+        test_file.add_line("  var int a, // first")?;
+        test_file.add_line("      b;")?;
+        test_file.add_line("")?;
+        test_file.add_line("")?;
+        test_file.add_line("     let a = 1 + // one")?;
+        test_file.add_line("       2;")?;
+        test_file.add_line("     // done")?;
+        test_file.add_line("  return; } }")?;
+        test_file.add_line("// footer")?;
+
+        let expected = "// header
+
+class Main { // the class
+    /** Entry point.
+     * Does nothing. */
+    function void main() {
+        var int a; // first
+        var int b;
+
+        let a = 1 + // one
+            2;
+        // done
+        return;
+    }
+}
+// footer
+";
+        assert_eq!(format(&test_file, VarStyle::Split)?, expected);
+
+        // formatting is idempotent
+        test_file.clear()?;
+        test_file.add_line(expected.trim_end())?;
+        assert_eq!(format(&test_file, VarStyle::Split)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_split_trailing_comment() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    field int x, y; // position")?;
+        test_file.add_line("    field int a, // first")?;
+        test_file.add_line("        b, c; // rest")?;
+        test_file.add_line("}")?;
+
+        let expected = "class Main {
+    field int x; // position
+    field int y;
+    field int a; // first
+    field int b;
+    field int c; // rest
+}
+";
+        assert_eq!(format(&test_file, VarStyle::Split)?, expected);
+
+        test_file.clear()?;
+        test_file.add_line(expected.trim_end())?;
+        assert_eq!(format(&test_file, VarStyle::Split)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_errors() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        for (source, expected) in [
+            ("class", "1: expected an identifier, found end of file"),
+            (
+                "class Main { function void f() { let x = ; } }",
+                "1: expected an expression, found `;`",
+            ),
+            (
+                "class Main { function void f() { static int x; } }",
+                "1: expected a statement, found `static`",
+            ),
+            (
+                "class Main { function 1 f() { return; } }",
+                "1: expected a type, found `1`",
+            ),
+        ] {
+            test_file.clear()?;
+            test_file.add_line(source)?;
+            let error = format(&test_file, VarStyle::Split)
+                .map(|_| "no error".to_string())
+                .unwrap_or_else(|e| e.to_string());
+            assert_eq!(error, expected, "{source}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_idempotent() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        for line in fs::read_to_string("test_files/Main.jack")?.lines() {
+            test_file.add_line(line)?;
+        }
+
+        let once = format(&test_file, VarStyle::Split)?;
+        test_file.clear()?;
+        test_file.add_line(once.trim_end())?;
+        let twice = format(&test_file, VarStyle::Split)?;
+
+        assert_eq!(once, twice);
+        assert!(once.contains("    function void main() {\n        var Array a;\n"));
+        assert!(once.contains("        var int i;\n        var int sum;\n"));
+        assert!(once.contains("            let a[i] = Keyboard.readInt(\"Enter a number: \");\n"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1308 @@
+use std::{
+    fs, io,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+};
+
+use crate::{
+    compilation_engine::{CompilationEngine, CompileOptions},
+    emulator::{Emulator, Exit},
+    formatter::{Formatter, VarStyle},
+    keyboard::Keyboard,
+    linker,
+    os::{Os, SharedOutput},
+    screen,
+    vm::VmInstruction,
+};
+
+const STATICS: usize = 2;
+/// `int` locals of each function, besides the loop counters and `result`
+const INTS: usize = 3;
+const BOOLEANS: usize = 2;
+const MAX_FUNCTIONS: usize = 3;
+const MAX_PARAMS: usize = 2;
+const MAX_STATEMENTS: usize = 4;
+const MAX_BLOCK_DEPTH: usize = 3;
+/// loops nest at most this deep, each level with its own counter
+const MAX_LOOP_DEPTH: usize = 2;
+const MAX_ITERATIONS: usize = 3;
+const MAX_EXPRESSION_DEPTH: usize = 3;
+/// elements of the array of each function; indices are masked to fit
+const ARRAY_SIZE: u16 = 8;
+/// programs running longer aren't compared
+const MAX_STEPS: u64 = 1_000_000;
+
+/// A seeded random number generator (splitmix64), so a seed gives the same
+/// program everywhere.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 to `n - 1`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// A generated `Main` class: functions `f0`, `f1`, ... returning an `int`,
+/// and `main`, which prints its result and the statics.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub main: Function,
+}
+
+/// Every function has the same locals: `int`s `x0`, `x1`, ..., `boolean`s
+/// `b0`, ..., loop counters `i0`, ..., `Array a` of [`ARRAY_SIZE`] and
+/// `result`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Function {
+    /// `int` parameters `p0`, `p1`, ...
+    pub params: usize,
+    pub body: Vec<Statement>,
+    pub result: Int,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Var {
+    Static(usize),
+    Param(usize),
+    Local(usize),
+    /// only assigned by its loop
+    Counter(usize),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IntOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompareOp {
+    Lt,
+    Gt,
+    Eq,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Int {
+    Const(u16),
+    Var(Var),
+    Neg(Box<Int>),
+    /// op, left, right, and whether an operation on the left is chained
+    /// without parentheses, as in `a - b - c`
+    Binary(IntOp, Box<Int>, Box<Int>, bool),
+    /// `l / (r | 1)`, never by 0
+    Div(Box<Int>, Box<Int>),
+    /// `a[i & 7]`, always in the array
+    Element(Box<Int>),
+    /// a call of function `f<n>`
+    Call(usize, Vec<Int>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Bool {
+    Const(bool),
+    Var(usize),
+    Not(Box<Bool>),
+    Compare(CompareOp, Box<Int>, Box<Int>),
+    And(Box<Bool>, Box<Bool>),
+    Or(Box<Bool>, Box<Bool>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Statement {
+    Let(Var, Int),
+    LetBool(usize, Bool),
+    LetElement(Int, Int),
+    If(Bool, Vec<Statement>, Vec<Statement>),
+    /// counter, iterations, body
+    While(usize, usize, Vec<Statement>),
+    Do(usize, Vec<Int>),
+    Print(Int),
+    PrintString(String),
+}
+
+/// The program of `seed`. Functions only call the ones after them, and
+/// loops count to at most [`MAX_ITERATIONS`], so every program ends.
+pub fn generate(seed: u64) -> Program {
+    let mut rng = Rng::new(seed);
+    let params = (0..rng.below(MAX_FUNCTIONS + 1))
+        .map(|_| rng.below(MAX_PARAMS + 1))
+        .collect::<Vec<_>>();
+    let mut generator = Generator {
+        rng,
+        params,
+        function: 0,
+        loop_depth: 0,
+    };
+    let functions = (0..generator.params.len())
+        .map(|i| generator.function(i))
+        .collect();
+    let main = generator.function(generator.params.len());
+    Program { functions, main }
+}
+
+struct Generator {
+    rng: Rng,
+    /// parameters of each function
+    params: Vec<usize>,
+    /// function being generated, `params.len()` for `main`
+    function: usize,
+    loop_depth: usize,
+}
+
+impl Generator {
+    fn function(&mut self, index: usize) -> Function {
+        self.function = index;
+        self.loop_depth = 0;
+        Function {
+            params: self.params.get(index).copied().unwrap_or(0),
+            body: self.block(0),
+            result: self.int(0),
+        }
+    }
+
+    fn block(&mut self, depth: usize) -> Vec<Statement> {
+        let count = 1 + self.rng.below(MAX_STATEMENTS);
+        (0..count).map(|_| self.statement(depth)).collect()
+    }
+
+    fn statement(&mut self, depth: usize) -> Statement {
+        let nested = depth < MAX_BLOCK_DEPTH;
+        match self.rng.below(10) {
+            0 | 1 => {
+                let var = self.target();
+                Statement::Let(var, self.int(0))
+            }
+            2 => {
+                let var = self.rng.below(BOOLEANS);
+                Statement::LetBool(var, self.boolean(0))
+            }
+            3 => {
+                let index = self.int(1);
+                Statement::LetElement(index, self.int(0))
+            }
+            4 if nested => {
+                let condition = self.boolean(0);
+                let then = self.block(depth + 1);
+                let otherwise = if self.rng.chance(50) {
+                    self.block(depth + 1)
+                } else {
+                    vec![]
+                };
+                Statement::If(condition, then, otherwise)
+            }
+            5 if nested && self.loop_depth < MAX_LOOP_DEPTH => {
+                let counter = self.loop_depth;
+                let iterations = self.rng.below(MAX_ITERATIONS + 1);
+                self.loop_depth += 1;
+                let body = self.block(depth + 1);
+                self.loop_depth -= 1;
+                Statement::While(counter, iterations, body)
+            }
+            6 if !self.callees().is_empty() => {
+                let (function, args) = self.call();
+                Statement::Do(function, args)
+            }
+            7 => {
+                let len = self.rng.below(6);
+                let text = (0..len)
+                    .map(|_| (b'a' + self.rng.below(26) as u8) as char)
+                    .collect();
+                Statement::PrintString(text)
+            }
+            _ => Statement::Print(self.int(0)),
+        }
+    }
+
+    /// Functions the current one may call.
+    fn callees(&self) -> std::ops::Range<usize> {
+        if self.function == self.params.len() {
+            0..self.params.len()
+        } else {
+            self.function + 1..self.params.len()
+        }
+    }
+
+    fn call(&mut self) -> (usize, Vec<Int>) {
+        let callees = self.callees();
+        let function = callees.start + self.rng.below(callees.len());
+        let args = (0..self.params[function]).map(|_| self.int(1)).collect();
+        (function, args)
+    }
+
+    /// A variable `let` can assign.
+    fn target(&mut self) -> Var {
+        let params = self.params.get(self.function).copied().unwrap_or(0);
+        let n = self.rng.below(STATICS + params + INTS);
+        if n < STATICS {
+            Var::Static(n)
+        } else if n < STATICS + params {
+            Var::Param(n - STATICS)
+        } else {
+            Var::Local(n - STATICS - params)
+        }
+    }
+
+    fn int(&mut self, depth: usize) -> Int {
+        if depth >= MAX_EXPRESSION_DEPTH || self.rng.chance(30) {
+            return self.int_leaf();
+        }
+        let operand = |generator: &mut Self| Box::new(generator.int(depth + 1));
+        match self.rng.below(7) {
+            0 => Int::Neg(operand(self)),
+            1 | 2 => {
+                let op =
+                    [IntOp::Add, IntOp::Sub, IntOp::Mul, IntOp::And, IntOp::Or][self.rng.below(5)];
+                let (l, r) = (operand(self), operand(self));
+                Int::Binary(op, l, r, self.rng.chance(50))
+            }
+            3 => Int::Div(operand(self), operand(self)),
+            4 => Int::Element(operand(self)),
+            5 if !self.callees().is_empty() => {
+                let (function, args) = self.call();
+                Int::Call(function, args)
+            }
+            _ => self.int_leaf(),
+        }
+    }
+
+    fn int_leaf(&mut self) -> Int {
+        if self.rng.chance(50) {
+            // mostly small numbers, which the optimizer has rules for
+            let max = if self.rng.chance(80) { 10 } else { 32768 };
+            Int::Const(self.rng.below(max) as u16)
+        } else if self.loop_depth > 0 && self.rng.chance(25) {
+            Int::Var(Var::Counter(self.rng.below(self.loop_depth)))
+        } else {
+            Int::Var(self.target())
+        }
+    }
+
+    fn boolean(&mut self, depth: usize) -> Bool {
+        if depth >= MAX_EXPRESSION_DEPTH || self.rng.chance(20) {
+            return if self.rng.chance(50) {
+                Bool::Const(self.rng.chance(50))
+            } else {
+                Bool::Var(self.rng.below(BOOLEANS))
+            };
+        }
+        match self.rng.below(5) {
+            0 => Bool::Not(Box::new(self.boolean(depth + 1))),
+            1 => Bool::And(
+                Box::new(self.boolean(depth + 1)),
+                Box::new(self.boolean(depth + 1)),
+            ),
+            2 => Bool::Or(
+                Box::new(self.boolean(depth + 1)),
+                Box::new(self.boolean(depth + 1)),
+            ),
+            _ => {
+                let op = [CompareOp::Lt, CompareOp::Gt, CompareOp::Eq][self.rng.below(3)];
+                Bool::Compare(
+                    op,
+                    Box::new(self.int(depth + 1)),
+                    Box::new(self.int(depth + 1)),
+                )
+            }
+        }
+    }
+}
+
+impl Program {
+    /// The Jack source, every expression in parentheses but the chained
+    /// ones.
+    pub fn to_jack(&self) -> String {
+        let mut buf = "class Main {\n".to_string();
+        let statics = (0..STATICS).map(|i| format!("s{i}")).collect::<Vec<_>>();
+        buf += &format!("    static int {};\n", statics.join(", "));
+        for (i, function) in self.functions.iter().enumerate() {
+            let params = (0..function.params)
+                .map(|i| format!("int p{i}"))
+                .collect::<Vec<_>>();
+            buf += &format!("\n    function int f{i}({}) {{\n", params.join(", "));
+            buf += &function.to_jack_body();
+            buf += &format!("        let result = {};\n", function.result.to_jack());
+            buf += "        do a.dispose();\n";
+            buf += "        return result;\n    }\n";
+        }
+        buf += "\n    function void main() {\n";
+        buf += &self.main.to_jack_body();
+        buf += &format!(
+            "        do Output.printInt({});\n",
+            self.main.result.to_jack()
+        );
+        for name in statics {
+            buf += "        do Output.printChar(32);\n";
+            buf += &format!("        do Output.printInt({name});\n");
+        }
+        buf += "        do a.dispose();\n";
+        buf += "        return;\n    }\n}\n";
+        buf
+    }
+
+    /// The programs one step simpler than this one, the likeliest to be
+    /// the most simple first.
+    fn candidates(&self) -> Vec<Program> {
+        let mut candidates = vec![];
+        for i in 0..self.functions.len() {
+            if !self.calls(i) {
+                let mut program = self.clone();
+                program.functions.remove(i);
+                program.renumber_calls(i);
+                candidates.push(program);
+            }
+        }
+        for i in 0..=self.functions.len() {
+            let function = self.functions.get(i).unwrap_or(&self.main);
+            let mut replace = |function: Function| {
+                let mut program = self.clone();
+                match program.functions.get_mut(i) {
+                    Some(f) => *f = function,
+                    None => program.main = function,
+                }
+                candidates.push(program);
+            };
+            for body in block_candidates(&function.body) {
+                replace(Function {
+                    body,
+                    ..function.clone()
+                });
+            }
+            for result in int_candidates(&function.result) {
+                replace(Function {
+                    result,
+                    ..function.clone()
+                });
+            }
+        }
+        candidates
+    }
+
+    /// Whether any function calls function `n`.
+    fn calls(&self, n: usize) -> bool {
+        let mut found = false;
+        self.clone().visit_calls(&mut |f| found |= *f == n);
+        found
+    }
+
+    /// Function `removed` is gone: the ones after it move down.
+    fn renumber_calls(&mut self, removed: usize) {
+        self.visit_calls(&mut |f| {
+            if *f > removed {
+                *f -= 1;
+            }
+        });
+    }
+
+    /// Call `f` on the called function of every call, `do` or expression.
+    fn visit_calls(&mut self, f: &mut impl FnMut(&mut usize)) {
+        for function in self.functions.iter_mut().chain([&mut self.main]) {
+            for statement in &mut function.body {
+                statement.visit_statements(&mut |statement| {
+                    if let Statement::Do(function, _) = statement {
+                        f(function);
+                    }
+                });
+            }
+            let mut call = |int: &mut Int| {
+                if let Int::Call(function, _) = int {
+                    f(function);
+                }
+            };
+            for statement in &mut function.body {
+                statement.visit_ints(&mut call);
+            }
+            call(&mut function.result);
+            function.result.visit_ints(&mut call);
+        }
+    }
+}
+
+impl Function {
+    /// The declarations and statements, before the result.
+    fn to_jack_body(&self) -> String {
+        let names = |prefix: &str, n: usize| {
+            (0..n)
+                .map(|i| format!("{prefix}{i}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut buf = format!("        var int {}, result;\n", names("x", INTS));
+        buf += &format!("        var boolean {};\n", names("b", BOOLEANS));
+        buf += &format!("        var int {};\n", names("i", MAX_LOOP_DEPTH));
+        buf += "        var Array a;\n";
+        buf += &format!("        let a = Array.new({ARRAY_SIZE});\n");
+        // the heap may hold anything, differently with strings pooled
+        buf += "        let i0 = 0;\n";
+        buf += &format!("        while (i0 < {ARRAY_SIZE}) {{\n");
+        buf += "            let a[i0] = 0;\n";
+        buf += "            let i0 = i0 + 1;\n";
+        buf += "        }\n";
+        for statement in &self.body {
+            statement.to_jack(2, &mut buf);
+        }
+        buf
+    }
+}
+
+impl Var {
+    fn name(self) -> String {
+        match self {
+            Var::Static(i) => format!("s{i}"),
+            Var::Param(i) => format!("p{i}"),
+            Var::Local(i) => format!("x{i}"),
+            Var::Counter(i) => format!("i{i}"),
+        }
+    }
+}
+
+impl Int {
+    /// A term, so it is an operand as it is.
+    fn to_jack(&self) -> String {
+        match self {
+            Int::Const(n) => n.to_string(),
+            Int::Var(var) => var.name(),
+            Int::Neg(e) => format!("(-{})", e.to_jack()),
+            Int::Binary(..) | Int::Div(..) => format!("({})", self.to_jack_expression()),
+            Int::Element(i) => format!("a[({} & {})]", i.to_jack(), ARRAY_SIZE - 1),
+            Int::Call(f, args) => format!("Main.f{f}({})", args_to_jack(args)),
+        }
+    }
+
+    /// An expression, which Jack applies from left to right: a chained
+    /// operation on the left goes without parentheses.
+    fn to_jack_expression(&self) -> String {
+        match self {
+            Int::Binary(op, l, r, chained) => {
+                let op = match op {
+                    IntOp::Add => "+",
+                    IntOp::Sub => "-",
+                    IntOp::Mul => "*",
+                    IntOp::And => "&",
+                    IntOp::Or => "|",
+                };
+                let l = match **l {
+                    Int::Binary(..) | Int::Div(..) if *chained => l.to_jack_expression(),
+                    _ => l.to_jack(),
+                };
+                format!("{l} {op} {}", r.to_jack())
+            }
+            Int::Div(l, r) => format!("{} / ({} | 1)", l.to_jack(), r.to_jack()),
+            _ => self.to_jack(),
+        }
+    }
+
+    /// Call `f` on every expression inside this one.
+    fn visit_ints(&mut self, f: &mut impl FnMut(&mut Int)) {
+        match self {
+            Int::Const(_) | Int::Var(_) => {}
+            Int::Neg(e) | Int::Element(e) => {
+                f(e);
+                e.visit_ints(f);
+            }
+            Int::Binary(_, l, r, _) | Int::Div(l, r) => {
+                for e in [l, r] {
+                    f(e);
+                    e.visit_ints(f);
+                }
+            }
+            Int::Call(_, args) => {
+                for e in args {
+                    f(e);
+                    e.visit_ints(f);
+                }
+            }
+        }
+    }
+}
+
+fn args_to_jack(args: &[Int]) -> String {
+    args.iter().map(Int::to_jack).collect::<Vec<_>>().join(", ")
+}
+
+impl Bool {
+    fn to_jack(&self) -> String {
+        match self {
+            Bool::Const(b) => b.to_string(),
+            Bool::Var(i) => format!("b{i}"),
+            Bool::Not(e) => format!("(~{})", e.to_jack()),
+            Bool::Compare(op, l, r) => {
+                let op = match op {
+                    CompareOp::Lt => "<",
+                    CompareOp::Gt => ">",
+                    CompareOp::Eq => "=",
+                };
+                format!("({} {op} {})", l.to_jack(), r.to_jack())
+            }
+            Bool::And(l, r) => format!("({} & {})", l.to_jack(), r.to_jack()),
+            Bool::Or(l, r) => format!("({} | {})", l.to_jack(), r.to_jack()),
+        }
+    }
+
+    fn visit_ints(&mut self, f: &mut impl FnMut(&mut Int)) {
+        match self {
+            Bool::Const(_) | Bool::Var(_) => {}
+            Bool::Not(e) => e.visit_ints(f),
+            Bool::Compare(_, l, r) => {
+                for e in [l, r] {
+                    f(e);
+                    e.visit_ints(f);
+                }
+            }
+            Bool::And(l, r) | Bool::Or(l, r) => {
+                l.visit_ints(f);
+                r.visit_ints(f);
+            }
+        }
+    }
+}
+
+impl Statement {
+    fn to_jack(&self, indent: usize, buf: &mut String) {
+        let pad = "    ".repeat(indent);
+        let block = |statements: &[Statement], buf: &mut String| {
+            for statement in statements {
+                statement.to_jack(indent + 1, buf);
+            }
+        };
+        match self {
+            Statement::Let(var, e) => {
+                *buf += &format!("{pad}let {} = {};\n", var.name(), e.to_jack())
+            }
+            Statement::LetBool(i, e) => *buf += &format!("{pad}let b{i} = {};\n", e.to_jack()),
+            Statement::LetElement(i, e) => {
+                *buf += &format!(
+                    "{pad}let a[({} & {})] = {};\n",
+                    i.to_jack(),
+                    ARRAY_SIZE - 1,
+                    e.to_jack()
+                )
+            }
+            Statement::If(condition, then, otherwise) => {
+                *buf += &format!("{pad}if ({}) {{\n", condition.to_jack());
+                block(then, buf);
+                if !otherwise.is_empty() {
+                    *buf += &format!("{pad}}} else {{\n");
+                    block(otherwise, buf);
+                }
+                *buf += &format!("{pad}}}\n");
+            }
+            Statement::While(counter, iterations, body) => {
+                *buf += &format!("{pad}let i{counter} = 0;\n");
+                *buf += &format!("{pad}while (i{counter} < {iterations}) {{\n");
+                block(body, buf);
+                *buf += &format!("{pad}    let i{counter} = i{counter} + 1;\n");
+                *buf += &format!("{pad}}}\n");
+            }
+            Statement::Do(f, args) => {
+                *buf += &format!("{pad}do Main.f{f}({});\n", args_to_jack(args))
+            }
+            Statement::Print(e) => *buf += &format!("{pad}do Output.printInt({});\n", e.to_jack()),
+            Statement::PrintString(text) => {
+                *buf += &format!("{pad}do Output.printString(\"{text}\");\n")
+            }
+        }
+    }
+
+    /// Call `f` on this statement and every statement inside it.
+    fn visit_statements(&mut self, f: &mut impl FnMut(&mut Statement)) {
+        f(self);
+        match self {
+            Statement::If(_, then, otherwise) => {
+                for statement in then.iter_mut().chain(otherwise) {
+                    statement.visit_statements(f);
+                }
+            }
+            Statement::While(_, _, body) => {
+                for statement in body {
+                    statement.visit_statements(f);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn visit_ints(&mut self, f: &mut impl FnMut(&mut Int)) {
+        let mut int = |e: &mut Int| {
+            f(e);
+            e.visit_ints(f);
+        };
+        match self {
+            Statement::Let(_, e) | Statement::Print(e) => int(e),
+            Statement::LetElement(i, e) => {
+                int(i);
+                int(e);
+            }
+            Statement::Do(_, args) => args.iter_mut().for_each(int),
+            Statement::LetBool(_, e) => e.visit_ints(f),
+            Statement::If(condition, then, otherwise) => {
+                condition.visit_ints(f);
+                for statement in then.iter_mut().chain(otherwise) {
+                    statement.visit_ints(f);
+                }
+            }
+            Statement::While(_, _, body) => {
+                for statement in body {
+                    statement.visit_ints(f);
+                }
+            }
+            Statement::PrintString(_) => {}
+        }
+    }
+}
+
+impl Program {
+    /// What the program prints, worked out from the generated tree with the
+    /// 16-bit arithmetic of the Hack computer, to check the compiled code
+    /// against.
+    pub fn output(&self) -> String {
+        let mut evaluator = Evaluator {
+            program: self,
+            statics: [0; STATICS],
+            output: String::new(),
+        };
+        let mut frame = Frame::new(vec![]);
+        evaluator.block(&self.main.body, &mut frame);
+        let result = evaluator.int(&self.main.result, &mut frame);
+        evaluator.output += &result.to_string();
+        for value in evaluator.statics {
+            evaluator.output += &format!(" {value}");
+        }
+        evaluator.output
+    }
+}
+
+struct Evaluator<'a> {
+    program: &'a Program,
+    statics: [i16; STATICS],
+    output: String,
+}
+
+/// The variables of a running function, zeroed like the VM does.
+struct Frame {
+    params: Vec<i16>,
+    locals: [i16; INTS],
+    booleans: [bool; BOOLEANS],
+    counters: [i16; MAX_LOOP_DEPTH],
+    array: [i16; ARRAY_SIZE as usize],
+}
+
+impl Frame {
+    fn new(params: Vec<i16>) -> Self {
+        Frame {
+            params,
+            locals: [0; INTS],
+            booleans: [false; BOOLEANS],
+            counters: [0; MAX_LOOP_DEPTH],
+            array: [0; ARRAY_SIZE as usize],
+        }
+    }
+}
+
+impl Evaluator<'_> {
+    fn call(&mut self, f: usize, args: &[Int], frame: &mut Frame) -> i16 {
+        let args = args.iter().map(|e| self.int(e, frame)).collect();
+        let function = &self.program.functions[f];
+        let mut frame = Frame::new(args);
+        self.block(&function.body, &mut frame);
+        self.int(&function.result, &mut frame)
+    }
+
+    fn var<'f>(&'f mut self, var: Var, frame: &'f mut Frame) -> &'f mut i16 {
+        match var {
+            Var::Static(i) => &mut self.statics[i],
+            Var::Param(i) => &mut frame.params[i],
+            Var::Local(i) => &mut frame.locals[i],
+            Var::Counter(i) => &mut frame.counters[i],
+        }
+    }
+
+    fn block(&mut self, block: &[Statement], frame: &mut Frame) {
+        for statement in block {
+            self.statement(statement, frame);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement, frame: &mut Frame) {
+        match statement {
+            Statement::Let(var, e) => {
+                let value = self.int(e, frame);
+                *self.var(*var, frame) = value;
+            }
+            Statement::LetBool(i, e) => frame.booleans[*i] = self.boolean(e, frame),
+            Statement::LetElement(i, e) => {
+                let index = self.int(i, frame) & (ARRAY_SIZE - 1) as i16;
+                frame.array[index as usize] = self.int(e, frame);
+            }
+            Statement::If(condition, then, otherwise) => {
+                if self.boolean(condition, frame) {
+                    self.block(then, frame);
+                } else {
+                    self.block(otherwise, frame);
+                }
+            }
+            Statement::While(counter, iterations, body) => {
+                frame.counters[*counter] = 0;
+                while frame.counters[*counter] < *iterations as i16 {
+                    self.block(body, frame);
+                    frame.counters[*counter] += 1;
+                }
+            }
+            Statement::Do(f, args) => {
+                self.call(*f, args, frame);
+            }
+            Statement::Print(e) => {
+                let value = self.int(e, frame);
+                self.output += &value.to_string();
+            }
+            Statement::PrintString(text) => self.output += text,
+        }
+    }
+
+    /// Operands are worked out from left to right, as the calls in them
+    /// may print or change the statics.
+    fn int(&mut self, e: &Int, frame: &mut Frame) -> i16 {
+        match e {
+            Int::Const(n) => *n as i16,
+            Int::Var(var) => *self.var(*var, frame),
+            Int::Neg(e) => self.int(e, frame).wrapping_neg(),
+            Int::Binary(op, l, r, _) => {
+                let (l, r) = (self.int(l, frame), self.int(r, frame));
+                match op {
+                    IntOp::Add => l.wrapping_add(r),
+                    IntOp::Sub => l.wrapping_sub(r),
+                    IntOp::Mul => l.wrapping_mul(r),
+                    IntOp::And => l & r,
+                    IntOp::Or => l | r,
+                }
+            }
+            Int::Div(l, r) => {
+                let (l, r) = (self.int(l, frame), self.int(r, frame));
+                l.wrapping_div(r | 1)
+            }
+            Int::Element(i) => {
+                let index = self.int(i, frame) & (ARRAY_SIZE - 1) as i16;
+                frame.array[index as usize]
+            }
+            Int::Call(f, args) => self.call(*f, args, frame),
+        }
+    }
+
+    fn boolean(&mut self, e: &Bool, frame: &mut Frame) -> bool {
+        match e {
+            Bool::Const(b) => *b,
+            Bool::Var(i) => frame.booleans[*i],
+            Bool::Not(e) => !self.boolean(e, frame),
+            Bool::Compare(op, l, r) => {
+                let (l, r) = (self.int(l, frame), self.int(r, frame));
+                match op {
+                    CompareOp::Lt => l < r,
+                    CompareOp::Gt => l > r,
+                    CompareOp::Eq => l == r,
+                }
+            }
+            // both sides run, as without short-circuit conditions
+            Bool::And(l, r) => {
+                let (l, r) = (self.boolean(l, frame), self.boolean(r, frame));
+                l && r
+            }
+            Bool::Or(l, r) => {
+                let (l, r) = (self.boolean(l, frame), self.boolean(r, frame));
+                l || r
+            }
+        }
+    }
+}
+
+/// The blocks one step simpler: a statement dropped, replaced by the
+/// statements inside it, or simplified.
+fn block_candidates(block: &[Statement]) -> Vec<Vec<Statement>> {
+    let mut candidates = vec![];
+    for (i, statement) in block.iter().enumerate() {
+        let mut dropped = block.to_vec();
+        dropped.remove(i);
+        candidates.push(dropped);
+
+        let inner = match statement {
+            Statement::If(_, then, otherwise) => vec![then, otherwise],
+            Statement::While(_, _, body) => vec![body],
+            _ => vec![],
+        };
+        for statements in inner {
+            let mut inlined = block.to_vec();
+            inlined.splice(i..=i, statements.iter().cloned());
+            candidates.push(inlined);
+        }
+
+        for simpler in statement_candidates(statement) {
+            let mut candidate = block.to_vec();
+            candidate[i] = simpler;
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
+
+fn statement_candidates(statement: &Statement) -> Vec<Statement> {
+    use Statement::*;
+    let mut candidates = vec![];
+    match statement {
+        Let(var, e) => candidates.extend(int_candidates(e).into_iter().map(|e| Let(*var, e))),
+        LetBool(var, e) => {
+            candidates.extend(bool_candidates(e).into_iter().map(|e| LetBool(*var, e)))
+        }
+        LetElement(i, e) => {
+            candidates.extend(
+                int_candidates(i)
+                    .into_iter()
+                    .map(|i| LetElement(i, e.clone())),
+            );
+            candidates.extend(
+                int_candidates(e)
+                    .into_iter()
+                    .map(|e| LetElement(i.clone(), e)),
+            );
+        }
+        If(condition, then, otherwise) => {
+            for condition in bool_candidates(condition) {
+                candidates.push(If(condition, then.clone(), otherwise.clone()));
+            }
+            for then in block_candidates(then) {
+                candidates.push(If(condition.clone(), then, otherwise.clone()));
+            }
+            for otherwise in block_candidates(otherwise) {
+                candidates.push(If(condition.clone(), then.clone(), otherwise));
+            }
+        }
+        While(counter, iterations, body) => {
+            if *iterations > 1 {
+                candidates.push(While(*counter, 1, body.clone()));
+            }
+            for body in block_candidates(body) {
+                candidates.push(While(*counter, *iterations, body));
+            }
+        }
+        Do(f, args) => {
+            for args in args_candidates(args) {
+                candidates.push(Do(*f, args));
+            }
+        }
+        Print(e) => candidates.extend(int_candidates(e).into_iter().map(Print)),
+        PrintString(text) => {
+            if !text.is_empty() {
+                candidates.push(PrintString(String::new()));
+            }
+        }
+    }
+    candidates
+}
+
+fn int_candidates(e: &Int) -> Vec<Int> {
+    let mut candidates = vec![];
+    match e {
+        Int::Const(0) => {}
+        Int::Const(n) => {
+            candidates.push(Int::Const(0));
+            if *n > 1 {
+                candidates.push(Int::Const(n / 2));
+            }
+        }
+        _ => candidates.push(Int::Const(0)),
+    }
+    match e {
+        Int::Const(_) | Int::Var(_) => {}
+        Int::Neg(x) => {
+            candidates.push(*x.clone());
+            candidates.extend(int_candidates(x).into_iter().map(|x| Int::Neg(Box::new(x))));
+        }
+        Int::Element(x) => {
+            candidates.push(*x.clone());
+            candidates.extend(
+                int_candidates(x)
+                    .into_iter()
+                    .map(|x| Int::Element(Box::new(x))),
+            );
+        }
+        Int::Binary(_, l, r, _) | Int::Div(l, r) => {
+            candidates.push(*l.clone());
+            candidates.push(*r.clone());
+            let rebuild = |l: Int, r: Int| match e {
+                Int::Binary(op, _, _, chained) => {
+                    Int::Binary(*op, Box::new(l), Box::new(r), *chained)
+                }
+                _ => Int::Div(Box::new(l), Box::new(r)),
+            };
+            for l in int_candidates(l) {
+                candidates.push(rebuild(l, *r.clone()));
+            }
+            for r in int_candidates(r) {
+                candidates.push(rebuild(*l.clone(), r));
+            }
+        }
+        Int::Call(f, args) => {
+            candidates.extend(args.iter().cloned());
+            for args in args_candidates(args) {
+                candidates.push(Int::Call(*f, args));
+            }
+        }
+    }
+    candidates
+}
+
+fn args_candidates(args: &[Int]) -> Vec<Vec<Int>> {
+    let mut candidates = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        for simpler in int_candidates(arg) {
+            let mut candidate = args.to_vec();
+            candidate[i] = simpler;
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
+
+fn bool_candidates(e: &Bool) -> Vec<Bool> {
+    let mut candidates = vec![];
+    if !matches!(e, Bool::Const(_)) {
+        candidates.extend([Bool::Const(false), Bool::Const(true)]);
+    }
+    match e {
+        Bool::Const(_) | Bool::Var(_) => {}
+        Bool::Not(x) => {
+            candidates.push(*x.clone());
+            candidates.extend(
+                bool_candidates(x)
+                    .into_iter()
+                    .map(|x| Bool::Not(Box::new(x))),
+            );
+        }
+        Bool::And(l, r) | Bool::Or(l, r) => {
+            candidates.push(*l.clone());
+            candidates.push(*r.clone());
+            let rebuild = |l: Bool, r: Bool| match e {
+                Bool::And(..) => Bool::And(Box::new(l), Box::new(r)),
+                _ => Bool::Or(Box::new(l), Box::new(r)),
+            };
+            for l in bool_candidates(l) {
+                candidates.push(rebuild(l, *r.clone()));
+            }
+            for r in bool_candidates(r) {
+                candidates.push(rebuild(*l.clone(), r));
+            }
+        }
+        Bool::Compare(op, l, r) => {
+            for l in int_candidates(l) {
+                candidates.push(Bool::Compare(*op, Box::new(l), r.clone()));
+            }
+            for r in int_candidates(r) {
+                candidates.push(Bool::Compare(*op, l.clone(), Box::new(r)));
+            }
+        }
+    }
+    candidates
+}
+
+/// Simplify `program` one step at a time for as long as it still `fails`,
+/// to the smallest program that does.
+pub fn shrink(mut program: Program, mut fails: impl FnMut(&Program) -> bool) -> Program {
+    'simpler: loop {
+        for candidate in program.candidates() {
+            if fails(&candidate) {
+                program = candidate;
+                continue 'simpler;
+            }
+        }
+        return program;
+    }
+}
+
+/// What a generated program shows to be wrong.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Failure {
+    /// `compile`, `output`, `optimize`, `format` or `panic`
+    pub check: &'static str,
+    pub detail: String,
+}
+
+/// Check what every program should do, writing it as `Main.jack` in `dir`:
+/// it compiles without errors, prints what [`Program::output`] says, the
+/// optimized code does what the unoptimized one does, and formatting it
+/// changes neither its VM code nor the formatted text.
+pub fn check(program: &Program, dir: &Path) -> io::Result<Result<(), Failure>> {
+    let path = dir.join("Main.jack");
+    fs::write(&path, program.to_jack())?;
+    match panic::catch_unwind(AssertUnwindSafe(|| check_file(program, &path))) {
+        Ok(result) => result,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            Ok(Err(Failure {
+                check: "panic",
+                detail: message,
+            }))
+        }
+    }
+}
+
+fn check_file(program: &Program, path: &Path) -> io::Result<Result<(), Failure>> {
+    let (functions, diagnostics) = compile(path, CompileOptions::default())?;
+    if !diagnostics.is_empty() {
+        return Ok(Err(Failure {
+            check: "compile",
+            detail: diagnostics.join("\n"),
+        }));
+    }
+
+    let unoptimized = run(&functions);
+    let expected = program.output();
+    if let Some((state, actual)) = &unoptimized {
+        if *actual != expected {
+            return Ok(Err(Failure {
+                check: "output",
+                detail: format!("printed {actual:?}, expected {expected:?} ({state})"),
+            }));
+        }
+    }
+
+    let optimized_options = CompileOptions {
+        optimize: true,
+        pool_strings: true,
+        // a condition skipping a call is allowed to change the result
+        short_circuit: false,
+    };
+    let (optimized, _) = compile(path, optimized_options)?;
+    if let (Some(expected), Some(actual)) = (unoptimized, run(&optimized)) {
+        if expected != actual {
+            return Ok(Err(Failure {
+                check: "optimize",
+                detail: format!("unoptimized {expected:?}, optimized {actual:?}"),
+            }));
+        }
+    }
+
+    let formatted = format(path)?;
+    fs::write(path, &formatted)?;
+    if compile(path, CompileOptions::default())?.0 != functions {
+        return Ok(Err(Failure {
+            check: "format",
+            detail: "formatting changes the VM code".to_string(),
+        }));
+    }
+    if format(path)? != formatted {
+        return Ok(Err(Failure {
+            check: "format",
+            detail: "formatting the formatted code changes it".to_string(),
+        }));
+    }
+    Ok(Ok(()))
+}
+
+fn compile(
+    path: &Path,
+    options: CompileOptions,
+) -> io::Result<(Vec<Vec<VmInstruction>>, Vec<String>)> {
+    let mut engine = CompilationEngine::with_vm_output(path, io::sink())?;
+    engine.set_options(options);
+    engine.compile_class()?;
+    let diagnostics = engine.diagnostics().iter().map(ToString::to_string);
+    Ok((engine.functions().to_vec(), diagnostics.collect()))
+}
+
+fn format(path: &Path) -> io::Result<String> {
+    let mut formatter = Formatter::new(path, VarStyle::Split)?;
+    formatter.format_class()?;
+    Ok(formatter.output().to_string())
+}
+
+/// How a run ended and what it left: the exit, the statics and a checksum
+/// of the screen, and the text it printed, or `None` if it ran too long to
+/// tell.
+fn run(functions: &[Vec<VmInstruction>]) -> Option<(String, String)> {
+    let program = match linker::link([functions.to_vec()], true) {
+        Ok(program) => program,
+        Err(errors) => return Some((format!("link errors {errors:?}"), String::new())),
+    };
+    let output = SharedOutput::default();
+    let os = Os::new(
+        Keyboard::new(Box::new(io::empty())),
+        Box::new(output.clone()),
+    );
+    let mut emulator = match Emulator::new(&program, os) {
+        Ok(emulator) => emulator,
+        Err(error) => return Some((format!("load error {error}"), String::new())),
+    };
+    let mut exit = None;
+    for _ in 0..MAX_STEPS {
+        match emulator.step() {
+            Ok(None) => {}
+            Ok(Some(Exit::Halt)) => exit = Some("halt".to_string()),
+            Ok(Some(Exit::Error(code))) => exit = Some(format!("ERR{code}")),
+            Err(error) => exit = Some(error),
+        }
+        if exit.is_some() {
+            break;
+        }
+    }
+
+    let ram = emulator.ram();
+    let statics = (0..STATICS as u16)
+        .map(|i| {
+            emulator
+                .static_address("Main", i)
+                .map_or(0, |address| ram[address as usize] as i16)
+        })
+        .collect::<Vec<_>>();
+    let screen = ram[screen::SCREEN..screen::KBD]
+        .iter()
+        .fold(0u64, |hash, &word| {
+            hash.wrapping_mul(0x100_0000_01b3) ^ word as u64
+        });
+    let state = format!("{}, statics {statics:?}, screen {screen:016x}", exit?);
+    Some((state, output.text()))
+}
+
+/// Check the programs of `runs` seeds from `seed` on. The first failure is
+/// returned with its seed and the smallest program failing the same check.
+pub fn fuzz(seed: u64, runs: u64, dir: &Path) -> io::Result<Option<(u64, Failure, Program)>> {
+    for seed in seed..seed.saturating_add(runs) {
+        let program = generate(seed);
+        let Err(failure) = check(&program, dir)? else {
+            continue;
+        };
+        let mut error = None;
+        let minimal = shrink(program, |candidate| match check(candidate, dir) {
+            Ok(Err(other)) => other.check == failure.check,
+            Ok(Ok(())) => false,
+            Err(e) => {
+                error.get_or_insert(e);
+                false
+            }
+        });
+        if let Some(error) = error {
+            return Err(error);
+        }
+        // the detail of the smallest program
+        let failure = check(&minimal, dir)?.err().unwrap_or(failure);
+        return Ok(Some((seed, failure, minimal)));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_file::TempDir;
+
+    #[test]
+    fn test_generate() {
+        // a seed always gives the same program
+        assert_eq!(generate(7), generate(7));
+        assert_ne!(generate(7).to_jack(), generate(8).to_jack());
+
+        let source = generate(3).to_jack();
+        assert!(source.starts_with("class Main {\n    static int s0, s1;\n"));
+        assert!(source.ends_with("        return;\n    }\n}\n"));
+    }
+
+    #[test]
+    fn test_output() -> io::Result<()> {
+        let binary = |op, l, r, chained| Int::Binary(op, Box::new(l), Box::new(r), chained);
+        // 1 + 2 * 4 and 10 - 3 - 2, from left to right
+        let product = binary(
+            IntOp::Mul,
+            binary(IntOp::Add, Int::Const(1), Int::Const(2), false),
+            Int::Const(4),
+            true,
+        );
+        let difference = binary(
+            IntOp::Sub,
+            binary(IntOp::Sub, Int::Const(10), Int::Const(3), false),
+            Int::Const(2),
+            true,
+        );
+        let program = Program {
+            functions: vec![],
+            main: Function {
+                params: 0,
+                body: vec![
+                    Statement::Let(Var::Static(0), product),
+                    Statement::Print(binary(IntOp::Sub, Int::Const(3), difference.clone(), true)),
+                    Statement::PrintString(" ".to_string()),
+                ],
+                result: difference,
+            },
+        };
+
+        let source = program.to_jack();
+        assert!(source.contains("let s0 = (1 + 2 * 4);"), "{source}");
+        assert!(source.contains("printInt((3 - (10 - 3 - 2)))"), "{source}");
+        assert_eq!(program.output(), "-2 5 12 0");
+
+        let dir = TempDir::new()?;
+        assert_eq!(check(&program, dir.path())?, Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuzz() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        assert_eq!(fuzz(0, 30, dir.path())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shrink() {
+        // a program failing when `main` prints a string in a loop, any
+        // string any number of times, shrinks to only that
+        let fails = |program: &Program| {
+            program.main.body.iter().any(|statement| match statement {
+                Statement::While(_, iterations, body) => {
+                    *iterations > 0
+                        && body
+                            .iter()
+                            .any(|statement| matches!(statement, Statement::PrintString(_)))
+                }
+                _ => false,
+            })
+        };
+        let program = (0..).map(generate).find(fails).unwrap();
+        assert!(program.to_jack().len() > 500);
+
+        let minimal = shrink(program, fails);
+        assert_eq!(minimal.functions, []);
+        assert_eq!(minimal.main.result, Int::Const(0));
+        assert!(
+            matches!(
+                &minimal.main.body[..],
+                [Statement::While(_, 1, body)]
+                    if body == &[Statement::PrintString(String::new())]
+            ),
+            "{}",
+            minimal.to_jack()
+        );
+    }
+}
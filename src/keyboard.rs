@@ -0,0 +1,280 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead},
+};
+
+/// Jack character codes of keys that are not ASCII.
+pub const NEW_LINE: u16 = 128;
+pub const BACKSPACE: u16 = 129;
+
+/// Names of keys in a script, besides single characters and `f1` to `f12`.
+const KEY_NAMES: [(&str, u16); 14] = [
+    ("space", 32),
+    ("newline", NEW_LINE),
+    ("backspace", BACKSPACE),
+    ("left", 130),
+    ("up", 131),
+    ("right", 132),
+    ("down", 133),
+    ("home", 134),
+    ("end", 135),
+    ("pageup", 136),
+    ("pagedown", 137),
+    ("insert", 138),
+    ("delete", 139),
+    ("esc", 140),
+];
+
+/// The keyboard the OS reads: typed text for `Keyboard.readChar` and
+/// friends, and the key held down, which `Keyboard.keyPressed` sees at RAM
+/// 24576, changing at set instruction counts or frames.
+pub struct Keyboard {
+    typed: Box<dyn BufRead>,
+    /// instruction count -> key held from then on, `0` for none, in order
+    at_steps: VecDeque<(u64, u16)>,
+    /// frame -> key, a frame ending with each `Sys.wait` call
+    at_frames: VecDeque<(u64, u16)>,
+}
+
+impl Keyboard {
+    /// A keyboard that only types the text read from `typed`, like stdin.
+    pub fn new(typed: Box<dyn BufRead>) -> Self {
+        Keyboard {
+            typed,
+            at_steps: VecDeque::new(),
+            at_frames: VecDeque::new(),
+        }
+    }
+
+    /// Parse an input script, one command a line, `#` starting a comment:
+    ///
+    /// ```text
+    /// type "3\n10\n"       # text for readChar, readLine and readInt
+    /// at 5000 press left   # from instruction 5000, hold the left arrow
+    /// at 6000 release
+    /// frame 10 press a     # after the 10th Sys.wait, hold `a`
+    /// frame 12 release
+    /// ```
+    ///
+    /// A key is a single character, a name like `space`, `up` or `esc`, or
+    /// a Jack character code. Every malformed line is returned as
+    /// `line: message`.
+    pub fn from_script(script: &str) -> Result<Self, Vec<String>> {
+        let mut typed = vec![];
+        let mut at_steps = vec![];
+        let mut at_frames = vec![];
+        let mut errors = vec![];
+
+        for (i, line) in script.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let result = if let Some(text) = line.strip_prefix("type ") {
+                parse_text(text).map(|text| typed.extend(text))
+            } else {
+                parse_event(line).map(|(clock, time, key)| match clock {
+                    "at" => at_steps.push((time, key)),
+                    _ => at_frames.push((time, key)),
+                })
+            };
+            if let Err(error) = result {
+                errors.push(format!("{line_number}: {error}"));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // events at the same time keep their order
+        at_steps.sort_by_key(|(time, _)| *time);
+        at_frames.sort_by_key(|(time, _)| *time);
+        Ok(Keyboard {
+            typed: Box::new(io::Cursor::new(typed)),
+            at_steps: at_steps.into(),
+            at_frames: at_frames.into(),
+        })
+    }
+
+    /// Next character typed: `\n` is the newline key and `\x08` or DEL the
+    /// backspace key.
+    pub fn read_char(&mut self) -> Result<u16, String> {
+        let buf = self.typed.fill_buf().map_err(|error| error.to_string())?;
+        let Some(&c) = buf.first() else {
+            return Err("the keyboard input is over".to_string());
+        };
+        self.typed.consume(1);
+        Ok(match c {
+            b'\n' => NEW_LINE,
+            b'\x08' | 127 => BACKSPACE,
+            c => c as u16,
+        })
+    }
+
+    /// The key held from instruction `step` on, if it changes then.
+    pub fn at_step(&mut self, step: u64) -> Option<u16> {
+        next_key(&mut self.at_steps, step)
+    }
+
+    /// The key held from frame `frame` on, if it changes then.
+    pub fn at_frame(&mut self, frame: u64) -> Option<u16> {
+        next_key(&mut self.at_frames, frame)
+    }
+}
+
+/// The last key of the events due at `time`, which are taken out.
+fn next_key(events: &mut VecDeque<(u64, u16)>, time: u64) -> Option<u16> {
+    let mut key = None;
+    while let Some(&(at, event_key)) = events.front() {
+        if at > time {
+            break;
+        }
+        key = Some(event_key);
+        events.pop_front();
+    }
+    key
+}
+
+/// `"..."` with `\n`, `\b`, `\"` and `\\` escapes.
+fn parse_text(text: &str) -> Result<Vec<u8>, String> {
+    let Some(inner) = text
+        .trim()
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+    else {
+        return Err(format!("`{text}` is not a quoted string"));
+    };
+
+    let mut bytes = vec![];
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('b') => '\x08',
+                Some('"') => '"',
+                Some('\\') => '\\',
+                other => {
+                    return Err(format!("unknown escape `\\{}`", other.unwrap_or(' ')));
+                }
+            },
+            '"' => return Err("`\"` in a string must be escaped".to_string()),
+            c if c.is_ascii() => c,
+            c => return Err(format!("`{c}` is not on the keyboard")),
+        };
+        bytes.push(c as u8);
+    }
+    Ok(bytes)
+}
+
+/// `at|frame <time> press <key>` or `at|frame <time> release`.
+fn parse_event(line: &str) -> Result<(&str, u64, u16), String> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let (clock, time, action) = match words[..] {
+        [clock @ ("at" | "frame"), time, action, ..] => (clock, time, action),
+        _ => return Err(format!("unknown command `{line}`")),
+    };
+    let Ok(time) = time.parse() else {
+        return Err(format!("`{time}` is not a number"));
+    };
+    let key = match (action, &words[3..]) {
+        ("press", [key]) => parse_key(key)?,
+        ("release", []) => 0,
+        _ => return Err(format!("unknown command `{line}`")),
+    };
+    Ok((clock, time, key))
+}
+
+fn parse_key(key: &str) -> Result<u16, String> {
+    if let Some(&(_, code)) = KEY_NAMES.iter().find(|(name, _)| *name == key) {
+        return Ok(code);
+    }
+    if let Some(n) = key.strip_prefix('f').and_then(|n| n.parse::<u16>().ok()) {
+        if (1..=12).contains(&n) {
+            return Ok(140 + n);
+        }
+    }
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_graphic() => Ok(c as u16),
+        _ => match key.parse::<u16>() {
+            Ok(code) if code > 0 => Ok(code),
+            _ => Err(format!("unknown key `{key}`")),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let mut keyboard = Keyboard::from_script(
+            r#"# a comment
+type "1\n"
+type "a\"\\\b"
+at 10 press left
+at 5 press a
+at 10 release
+frame 2 press f5
+frame 3 press 7
+frame 3 press 200
+"#,
+        )
+        .unwrap();
+
+        let typed = (0..6)
+            .map(|_| keyboard.read_char().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            typed,
+            [
+                b'1' as u16,
+                NEW_LINE,
+                b'a' as u16,
+                b'"' as u16,
+                b'\\' as u16,
+                BACKSPACE
+            ]
+        );
+        // nothing more to type
+        assert!(keyboard.read_char().is_err());
+
+        assert_eq!(keyboard.at_step(4), None);
+        assert_eq!(keyboard.at_step(5), Some(b'a' as u16));
+        // `press left` then `release`, in script order
+        assert_eq!(keyboard.at_step(12), Some(0));
+        assert_eq!(keyboard.at_frame(2), Some(145));
+        assert_eq!(keyboard.at_frame(3), Some(200));
+        assert_eq!(keyboard.at_frame(4), None);
+    }
+
+    #[test]
+    fn test_script_errors() {
+        assert_eq!(
+            Keyboard::from_script(
+                "type 1
+at x press a
+frame 1 press
+at 1 release a
+frame 1 press shift
+type \"\\t\"
+jump
+"
+            )
+            .err(),
+            Some(vec![
+                "1: `1` is not a quoted string".to_string(),
+                "2: `x` is not a number".to_string(),
+                "3: unknown command `frame 1 press`".to_string(),
+                "4: unknown command `at 1 release a`".to_string(),
+                "5: unknown key `shift`".to_string(),
+                "6: unknown escape `\\t`".to_string(),
+                "7: unknown command `jump`".to_string(),
+            ])
+        );
+    }
+}
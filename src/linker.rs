@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+
+use crate::{
+    os::OsFunction,
+    vm::{self, Segment, VmInstruction},
+};
+
+/// Where a program starts: the OS's `Sys.init` calls `Main.main`.
+pub const PROGRAM_ENTRIES: [&str; 2] = ["Main.main", "Sys.init"];
+
+/// OS classes `Sys.init` initialises, in order.
+const OS_INITS: [&str; 5] = [
+    "Memory.init",
+    "Math.init",
+    "Screen.init",
+    "Output.init",
+    "Keyboard.init",
+];
+
+/// Functions a program is run from: its [`PROGRAM_ENTRIES`], and with a
+/// `bootstrap` from `link`, the `init` of the OS classes compiled along,
+/// which it calls before `Main.main`.
+pub fn entries(bootstrap: bool) -> Vec<&'static str> {
+    let mut entries = PROGRAM_ENTRIES.to_vec();
+    if bootstrap {
+        entries.extend(OS_INITS);
+    }
+    entries
+}
+
+/// Put the functions of all classes of a program together, in the order
+/// given, behind a `Sys.init` bootstrap when the program has none. Every
+/// problem found is returned: a missing `Main.main`, a function defined
+/// twice, or a call to a function that is neither in the program nor, with
+/// `builtin_os`, in the OS the VM emulator has built in.
+pub fn link(
+    classes: impl IntoIterator<Item = Vec<Vec<VmInstruction>>>,
+    builtin_os: bool,
+) -> Result<Vec<Vec<VmInstruction>>, Vec<String>> {
+    let mut functions = classes.into_iter().flatten().collect::<Vec<_>>();
+    let mut errors = vec![];
+
+    let mut defined = HashSet::new();
+    for function in &functions {
+        let name = vm::function_name(function);
+        if !defined.insert(name.to_string()) {
+            errors.push(format!("`{name}` is defined more than once"));
+        }
+    }
+
+    if !defined.contains("Main.main") {
+        errors.push("`Main.main` is missing".to_string());
+    }
+
+    for function in &functions {
+        for instruction in function {
+            let VmInstruction::Call(callee, _) = instruction else {
+                continue;
+            };
+            let is_os_function = builtin_os && OsFunction::from_name(callee).is_some();
+            if !defined.contains(callee) && !is_os_function {
+                errors.push(format!(
+                    "`{}` calls `{callee}`, which is not defined",
+                    vm::function_name(function)
+                ));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    if !defined.contains("Sys.init") {
+        functions.insert(0, bootstrap(&defined));
+    }
+    Ok(functions)
+}
+
+/// A `Sys.init` that initialises the OS classes the program has, runs
+/// `Main.main` and then loops forever, like `Sys.halt`.
+fn bootstrap(defined: &HashSet<String>) -> Vec<VmInstruction> {
+    let mut code = vec![VmInstruction::Function("Sys.init".to_string(), 0)];
+    for init in OS_INITS.iter().filter(|init| defined.contains(**init)) {
+        code.push(VmInstruction::Call(init.to_string(), 0));
+        code.push(VmInstruction::Pop(Segment::Temp, 0));
+    }
+    code.extend([
+        VmInstruction::Call("Main.main".to_string(), 0),
+        VmInstruction::Pop(Segment::Temp, 0),
+        VmInstruction::Label("halt".to_string()),
+        VmInstruction::Goto("halt".to_string()),
+    ]);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer;
+    use crate::vm::VmInstruction::*;
+
+    fn function(name: &str, callees: &[&str]) -> Vec<VmInstruction> {
+        let mut code = vec![Function(name.to_string(), 0)];
+        for callee in callees {
+            code.push(Call(callee.to_string(), 0));
+            code.push(Pop(Segment::Temp, 0));
+        }
+        code.push(Push(Segment::Constant, 0));
+        code.push(Return);
+        code
+    }
+
+    #[test]
+    fn test_link() {
+        let linked = link(
+            [
+                vec![function("Main.main", &["Memory.init", "Output.printInt"])],
+                vec![function("Memory.init", &[])],
+            ],
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            linked,
+            vec![
+                vec![
+                    Function("Sys.init".to_string(), 0),
+                    Call("Memory.init".to_string(), 0),
+                    Pop(Segment::Temp, 0),
+                    Call("Main.main".to_string(), 0),
+                    Pop(Segment::Temp, 0),
+                    Label("halt".to_string()),
+                    Goto("halt".to_string()),
+                ],
+                function("Main.main", &["Memory.init", "Output.printInt"]),
+                function("Memory.init", &[]),
+            ]
+        );
+
+        // the program's own `Sys.init` is kept
+        let classes = vec![
+            vec![function("Main.main", &[])],
+            vec![function("Sys.init", &["Main.main"])],
+        ];
+        assert_eq!(
+            link(classes.clone(), true).unwrap(),
+            classes.into_iter().flatten().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_link_errors() {
+        assert_eq!(
+            link(
+                [
+                    vec![function("Game.run", &["Game.step", "Math.max"])],
+                    vec![function("Game.run", &[])],
+                ],
+                true
+            ),
+            Err(vec![
+                "`Game.run` is defined more than once".to_string(),
+                "`Main.main` is missing".to_string(),
+                "`Game.run` calls `Game.step`, which is not defined".to_string(),
+            ])
+        );
+
+        // without the built-in OS, the OS has to be linked in as well
+        assert_eq!(
+            link([vec![function("Main.main", &["Math.max"])]], false),
+            Err(vec![
+                "`Main.main` calls `Math.max`, which is not defined".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_entries_keep_os_inits() {
+        let classes = [
+            vec![function("Main.main", &["Math.max"])],
+            vec![
+                function("Math.init", &[]),
+                function("Math.max", &[]),
+                function("Math.sqrt", &[]),
+            ],
+        ];
+        let reachable = |bootstrap| {
+            let functions = classes.iter().flatten().map(Vec::as_slice);
+            let mut reachable = optimizer::reachable_functions(functions, &entries(bootstrap))
+                .into_iter()
+                .collect::<Vec<_>>();
+            reachable.sort();
+            reachable
+        };
+
+        // the bootstrap calls `Math.init`, which nothing else calls
+        assert_eq!(reachable(true), ["Main.main", "Math.init", "Math.max"]);
+        assert_eq!(reachable(false), ["Main.main", "Math.max"]);
+    }
+}
@@ -1,44 +1,764 @@
+mod assembler;
+mod comparer;
 mod compilation_engine;
+mod debug_info;
+mod debugger;
+mod doc;
+mod emulator;
+mod formatter;
+mod fuzz;
+mod keyboard;
+mod linker;
+mod optimizer;
+mod os;
+mod profiler;
+mod screen;
+mod source_map;
+mod symbol_table;
+#[cfg(test)]
 mod test_file;
+#[cfg(test)]
+mod test_programs;
 mod tokenizer;
-mod symbol_table;
+mod translator;
+mod vm;
 mod vm_writer;
 
-use std::{env::args, error::Error, ffi::OsStr, fs::OpenOptions, io::Write, path::Path, result};
+use std::{
+    env::{self, args},
+    error::Error,
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process, result,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::{compilation_engine::CompilationEngine, tokenizer::*};
+use crate::{
+    compilation_engine::{CompilationEngine, CompileOptions, Diagnostic},
+    debug_info::ClassDebugInfo,
+    debugger::Debugger,
+    emulator::{Emulator, Exit},
+    formatter::{Formatter, VarStyle},
+    keyboard::Keyboard,
+    os::Os,
+    tokenizer::*,
+    translator::Translator,
+    vm::VmInstruction,
+    vm_writer::VmWriter,
+};
 
 fn main() -> result::Result<(), Box<dyn Error>> {
-    let Some(source) = args().nth(1) else {
+    let args = args().skip(1).collect::<Vec<_>>();
+
+    match args.first().map(String::as_str) {
+        Some("fmt") => return format(&args[1..]),
+        Some("doc") => return document(&args[1..]),
+        Some("asm") => return assemble(&args[1..]),
+        Some("run") => return run(&args[1..]),
+        Some("debug") => return debug(&args[1..]),
+        Some("check") => return check(&args[1..]),
+        Some("compare") => return compare(&args[1..]),
+        Some("fuzz") => return fuzz(&args[1..]),
+        _ => {}
+    }
+
+    // `jackc [-O] [--short-circuit] [--pool-strings] [--drop-unused] [--link]
+    //        [--emit vm|asm|hack] [--source-map] [--vm-comments] [-o <output>]
+    //        <source>`
+    let mut options = CompileOptions::default();
+    let mut drop_unused = false;
+    let mut link = false;
+    let mut source_map = false;
+    let mut vm_comments = false;
+    let mut emit = Emit::Vm;
+    let mut output = None;
+    let mut source = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O" => options.optimize = true,
+            "--short-circuit" => options.short_circuit = true,
+            "--pool-strings" => options.pool_strings = true,
+            "--drop-unused" => drop_unused = true,
+            "--link" => link = true,
+            "--emit" => match args.next().map(String::as_str) {
+                Some("vm") => emit = Emit::Vm,
+                Some("asm") => emit = Emit::Asm,
+                Some("hack") => emit = Emit::Hack,
+                emit => return Err(format!("unknown --emit `{}`", emit.unwrap_or("")).into()),
+            },
+            "--source-map" => source_map = true,
+            "--vm-comments" => vm_comments = true,
+            "-o" => output = args.next().map(String::as_str),
+            _ => source = Some(arg),
+        }
+    }
+    let Some(source) = source else {
         panic!("jackc need a `source` argument");
     };
+    if source_map || vm_comments {
+        if emit != Emit::Vm {
+            return Err("--source-map and --vm-comments are for VM output".into());
+        }
+        // the optimizer doesn't keep track of lines
+        if options.optimize {
+            return Err("--source-map and --vm-comments don't work with -O".into());
+        }
+    }
+    if source_map && output == Some("-") {
+        return Err("--source-map needs an output file, not stdout".into());
+    }
+
+    // VM code of every class goes to one output, `-` for stdout; by default
+    // each class gets its own `.my-vm.vm` file
+    let mut vm_output: Option<Box<dyn Write>> = match output {
+        Some("-") => Some(Box::new(io::stdout().lock())),
+        Some(output) => Some(Box::new(BufWriter::new(File::create(output)?))),
+        None => None,
+    };
+
+    // whole-program passes need every class compiled before any VM code is
+    // written; otherwise the engine writes each class as it compiles it
+    let whole_program = drop_unused || link || emit != Emit::Vm || vm_comments;
+
+    let mut error_count = 0;
+    let mut classes = vec![];
+    let mut debug_info = vec![];
+    for filepath in jack_files(Path::new(source))? {
+        let filename = filepath.file_name().unwrap().to_string_lossy();
+        if output == Some("-") {
+            eprintln!("complie {}", filename);
+        } else {
+            println!("complie {}", filename);
+        }
+        let class = match &mut vm_output {
+            _ if whole_program => compile(&filepath, io::sink(), options)?,
+            Some(vm_output) => compile(&filepath, vm_output, options)?,
+            None => {
+                let path = filepath.with_extension(OUTPUT_VM_FILE_EXTENSION);
+                compile(&filepath, BufWriter::new(File::create(path)?), options)?
+            }
+        };
+        error_count += class.error_count;
+        classes.push((filepath, class.functions));
+        debug_info.push(class.debug_info);
+    }
+
+    if drop_unused {
+        // `--link` and `--emit` give the program a bootstrap, calling the OS
+        // inits
+        let reachable = optimizer::reachable_functions(
+            classes
+                .iter()
+                .flat_map(|(_, functions)| functions.iter().map(Vec::as_slice)),
+            &linker::entries(link || emit != Emit::Vm),
+        );
+        if !linker::PROGRAM_ENTRIES
+            .iter()
+            .any(|&entry| reachable.contains(entry))
+        {
+            return Err("--drop-unused needs a `Main.main` or `Sys.init` function".into());
+        }
+        let mut dropped = 0;
+        for (_, functions) in &mut classes {
+            let count = functions.len();
+            functions.retain(|function| reachable.contains(vm::function_name(function)));
+            dropped += count - functions.len();
+        }
+        if output == Some("-") {
+            eprintln!("drop {dropped} unused function(s)");
+        } else {
+            println!("drop {dropped} unused function(s)");
+        }
+    }
 
-    let path = Path::new(&source);
+    // Hack assembly is for a whole program, with no OS built in
+    if link || emit != Emit::Vm {
+        // nothing to link when a class doesn't compile
+        if error_count > 0 {
+            return Err(format!("compile fail with {error_count} error(s)").into());
+        }
+
+        let functions = match linker::link(
+            classes.into_iter().map(|(_, functions)| functions),
+            emit == Emit::Vm,
+        ) {
+            Ok(functions) => functions,
+            Err(errors) => {
+                for error in &errors {
+                    eprintln!("link: {error}");
+                }
+                return Err(format!("link fail with {} error(s)", errors.len()).into());
+            }
+        };
+
+        let code = match emit {
+            Emit::Vm => None,
+            Emit::Asm => Some((Translator::translate(&functions), OUTPUT_ASM_FILE_EXTENSION)),
+            Emit::Hack => {
+                let asm = Translator::translate(&functions);
+                let hack = assembler::assemble(&asm).map_err(|errors| {
+                    // the translator only writes valid assembly, but a big
+                    // program may not fit in the ROM
+                    format!("assemble fail: {}", errors.join(", "))
+                })?;
+                Some((hack, OUTPUT_HACK_FILE_EXTENSION))
+            }
+        };
+        let extension = code.as_ref().map_or(OUTPUT_VM_FILE_EXTENSION, |(_, e)| e);
+        let path = match output {
+            Some(output) => PathBuf::from(output),
+            None => linked_path(Path::new(source), extension),
+        };
+        match (code, &mut vm_output) {
+            (None, Some(vm_output)) => {
+                write_vm_code(vm_output, &functions, &debug_info, vm_comments)?
+            }
+            (None, None) => write_vm_code(
+                BufWriter::new(File::create(&path)?),
+                &functions,
+                &debug_info,
+                vm_comments,
+            )?,
+            (Some((code, _)), Some(vm_output)) => {
+                vm_output.write_all(code.as_bytes())?;
+                vm_output.flush()?;
+            }
+            (Some((code, _)), None) => fs::write(&path, code)?,
+        }
+        if source_map {
+            write_source_map(&path, &functions, &debug_info)?;
+        }
+        return Ok(());
+    }
 
-    if path.is_file() && path.extension() == Some(OsStr::new(JACK_FILE_EXTENSION)) {
-        // process a .jack file
-        compile(path)?;
+    for (filepath, functions) in &classes {
+        let path = filepath.with_extension(OUTPUT_VM_FILE_EXTENSION);
+        if whole_program {
+            match &mut vm_output {
+                Some(vm_output) => write_vm_code(vm_output, functions, &debug_info, vm_comments)?,
+                None => write_vm_code(
+                    BufWriter::new(File::create(&path)?),
+                    functions,
+                    &debug_info,
+                    vm_comments,
+                )?,
+            }
+        }
+        if source_map && output.is_none() {
+            write_source_map(&path, functions, &debug_info)?;
+        }
+    }
+    // one map for all the classes in the output
+    if let (true, Some(output)) = (source_map, output) {
+        let functions = classes
+            .iter()
+            .flat_map(|(_, functions)| functions.iter().cloned())
+            .collect::<Vec<_>>();
+        write_source_map(Path::new(output), &functions, &debug_info)?;
+    }
+
+    if error_count > 0 {
+        return Err(format!("compile fail with {error_count} error(s)").into());
+    }
+    Ok(())
+}
+
+/// Write the VM code of `functions` to `out`, with their source lines as
+/// comments when asked.
+fn write_vm_code(
+    out: impl Write,
+    functions: &[Vec<VmInstruction>],
+    debug_info: &[ClassDebugInfo],
+    comments: bool,
+) -> io::Result<()> {
+    let mut vm_writer = VmWriter::new(out);
+    for instruction in functions.iter().flatten() {
+        vm_writer.write(instruction.clone());
+    }
+    if comments {
+        vm_writer.finish_with_comments(debug_info)
+    } else {
+        vm_writer.finish()
+    }
+}
+
+/// Write the source map of the VM code at `vm_path` next to it, in
+/// `<vm_path>.map`.
+fn write_source_map(
+    vm_path: &Path,
+    functions: &[Vec<VmInstruction>],
+    debug_info: &[ClassDebugInfo],
+) -> io::Result<()> {
+    let mut path = vm_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(OUTPUT_SOURCE_MAP_FILE_EXTENSION);
+    let locations = source_map::locations(functions, debug_info);
+    fs::write(path, source_map::render(&locations))
+}
+
+/// What the compiler writes.
+#[derive(PartialEq, Eq)]
+enum Emit {
+    Vm,
+    Asm,
+    Hack,
+}
+
+/// `XXX/XXX.<extension>` for a folder `XXX`, `XXX.<extension>` for
+/// `XXX.jack`.
+fn linked_path(source: &Path, extension: &str) -> PathBuf {
+    if source.is_dir() {
+        let name = source
+            .canonicalize()
+            .ok()
+            .and_then(|path| path.file_name().map(|name| name.to_os_string()))
+            .unwrap_or_else(|| "out".into());
+        source.join(name).with_extension(extension)
+    } else {
+        source.with_extension(extension)
+    }
+}
+
+/// The `.jack` file itself, or every `.jack` file in a folder.
+fn jack_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    source_files(path, JACK_FILE_EXTENSION)
+}
+
+/// The file itself if it has `extension`, or every such file in a folder.
+fn source_files(path: &Path, extension: &str) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+
+    if path.is_file() && path.extension() == Some(OsStr::new(extension)) {
+        // process a single file
+        files.push(path.to_path_buf());
     } else if path.is_dir() {
         // process a folder
-        for entry in path.read_dir().unwrap() {
-            let entry = entry.unwrap();
-            let child_path = entry.path();
-            if child_path.is_file()
-                && child_path.extension() == Some(OsStr::new(JACK_FILE_EXTENSION))
-            {
-                compile(&child_path)?;
+        for entry in path.read_dir()? {
+            let child_path = entry?.path();
+            if child_path.is_file() && child_path.extension() == Some(OsStr::new(extension)) {
+                files.push(child_path);
             }
         }
+        files.sort();
     }
 
+    Ok(files)
+}
+
+/// `jackc fmt [--check] [--group-vars] <source>`
+fn format(args: &[String]) -> result::Result<(), Box<dyn Error>> {
+    let mut check = false;
+    let mut var_style = VarStyle::Split;
+    let mut source = None;
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--group-vars" => var_style = VarStyle::Grouped,
+            _ => source = Some(arg),
+        }
+    }
+    let Some(source) = source else {
+        panic!("jackc fmt need a `source` argument");
+    };
+
+    let mut unformatted = 0;
+    let mut error_count = 0;
+    for filepath in jack_files(Path::new(source))? {
+        // the formatter expects a class the compiler accepts
+        let mut engine = CompilationEngine::without_output(&filepath)?;
+        engine.compile_class()?;
+        let errors = report_diagnostics(&filepath, engine.diagnostics());
+        if errors > 0 {
+            error_count += errors;
+            continue;
+        }
+
+        let mut formatter = Formatter::new(&filepath, var_style)?;
+        formatter.format_class()?;
+
+        if formatter.output() == fs::read_to_string(&filepath)? {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", filepath.display());
+            unformatted += 1;
+        } else {
+            println!("format {}", filepath.display());
+            fs::write(&filepath, formatter.output())?;
+        }
+    }
+
+    if error_count > 0 {
+        return Err(format!("format fail with {error_count} error(s)").into());
+    }
+    if unformatted > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// `jackc doc [--html] <source>`
+fn document(args: &[String]) -> result::Result<(), Box<dyn Error>> {
+    let mut html = false;
+    let mut source = None;
+    for arg in args {
+        match arg.as_str() {
+            "--html" => html = true,
+            _ => source = Some(arg),
+        }
+    }
+    let Some(source) = source else {
+        panic!("jackc doc need a `source` argument");
+    };
+
+    let mut error_count = 0;
+    for filepath in jack_files(Path::new(source))? {
+        println!("document {}", filepath.display());
+
+        // the engine collects declarations and doc comments while compiling
+        let mut engine = CompilationEngine::without_output(&filepath)?;
+        engine.compile_class()?;
+        let errors = report_diagnostics(&filepath, engine.diagnostics());
+        if errors > 0 {
+            error_count += errors;
+            continue;
+        }
+
+        let (content, extension) = if html {
+            (
+                doc::render_html(engine.class_doc()),
+                OUTPUT_DOC_HTML_FILE_EXTENSION,
+            )
+        } else {
+            (
+                doc::render_markdown(engine.class_doc()),
+                OUTPUT_DOC_MARKDOWN_FILE_EXTENSION,
+            )
+        };
+        fs::write(filepath.with_extension(extension), content)?;
+    }
+
+    if error_count > 0 {
+        return Err(format!("doc fail with {error_count} error(s)").into());
+    }
     Ok(())
 }
 
-fn compile(filepath: &Path) -> result::Result<(), Box<dyn Error>> {
+/// `jackc asm [-o <output>] <source.asm>`
+fn assemble(args: &[String]) -> result::Result<(), Box<dyn Error>> {
+    let mut output = None;
+    let mut source = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = args.next().map(String::as_str),
+            _ => source = Some(arg),
+        }
+    }
+    let Some(source) = source else {
+        panic!("jackc asm need a `source` argument");
+    };
+
+    let source = Path::new(source);
+    let hack = match assembler::assemble(&fs::read_to_string(source)?) {
+        Ok(hack) => hack,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}:{}", source.display(), error);
+            }
+            return Err(format!("assemble fail with {} error(s)", errors.len()).into());
+        }
+    };
+
+    match output {
+        Some("-") => io::stdout().lock().write_all(hack.as_bytes())?,
+        Some(output) => fs::write(output, hack)?,
+        None => fs::write(source.with_extension(OUTPUT_HACK_FILE_EXTENSION), hack)?,
+    }
+    Ok(())
+}
+
+/// `jackc check <source.vm>`, or every `.vm` file of a folder
+fn check(args: &[String]) -> result::Result<(), Box<dyn Error>> {
+    let Some(source) = args.first() else {
+        panic!("jackc check need a `source` argument");
+    };
+
+    let mut error_count = 0;
+    for filepath in source_files(Path::new(source), VM_FILE_EXTENSION)? {
+        println!("check {}", filepath.display());
+        if let Err(errors) = vm::parse(&fs::read_to_string(&filepath)?) {
+            for error in &errors {
+                eprintln!("{}:{}", filepath.display(), error);
+            }
+            error_count += errors.len();
+        }
+    }
+
+    if error_count > 0 {
+        return Err(format!("check fail with {error_count} error(s)").into());
+    }
+    Ok(())
+}
+
+/// `jackc compare <source>`: compile every class as `jackc` does, and
+/// compare its tokens, AST and VM code with the reference `XXXT.xml`,
+/// `XXX.xml` and `XXX.vm` files next to it, the ones that exist
+fn compare(args: &[String]) -> result::Result<(), Box<dyn Error>> {
+    let Some(source) = args.first() else {
+        panic!("jackc compare need a `source` argument");
+    };
+
+    let mut mismatches = 0;
+    for filepath in jack_files(Path::new(source))? {
+        println!("compare {}", filepath.display());
+        let vm_output = File::create(filepath.with_extension(OUTPUT_VM_FILE_EXTENSION))?;
+        let class = compile(
+            &filepath,
+            BufWriter::new(vm_output),
+            CompileOptions::default(),
+        )?;
+        if class.error_count > 0 {
+            mismatches += 1;
+            continue;
+        }
+
+        let stem = filepath.file_stem().unwrap().to_string_lossy();
+        let outputs = [
+            (
+                "tokens",
+                OUTPUT_TOKEN_FILE_EXTENSION,
+                format!("{stem}T.xml"),
+            ),
+            ("AST", OUTPUT_AST_FILE_EXTENSION, format!("{stem}.xml")),
+            ("VM code", OUTPUT_VM_FILE_EXTENSION, format!("{stem}.vm")),
+        ];
+        for (what, extension, reference) in outputs {
+            let reference_path = filepath.with_file_name(&reference);
+            if !reference_path.is_file() {
+                continue;
+            }
+            let output_path = filepath.with_extension(extension);
+            let mut output = fs::read_to_string(&output_path)?;
+            if extension == OUTPUT_AST_FILE_EXTENSION {
+                output = comparer::strip_info(&output);
+            }
+            match comparer::compare(
+                &output,
+                &output_path.display().to_string(),
+                &fs::read_to_string(&reference_path)?,
+                &reference_path.display().to_string(),
+            ) {
+                Ok(()) => println!("  {what} match {reference}"),
+                Err(report) => {
+                    eprintln!("  {what} differ from {reference}");
+                    eprint!("{report}");
+                    mismatches += 1;
+                }
+            }
+        }
+    }
+
+    if mismatches > 0 {
+        return Err(format!("compare fail with {mismatches} mismatch(es)").into());
+    }
+    Ok(())
+}
+
+/// `jackc fuzz [--seed <n>] [--runs <n>]`: check random programs, and
+/// print the smallest program failing a check
+fn fuzz(args: &[String]) -> result::Result<(), Box<dyn Error>> {
+    let mut seed = None;
+    let mut runs = 100;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = args.next().map(|n| n.parse()).transpose()?,
+            "--runs" => runs = args.next().map_or(Ok(runs), |n| n.parse())?,
+            _ => panic!("jackc fuzz don't take `{arg}`"),
+        }
+    }
+    // a new seed each time, printed to reproduce the run with `--seed`
+    let seed = match seed {
+        Some(seed) => seed,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    println!("fuzz {runs} program(s) from seed {seed}");
+
+    let dir = env::temp_dir().join(format!("jackc-fuzz-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let result = fuzz::fuzz(seed, runs, &dir);
+    fs::remove_dir_all(&dir)?;
+    let Some((seed, failure, program)) = result? else {
+        return Ok(());
+    };
+    eprintln!(
+        "seed {seed} fails the {} check: {}",
+        failure.check, failure.detail
+    );
+    eprintln!("the smallest program failing it:");
+    eprint!("{}", program.to_jack());
+    Err(format!("fuzz fail with seed {seed}").into())
+}
+
+/// `jackc run [-O] [--short-circuit] [--pool-strings] [--keys <script>]
+///            [--screen <image>] <source>`
+fn run(args: &[String]) -> result::Result<(), Box<dyn Error>> {
+    let mut options = CompileOptions::default();
+    let mut key_script = None;
+    let mut screen_snapshot = None;
+    let mut profile_report = None;
+    let mut folded_stacks = None;
+    let mut source = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O" => options.optimize = true,
+            "--short-circuit" => options.short_circuit = true,
+            "--pool-strings" => options.pool_strings = true,
+            "--keys" => key_script = args.next().map(Path::new),
+            "--screen" => screen_snapshot = args.next().map(Path::new),
+            "--profile" => profile_report = args.next().map(Path::new),
+            "--folded" => folded_stacks = args.next().map(Path::new),
+            _ => source = Some(arg),
+        }
+    }
+    let Some(source) = source else {
+        panic!("jackc run need a `source` argument");
+    };
+
+    let program = compile_program(Path::new(source), options)?;
+
+    // stdin types for the keyboard unless a script does, and stdout shows
+    // the text output
+    let keyboard = match key_script {
+        Some(path) => read_key_script(path)?,
+        None => Keyboard::new(Box::new(io::stdin().lock())),
+    };
+    let os = Os::new(keyboard, Box::new(BufWriter::new(io::stdout().lock())));
+    let mut emulator = Emulator::new(&program.functions, os)?;
+    if profile_report.is_some() || folded_stacks.is_some() {
+        emulator.enable_profiler();
+    }
+    let exit = emulator.run();
+    // what the screen shows and where the time went, even when the program
+    // went wrong
+    if let Some(path) = screen_snapshot {
+        screen::write_snapshot(path, emulator.ram())?;
+    }
+    if let Some(profile) = emulator.profile() {
+        if let Some(path) = profile_report {
+            fs::write(path, profile.render_table(&program.debug_info))?;
+        }
+        if let Some(path) = folded_stacks {
+            fs::write(path, profile.render_folded())?;
+        }
+    }
+    match exit? {
+        Exit::Halt => Ok(()),
+        Exit::Error(_) => process::exit(1),
+    }
+}
+
+/// `jackc debug [--keys <script>] <source>`: run a program under the
+/// source-level debugger, which reads its commands from stdin.
+fn debug(args: &[String]) -> result::Result<(), Box<dyn Error>> {
+    let mut key_script = None;
+    let mut source = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--keys" => key_script = args.next().map(Path::new),
+            _ => source = Some(arg),
+        }
+    }
+    let Some(source) = source else {
+        panic!("jackc debug need a `source` argument");
+    };
+
+    // unoptimized, so every instruction has a line
+    let program = compile_program(Path::new(source), CompileOptions::default())?;
+
+    // stdin is for the debugger commands, so only a script types
+    let keyboard = match key_script {
+        Some(path) => read_key_script(path)?,
+        None => Keyboard::from_script("").unwrap(),
+    };
+    let os = Os::new(keyboard, Box::new(io::stdout()));
+    let emulator = Emulator::new(&program.functions, os)?;
+    Debugger::new(emulator, program.debug_info).run(&mut io::stdin().lock(), &mut io::stdout())?;
+    Ok(())
+}
+
+fn read_key_script(path: &Path) -> result::Result<Keyboard, Box<dyn Error>> {
+    Keyboard::from_script(&fs::read_to_string(path)?).map_err(|errors| {
+        for error in &errors {
+            eprintln!("{}:{}", path.display(), error);
+        }
+        format!("key script fail with {} error(s)", errors.len()).into()
+    })
+}
+
+/// A linked program and the debug info of its classes.
+struct Program {
+    functions: Vec<Vec<VmInstruction>>,
+    debug_info: Vec<ClassDebugInfo>,
+}
+
+/// Compile every class of a program and link them, with the OS built into
+/// the emulator.
+fn compile_program(
+    source: &Path,
+    options: CompileOptions,
+) -> result::Result<Program, Box<dyn Error>> {
+    let mut error_count = 0;
+    let mut classes = vec![];
+    let mut debug_info = vec![];
+    for filepath in jack_files(source)? {
+        // no token, AST or VM files next to the sources of a program run
+        let engine = CompilationEngine::without_output(&filepath)?;
+        let class = compile_with(engine, &filepath, options)?;
+        error_count += class.error_count;
+        classes.push(class.functions);
+        debug_info.push(class.debug_info);
+    }
+    if error_count > 0 {
+        return Err(format!("compile fail with {error_count} error(s)").into());
+    }
+
+    let functions = linker::link(classes, true).map_err(|errors| {
+        for error in &errors {
+            eprintln!("link: {error}");
+        }
+        format!("link fail with {} error(s)", errors.len())
+    })?;
+    Ok(Program {
+        functions,
+        debug_info,
+    })
+}
+
+/// A compiled `.jack` file.
+struct CompiledClass {
+    /// how many errors were found in the source
+    error_count: usize,
+    functions: Vec<Vec<VmInstruction>>,
+    debug_info: ClassDebugInfo,
+}
+
+/// Compile a `.jack` file, writing its tokens and AST next to it, and its
+/// VM code to `vm_output`.
+fn compile(
+    filepath: &Path,
+    vm_output: impl Write,
+    options: CompileOptions,
+) -> result::Result<CompiledClass, Box<dyn Error>> {
     let Some(filename) = filepath.file_name().unwrap().to_str() else {
         panic!();
     };
-    println!("complie {}", filename);
 
     // output file settings
     let file_basename = &filename[..(filename.len() - (JACK_FILE_EXTENSION.len() + 1))];
@@ -51,81 +771,54 @@ fn compile(filepath: &Path) -> result::Result<(), Box<dyn Error>> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(true)
         .open(output_token_file_path)?;
 
-    // construct xml file content
-    let mut buf = String::new();
-    buf += "<tokens>\n";
-
-    // test tokenizer
-    let mut tokenizer = Tokenizer::new(filepath)?;
-    while tokenizer.has_more_tokens() {
-        use TokenType::*;
-        match tokenizer.token_type() {
-            Some(Keyword) => {
-                buf += &format!("<{}>", XML_TAG_KEYWORD);
-                let keyword = tokenizer.keyword();
-                buf += &keyword;
-                buf += &format!("</{}>\n", XML_TAG_KEYWORD);
-            }
-            Some(Symbol) => {
-                buf += &format!("<{}>", XML_TAG_SYMBOL);
-                let symbol = tokenizer.symbol();
-                let mut symbol_string = symbol.to_string();
-                if symbol == '<' {
-                    symbol_string = "&lt;".to_string();
-                } else if symbol == '>' {
-                    symbol_string = "&gt;".to_string();
-                } else if symbol == '&' {
-                    symbol_string = "&amp;".to_string();
-                }
-                buf += &symbol_string;
-
-                buf += &format!("</{}>\n", XML_TAG_SYMBOL);
-            }
-            Some(IntConst) => {
-                buf += &format!("<{}>", XML_TAG_INT_CONST);
-                let int_const = tokenizer.int_const();
-                buf += &int_const.to_string();
-                buf += &format!("</{}>\n", XML_TAG_INT_CONST);
-            }
-            Some(StringConst) => {
-                buf += &format!("<{}>", XML_TAG_STRING_CONST);
-                let string_const = tokenizer.string_const();
-                buf += &string_const;
-                buf += &format!("</{}>\n", XML_TAG_STRING_CONST);
-            }
-            Some(Identifier) => {
-                buf += &format!("<{}>", XML_TAG_IDENTIFIER);
-                let identifier = tokenizer.identifier();
-                buf += &identifier;
-                buf += &format!("</{}>\n", XML_TAG_IDENTIFIER);
-            }
-            None => panic!(),
-        }
-        tokenizer.advance()?;
-    }
-
-    buf += r#"</tokens>"#;
+    let buf = token_xml(filepath)?;
 
     // write to xml file
     output_token_file.write_all(buf.as_bytes())?;
 
-    // use compilation engine to genrate ast and vm file
-    let mut engine = CompilationEngine::new(filepath)?;
+    // use compilation engine to genrate ast file and vm code
+    let engine = CompilationEngine::with_vm_output(filepath, vm_output)?;
+    compile_with(engine, filepath, options)
+}
+
+/// Compile a `.jack` file with `engine`, which writes the AST and VM code or
+/// not.
+fn compile_with<W: Write>(
+    mut engine: CompilationEngine<W>,
+    filepath: &Path,
+    options: CompileOptions,
+) -> result::Result<CompiledClass, Box<dyn Error>> {
+    engine.set_options(options);
     engine.compile_class()?;
 
-    Ok(())
+    Ok(CompiledClass {
+        error_count: report_diagnostics(filepath, engine.diagnostics()),
+        functions: engine.functions().to_vec(),
+        debug_info: engine.debug_info().clone(),
+    })
 }
 
-fn report_syntax_error(msg: &str) {
-    panic!("{}", msg);
+/// Print diagnostics as `path:line: message`, returning how many there are.
+fn report_diagnostics(filepath: &Path, diagnostics: &[Diagnostic]) -> usize {
+    for diagnostic in diagnostics {
+        eprintln!("{}:{}", filepath.display(), diagnostic);
+    }
+    diagnostics.len()
 }
 
 const JACK_FILE_EXTENSION: &str = "jack";
+const VM_FILE_EXTENSION: &str = "vm";
 const OUTPUT_TOKEN_FILE_EXTENSION: &str = "my-token.xml";
 const OUTPUT_AST_FILE_EXTENSION: &str = "my-ast.xml";
 const OUTPUT_VM_FILE_EXTENSION: &str = "my-vm.vm";
+const OUTPUT_SOURCE_MAP_FILE_EXTENSION: &str = "map";
+const OUTPUT_ASM_FILE_EXTENSION: &str = "asm";
+const OUTPUT_HACK_FILE_EXTENSION: &str = "hack";
+const OUTPUT_DOC_MARKDOWN_FILE_EXTENSION: &str = "md";
+const OUTPUT_DOC_HTML_FILE_EXTENSION: &str = "html";
 
 // TOKEN
 const XML_TAG_KEYWORD: &str = "keyword";
@@ -150,4 +843,3 @@ const XML_TAG_STATEMENT_DO: &str = "doStatement";
 const XML_TAG_STATEMENT_RETURN: &str = "returnStatement";
 const XML_TAG_TERM: &str = "term";
 const XML_TAG_EXPRESSION_LIST: &str = "expressionList";
-
@@ -0,0 +1,1116 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::vm::{self, ArithmeticOp, Segment, VmInstruction};
+
+/// Optimise the VM code of a function until no rule applies any more.
+pub fn optimize(function: &mut Vec<VmInstruction>) {
+    loop {
+        let before = function.clone();
+        fold_constants(function);
+        reduce_strength(function);
+        peephole(function);
+        thread_jumps(function);
+        remove_dead_code(function);
+        if *function == before {
+            break;
+        }
+    }
+}
+
+/// Names of the `functions` that `entries` can call, directly or through
+/// other functions. Calls to functions not in `functions`, like the OS's
+/// when it isn't compiled along, lead nowhere.
+pub fn reachable_functions<'a>(
+    functions: impl IntoIterator<Item = &'a [VmInstruction]>,
+    entries: &[&str],
+) -> HashSet<String> {
+    let calls = functions
+        .into_iter()
+        .map(|function| {
+            let callees = function
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    VmInstruction::Call(name, _) => Some(name.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            (vm::function_name(function), callees)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut reachable = HashSet::new();
+    let mut pending = entries.to_vec();
+    while let Some(name) = pending.pop() {
+        let Some(callees) = calls.get(name) else {
+            continue;
+        };
+        if reachable.insert(name.to_string()) {
+            pending.extend(callees);
+        }
+    }
+    reachable
+}
+
+/// Evaluate arithmetic on constants at compile time, with the 16-bit
+/// wraparound of the Hack platform, and drop operations that don't change
+/// their operand: `x + 0`, `x - 0`, `x | 0`, `x & -1`, `x * 1`, `x / 1`,
+/// `~~x` and `-(-x)`. A conditional jump on a constant becomes a `goto` or
+/// nothing.
+pub fn fold_constants(function: &mut Vec<VmInstruction>) {
+    let mut out: Vec<VmInstruction> = vec![];
+
+    for instruction in function.drain(..) {
+        match &instruction {
+            VmInstruction::Arithmetic(op @ (ArithmeticOp::Neg | ArithmeticOp::Not)) => {
+                if let Some((value, len)) = trailing_constant(&out) {
+                    out.truncate(out.len() - len);
+                    push_constant(&mut out, unary(*op, value));
+                } else if out.last() == Some(&instruction) {
+                    out.pop();
+                } else {
+                    out.push(instruction);
+                }
+            }
+            VmInstruction::IfGoto(label) => {
+                if let Some((value, len)) = trailing_constant(&out) {
+                    out.truncate(out.len() - len);
+                    if value != 0 {
+                        out.push(VmInstruction::Goto(label.clone()));
+                    }
+                } else {
+                    out.push(instruction);
+                }
+            }
+            _ => {
+                let Some(op) = BinaryOp::of(&instruction) else {
+                    out.push(instruction);
+                    continue;
+                };
+                let Some((right, right_len)) = trailing_constant(&out) else {
+                    out.push(instruction);
+                    continue;
+                };
+                let left = trailing_constant(&out[..out.len() - right_len]);
+
+                if let Some(value) = left.and_then(|(left, _)| op.eval(left, right)) {
+                    let len = right_len + left.unwrap().1;
+                    out.truncate(out.len() - len);
+                    push_constant(&mut out, value);
+                } else if op.is_identity(right) {
+                    out.truncate(out.len() - right_len);
+                } else {
+                    out.push(instruction);
+                }
+            }
+        }
+    }
+
+    *function = out;
+}
+
+/// Multiply by a constant with `add`s instead of calling `Math.multiply`,
+/// when the constant is a power of two or smaller than `SMALL_MULTIPLIER`.
+/// `c * x` is turned around to `x * c` when `x` is a plain variable.
+///
+/// Divide by a power of two without `Math.divide` when the dividend is known
+/// to be non-negative, as `x & m` is for a constant `m`: the quotient is
+/// built from the bits `m` lets through. A negative dividend would need
+/// rounding toward zero, so other divisions keep calling `Math.divide`.
+/// Constant dividends are already divided by `fold_constants`.
+pub fn reduce_strength(function: &mut Vec<VmInstruction>) {
+    let mut out: Vec<VmInstruction> = vec![];
+
+    for instruction in function.drain(..) {
+        if let Some(BinaryOp::Multiply) = BinaryOp::of(&instruction) {
+            // x * c
+            if let Some((c, len)) = trailing_constant(&out) {
+                if let Some(code) = multiply_by(c) {
+                    out.truncate(out.len() - len);
+                    out.extend(code);
+                    continue;
+                }
+            }
+
+            // c * x
+            if let [.., VmInstruction::Push(segment, _)] = &out[..] {
+                let x_start = out.len() - 1;
+                if *segment != Segment::Constant {
+                    if let Some((c, len)) = trailing_constant(&out[..x_start]) {
+                        if let Some(code) = multiply_by(c) {
+                            let x = out.pop().unwrap();
+                            out.truncate(x_start - len);
+                            out.push(x);
+                            out.extend(code);
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(BinaryOp::Divide) = BinaryOp::of(&instruction) {
+            // x / 2^k, with 0 <= x <= max
+            if let Some((c, len)) = trailing_constant(&out) {
+                let x_end = out.len() - len;
+                if c.is_power_of_two() && (c as i16) > 0 {
+                    if let Some(max) = non_negative_bound(&out[..x_end]) {
+                        out.truncate(x_end);
+                        out.extend(divide_by_power_of_two(max, c.ilog2()));
+                        continue;
+                    }
+                }
+            }
+        }
+        out.push(instruction);
+    }
+
+    *function = out;
+}
+
+/// The largest value the last instructions of `code` can leave on the
+/// stack, if they compute `x & m` or `m & x` for a constant `m`, which
+/// `push constant` keeps non-negative.
+fn non_negative_bound(code: &[VmInstruction]) -> Option<u16> {
+    use VmInstruction::*;
+
+    let m = match code {
+        [.., Push(Segment::Constant, m), Arithmetic(ArithmeticOp::And)] => *m,
+        [.., Push(Segment::Constant, m), Push(segment, _), Arithmetic(ArithmeticOp::And)]
+            if *segment != Segment::Constant =>
+        {
+            *m
+        }
+        _ => return None,
+    };
+    ((m as i16) >= 0).then_some(m)
+}
+
+/// Code dividing the top of the stack by `2^k`, the value being between 0
+/// and `max`: each bit `b >= k` that `max` has is tested, and adds `2^(b-k)`
+/// to the quotient when set.
+fn divide_by_power_of_two(max: u16, k: u32) -> Vec<VmInstruction> {
+    use ArithmeticOp::*;
+    use Segment::*;
+    use VmInstruction::*;
+
+    let mut code = vec![Pop(Temp, 0)];
+    let mut terms = 0;
+    for bit in (k..16).filter(|bit| max & (1 << bit) != 0) {
+        code.extend([
+            Push(Temp, 0),
+            Push(Constant, 1 << bit),
+            Arithmetic(And),
+            Push(Constant, 0),
+            Arithmetic(Gt),
+            Push(Constant, 1 << (bit - k)),
+            Arithmetic(And),
+        ]);
+        if terms > 0 {
+            code.push(Arithmetic(Add));
+        }
+        terms += 1;
+    }
+    if terms == 0 {
+        // x is still computed for its side effects
+        code.push(Push(Constant, 0));
+    }
+    code
+}
+
+const SMALL_MULTIPLIER: u16 = 64;
+
+/// Code multiplying the top of the stack by `c`, with 16-bit wraparound like
+/// `Math.multiply`. `None` if calling `Math.multiply` is about as fast.
+fn multiply_by(c: u16) -> Option<Vec<VmInstruction>> {
+    use ArithmeticOp::*;
+    use Segment::*;
+    use VmInstruction::*;
+
+    let negative = (c as i16) < 0;
+    let n = if negative { c.wrapping_neg() } else { c };
+    if n == 0 {
+        // x is still computed for its side effects
+        return Some(vec![Pop(Temp, 0), Push(Constant, 0)]);
+    }
+    if !n.is_power_of_two() && n >= SMALL_MULTIPLIER {
+        return None;
+    }
+
+    // x + x, keeping the sum on the stack
+    let double = [Pop(Temp, 0), Push(Temp, 0), Push(Temp, 0), Arithmetic(Add)];
+
+    // shift and add, from the highest bit of n down
+    let mut code = vec![];
+    if !n.is_power_of_two() {
+        code.extend([Pop(Temp, 1), Push(Temp, 1)]);
+    }
+    for bit in (0..n.ilog2()).rev() {
+        code.extend(double.clone());
+        if n & (1 << bit) != 0 {
+            code.extend([Push(Temp, 1), Arithmetic(Add)]);
+        }
+    }
+    if negative {
+        code.push(Arithmetic(Neg));
+    }
+    Some(code)
+}
+
+/// Drop code that follows a `goto` or `return` and can't be jumped to,
+/// labels nobody jumps to, and a `goto` to the very next instruction.
+pub fn remove_dead_code(function: &mut Vec<VmInstruction>) {
+    let targets = function
+        .iter()
+        .filter_map(|instruction| match instruction {
+            VmInstruction::Goto(label) | VmInstruction::IfGoto(label) => Some(label.clone()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    let mut out: Vec<VmInstruction> = vec![];
+    let mut reachable = true;
+    for instruction in function.drain(..) {
+        match &instruction {
+            VmInstruction::Label(label) if !targets.contains(label) => continue,
+            VmInstruction::Label(label) => {
+                if out.last() == Some(&VmInstruction::Goto(label.clone())) {
+                    out.pop();
+                }
+                reachable = true;
+            }
+            _ if !reachable => continue,
+            VmInstruction::Goto(_) | VmInstruction::Return => reachable = false,
+            _ => {}
+        }
+        out.push(instruction);
+    }
+
+    *function = out;
+}
+
+/// Rewrite short instruction sequences to shorter ones doing the same:
+///
+/// | before                                          | after                           |
+/// |-------------------------------------------------|---------------------------------|
+/// | `push s i; pop s i`                             |                                 |
+/// | `pop temp i; push temp i`, if `temp i` is dead  |                                 |
+/// | `if-goto L; label L`                            | `pop temp 0; label L`           |
+/// | `v; pop temp 0; pop pointer 1; push temp 0; pop that 0` | `pop pointer 1; v; pop that 0` |
+///
+/// where `v` pushes a value without reading `that` or `pointer`: a `push`,
+/// optionally followed by `neg` or `not`. The last rule shortens the array
+/// element assignment of `compile_let` when its right-hand side is simple.
+///
+/// The `temp` segment belongs to the compiler, which always writes a `temp`
+/// entry before reading it in the same statement, so the rules may leave a
+/// different value in it.
+pub fn peephole(function: &mut Vec<VmInstruction>) {
+    use ArithmeticOp::*;
+    use Segment::*;
+    use VmInstruction::*;
+
+    let code = std::mem::take(function);
+    let mut out: Vec<VmInstruction> = vec![];
+
+    for (i, instruction) in code.iter().enumerate() {
+        out.push(instruction.clone());
+
+        match &out[..] {
+            [.., Push(push_segment, push_index), Pop(pop_segment, pop_index)]
+                if push_segment == pop_segment && push_index == pop_index =>
+            {
+                out.truncate(out.len() - 2);
+            }
+            [.., Pop(Temp, pop_index), Push(Temp, push_index)]
+                if pop_index == push_index && is_dead_temp(&code[i + 1..], *pop_index) =>
+            {
+                out.truncate(out.len() - 2);
+            }
+            [.., IfGoto(target), Label(label)] if target == label => {
+                let n = out.len();
+                out[n - 2] = Pop(Temp, 0);
+            }
+            [.., Pop(Temp, 0), Pop(Pointer, 1), Push(Temp, 0), Pop(That, 0)] => {
+                let code = &out[..out.len() - 4];
+                let value_len = match code {
+                    [.., Push(segment, _), Arithmetic(Neg | Not)]
+                        if !matches!(segment, That | Pointer) =>
+                    {
+                        2
+                    }
+                    [.., Push(segment, _)] if !matches!(segment, That | Pointer) => 1,
+                    _ => continue,
+                };
+                let value = code[code.len() - value_len..].to_vec();
+                out.truncate(code.len() - value_len);
+                out.push(Pop(Pointer, 1));
+                out.extend(value);
+                out.push(Pop(That, 0));
+            }
+            _ => {}
+        }
+    }
+
+    *function = out;
+}
+
+/// Whether `code` overwrites or never reads `temp index` before leaving the
+/// straight-line code it starts with.
+fn is_dead_temp(code: &[VmInstruction], index: u16) -> bool {
+    for instruction in code {
+        match instruction {
+            VmInstruction::Pop(Segment::Temp, i) if *i == index => return true,
+            VmInstruction::Push(Segment::Temp, i) if *i == index => return false,
+            VmInstruction::Return => return true,
+            VmInstruction::Label(_)
+            | VmInstruction::Goto(_)
+            | VmInstruction::IfGoto(_)
+            | VmInstruction::Call(..)
+            | VmInstruction::Function(..) => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Jump straight to where a `goto` chain ends: with `label L1; goto L2`, a
+/// jump to `L1` becomes a jump to `L2`.
+pub fn thread_jumps(function: &mut [VmInstruction]) {
+    let mut forward = HashMap::new();
+    for pair in function.windows(2) {
+        if let [VmInstruction::Label(label), VmInstruction::Goto(target)] = pair {
+            forward.insert(label.clone(), target.clone());
+        }
+    }
+
+    let resolve = |label: &String| {
+        let mut seen = HashSet::new();
+        let mut label = label;
+        while let Some(target) = forward.get(label) {
+            // a loop of `goto`s never ends, keep it as it is
+            if !seen.insert(label) {
+                break;
+            }
+            label = target;
+        }
+        label.clone()
+    };
+
+    for instruction in function.iter_mut() {
+        match instruction {
+            VmInstruction::Goto(label) | VmInstruction::IfGoto(label) => *label = resolve(label),
+            _ => {}
+        }
+    }
+}
+
+/// The value left on the stack by the last instructions of `code`, if it is a
+/// constant: `push constant n`, optionally followed by `neg` or `not`.
+/// Returns the value and how many instructions compute it.
+fn trailing_constant(code: &[VmInstruction]) -> Option<(u16, usize)> {
+    match code {
+        [.., VmInstruction::Push(Segment::Constant, value)] => Some((*value, 1)),
+        [.., VmInstruction::Push(Segment::Constant, value), VmInstruction::Arithmetic(op @ (ArithmeticOp::Neg | ArithmeticOp::Not))] => {
+            Some((unary(*op, *value), 2))
+        }
+        _ => None,
+    }
+}
+
+/// Push any 16-bit value, though `push constant` only takes 0..=32767.
+fn push_constant(out: &mut Vec<VmInstruction>, value: u16) {
+    const MAX_CONSTANT: u16 = i16::MAX as u16;
+
+    if value <= MAX_CONSTANT {
+        out.push(VmInstruction::Push(Segment::Constant, value));
+    } else if value.wrapping_neg() <= MAX_CONSTANT {
+        out.push(VmInstruction::Push(Segment::Constant, value.wrapping_neg()));
+        out.push(VmInstruction::Arithmetic(ArithmeticOp::Neg));
+    } else {
+        out.push(VmInstruction::Push(Segment::Constant, !value));
+        out.push(VmInstruction::Arithmetic(ArithmeticOp::Not));
+    }
+}
+
+fn unary(op: ArithmeticOp, value: u16) -> u16 {
+    match op {
+        ArithmeticOp::Neg => value.wrapping_neg(),
+        ArithmeticOp::Not => !value,
+        _ => unreachable!("`{op}` is not unary"),
+    }
+}
+
+/// An instruction taking two operands from the stack.
+#[derive(Clone, Copy)]
+enum BinaryOp {
+    Arithmetic(ArithmeticOp),
+    Multiply,
+    Divide,
+}
+
+impl BinaryOp {
+    fn of(instruction: &VmInstruction) -> Option<Self> {
+        match instruction {
+            VmInstruction::Arithmetic(ArithmeticOp::Neg | ArithmeticOp::Not) => None,
+            VmInstruction::Arithmetic(op) => Some(BinaryOp::Arithmetic(*op)),
+            VmInstruction::Call(name, 2) if name == "Math.multiply" => Some(BinaryOp::Multiply),
+            VmInstruction::Call(name, 2) if name == "Math.divide" => Some(BinaryOp::Divide),
+            _ => None,
+        }
+    }
+
+    /// `None` when the result is only known at runtime: division by zero is
+    /// left to `Math.divide` to report.
+    fn eval(self, left: u16, right: u16) -> Option<u16> {
+        let (l, r) = (left as i16, right as i16);
+        let value = match self {
+            BinaryOp::Arithmetic(ArithmeticOp::Add) => left.wrapping_add(right),
+            BinaryOp::Arithmetic(ArithmeticOp::Sub) => left.wrapping_sub(right),
+            BinaryOp::Arithmetic(ArithmeticOp::And) => left & right,
+            BinaryOp::Arithmetic(ArithmeticOp::Or) => left | right,
+            BinaryOp::Arithmetic(ArithmeticOp::Eq) => boolean(l == r),
+            BinaryOp::Arithmetic(ArithmeticOp::Gt) => boolean(l > r),
+            BinaryOp::Arithmetic(ArithmeticOp::Lt) => boolean(l < r),
+            BinaryOp::Arithmetic(op) => unreachable!("`{op}` is not binary"),
+            BinaryOp::Multiply => left.wrapping_mul(right),
+            BinaryOp::Divide if right == 0 => return None,
+            BinaryOp::Divide => l.wrapping_div(r) as u16,
+        };
+        Some(value)
+    }
+
+    /// Whether `x op right` is always `x`.
+    fn is_identity(self, right: u16) -> bool {
+        match self {
+            BinaryOp::Arithmetic(ArithmeticOp::Add | ArithmeticOp::Sub | ArithmeticOp::Or) => {
+                right == 0
+            }
+            BinaryOp::Arithmetic(ArithmeticOp::And) => right == u16::MAX,
+            BinaryOp::Multiply | BinaryOp::Divide => right == 1,
+            BinaryOp::Arithmetic(_) => false,
+        }
+    }
+}
+
+/// `true` is -1 and `false` is 0.
+fn boolean(value: bool) -> u16 {
+    if value {
+        u16::MAX
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{ArithmeticOp::*, Segment::*, VmInstruction::*};
+
+    fn constant(value: u16) -> VmInstruction {
+        Push(Constant, value)
+    }
+
+    fn call(name: &str, n_args: u16) -> VmInstruction {
+        Call(name.to_string(), n_args)
+    }
+
+    fn optimized(mut function: Vec<VmInstruction>) -> Vec<VmInstruction> {
+        optimize(&mut function);
+        function
+    }
+
+    /// Runs VM code without `call`s on a small Hack RAM, laid out the way the
+    /// VM translator does it.
+    struct Machine {
+        ram: Vec<u16>,
+    }
+
+    impl Machine {
+        const RAM_SIZE: usize = 4096;
+
+        /// RAM filled with arbitrary values, the same ones for the same seed.
+        fn new(seed: u32) -> Self {
+            let mut state = seed.wrapping_mul(2654435761).wrapping_add(1);
+            let mut ram = (0..Machine::RAM_SIZE)
+                .map(|_| {
+                    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                    ((state >> 16) % 1024) as u16
+                })
+                .collect::<Vec<_>>();
+            ram[1] = 300; // LCL
+            ram[2] = 400; // ARG
+            ram[0] = 512; // SP
+            ram[3] = 2048; // THIS
+            ram[4] = 3072; // THAT
+            Machine { ram }
+        }
+
+        fn address(&self, segment: Segment, index: u16) -> usize {
+            let index = index as usize;
+            let address = match segment {
+                Local => self.ram[1] as usize + index,
+                Argument => self.ram[2] as usize + index,
+                This => self.ram[3] as usize + index,
+                That => self.ram[4] as usize + index,
+                Pointer => 3 + index,
+                Temp => 5 + index,
+                Static => 16 + index,
+                Constant => unreachable!(),
+            };
+            address % Machine::RAM_SIZE
+        }
+
+        fn push(&mut self, value: u16) {
+            let sp = self.ram[0] as usize;
+            self.ram[sp] = value;
+            self.ram[0] += 1;
+        }
+
+        fn pop(&mut self) -> u16 {
+            self.ram[0] -= 1;
+            self.ram[self.ram[0] as usize]
+        }
+
+        fn run(&mut self, code: &[VmInstruction]) {
+            let mut pc = 0;
+            while pc < code.len() {
+                pc += 1;
+                match &code[pc - 1] {
+                    Push(Constant, value) => self.push(*value),
+                    Push(segment, index) => self.push(self.ram[self.address(*segment, *index)]),
+                    Pop(segment, index) => {
+                        let value = self.pop();
+                        let address = self.address(*segment, *index);
+                        self.ram[address] = value;
+                    }
+                    Arithmetic(op @ (Neg | Not)) => {
+                        let value = self.pop();
+                        self.push(unary(*op, value));
+                    }
+                    Arithmetic(op) => {
+                        let right = self.pop();
+                        let left = self.pop();
+                        self.push(BinaryOp::Arithmetic(*op).eval(left, right).unwrap());
+                    }
+                    Label(_) => {}
+                    Goto(label) => pc = self.find(code, label),
+                    IfGoto(label) => {
+                        if self.pop() != 0 {
+                            pc = self.find(code, label);
+                        }
+                    }
+                    instruction => panic!("can't run `{instruction}`"),
+                }
+            }
+        }
+
+        fn find(&self, code: &[VmInstruction], label: &str) -> usize {
+            code.iter()
+                .position(|instruction| *instruction == Label(label.to_string()))
+                .unwrap()
+        }
+    }
+
+    /// Both versions leave the same stack and memory, except for `temp` and
+    /// what was popped off the stack.
+    fn assert_same_behaviour(before: &[VmInstruction], after: &[VmInstruction]) {
+        for seed in 0..16 {
+            let [expected, actual] = [before, after].map(|code| {
+                let mut machine = Machine::new(seed);
+                machine.run(code);
+                machine.ram[5..13].fill(0);
+                let sp = machine.ram[0] as usize;
+                machine.ram[sp..2048].fill(0);
+                machine.ram
+            });
+
+            assert!(
+                expected == actual,
+                "{before:?} and {after:?} differ with seed {seed}"
+            );
+        }
+    }
+
+    fn assert_peephole(before: Vec<VmInstruction>, expected: Vec<VmInstruction>) {
+        let mut after = before.clone();
+        peephole(&mut after);
+        assert_eq!(after, expected);
+        assert_same_behaviour(&before, &after);
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        // 2 * (8 + 1)
+        assert_eq!(
+            optimized(vec![
+                constant(2),
+                constant(8),
+                constant(1),
+                Arithmetic(Add),
+                call("Math.multiply", 2),
+                Pop(Local, 0),
+            ]),
+            vec![constant(18), Pop(Local, 0)]
+        );
+
+        // 16-bit wraparound, results out of `push constant` range
+        assert_eq!(
+            optimized(vec![constant(32767), constant(1), Arithmetic(Add)]),
+            vec![constant(32767), Arithmetic(Not)]
+        );
+        assert_eq!(
+            optimized(vec![constant(3), constant(5), Arithmetic(Sub)]),
+            vec![constant(2), Arithmetic(Neg)]
+        );
+        assert_eq!(
+            optimized(vec![constant(300), constant(300), call("Math.multiply", 2)]),
+            vec![constant(24464)]
+        );
+
+        // signed division and comparison
+        assert_eq!(
+            optimized(vec![
+                constant(7),
+                Arithmetic(Neg),
+                constant(2),
+                call("Math.divide", 2)
+            ]),
+            vec![constant(3), Arithmetic(Neg)]
+        );
+        assert_eq!(
+            optimized(vec![
+                constant(1),
+                Arithmetic(Neg),
+                constant(0),
+                Arithmetic(Lt)
+            ]),
+            vec![constant(1), Arithmetic(Neg)]
+        );
+
+        // booleans
+        assert_eq!(
+            optimized(vec![
+                constant(1),
+                Arithmetic(Neg),
+                constant(0),
+                Arithmetic(And),
+                Arithmetic(Not),
+            ]),
+            vec![constant(1), Arithmetic(Neg)]
+        );
+
+        // division by zero is left to `Math.divide`
+        let divide_by_zero = vec![constant(1), constant(0), call("Math.divide", 2)];
+        assert_eq!(optimized(divide_by_zero.clone()), divide_by_zero);
+    }
+
+    #[test]
+    fn test_simplify() {
+        for (op, right) in [
+            (Arithmetic(Add), constant(0)),
+            (Arithmetic(Sub), constant(0)),
+            (Arithmetic(Or), constant(0)),
+            (call("Math.multiply", 2), constant(1)),
+            (call("Math.divide", 2), constant(1)),
+        ] {
+            assert_eq!(
+                optimized(vec![Push(Local, 0), right, op]),
+                vec![Push(Local, 0)]
+            );
+        }
+        assert_eq!(
+            optimized(vec![
+                Push(Local, 0),
+                constant(1),
+                Arithmetic(Neg),
+                Arithmetic(And)
+            ]),
+            vec![Push(Local, 0)]
+        );
+
+        // ~~x and -(-x)
+        assert_eq!(
+            optimized(vec![Push(Local, 0), Arithmetic(Not), Arithmetic(Not)]),
+            vec![Push(Local, 0)]
+        );
+        assert_eq!(
+            optimized(vec![Push(Local, 0), Arithmetic(Neg), Arithmetic(Neg)]),
+            vec![Push(Local, 0)]
+        );
+
+        // only the right operand is known
+        let unknown = vec![constant(0), Push(Local, 0), Arithmetic(Add)];
+        assert_eq!(optimized(unknown.clone()), unknown);
+        let unknown = vec![Push(Local, 0), constant(2), Arithmetic(Add)];
+        assert_eq!(optimized(unknown.clone()), unknown);
+    }
+
+    #[test]
+    fn test_remove_constant_branches() {
+        let label = |name: &str| Label(name.to_string());
+        let if_goto = |name: &str| IfGoto(name.to_string());
+        let goto = |name: &str| Goto(name.to_string());
+
+        // while (false) { do Main.f(); }
+        assert_eq!(
+            optimized(vec![
+                Function("Main.main".to_string(), 0),
+                label("while_start_1"),
+                constant(0),
+                Arithmetic(Not),
+                if_goto("while_end_1"),
+                call("Main.f", 0),
+                Pop(Temp, 0),
+                goto("while_start_1"),
+                label("while_end_1"),
+                constant(0),
+                Return,
+            ]),
+            vec![Function("Main.main".to_string(), 0), constant(0), Return]
+        );
+
+        // if (true) { do Main.f(); } else { do Main.g(); }
+        assert_eq!(
+            optimized(vec![
+                Function("Main.main".to_string(), 0),
+                constant(1),
+                Arithmetic(Neg),
+                Arithmetic(Not),
+                if_goto("else_1"),
+                call("Main.f", 0),
+                Pop(Temp, 0),
+                goto("end_1"),
+                label("else_1"),
+                call("Main.g", 0),
+                Pop(Temp, 0),
+                label("end_1"),
+                constant(0),
+                Return,
+            ]),
+            vec![
+                Function("Main.main".to_string(), 0),
+                call("Main.f", 0),
+                Pop(Temp, 0),
+                constant(0),
+                Return,
+            ]
+        );
+
+        // a loop that is still jumped to is kept
+        let function = vec![
+            Function("Main.main".to_string(), 0),
+            label("while_start_1"),
+            Push(Local, 0),
+            Arithmetic(Not),
+            if_goto("while_end_1"),
+            goto("while_start_1"),
+            label("while_end_1"),
+            constant(0),
+            Return,
+        ];
+        assert_eq!(optimized(function.clone()), function);
+    }
+
+    #[test]
+    fn test_peephole_push_pop() {
+        assert_peephole(
+            vec![
+                Push(Local, 0),
+                Push(Local, 2),
+                Pop(Local, 2),
+                Pop(Static, 1),
+            ],
+            vec![Push(Local, 0), Pop(Static, 1)],
+        );
+        assert_peephole(
+            vec![
+                Push(Argument, 1),
+                Pop(Temp, 0),
+                Push(Temp, 0),
+                Pop(Local, 1),
+            ],
+            vec![Push(Argument, 1), Pop(Local, 1)],
+        );
+
+        // `temp 0` is read again
+        let code = vec![
+            Push(Local, 0),
+            Pop(Temp, 0),
+            Push(Temp, 0),
+            Push(Temp, 0),
+            Arithmetic(Add),
+        ];
+        assert_peephole(code.clone(), code);
+
+        // different places are left alone
+        let code = vec![Push(Local, 0), Pop(Local, 1), Push(Temp, 0), Pop(Temp, 1)];
+        assert_peephole(code.clone(), code);
+    }
+
+    #[test]
+    fn test_peephole_jump_to_next() {
+        assert_peephole(
+            vec![
+                Push(Local, 0),
+                IfGoto("L".to_string()),
+                Label("L".to_string()),
+                Push(Local, 1),
+            ],
+            vec![
+                Push(Local, 0),
+                Pop(Temp, 0),
+                Label("L".to_string()),
+                Push(Local, 1),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_peephole_array_assignment() {
+        // let a[i] = x;
+        let address = [Push(Local, 0), Push(Local, 1), Arithmetic(Add)];
+        let store = [Pop(Temp, 0), Pop(Pointer, 1), Push(Temp, 0), Pop(That, 0)];
+
+        for value in [
+            vec![Push(Argument, 0)],
+            vec![Push(Constant, 1), Arithmetic(Neg)],
+            vec![Push(This, 2), Arithmetic(Not)],
+        ] {
+            assert_peephole(
+                [&address[..], &value, &store].concat(),
+                [&address[..], &[Pop(Pointer, 1)], &value, &[Pop(That, 0)]].concat(),
+            );
+        }
+
+        // the value depends on `pointer 1`
+        for value in [vec![Push(That, 0)], vec![Push(Pointer, 1), Arithmetic(Neg)]] {
+            let code = [&address[..], &value, &store].concat();
+            assert_peephole(code.clone(), code);
+        }
+
+        // the value isn't simple
+        let code = [
+            &address[..],
+            &[Push(Local, 2), Push(Local, 3), Arithmetic(Add)],
+            &store,
+        ]
+        .concat();
+        assert_peephole(code.clone(), code);
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        let before = vec![
+            Push(Local, 0),
+            IfGoto("A".to_string()),
+            Push(Constant, 1),
+            Pop(Local, 1),
+            Label("A".to_string()),
+            Goto("B".to_string()),
+            Label("C".to_string()),
+            Push(Constant, 2),
+            Pop(Local, 1),
+            Label("B".to_string()),
+            Push(Local, 1),
+            Pop(Static, 0),
+        ];
+        let mut after = before.clone();
+        thread_jumps(&mut after);
+        assert_eq!(after[1], IfGoto("B".to_string()));
+        assert_same_behaviour(&before, &after);
+
+        // the optimizer then drops the unreachable code
+        assert_eq!(
+            optimized(before),
+            vec![
+                Push(Local, 0),
+                IfGoto("B".to_string()),
+                Push(Constant, 1),
+                Pop(Local, 1),
+                Label("B".to_string()),
+                Push(Local, 1),
+                Pop(Static, 0),
+            ]
+        );
+
+        // `label L; goto L` loops forever
+        let mut code = vec![Label("L".to_string()), Goto("L".to_string())];
+        thread_jumps(&mut code);
+        assert_eq!(code, vec![Label("L".to_string()), Goto("L".to_string())]);
+    }
+
+    #[test]
+    fn test_reduce_strength() {
+        for c in [
+            0, 1, 2, 3, 5, 7, 10, 16, 32, 63, 256, 16384, 0x8000, 0xffff, 0xfffd, 0xffe0,
+        ] {
+            let mut code = vec![constant(c), call("Math.multiply", 2)];
+            reduce_strength(&mut code);
+            assert!(!code.contains(&call("Math.multiply", 2)), "{c}");
+
+            // local 0 * c, and c * local 0
+            let x_c = [&[Push(Local, 0)], &code[..], &[Pop(Static, 0)]].concat();
+            let mut c_x = vec![constant(c), Push(Local, 0), call("Math.multiply", 2)];
+            reduce_strength(&mut c_x);
+            assert_eq!(c_x, x_c[..x_c.len() - 1]);
+
+            for seed in 0..16 {
+                let mut machine = Machine::new(seed);
+                let x = machine.ram[machine.address(Local, 0)];
+                machine.run(&x_c);
+                assert_eq!(machine.ram[16], x.wrapping_mul(c), "{x} * {c}");
+                assert_eq!(machine.ram[0], 512);
+            }
+        }
+
+        // not worth it
+        let code = vec![Push(Local, 0), constant(100), call("Math.multiply", 2)];
+        let mut reduced = code.clone();
+        reduce_strength(&mut reduced);
+        assert_eq!(reduced, code);
+
+        // `x` may be negative
+        for code in [
+            vec![Push(Local, 0), constant(16), call("Math.divide", 2)],
+            vec![
+                Push(Local, 0),
+                Push(Local, 1),
+                Arithmetic(And),
+                constant(16),
+                call("Math.divide", 2),
+            ],
+        ] {
+            let mut reduced = code.clone();
+            reduce_strength(&mut reduced);
+            assert_eq!(reduced, code);
+        }
+
+        // `x` isn't a plain variable
+        let code = vec![
+            constant(2),
+            Push(Local, 0),
+            Push(Local, 1),
+            Arithmetic(Add),
+            call("Math.multiply", 2),
+        ];
+        let mut reduced = code.clone();
+        reduce_strength(&mut reduced);
+        assert_eq!(reduced, code);
+
+        // y * 32 doubles y five times
+        let double = [Pop(Temp, 0), Push(Temp, 0), Push(Temp, 0), Arithmetic(Add)];
+        let mut expected = vec![Push(Local, 0)];
+        for _ in 0..5 {
+            expected.extend(double.clone());
+        }
+        assert_eq!(
+            optimized(vec![Push(Local, 0), constant(32), call("Math.multiply", 2)]),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_divide_by_power_of_two() {
+        for k in 1..15 {
+            for m in [0, 1, 7, 255, 0x5a5a, 0x7fff] {
+                // local 0 & m / 2^k, and m & local 0 / 2^k
+                let x_m = [Push(Local, 0), constant(m), Arithmetic(And)];
+                let m_x = [constant(m), Push(Local, 0), Arithmetic(And)];
+                for x in [x_m, m_x] {
+                    let mut code = [&x[..], &[constant(1 << k), call("Math.divide", 2)]].concat();
+                    reduce_strength(&mut code);
+                    assert!(!code.contains(&call("Math.divide", 2)), "{m} / {}", 1 << k);
+                    code.push(Pop(Static, 0));
+
+                    for value in [0, 1, 2, 100, 1023, 12345, 0x7fff, 0x8000, 0xa5a5, 0xffff] {
+                        let mut machine = Machine::new(0);
+                        let address = machine.address(Local, 0);
+                        machine.ram[address] = value;
+                        machine.run(&code);
+                        assert_eq!(
+                            machine.ram[16],
+                            (value & m) >> k,
+                            "{value} & {m} / {}",
+                            1 << k
+                        );
+                        assert_eq!(machine.ram[0], 512);
+                    }
+                }
+            }
+        }
+
+        // a constant dividend is divided at compile time
+        assert_eq!(
+            optimized(vec![constant(100), constant(4), call("Math.divide", 2)]),
+            vec![constant(25)]
+        );
+        assert_eq!(
+            optimized(vec![
+                constant(100),
+                Arithmetic(Neg),
+                constant(8),
+                call("Math.divide", 2)
+            ]),
+            vec![constant(12), Arithmetic(Neg)]
+        );
+
+        // local 0 & 12 / 4 tests bits 2 and 3
+        assert_eq!(
+            optimized(vec![
+                Push(Local, 0),
+                constant(12),
+                Arithmetic(And),
+                constant(4),
+                call("Math.divide", 2),
+                Pop(Static, 0),
+            ]),
+            vec![
+                Push(Local, 0),
+                constant(12),
+                Arithmetic(And),
+                Pop(Temp, 0),
+                Push(Temp, 0),
+                constant(4),
+                Arithmetic(And),
+                constant(0),
+                Arithmetic(Gt),
+                constant(1),
+                Arithmetic(And),
+                Push(Temp, 0),
+                constant(8),
+                Arithmetic(And),
+                constant(0),
+                Arithmetic(Gt),
+                constant(2),
+                Arithmetic(And),
+                Arithmetic(Add),
+                Pop(Static, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reachable_functions() {
+        let function = |name: &str, callees: &[&str]| {
+            let mut code = vec![Function(name.to_string(), 0)];
+            code.extend(callees.iter().map(|callee| call(callee, 0)));
+            code.push(Return);
+            code
+        };
+        let functions = [
+            function("Main.main", &["Game.new", "Output.printInt"]),
+            function("Game.new", &["Game.run", "Memory.alloc"]),
+            function("Game.run", &["Game.run"]),
+            function("Game.dispose", &["Memory.deAlloc"]),
+            function("Util.unused", &["Game.new"]),
+        ];
+
+        let reachable = reachable_functions(
+            functions.iter().map(Vec::as_slice),
+            &["Main.main", "Sys.init"],
+        );
+        let mut reachable = reachable.into_iter().collect::<Vec<_>>();
+        reachable.sort();
+        assert_eq!(reachable, ["Game.new", "Game.run", "Main.main"]);
+    }
+}
@@ -0,0 +1,720 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use crate::{
+    emulator::Exit,
+    keyboard::{Keyboard, BACKSPACE, NEW_LINE},
+    screen::{self, KBD, SCREEN},
+};
+
+const HEAP_START: u16 = 2048;
+const HEAP_END: u16 = 16384;
+
+const DOUBLE_QUOTE: u16 = 34;
+
+/// Text output to read after an emulator took it.
+#[derive(Clone, Default)]
+pub struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl SharedOutput {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A function of the Jack OS, built into the emulator.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum OsFunction {
+    MathInit,
+    MathAbs,
+    MathMultiply,
+    MathDivide,
+    MathMin,
+    MathMax,
+    MathSqrt,
+    StringNew,
+    StringDispose,
+    StringLength,
+    StringCharAt,
+    StringSetCharAt,
+    StringAppendChar,
+    StringEraseLastChar,
+    StringIntValue,
+    StringSetInt,
+    StringBackSpace,
+    StringDoubleQuote,
+    StringNewLine,
+    ArrayNew,
+    ArrayDispose,
+    OutputInit,
+    OutputMoveCursor,
+    OutputPrintChar,
+    OutputPrintString,
+    OutputPrintInt,
+    OutputPrintln,
+    OutputBackSpace,
+    ScreenInit,
+    ScreenClearScreen,
+    ScreenSetColor,
+    ScreenDrawPixel,
+    ScreenDrawLine,
+    ScreenDrawRectangle,
+    ScreenDrawCircle,
+    KeyboardInit,
+    KeyboardKeyPressed,
+    KeyboardReadChar,
+    KeyboardReadLine,
+    KeyboardReadInt,
+    MemoryInit,
+    MemoryPeek,
+    MemoryPoke,
+    MemoryAlloc,
+    MemoryDeAlloc,
+    SysHalt,
+    SysError,
+    SysWait,
+}
+
+impl OsFunction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        use OsFunction::*;
+        let function = match name {
+            "Math.init" => MathInit,
+            "Math.abs" => MathAbs,
+            "Math.multiply" => MathMultiply,
+            "Math.divide" => MathDivide,
+            "Math.min" => MathMin,
+            "Math.max" => MathMax,
+            "Math.sqrt" => MathSqrt,
+            "String.new" => StringNew,
+            "String.dispose" => StringDispose,
+            "String.length" => StringLength,
+            "String.charAt" => StringCharAt,
+            "String.setCharAt" => StringSetCharAt,
+            "String.appendChar" => StringAppendChar,
+            "String.eraseLastChar" => StringEraseLastChar,
+            "String.intValue" => StringIntValue,
+            "String.setInt" => StringSetInt,
+            "String.backSpace" => StringBackSpace,
+            "String.doubleQuote" => StringDoubleQuote,
+            "String.newLine" => StringNewLine,
+            "Array.new" => ArrayNew,
+            "Array.dispose" => ArrayDispose,
+            "Output.init" => OutputInit,
+            "Output.moveCursor" => OutputMoveCursor,
+            "Output.printChar" => OutputPrintChar,
+            "Output.printString" => OutputPrintString,
+            "Output.printInt" => OutputPrintInt,
+            "Output.println" => OutputPrintln,
+            "Output.backSpace" => OutputBackSpace,
+            "Screen.init" => ScreenInit,
+            "Screen.clearScreen" => ScreenClearScreen,
+            "Screen.setColor" => ScreenSetColor,
+            "Screen.drawPixel" => ScreenDrawPixel,
+            "Screen.drawLine" => ScreenDrawLine,
+            "Screen.drawRectangle" => ScreenDrawRectangle,
+            "Screen.drawCircle" => ScreenDrawCircle,
+            "Keyboard.init" => KeyboardInit,
+            "Keyboard.keyPressed" => KeyboardKeyPressed,
+            "Keyboard.readChar" => KeyboardReadChar,
+            "Keyboard.readLine" => KeyboardReadLine,
+            "Keyboard.readInt" => KeyboardReadInt,
+            "Memory.init" => MemoryInit,
+            "Memory.peek" => MemoryPeek,
+            "Memory.poke" => MemoryPoke,
+            "Memory.alloc" => MemoryAlloc,
+            "Memory.deAlloc" => MemoryDeAlloc,
+            "Sys.halt" => SysHalt,
+            "Sys.error" => SysError,
+            "Sys.wait" => SysWait,
+            _ => return None,
+        };
+        Some(function)
+    }
+
+    /// How many arguments it takes, `this` included.
+    pub fn arity(self) -> u16 {
+        use OsFunction::*;
+        match self {
+            MathInit | StringBackSpace | StringDoubleQuote | StringNewLine | OutputInit
+            | OutputPrintln | OutputBackSpace | ScreenInit | ScreenClearScreen | KeyboardInit
+            | KeyboardKeyPressed | KeyboardReadChar | MemoryInit | SysHalt => 0,
+            MathAbs | MathSqrt | StringNew | StringDispose | StringLength | StringEraseLastChar
+            | StringIntValue | ArrayNew | ArrayDispose | OutputPrintChar | OutputPrintString
+            | OutputPrintInt | ScreenSetColor | KeyboardReadLine | KeyboardReadInt | MemoryPeek
+            | MemoryAlloc | MemoryDeAlloc | SysError | SysWait => 1,
+            MathMultiply | MathDivide | MathMin | MathMax | StringCharAt | StringAppendChar
+            | StringSetInt | OutputMoveCursor | ScreenDrawPixel | MemoryPoke => 2,
+            StringSetCharAt | ScreenDrawCircle => 3,
+            ScreenDrawLine | ScreenDrawRectangle => 4,
+        }
+    }
+}
+
+impl fmt::Display for OsFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the variant name is the class name followed by the function name
+        let name = format!("{self:?}");
+        let split = name[1..].find(char::is_uppercase).unwrap() + 1;
+        let (class_name, function_name) = name.split_at(split);
+        let mut function_name = function_name.to_string();
+        function_name[..1].make_ascii_lowercase();
+        write!(f, "{class_name}.{function_name}")
+    }
+}
+
+/// What an OS function did.
+pub enum OsReturn {
+    Value(u16),
+    Exit(Exit),
+}
+
+/// The Jack OS, implemented natively on the emulator's RAM.
+///
+/// Objects live on the heap like those of the Jack OS: an `Array` is its
+/// words, a `String` is its maximum length, its length and its characters.
+/// Text output goes to `output` as well as to the screen.
+pub struct Os {
+    heap: Heap,
+    color: bool,
+    /// text row and column `Output` draws at next
+    cursor: (i32, i32),
+    keyboard: Keyboard,
+    /// `Sys.wait` calls so far
+    frames: u64,
+    output: Box<dyn Write>,
+}
+
+impl Os {
+    pub fn new(keyboard: Keyboard, output: Box<dyn Write>) -> Self {
+        Os {
+            heap: Heap::new(),
+            color: true,
+            cursor: (0, 0),
+            keyboard,
+            frames: 0,
+            output,
+        }
+    }
+
+    /// Run an OS function. An error is returned when the program can't go
+    /// on, like the keyboard input ending.
+    pub fn call(
+        &mut self,
+        function: OsFunction,
+        args: &[u16],
+        ram: &mut [u16],
+    ) -> Result<OsReturn, String> {
+        use OsFunction::*;
+
+        let arg = |i: usize| args[i] as i16;
+        let value = match function {
+            MathInit | OutputInit | ScreenInit | KeyboardInit | MemoryInit => 0,
+            MathAbs => arg(0).wrapping_abs() as u16,
+            MathMultiply => arg(0).wrapping_mul(arg(1)) as u16,
+            MathDivide => {
+                if arg(1) == 0 {
+                    return self.error(ram, 3);
+                }
+                arg(0).wrapping_div(arg(1)) as u16
+            }
+            MathMin => arg(0).min(arg(1)) as u16,
+            MathMax => arg(0).max(arg(1)) as u16,
+            MathSqrt => {
+                if arg(0) < 0 {
+                    return self.error(ram, 4);
+                }
+                (arg(0) as f64).sqrt() as u16
+            }
+
+            StringNew => {
+                if arg(0) < 0 {
+                    return self.error(ram, 14);
+                }
+                let Some(string) = self.heap.alloc(args[0] + 2) else {
+                    return self.error(ram, 6);
+                };
+                ram[string as usize] = args[0];
+                ram[string as usize + 1] = 0;
+                string
+            }
+            StringDispose | ArrayDispose | MemoryDeAlloc => {
+                self.heap.de_alloc(args[0])?;
+                0
+            }
+            StringLength => ram[self.string(args[0])? + 1],
+            StringCharAt | StringSetCharAt => {
+                let string = self.string(args[0])?;
+                if arg(1) < 0 || args[1] >= ram[string + 1] {
+                    return self.error(ram, if function == StringCharAt { 15 } else { 16 });
+                }
+                let address = string + 2 + args[1] as usize;
+                if function == StringSetCharAt {
+                    ram[address] = args[2];
+                    0
+                } else {
+                    ram[address]
+                }
+            }
+            StringAppendChar => {
+                let string = self.string(args[0])?;
+                let length = ram[string + 1];
+                if length >= ram[string] {
+                    return self.error(ram, 17);
+                }
+                ram[string + 2 + length as usize] = args[1];
+                ram[string + 1] = length + 1;
+                args[0]
+            }
+            StringEraseLastChar => {
+                let string = self.string(args[0])?;
+                if ram[string + 1] == 0 {
+                    return self.error(ram, 18);
+                }
+                ram[string + 1] -= 1;
+                0
+            }
+            StringIntValue => int_value(&string_chars(ram, self.string(args[0])?)) as u16,
+            StringSetInt => {
+                let string = self.string(args[0])?;
+                let digits = arg(1).to_string();
+                if digits.len() > ram[string] as usize {
+                    return self.error(ram, 19);
+                }
+                for (i, c) in digits.bytes().enumerate() {
+                    ram[string + 2 + i] = c as u16;
+                }
+                ram[string + 1] = digits.len() as u16;
+                0
+            }
+            StringBackSpace => BACKSPACE,
+            StringDoubleQuote => DOUBLE_QUOTE,
+            StringNewLine => NEW_LINE,
+
+            ArrayNew => {
+                if arg(0) <= 0 {
+                    return self.error(ram, 2);
+                }
+                match self.heap.alloc(args[0]) {
+                    Some(array) => array,
+                    None => return self.error(ram, 6),
+                }
+            }
+
+            OutputMoveCursor => {
+                let (row, column) = (arg(0) as i32, arg(1) as i32);
+                if !(0..screen::TEXT_ROWS).contains(&row)
+                    || !(0..screen::TEXT_COLUMNS).contains(&column)
+                {
+                    return self.error(ram, 20);
+                }
+                self.cursor = (row, column);
+                0
+            }
+            OutputPrintChar => {
+                self.print_char(ram, args[0])?;
+                0
+            }
+            OutputPrintString => {
+                for c in string_chars(ram, self.string(args[0])?) {
+                    self.print_char(ram, c)?;
+                }
+                0
+            }
+            OutputPrintInt => {
+                self.print(ram, &arg(0).to_string())?;
+                0
+            }
+            OutputPrintln => {
+                self.print_char(ram, NEW_LINE)?;
+                0
+            }
+            OutputBackSpace => {
+                self.print_char(ram, BACKSPACE)?;
+                0
+            }
+
+            ScreenClearScreen => {
+                ram[SCREEN..KBD].fill(0);
+                0
+            }
+            ScreenSetColor => {
+                self.color = args[0] != 0;
+                0
+            }
+            ScreenDrawPixel => {
+                let (x, y) = (arg(0) as i32, arg(1) as i32);
+                if !on_screen(x, y) {
+                    return self.error(ram, 7);
+                }
+                self.draw_pixel(ram, x, y);
+                0
+            }
+            ScreenDrawLine => {
+                let [x1, y1, x2, y2] = [0, 1, 2, 3].map(|i| arg(i) as i32);
+                if !on_screen(x1, y1) || !on_screen(x2, y2) {
+                    return self.error(ram, 8);
+                }
+                self.draw_line(ram, x1, y1, x2, y2);
+                0
+            }
+            ScreenDrawRectangle => {
+                let [x1, y1, x2, y2] = [0, 1, 2, 3].map(|i| arg(i) as i32);
+                if !on_screen(x1, y1) || !on_screen(x2, y2) || x1 > x2 || y1 > y2 {
+                    return self.error(ram, 9);
+                }
+                for y in y1..=y2 {
+                    self.draw_row(ram, x1, x2, y);
+                }
+                0
+            }
+            ScreenDrawCircle => {
+                let [x, y, r] = [0, 1, 2].map(|i| arg(i) as i32);
+                if !on_screen(x, y) {
+                    return self.error(ram, 12);
+                }
+                if !(0..=181).contains(&r) {
+                    return self.error(ram, 13);
+                }
+                for dy in -r..=r {
+                    let dx = ((r * r - dy * dy) as f64).sqrt() as i32;
+                    if (0..screen::HEIGHT).contains(&(y + dy)) {
+                        let x1 = (x - dx).max(0);
+                        let x2 = (x + dx).min(screen::WIDTH - 1);
+                        self.draw_row(ram, x1, x2, y + dy);
+                    }
+                }
+                0
+            }
+
+            KeyboardKeyPressed => ram[KBD],
+            KeyboardReadChar => self.read_char(ram)?,
+            KeyboardReadLine | KeyboardReadInt => {
+                let message = string_chars(ram, self.string(args[0])?);
+                for c in message {
+                    self.print_char(ram, c)?;
+                }
+                let mut line = vec![];
+                loop {
+                    match self.read_char(ram)? {
+                        NEW_LINE => break,
+                        BACKSPACE => {
+                            line.pop();
+                        }
+                        c => line.push(c),
+                    }
+                }
+                if function == KeyboardReadInt {
+                    int_value(&line) as u16
+                } else {
+                    let Some(string) = self.heap.alloc(line.len() as u16 + 2) else {
+                        return self.error(ram, 6);
+                    };
+                    let string = string as usize;
+                    ram[string] = line.len() as u16;
+                    ram[string + 1] = line.len() as u16;
+                    ram[string + 2..string + 2 + line.len()].copy_from_slice(&line);
+                    string as u16
+                }
+            }
+
+            MemoryPeek => ram[args[0] as usize % ram.len()],
+            MemoryPoke => {
+                ram[args[0] as usize % ram.len()] = args[1];
+                0
+            }
+            MemoryAlloc => {
+                if arg(0) <= 0 {
+                    return self.error(ram, 5);
+                }
+                match self.heap.alloc(args[0]) {
+                    Some(block) => block,
+                    None => return self.error(ram, 6),
+                }
+            }
+
+            SysHalt => return Ok(OsReturn::Exit(Exit::Halt)),
+            SysError => return self.error(ram, arg(0)),
+            SysWait => {
+                if arg(0) < 0 {
+                    return self.error(ram, 1);
+                }
+                self.frames += 1;
+                if let Some(key) = self.keyboard.at_frame(self.frames) {
+                    ram[KBD] = key;
+                }
+                0
+            }
+        };
+        Ok(OsReturn::Value(value))
+    }
+
+    /// Press or release keys due after `step` instructions.
+    pub fn tick(&mut self, step: u64, ram: &mut [u16]) {
+        if let Some(key) = self.keyboard.at_step(step) {
+            ram[KBD] = key;
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    /// `Sys.error`: print `ERR<code>` and stop.
+    fn error(&mut self, ram: &mut [u16], code: i16) -> Result<OsReturn, String> {
+        self.print(ram, &format!("ERR{code}"))?;
+        Ok(OsReturn::Exit(Exit::Error(code)))
+    }
+
+    /// Address of a string object, which must be on the heap.
+    fn string(&self, string: u16) -> Result<usize, String> {
+        if self.heap.is_allocated(string) {
+            Ok(string as usize)
+        } else {
+            Err(format!("{string} is not a string"))
+        }
+    }
+
+    /// Draw a character on the screen and write it to the text output.
+    fn print_char(&mut self, ram: &mut [u16], c: u16) -> Result<(), String> {
+        self.draw_char(ram, c);
+        let c = match c {
+            NEW_LINE => '\n',
+            BACKSPACE => '\x08',
+            _ => char::from_u32(c as u32).unwrap_or(' '),
+        };
+        self.output
+            .write_all(c.to_string().as_bytes())
+            .map_err(|error| error.to_string())
+    }
+
+    fn print(&mut self, ram: &mut [u16], text: &str) -> Result<(), String> {
+        for c in text.chars() {
+            self.print_char(ram, c as u16)?;
+        }
+        Ok(())
+    }
+
+    /// Draw a character at the cursor and move it on, wrapping to the next
+    /// line after the last column and to the top after the last line.
+    fn draw_char(&mut self, ram: &mut [u16], c: u16) {
+        let (row, column) = &mut self.cursor;
+        match c {
+            NEW_LINE => {
+                *row = (*row + 1) % screen::TEXT_ROWS;
+                *column = 0;
+            }
+            BACKSPACE => {
+                if *column > 0 {
+                    *column -= 1;
+                } else if *row > 0 {
+                    *row -= 1;
+                    *column = screen::TEXT_COLUMNS - 1;
+                }
+                screen::draw_char(ram, *row, *column, b' ' as u16);
+            }
+            _ => {
+                screen::draw_char(ram, *row, *column, c);
+                *column += 1;
+                if *column == screen::TEXT_COLUMNS {
+                    self.draw_char(ram, NEW_LINE);
+                }
+            }
+        }
+    }
+
+    /// Next character typed, `\n` is the newline key. It is echoed on the
+    /// screen, but not to the text output: the terminal already shows it.
+    fn read_char(&mut self, ram: &mut [u16]) -> Result<u16, String> {
+        self.flush().map_err(|error| error.to_string())?;
+        let c = self.keyboard.read_char()?;
+        self.draw_char(ram, c);
+        Ok(c)
+    }
+
+    fn draw_pixel(&self, ram: &mut [u16], x: i32, y: i32) {
+        screen::set_pixel(ram, x, y, self.color);
+    }
+
+    fn draw_row(&self, ram: &mut [u16], x1: i32, x2: i32, y: i32) {
+        for x in x1..=x2 {
+            self.draw_pixel(ram, x, y);
+        }
+    }
+
+    fn draw_line(&self, ram: &mut [u16], x1: i32, y1: i32, x2: i32, y2: i32) {
+        let (dx, dy) = ((x2 - x1).abs(), -(y2 - y1).abs());
+        let (sx, sy) = ((x2 - x1).signum(), (y2 - y1).signum());
+        let (mut x, mut y, mut error) = (x1, y1, dx + dy);
+        loop {
+            self.draw_pixel(ram, x, y);
+            if x == x2 && y == y2 {
+                break;
+            }
+            if 2 * error >= dy {
+                error += dy;
+                x += sx;
+            }
+            if 2 * error <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+    }
+}
+
+fn on_screen(x: i32, y: i32) -> bool {
+    (0..screen::WIDTH).contains(&x) && (0..screen::HEIGHT).contains(&y)
+}
+
+fn string_chars(ram: &[u16], string: usize) -> Vec<u16> {
+    let length = ram[string + 1] as usize;
+    ram[string + 2..string + 2 + length].to_vec()
+}
+
+/// `String.intValue`: the leading digits, after an optional `-`.
+fn int_value(chars: &[u16]) -> i16 {
+    let (negative, digits) = match chars.first() {
+        Some(&c) if c == b'-' as u16 => (true, &chars[1..]),
+        _ => (false, chars),
+    };
+    let mut value: i16 = 0;
+    for &c in digits {
+        if !(b'0' as u16..=b'9' as u16).contains(&c) {
+            break;
+        }
+        value = value
+            .wrapping_mul(10)
+            .wrapping_add((c - b'0' as u16) as i16);
+    }
+    if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    }
+}
+
+/// First-fit allocator of the heap, `2048` to `16383`.
+struct Heap {
+    /// start -> size of free blocks, neighbours merged
+    free: BTreeMap<u16, u16>,
+    /// start -> size of allocated blocks
+    allocated: HashMap<u16, u16>,
+}
+
+impl Heap {
+    fn new() -> Self {
+        Heap {
+            free: BTreeMap::from([(HEAP_START, HEAP_END - HEAP_START)]),
+            allocated: HashMap::new(),
+        }
+    }
+
+    fn alloc(&mut self, size: u16) -> Option<u16> {
+        let (&start, &free_size) = self.free.iter().find(|(_, &free)| free >= size)?;
+        self.free.remove(&start);
+        if free_size > size {
+            self.free.insert(start + size, free_size - size);
+        }
+        self.allocated.insert(start, size);
+        Some(start)
+    }
+
+    fn de_alloc(&mut self, start: u16) -> Result<(), String> {
+        let Some(mut size) = self.allocated.remove(&start) else {
+            return Err(format!("{start} is not an allocated block"));
+        };
+        let mut start = start;
+        if let Some(&next_size) = self.free.get(&(start + size)) {
+            self.free.remove(&(start + size));
+            size += next_size;
+        }
+        if let Some((&previous, &previous_size)) = self.free.range(..start).next_back() {
+            if previous + previous_size == start {
+                self.free.remove(&previous);
+                start = previous;
+                size += previous_size;
+            }
+        }
+        self.free.insert(start, size);
+        Ok(())
+    }
+
+    fn is_allocated(&self, start: u16) -> bool {
+        self.allocated.contains_key(&start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_os_functions() {
+        for name in [
+            "Math.init",
+            "String.eraseLastChar",
+            "Output.printInt",
+            "Screen.drawRectangle",
+            "Keyboard.keyPressed",
+            "Memory.deAlloc",
+            "Sys.wait",
+        ] {
+            let function = OsFunction::from_name(name).unwrap();
+            assert_eq!(function.to_string(), name);
+        }
+        assert_eq!(OsFunction::from_name("Main.main"), None);
+        assert_eq!(OsFunction::ScreenDrawLine.arity(), 4);
+    }
+
+    #[test]
+    fn test_heap() {
+        let mut heap = Heap::new();
+        let a = heap.alloc(10).unwrap();
+        let b = heap.alloc(5).unwrap();
+        let c = heap.alloc(5).unwrap();
+        assert_eq!([a, b, c], [2048, 2058, 2063]);
+
+        // freed blocks are reused and merged
+        heap.de_alloc(a).unwrap();
+        heap.de_alloc(b).unwrap();
+        assert_eq!(heap.alloc(15), Some(2048));
+        assert!(heap.de_alloc(b).is_err());
+        assert_eq!(heap.alloc(20000), None);
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut os = Os::new(Keyboard::new(Box::new(io::empty())), Box::new(io::sink()));
+        let mut ram = vec![0; 32768];
+        let mut call = |function, args: &[u16]| match os.call(function, args, &mut ram) {
+            Ok(OsReturn::Value(value)) => value,
+            _ => panic!("`{function}` fail"),
+        };
+
+        let string = call(OsFunction::StringNew, &[6]);
+        call(OsFunction::StringSetInt, &[string, -123i16 as u16]);
+        call(OsFunction::StringAppendChar, &[string, b'4' as u16]);
+        assert_eq!(call(OsFunction::StringLength, &[string]), 5);
+        assert_eq!(call(OsFunction::StringIntValue, &[string]) as i16, -1234);
+        call(OsFunction::StringEraseLastChar, &[string]);
+        assert_eq!(call(OsFunction::StringCharAt, &[string, 3]), b'3' as u16);
+        assert_eq!(call(OsFunction::StringIntValue, &[string]) as i16, -123);
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+
+use crate::{debug_info::ClassDebugInfo, os::OsFunction};
+
+/// How many of the hottest lines the report lists.
+const HOT_LINES: usize = 20;
+
+/// A frame of the call tree.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Frame {
+    /// index of a function of the program
+    Vm(usize),
+    /// a function of the OS built into the emulator
+    Os(OsFunction),
+}
+
+struct Node {
+    frame: Frame,
+    parent: usize,
+    children: HashMap<Frame, usize>,
+    /// instructions run with this stack, not counting the calls it makes
+    count: u64,
+}
+
+/// Counts what a running program does, as [`crate::emulator::Emulator`]
+/// tells it: how often each instruction runs, how often each function is
+/// called, and how many instructions each call stack runs.
+pub struct Profiler {
+    /// times each instruction ran, by address
+    counts: Vec<u64>,
+    /// times each function of the program was called, by index
+    calls: Vec<u64>,
+    os_calls: HashMap<OsFunction, u64>,
+    /// the call tree, its root with the empty stack first
+    nodes: Vec<Node>,
+    /// node of the stack being run
+    current: usize,
+}
+
+impl Profiler {
+    pub fn new(code_len: usize, function_count: usize) -> Self {
+        Profiler {
+            counts: vec![0; code_len],
+            calls: vec![0; function_count],
+            os_calls: HashMap::new(),
+            nodes: vec![Node {
+                frame: Frame::Vm(usize::MAX),
+                parent: 0,
+                children: HashMap::new(),
+                count: 0,
+            }],
+            current: 0,
+        }
+    }
+
+    /// The instruction at `address` is about to run.
+    pub fn instruction(&mut self, address: usize) {
+        self.counts[address] += 1;
+        self.nodes[self.current].count += 1;
+    }
+
+    pub fn call(&mut self, function: usize) {
+        self.calls[function] += 1;
+        self.current = self.child(Frame::Vm(function));
+    }
+
+    pub fn return_(&mut self) {
+        self.current = self.nodes[self.current].parent;
+    }
+
+    /// A call to the OS, which runs natively: its `call` instruction counts
+    /// for the OS function.
+    pub fn os_call(&mut self, function: OsFunction) {
+        *self.os_calls.entry(function).or_default() += 1;
+        self.nodes[self.current].count -= 1;
+        let child = self.child(Frame::Os(function));
+        self.nodes[child].count += 1;
+    }
+
+    fn child(&mut self, frame: Frame) -> usize {
+        if let Some(&child) = self.nodes[self.current].children.get(&frame) {
+            return child;
+        }
+        self.nodes.push(Node {
+            frame,
+            parent: self.current,
+            children: HashMap::new(),
+            count: 0,
+        });
+        let child = self.nodes.len() - 1;
+        self.nodes[self.current].children.insert(frame, child);
+        child
+    }
+
+    /// The profile of a program with `functions`, (name, address of the
+    /// first instruction) in program order.
+    pub fn profile(&self, functions: Vec<(String, usize)>) -> Profile {
+        let frame_name = |frame| match frame {
+            Frame::Vm(function) => functions[function].0.clone(),
+            Frame::Os(function) => function.to_string(),
+        };
+
+        let mut stacks = vec![];
+        for (i, node) in self.nodes.iter().enumerate().skip(1) {
+            if node.count == 0 {
+                continue;
+            }
+            let mut frames = vec![];
+            let mut j = i;
+            while j != 0 {
+                frames.push(frame_name(self.nodes[j].frame));
+                j = self.nodes[j].parent;
+            }
+            frames.reverse();
+            stacks.push((frames.join(";"), node.count));
+        }
+        stacks.sort();
+
+        let mut os_calls = self
+            .os_calls
+            .iter()
+            .map(|(function, &calls)| (function.to_string(), calls))
+            .collect::<Vec<_>>();
+        os_calls.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        Profile {
+            functions,
+            counts: self.counts.clone(),
+            calls: self.calls.clone(),
+            os_calls,
+            stacks,
+        }
+    }
+}
+
+/// What a run of a program did.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Profile {
+    /// (name, address of the first instruction) of each function
+    pub functions: Vec<(String, usize)>,
+    /// times each instruction ran, in program order, which is the order of
+    /// the VM code and of [`crate::source_map::locations`]
+    pub counts: Vec<u64>,
+    /// times each function was called, in program order
+    pub calls: Vec<u64>,
+    /// (name, calls) of the OS functions run natively, most called first
+    pub os_calls: Vec<(String, u64)>,
+    /// (`Sys.init;Main.main;...`, instructions) of each call stack
+    pub stacks: Vec<(String, u64)>,
+}
+
+impl Profile {
+    /// A report of the run: the functions that ran the most instructions,
+    /// the OS calls, and the hottest lines of the classes in `debug_info`.
+    pub fn render_table(&self, debug_info: &[ClassDebugInfo]) -> String {
+        let functions = &self.functions;
+        let total = self.counts.iter().sum::<u64>().max(1);
+        let percent = |count: u64| count as f64 * 100.0 / total as f64;
+        let mut buf = format!("{total} instructions\n\n");
+
+        // instructions run in each function, not counting the calls
+        let mut rows = functions
+            .iter()
+            .enumerate()
+            .map(|(i, (name, start))| {
+                let end = functions.get(i + 1).map_or(self.counts.len(), |f| f.1);
+                let count = self.counts[*start..end].iter().sum::<u64>();
+                (name.as_str(), self.calls[i], count)
+            })
+            .filter(|&(_, calls, count)| calls > 0 || count > 0)
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(b.0)));
+        buf += &format!(
+            "{:<32} {:>10} {:>14} {:>7}\n",
+            "function", "calls", "instructions", "%"
+        );
+        for (name, calls, count) in rows {
+            buf += &format!(
+                "{name:<32} {calls:>10} {count:>14} {:>7.2}\n",
+                percent(count)
+            );
+        }
+
+        if !self.os_calls.is_empty() {
+            buf += &format!("\n{:<32} {:>10}\n", "OS function", "calls");
+            for (name, calls) in &self.os_calls {
+                buf += &format!("{name:<32} {calls:>10}\n");
+            }
+        }
+
+        let lines = self.hot_lines(debug_info);
+        if !lines.is_empty() {
+            buf += &format!(
+                "\n{:<32} {:>10} {:>14} {:>7}\n",
+                "line", "", "instructions", "%"
+            );
+            for ((file, line, text), count) in lines {
+                let location = format!("{file}:{line}");
+                buf += &format!(
+                    "{location:<32} {:>10} {count:>14} {:>7.2}  {text}\n",
+                    "",
+                    percent(count)
+                );
+            }
+        }
+        buf
+    }
+
+    /// The lines that ran the most instructions, as ((file name, line,
+    /// source text), instructions).
+    fn hot_lines(&self, debug_info: &[ClassDebugInfo]) -> Vec<((String, usize, String), u64)> {
+        let mut counts = BTreeMap::<_, u64>::new();
+        for class in debug_info {
+            let source = fs::read_to_string(&class.file).unwrap_or_default();
+            let source = source.lines().collect::<Vec<_>>();
+            for subroutine in &class.subroutines {
+                let Some(&(_, start)) = self
+                    .functions
+                    .iter()
+                    .find(|(name, _)| *name == subroutine.name)
+                else {
+                    continue;
+                };
+                for (i, &line) in subroutine.lines.iter().enumerate() {
+                    // the prologue runs with the declaration
+                    let line = if line == 0 {
+                        subroutine.position.0
+                    } else {
+                        line
+                    };
+                    let text = source.get(line - 1).map_or("", |text| text.trim());
+                    *counts
+                        .entry((class.file_name(), line, text.to_string()))
+                        .or_default() += self.counts[start + i];
+                }
+            }
+        }
+        let mut lines = counts
+            .into_iter()
+            .filter(|&(_, count)| count > 0)
+            .collect::<Vec<_>>();
+        lines.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        lines.truncate(HOT_LINES);
+        lines
+    }
+
+    /// The call stacks in the folded format of flame graph tools: a
+    /// `Sys.init;Main.main;Main.draw 1234` line for each.
+    pub fn render_folded(&self) -> String {
+        let mut buf = String::new();
+        for (stack, count) in &self.stacks {
+            buf += &format!("{stack} {count}\n");
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::test_file;
+
+    #[test]
+    fn test_profile() -> io::Result<()> {
+        let mut linked = test_file::compile_and_link(
+            "class Main {
+    function void main() {
+        var int i;
+        while (i < 4) {
+            let i = Main.next(i);
+        }
+        return;
+    }
+    function int next(int i) {
+        return (i * 1) + 1;
+    }
+}",
+            "",
+        )?;
+        let emulator = &mut linked.emulator;
+        emulator.enable_profiler();
+        emulator.run().unwrap();
+        let profile = emulator.profile().unwrap();
+
+        let names = profile
+            .functions
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Sys.init", "Main.main", "Main.next"]);
+        assert_eq!(profile.calls, [1, 1, 4]);
+        assert_eq!(profile.os_calls, [("Math.multiply".to_string(), 4)]);
+        // `next` runs 7 instructions a call, its `call Math.multiply 2`
+        // counting for `Math.multiply`
+        assert_eq!(
+            profile.render_folded(),
+            "Sys.init 5
+Sys.init;Main.main 50
+Sys.init;Main.main;Main.next 24
+Sys.init;Main.main;Main.next;Math.multiply 4
+"
+        );
+
+        let table = profile.render_table(&[linked.debug_info.clone()]);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "83 instructions");
+        assert_eq!(
+            lines[3..6],
+            [
+                "Main.main                                 1             50   60.24",
+                "Main.next                                 4             28   33.73",
+                "Sys.init                                  1              5    6.02",
+            ]
+        );
+        assert_eq!(lines[8], "Math.multiply                             4");
+        // the bootstrap `Sys.init` has no lines
+        assert_eq!(
+            lines[11..13],
+            [
+                "test.test:4                                             35   42.17  while (i < 4) {",
+                "test.test:10                                            24   28.92  return (i * 1) + 1;",
+            ]
+        );
+
+        Ok(())
+    }
+}
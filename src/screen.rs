@@ -0,0 +1,293 @@
+use std::{fs, io, path::Path};
+
+/// The screen memory map: 256 rows of 512 pixels, 32 words per row, the
+/// lowest bit of a word being its leftmost pixel and `1` black.
+pub const SCREEN: usize = 16384;
+pub const KBD: usize = 24576;
+pub const WIDTH: i32 = 512;
+pub const HEIGHT: i32 = 256;
+const WORDS_PER_ROW: usize = 32;
+
+/// `Output` draws characters in 23 rows of 64, 8x11 pixels each.
+pub const TEXT_ROWS: i32 = 23;
+pub const TEXT_COLUMNS: i32 = 64;
+const CHAR_WIDTH: usize = 8;
+const CHAR_HEIGHT: usize = 11;
+
+/// Bitmaps of the characters `32` to `126` of the Jack OS `Output` class,
+/// one byte per row, the lowest bit leftmost.
+const FONT: [[u8; CHAR_HEIGHT]; 95] = [
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],           //
+    [12, 30, 30, 30, 12, 12, 0, 12, 12, 0, 0],   // !
+    [54, 54, 20, 0, 0, 0, 0, 0, 0, 0, 0],        // "
+    [0, 18, 18, 63, 18, 18, 63, 18, 18, 0, 0],   // #
+    [12, 30, 51, 3, 30, 48, 51, 30, 12, 12, 0],  // $
+    [0, 0, 35, 51, 24, 12, 6, 51, 49, 0, 0],     // %
+    [12, 30, 30, 12, 54, 27, 27, 27, 54, 0, 0],  // &
+    [12, 12, 6, 0, 0, 0, 0, 0, 0, 0, 0],         // '
+    [24, 12, 6, 6, 6, 6, 6, 12, 24, 0, 0],       // (
+    [6, 12, 24, 24, 24, 24, 24, 12, 6, 0, 0],    // )
+    [0, 0, 0, 51, 30, 63, 30, 51, 0, 0, 0],      // *
+    [0, 0, 0, 12, 12, 63, 12, 12, 0, 0, 0],      // +
+    [0, 0, 0, 0, 0, 0, 0, 12, 12, 6, 0],         // ,
+    [0, 0, 0, 0, 0, 63, 0, 0, 0, 0, 0],          // -
+    [0, 0, 0, 0, 0, 0, 0, 12, 12, 0, 0],         // .
+    [0, 0, 32, 48, 24, 12, 6, 3, 1, 0, 0],       // /
+    [12, 30, 51, 51, 51, 51, 51, 30, 12, 0, 0],  // 0
+    [12, 14, 15, 12, 12, 12, 12, 12, 63, 0, 0],  // 1
+    [30, 51, 48, 24, 12, 6, 3, 51, 63, 0, 0],    // 2
+    [30, 51, 48, 48, 28, 48, 48, 51, 30, 0, 0],  // 3
+    [16, 24, 28, 26, 25, 63, 24, 24, 60, 0, 0],  // 4
+    [63, 3, 3, 31, 48, 48, 48, 51, 30, 0, 0],    // 5
+    [28, 6, 3, 3, 31, 51, 51, 51, 30, 0, 0],     // 6
+    [63, 49, 48, 48, 24, 12, 12, 12, 12, 0, 0],  // 7
+    [30, 51, 51, 51, 30, 51, 51, 51, 30, 0, 0],  // 8
+    [30, 51, 51, 51, 62, 48, 48, 24, 14, 0, 0],  // 9
+    [0, 0, 12, 12, 0, 0, 12, 12, 0, 0, 0],       // :
+    [0, 0, 12, 12, 0, 0, 12, 12, 6, 0, 0],       // ;
+    [0, 0, 24, 12, 6, 3, 6, 12, 24, 0, 0],       // <
+    [0, 0, 0, 63, 0, 0, 63, 0, 0, 0, 0],         // =
+    [0, 0, 3, 6, 12, 24, 12, 6, 3, 0, 0],        // >
+    [30, 51, 51, 24, 12, 12, 0, 12, 12, 0, 0],   // ?
+    [30, 51, 51, 59, 59, 59, 27, 3, 30, 0, 0],   // @
+    [12, 30, 51, 51, 63, 51, 51, 51, 51, 0, 0],  // A
+    [31, 51, 51, 51, 31, 51, 51, 51, 31, 0, 0],  // B
+    [28, 54, 35, 3, 3, 3, 35, 54, 28, 0, 0],     // C
+    [15, 27, 51, 51, 51, 51, 51, 27, 15, 0, 0],  // D
+    [63, 51, 35, 11, 15, 11, 35, 51, 63, 0, 0],  // E
+    [63, 51, 35, 11, 15, 11, 3, 3, 3, 0, 0],     // F
+    [28, 54, 35, 3, 59, 51, 51, 54, 44, 0, 0],   // G
+    [51, 51, 51, 51, 63, 51, 51, 51, 51, 0, 0],  // H
+    [30, 12, 12, 12, 12, 12, 12, 12, 30, 0, 0],  // I
+    [60, 24, 24, 24, 24, 24, 27, 27, 14, 0, 0],  // J
+    [51, 51, 51, 27, 15, 27, 51, 51, 51, 0, 0],  // K
+    [3, 3, 3, 3, 3, 3, 35, 51, 63, 0, 0],        // L
+    [33, 51, 63, 63, 51, 51, 51, 51, 51, 0, 0],  // M
+    [51, 51, 55, 55, 63, 59, 59, 51, 51, 0, 0],  // N
+    [30, 51, 51, 51, 51, 51, 51, 51, 30, 0, 0],  // O
+    [31, 51, 51, 51, 31, 3, 3, 3, 3, 0, 0],      // P
+    [30, 51, 51, 51, 51, 51, 63, 59, 30, 48, 0], // Q
+    [31, 51, 51, 51, 31, 27, 51, 51, 51, 0, 0],  // R
+    [30, 51, 51, 6, 28, 48, 51, 51, 30, 0, 0],   // S
+    [63, 63, 45, 12, 12, 12, 12, 12, 30, 0, 0],  // T
+    [51, 51, 51, 51, 51, 51, 51, 51, 30, 0, 0],  // U
+    [51, 51, 51, 51, 51, 30, 30, 12, 12, 0, 0],  // V
+    [51, 51, 51, 51, 51, 63, 63, 63, 18, 0, 0],  // W
+    [51, 51, 30, 30, 12, 30, 30, 51, 51, 0, 0],  // X
+    [51, 51, 51, 51, 30, 12, 12, 12, 30, 0, 0],  // Y
+    [63, 51, 49, 24, 12, 6, 35, 51, 63, 0, 0],   // Z
+    [30, 6, 6, 6, 6, 6, 6, 6, 30, 0, 0],         // [
+    [0, 0, 1, 3, 6, 12, 24, 48, 32, 0, 0],       // \
+    [30, 24, 24, 24, 24, 24, 24, 24, 30, 0, 0],  // ]
+    [8, 28, 54, 0, 0, 0, 0, 0, 0, 0, 0],         // ^
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 63, 0],          // _
+    [6, 12, 24, 0, 0, 0, 0, 0, 0, 0, 0],         // `
+    [0, 0, 0, 14, 24, 30, 27, 27, 54, 0, 0],     // a
+    [3, 3, 3, 15, 27, 51, 51, 51, 30, 0, 0],     // b
+    [0, 0, 0, 30, 51, 3, 3, 51, 30, 0, 0],       // c
+    [48, 48, 48, 60, 54, 51, 51, 51, 30, 0, 0],  // d
+    [0, 0, 0, 30, 51, 63, 3, 51, 30, 0, 0],      // e
+    [28, 54, 38, 6, 15, 6, 6, 6, 15, 0, 0],      // f
+    [0, 0, 30, 51, 51, 51, 62, 48, 51, 30, 0],   // g
+    [3, 3, 3, 27, 55, 51, 51, 51, 51, 0, 0],     // h
+    [12, 12, 0, 14, 12, 12, 12, 12, 30, 0, 0],   // i
+    [48, 48, 0, 56, 48, 48, 48, 48, 51, 30, 0],  // j
+    [3, 3, 3, 51, 27, 15, 15, 27, 51, 0, 0],     // k
+    [14, 12, 12, 12, 12, 12, 12, 12, 30, 0, 0],  // l
+    [0, 0, 0, 29, 63, 43, 43, 43, 43, 0, 0],     // m
+    [0, 0, 0, 29, 51, 51, 51, 51, 51, 0, 0],     // n
+    [0, 0, 0, 30, 51, 51, 51, 51, 30, 0, 0],     // o
+    [0, 0, 0, 30, 51, 51, 51, 31, 3, 3, 0],      // p
+    [0, 0, 0, 30, 51, 51, 51, 62, 48, 48, 0],    // q
+    [0, 0, 0, 29, 55, 51, 3, 3, 7, 0, 0],        // r
+    [0, 0, 0, 30, 51, 6, 24, 51, 30, 0, 0],      // s
+    [4, 6, 6, 15, 6, 6, 6, 54, 28, 0, 0],        // t
+    [0, 0, 0, 27, 27, 27, 27, 27, 54, 0, 0],     // u
+    [0, 0, 0, 51, 51, 51, 51, 30, 12, 0, 0],     // v
+    [0, 0, 0, 51, 51, 51, 63, 63, 18, 0, 0],     // w
+    [0, 0, 0, 51, 30, 12, 12, 30, 51, 0, 0],     // x
+    [0, 0, 0, 51, 51, 51, 62, 48, 24, 15, 0],    // y
+    [0, 0, 0, 63, 27, 12, 6, 51, 63, 0, 0],      // z
+    [56, 12, 12, 12, 7, 12, 12, 12, 56, 0, 0],   // {
+    [12, 12, 12, 12, 12, 12, 12, 12, 12, 0, 0],  // |
+    [7, 12, 12, 12, 56, 12, 12, 12, 7, 0, 0],    // }
+    [38, 45, 25, 0, 0, 0, 0, 0, 0, 0, 0],        // ~
+];
+
+/// What `Output` draws for a character it has no bitmap for.
+const BLACK_SQUARE: [u8; CHAR_HEIGHT] = [63, 63, 63, 63, 63, 63, 63, 63, 63, 0, 0];
+
+/// Whether a pixel is black.
+pub fn pixel(ram: &[u16], x: i32, y: i32) -> bool {
+    let word = ram[SCREEN + y as usize * WORDS_PER_ROW + x as usize / 16];
+    word >> (x % 16) & 1 == 1
+}
+
+pub fn set_pixel(ram: &mut [u16], x: i32, y: i32, black: bool) {
+    let address = SCREEN + y as usize * WORDS_PER_ROW + x as usize / 16;
+    let bit = 1 << (x % 16);
+    if black {
+        ram[address] |= bit;
+    } else {
+        ram[address] &= !bit;
+    }
+}
+
+/// Draw a character in the text cell at `row`, `column`, replacing what
+/// was there.
+pub fn draw_char(ram: &mut [u16], row: i32, column: i32, c: u16) {
+    let bitmap = match c {
+        32..=126 => &FONT[c as usize - 32],
+        _ => &BLACK_SQUARE,
+    };
+    // two characters share a word, the even column in the low byte
+    let shift = (column % 2) * 8;
+    let mask = !(0xff << shift);
+    for (i, &bits) in bitmap.iter().enumerate() {
+        let y = row as usize * CHAR_HEIGHT + i;
+        let address = SCREEN + y * WORDS_PER_ROW + (column as usize * CHAR_WIDTH) / 16;
+        ram[address] = ram[address] & mask | (bits as u16) << shift;
+    }
+}
+
+/// The screen as a binary PPM (`P6`) image.
+pub fn to_ppm(ram: &[u16]) -> Vec<u8> {
+    let mut image = format!("P6\n{WIDTH} {HEIGHT}\n255\n").into_bytes();
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let level = if pixel(ram, x, y) { 0 } else { 255 };
+            image.extend([level; 3]);
+        }
+    }
+    image
+}
+
+/// The screen as a 1 bit grayscale PNG image, stored without compression.
+pub fn to_png(ram: &[u16]) -> Vec<u8> {
+    // each row starts with filter type 0, then 8 pixels a byte, leftmost in
+    // the highest bit, `1` white
+    let mut data = vec![];
+    for y in 0..HEIGHT {
+        data.push(0);
+        for x in (0..WIDTH).step_by(8) {
+            let mut byte = 0;
+            for i in 0..8 {
+                if !pixel(ram, x + i, y) {
+                    byte |= 0x80 >> i;
+                }
+            }
+            data.push(byte);
+        }
+    }
+
+    // a zlib stream of one stored deflate block, which holds up to 65535
+    // bytes: a row is 65 bytes
+    let mut zlib = vec![0x78, 0x01, 0x01];
+    zlib.extend((data.len() as u16).to_le_bytes());
+    zlib.extend((!(data.len() as u16)).to_le_bytes());
+    zlib.extend(&data);
+    zlib.extend(adler32(&data).to_be_bytes());
+
+    let mut header = vec![];
+    header.extend((WIDTH as u32).to_be_bytes());
+    header.extend((HEIGHT as u32).to_be_bytes());
+    // bit depth 1, grayscale, deflate, no filter, no interlace
+    header.extend([1, 0, 0, 0, 0]);
+
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, content) in [(b"IHDR", header), (b"IDAT", zlib), (b"IEND", vec![])] {
+        image.extend((content.len() as u32).to_be_bytes());
+        let start = image.len();
+        image.extend(kind);
+        image.extend(&content);
+        let crc = crc32(&image[start..]);
+        image.extend(crc.to_be_bytes());
+    }
+    image
+}
+
+/// Write the screen to a `.png` file, or a `.ppm` file.
+pub fn write_snapshot(path: &Path, ram: &[u16]) -> io::Result<()> {
+    let image = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("png") => to_png(ram),
+        Some("ppm") => to_ppm(ram),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a .png or .ppm file", path.display()),
+            ))
+        }
+    };
+    fs::write(path, image)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw_char() {
+        let mut ram = vec![0; 32768];
+        draw_char(&mut ram, 0, 0, b'A' as u16);
+        draw_char(&mut ram, 0, 1, b'|' as u16);
+        draw_char(&mut ram, 1, 0, 0);
+
+        // 'A' in the low byte, '|' in the high byte of the same words
+        assert_eq!(ram[SCREEN], 12 | 12 << 8);
+        assert_eq!(ram[SCREEN + 4 * WORDS_PER_ROW], 63 | 12 << 8);
+        assert!(pixel(&ram, 2, 0) && pixel(&ram, 3, 0) && !pixel(&ram, 4, 0));
+        // the black square on the second row of text
+        assert_eq!(ram[SCREEN + 11 * WORDS_PER_ROW], 63);
+
+        // drawing replaces the cell
+        draw_char(&mut ram, 0, 0, b' ' as u16);
+        assert_eq!(ram[SCREEN], 12 << 8);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let mut ram = vec![0; 32768];
+        set_pixel(&mut ram, 0, 0, true);
+        set_pixel(&mut ram, 9, 1, true);
+
+        let ppm = to_ppm(&ram);
+        let pixels = &ppm[b"P6\n512 256\n255\n".len()..];
+        assert_eq!(pixels.len(), 512 * 256 * 3);
+        assert_eq!(pixels[..6], [0, 0, 0, 255, 255, 255]);
+        assert_eq!(pixels[(512 + 9) * 3], 0);
+
+        let png = to_png(&ram);
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        assert_eq!(png[12..16], *b"IHDR");
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(png[png.len() - 4..], 0xae42_6082u32.to_be_bytes());
+        // IDAT: zlib header, stored block header, then the rows
+        let rows = &png[8 + 25 + 8 + 7..][..256 * 65];
+        assert_eq!(rows[..3], [0, 0x7f, 0xff]);
+        assert_eq!(rows[65..68], [0, 0xff, 0xbf]);
+    }
+}
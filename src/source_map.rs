@@ -0,0 +1,201 @@
+use std::{collections::HashMap, fs};
+
+use crate::{
+    debug_info::{ClassDebugInfo, SubroutineDebugInfo},
+    vm::{self, VmInstruction},
+};
+
+/// Where a VM instruction comes from in the Jack source.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SourceLocation {
+    /// file name, like `Main.jack`
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    /// VM function name of the enclosing subroutine
+    pub subroutine: String,
+}
+
+/// Finds the debug info of the functions of a program.
+struct Subroutines<'a> {
+    classes: &'a [ClassDebugInfo],
+    /// VM function name -> (class, subroutine) index in `classes`
+    index: HashMap<&'a str, (usize, usize)>,
+}
+
+impl<'a> Subroutines<'a> {
+    fn new(classes: &'a [ClassDebugInfo]) -> Self {
+        let mut index = HashMap::new();
+        for (i, class) in classes.iter().enumerate() {
+            for (j, subroutine) in class.subroutines.iter().enumerate() {
+                index.insert(subroutine.name.as_str(), (i, j));
+            }
+        }
+        Subroutines { classes, index }
+    }
+
+    fn get(&self, function: &[VmInstruction]) -> Option<(usize, &'a SubroutineDebugInfo)> {
+        let &(class, subroutine) = self.index.get(vm::function_name(function))?;
+        Some((class, &self.classes[class].subroutines[subroutine]))
+    }
+}
+
+/// (line, column) of instruction `i` of a subroutine; the prologue before
+/// the first statement belongs to the declaration.
+fn position(subroutine: &SubroutineDebugInfo, i: usize) -> Option<(usize, usize)> {
+    match (subroutine.lines.get(i)?, subroutine.columns.get(i)?) {
+        (0, _) => Some(subroutine.position),
+        (&line, &column) => Some((line, column)),
+    }
+}
+
+/// The source location of each instruction of `functions`, in order. Code
+/// that wasn't compiled from a class, like the `Sys.init` bootstrap of the
+/// linker, has none; neither has optimized code.
+pub fn locations(
+    functions: &[Vec<VmInstruction>],
+    classes: &[ClassDebugInfo],
+) -> Vec<Option<SourceLocation>> {
+    let subroutines = Subroutines::new(classes);
+    let mut locations = vec![];
+    for function in functions {
+        let subroutine = subroutines.get(function);
+        for i in 0..function.len() {
+            locations.push(subroutine.and_then(|(class, subroutine)| {
+                let (line, column) = position(subroutine, i)?;
+                Some(SourceLocation {
+                    file: classes[class].file_name(),
+                    line,
+                    column,
+                    subroutine: subroutine.name.clone(),
+                })
+            }));
+        }
+    }
+    locations
+}
+
+/// The source map of a VM file: a line for each instruction with a source
+/// location, `<index> <file>:<line>:<column> <subroutine>`, the index
+/// counting instructions from 0, not lines of the VM file.
+pub fn render(locations: &[Option<SourceLocation>]) -> String {
+    let mut buf = String::new();
+    for (i, location) in locations.iter().enumerate() {
+        if let Some(location) = location {
+            buf += &format!(
+                "{i} {}:{}:{} {}\n",
+                location.file, location.line, location.column, location.subroutine
+            );
+        }
+    }
+    buf
+}
+
+/// VM code like [`vm::to_vm_code`], with a `// Main.jack:12 let x = 1;`
+/// comment in front of the code of each source line.
+pub fn to_commented_vm_code(
+    functions: &[Vec<VmInstruction>],
+    classes: &[ClassDebugInfo],
+) -> String {
+    let subroutines = Subroutines::new(classes);
+    let sources = classes
+        .iter()
+        .map(|class| {
+            fs::read_to_string(&class.file)
+                .map(|source| source.lines().map(str::to_string).collect::<Vec<_>>())
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+
+    let mut buf = String::new();
+    let mut last_line = None;
+    for function in functions {
+        let subroutine = subroutines.get(function);
+        for (i, instruction) in function.iter().enumerate() {
+            let line = subroutine
+                .and_then(|(class, subroutine)| Some((class, position(subroutine, i)?.0)));
+            if let Some((class, line)) = line.filter(|_| line != last_line) {
+                let text = sources[class].get(line - 1).map_or("", |text| text.trim());
+                buf += &format!("// {}:{line} {text}\n", classes[class].file_name());
+            }
+            last_line = line;
+            buf += &format!("{instruction}\n");
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::Path};
+
+    use super::*;
+    use crate::{compilation_engine::CompilationEngine, linker, test_file::TestFile};
+
+    #[test]
+    fn test_source_map() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("class Main {")?;
+        test_file.add_line("    function void main() {")?;
+        test_file.add_line("        var int x;")?;
+        test_file.add_line("        let x = 1;")?;
+        test_file.add_line("        if (x) { return; }")?;
+        test_file.add_line("        return;")?;
+        test_file.add_line("    }")?;
+        test_file.add_line("}")?;
+
+        let mut engine = CompilationEngine::with_vm_output(Path::new(&test_file.path), io::sink())?;
+        engine.compile_class()?;
+        let classes = [engine.debug_info().clone()];
+        let functions = engine.functions();
+
+        assert_eq!(
+            render(&locations(functions, &classes)),
+            "0 test.test:2:5 Main.main
+1 test.test:4:9 Main.main
+2 test.test:4:9 Main.main
+3 test.test:5:9 Main.main
+4 test.test:5:9 Main.main
+5 test.test:5:9 Main.main
+6 test.test:5:18 Main.main
+7 test.test:5:18 Main.main
+8 test.test:5:9 Main.main
+9 test.test:5:9 Main.main
+10 test.test:5:9 Main.main
+11 test.test:6:9 Main.main
+12 test.test:6:9 Main.main
+"
+        );
+        assert_eq!(
+            to_commented_vm_code(functions, &classes),
+            "// test.test:2 function void main() {
+function Main.main 1
+// test.test:4 let x = 1;
+push constant 1
+pop local 0
+// test.test:5 if (x) { return; }
+push local 0
+not
+if-goto else_1
+push constant 0
+return
+goto end_1
+label else_1
+label end_1
+// test.test:6 return;
+push constant 0
+return
+"
+        );
+
+        // the bootstrap of the linker has no source
+        let program = linker::link([functions.to_vec()], false).unwrap();
+        assert_eq!(vm::function_name(&program[0]), "Sys.init");
+        let locations = locations(&program, &classes);
+        assert!(locations[..program[0].len()].iter().all(Option::is_none));
+        assert!(locations[program[0].len()..].iter().all(Option::is_some));
+
+        Ok(())
+    }
+}
@@ -11,14 +11,14 @@ pub enum Kind {
 struct Item {
     type_: String,
     kind: Kind,
-    index: i32,
+    index: u16,
 }
 pub struct SymbolTable {
     table: HashMap<String, Item>,
-    static_count: i32,
-    field_count: i32,
-    arg_count: i32,
-    var_count: i32,
+    static_count: u16,
+    field_count: u16,
+    arg_count: u16,
+    var_count: u16,
 }
 
 impl SymbolTable {
@@ -75,7 +75,7 @@ impl SymbolTable {
         );
     }
 
-    pub fn var_count(&self, kind: Kind) -> i32 {
+    pub fn var_count(&self, kind: Kind) -> u16 {
         use Kind::*;
         match kind {
             Static => self.static_count,
@@ -93,9 +93,23 @@ impl SymbolTable {
         self.table.get(name).map(|item| item.type_.to_string())
     }
 
-    pub fn index_of(&self, name: &str) -> Option<i32> {
+    pub fn index_of(&self, name: &str) -> Option<u16> {
         self.table.get(name).map(|item| item.index)
     }
+
+    /// Names defined with the given kind, in index order.
+    pub fn names_of(&self, kind: Kind) -> Vec<String> {
+        let mut items = self
+            .table
+            .iter()
+            .filter(|(_, item)| item.kind == kind)
+            .collect::<Vec<_>>();
+        items.sort_by_key(|(_, item)| item.index);
+        items
+            .into_iter()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -144,4 +158,16 @@ mod tests {
 
         assert_eq!(symble_table.index_of(name), Some(0));
     }
+
+    #[test]
+    fn test_names_of() {
+        let mut symble_table = SymbolTable::new();
+        symble_table.define("b", "int", Kind::Arg);
+        symble_table.define("x", "int", Kind::Var);
+        symble_table.define("a", "char", Kind::Arg);
+
+        assert_eq!(symble_table.names_of(Kind::Arg), vec!["b", "a"]);
+        assert_eq!(symble_table.names_of(Kind::Var), vec!["x"]);
+        assert!(symble_table.names_of(Kind::Field).is_empty());
+    }
 }
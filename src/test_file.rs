@@ -1,26 +1,79 @@
+//! Test fixtures: Jack source files in directories of their own, compiled
+//! in memory, and snapshot files to check outputs against.
 #![allow(unused)]
 use std::{
+    env,
     fs::{self, File, OpenOptions},
     io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    comparer,
+    compilation_engine::{CompilationEngine, CompileOptions},
+    debug_info::ClassDebugInfo,
+    emulator::Emulator,
+    keyboard::Keyboard,
+    linker,
+    os::{Os, SharedOutput},
+    tokenizer,
+    vm::{self, VmInstruction},
 };
 
 const TEST_FILE_INIT_LINE_TOTAL: usize = 10;
 
+/// Set to write the snapshot files instead of checking them.
+const UPDATE_SNAPSHOTS: &str = "UPDATE_SNAPSHOTS";
+
+/// A directory of its own for a test, removed with everything in it when
+/// dropped, so tests running in parallel don't share files.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> io::Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("jackc-test-{}-{id}", process::id()));
+        fs::create_dir_all(&path)?;
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // a leftover in the temp directory is no reason to fail a test
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A `test.test` source file, alone in a [`TempDir`] with the files the
+/// compiler writes next to it.
 pub struct TestFile {
     pub file: File,
     pub path: String,
     pub lines: Vec<String>,
+    dir: TempDir,
 }
 
 impl TestFile {
     pub fn new() -> io::Result<Self> {
-        let path = "./test.test";
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test.test");
         let lines = TEST_FILE_INIT_LINE_TOTAL;
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)?;
+            .truncate(true)
+            .open(&path)?;
 
         for i in 0..lines {
             file.write_all(format!("{i}\n").as_bytes())?
@@ -28,8 +81,9 @@ impl TestFile {
 
         let mut f = Self {
             file,
-            path: path.to_string(),
+            path: path.to_string_lossy().into_owned(),
             lines: vec![],
+            dir,
         };
         f.read_to_lines()?;
         Ok(f)
@@ -49,6 +103,11 @@ impl TestFile {
         Ok(())
     }
 
+    /// Compile the file as a class.
+    pub fn compile(&self, options: CompileOptions) -> io::Result<Compiled> {
+        compile(Path::new(&self.path), options)
+    }
+
     // privates
     fn read_to_lines(&mut self) -> io::Result<()> {
         let mut contents = String::new();
@@ -61,10 +120,94 @@ impl TestFile {
     }
 }
 
-impl Drop for TestFile {
-    fn drop(&mut self) {
-        fs::remove_file(&mut self.path)
-            .expect(format!("remove test file `{}` fail...", &self.path).as_str());
+/// The outputs of compiling a class.
+pub struct Compiled {
+    /// the `.my-token.xml` content
+    pub tokens: String,
+    /// the `.my-ast.xml` content
+    pub ast: String,
+    pub functions: Vec<Vec<VmInstruction>>,
+    /// `line: message` of each error
+    pub diagnostics: Vec<String>,
+}
+
+impl Compiled {
+    /// The `.my-vm.vm` content.
+    pub fn vm(&self) -> String {
+        vm::to_vm_code(&self.functions)
+    }
+}
+
+/// Compile a class in memory: no file is written next to `path`.
+pub fn compile(path: &Path, options: CompileOptions) -> io::Result<Compiled> {
+    let mut engine = CompilationEngine::without_output(path)?;
+    engine.set_options(options);
+    engine.compile_class()?;
+    Ok(Compiled {
+        tokens: tokenizer::token_xml(path)?,
+        ast: engine.ast().to_string(),
+        functions: engine.functions().to_vec(),
+        diagnostics: engine
+            .diagnostics()
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+    })
+}
+
+/// A class linked with the OS the emulator has built in, ready to run.
+pub struct Linked {
+    pub emulator: Emulator,
+    pub debug_info: ClassDebugInfo,
+    /// what the program prints
+    pub output: SharedOutput,
+    /// the source, kept for its lines to be shown
+    pub test_file: TestFile,
+}
+
+/// Compile `source` as a class in memory and link it, with the keyboard
+/// typing `script`.
+pub fn compile_and_link(source: &str, script: &str) -> io::Result<Linked> {
+    let mut test_file = TestFile::new()?;
+    test_file.clear()?;
+    test_file.add_line(source)?;
+    let mut engine = CompilationEngine::without_output(Path::new(&test_file.path))?;
+    engine.compile_class()?;
+    let program = linker::link([engine.functions().to_vec()], true).unwrap();
+
+    let output = SharedOutput::default();
+    let os = Os::new(
+        Keyboard::from_script(script).unwrap(),
+        Box::new(output.clone()),
+    );
+    Ok(Linked {
+        emulator: Emulator::new(&program, os).unwrap(),
+        debug_info: engine.debug_info().clone(),
+        output,
+        test_file,
+    })
+}
+
+/// Assert `actual` is what the snapshot file at `path` holds. With the
+/// `UPDATE_SNAPSHOTS` environment variable set, the file is written
+/// instead, for the change to be reviewed in the diff.
+pub fn assert_snapshot(path: &Path, actual: &str) {
+    if env::var_os(UPDATE_SNAPSHOTS).is_some() {
+        fs::write(path, actual).unwrap();
+        return;
+    }
+    let Ok(expected) = fs::read_to_string(path) else {
+        panic!(
+            "no snapshot {}, run with {UPDATE_SNAPSHOTS}=1 to write it",
+            path.display()
+        );
+    };
+    if actual != expected {
+        let snapshot = path.display().to_string();
+        let report = comparer::compare(actual, "output", &expected, &snapshot)
+            .err()
+            .unwrap_or_else(|| "they differ in whitespace only\n".to_string());
+        panic!("{snapshot} is out of date, run with {UPDATE_SNAPSHOTS}=1 to update it\n{report}");
     }
 }
 
@@ -97,4 +240,36 @@ mod tests {
         assert_eq!(f.lines[TEST_FILE_INIT_LINE_TOTAL], newline);
         Ok(())
     }
+
+    #[test]
+    fn test_hermetic() -> io::Result<()> {
+        let mut a = TestFile::new()?;
+        let b = TestFile::new()?;
+        assert_ne!(a.path, b.path);
+        a.clear()?;
+        a.add_line("class Main { function int one() { return 1; } }")?;
+        assert_eq!(b.lines.len(), TEST_FILE_INIT_LINE_TOTAL);
+
+        let compiled = a.compile(CompileOptions::default())?;
+        assert!(compiled
+            .tokens
+            .starts_with("<tokens>\n<keyword>class</keyword>\n"));
+        assert!(compiled.ast.starts_with("<class>\n"));
+        assert_eq!(
+            compiled.vm(),
+            "function Main.one 0\npush constant 1\nreturn\n"
+        );
+        assert_eq!(compiled.diagnostics, [] as [String; 0]);
+
+        // compiling writes nothing, and the source goes with the directory
+        let dir = Path::new(&a.path).parent().unwrap().to_path_buf();
+        let files: Vec<_> = fs::read_dir(&dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(files, ["test.test"]);
+        drop(a);
+        assert!(!dir.exists());
+        assert!(!Path::new("test.test").exists());
+        Ok(())
+    }
 }
@@ -0,0 +1,223 @@
+//! Runs the programs of `test_programs/`, one folder each, on the emulator
+//! and checks what they leave in the RAM, print and draw, compiled with
+//! every option off and with every option on. The tokens, AST and VM code
+//! this compiler gives for each class are snapshots next to it, named like
+//! its own outputs: `XXX.snap-token.xml`, `XXX.snap-ast.xml` and
+//! `XXX.snap-vm.vm`. They are not nand2tetris reference files.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    comparer,
+    compilation_engine::CompileOptions,
+    emulator::{Emulator, Exit},
+    keyboard::Keyboard,
+    linker,
+    os::{Os, SharedOutput},
+    screen,
+    test_file::{self, TempDir},
+};
+
+const PROGRAMS: [&str; 6] = [
+    "Seven",
+    "ConvertToBin",
+    "Average",
+    "ComplexArrays",
+    "Square",
+    "Expressions",
+];
+
+const OPTIONS: [CompileOptions; 2] = [
+    CompileOptions {
+        optimize: false,
+        short_circuit: false,
+        pool_strings: false,
+    },
+    CompileOptions {
+        optimize: true,
+        short_circuit: true,
+        pool_strings: true,
+    },
+];
+
+/// A program ready to run, with its text output.
+struct Program {
+    emulator: Emulator,
+    output: SharedOutput,
+}
+
+impl Program {
+    /// Compile and link the classes of `test_programs/<name>`, with the
+    /// keyboard `script`.
+    fn load(name: &str, options: CompileOptions, script: &str) -> io::Result<Self> {
+        let dir = TempDir::new()?;
+        let mut functions = vec![];
+        for (_, source) in copy_sources(name, &dir)? {
+            let compiled = test_file::compile(&source, options)?;
+            assert_eq!(
+                compiled.diagnostics,
+                [] as [String; 0],
+                "{}",
+                source.display()
+            );
+            functions.push(compiled.functions);
+        }
+
+        let program = linker::link(functions, true).unwrap();
+        let output = SharedOutput::default();
+        let os = Os::new(
+            Keyboard::from_script(script).unwrap(),
+            Box::new(output.clone()),
+        );
+        let emulator = Emulator::new(&program, os).unwrap();
+        Ok(Program { emulator, output })
+    }
+
+    fn run(&mut self) -> Exit {
+        let exit = self.emulator.run().unwrap();
+        self.emulator.flush().unwrap();
+        exit
+    }
+}
+
+/// Copy the `.jack` files of a program to `dir`, where the compiler can
+/// write next to them, returning (original, copy) paths in name order.
+fn copy_sources(name: &str, dir: &TempDir) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut sources = vec![];
+    for entry in Path::new("test_programs").join(name).read_dir()? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "jack")
+        {
+            let copy = dir.path().join(path.file_name().unwrap());
+            fs::copy(&path, &copy)?;
+            sources.push((path, copy));
+        }
+    }
+    sources.sort();
+    Ok(sources)
+}
+
+#[test]
+fn test_snapshots() -> io::Result<()> {
+    for name in PROGRAMS {
+        let dir = TempDir::new()?;
+        for (source, copy) in copy_sources(name, &dir)? {
+            let compiled = test_file::compile(&copy, CompileOptions::default())?;
+            let stem = source.file_stem().unwrap().to_string_lossy();
+            let snapshot = |name: String| source.with_file_name(name);
+            test_file::assert_snapshot(
+                &snapshot(format!("{stem}.snap-token.xml")),
+                &compiled.tokens,
+            );
+            test_file::assert_snapshot(
+                &snapshot(format!("{stem}.snap-ast.xml")),
+                &comparer::strip_info(&compiled.ast),
+            );
+            test_file::assert_snapshot(&snapshot(format!("{stem}.snap-vm.vm")), &compiled.vm());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_seven() -> io::Result<()> {
+    for options in OPTIONS {
+        let mut program = Program::load("Seven", options, "")?;
+        assert_eq!(program.run(), Exit::Halt);
+        assert_eq!(program.output.text(), "7");
+        // the digit is drawn in the first character cell
+        let ram = program.emulator.ram();
+        assert!((0..11).any(|y| ram[screen::SCREEN + y * 32] & 0xff != 0));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_convert_to_bin() -> io::Result<()> {
+    for options in OPTIONS {
+        let mut program = Program::load("ConvertToBin", options, "")?;
+        program.emulator.ram_mut()[8000] = 0b1000_0000_0000_0101;
+        assert_eq!(program.run(), Exit::Halt);
+        assert_eq!(
+            program.emulator.ram()[8001..8017],
+            [1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_average() -> io::Result<()> {
+    for options in OPTIONS {
+        let mut program = Program::load("Average", options, r#"type "3\n10\n20\n-3\n""#)?;
+        assert_eq!(program.run(), Exit::Halt);
+        assert_eq!(
+            program.output.text(),
+            "How many numbers? Enter a number: Enter a number: Enter a number: \
+             The average is 9\n"
+        );
+        let average = program.emulator.static_address("Main", 0).unwrap();
+        assert_eq!(program.emulator.ram()[average as usize], 9);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_complex_arrays() -> io::Result<()> {
+    for options in OPTIONS {
+        let mut program = Program::load("ComplexArrays", options, "")?;
+        assert_eq!(program.run(), Exit::Halt);
+        let output = program.output.text();
+        let results = output
+            .lines()
+            .map(|line| line.rsplit(' ').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results, ["5", "40", "0", "77", "110"], "{output}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_expressions() -> io::Result<()> {
+    for options in OPTIONS {
+        let mut program = Program::load("Expressions", options, "")?;
+        assert_eq!(program.run(), Exit::Halt);
+        assert_eq!(
+            program.output.text(),
+            "5 2 2 20 9 -8\nYYYYYYYYY",
+            "{options:?}"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_square() -> io::Result<()> {
+    // right to the edge, down to the bottom, then quit; a `do` leaving its
+    // value on the stack would overflow it long before
+    let script = "frame 1 press right
+        frame 2 release
+        frame 400 press down
+        frame 401 release
+        frame 2000 press Q
+        frame 2001 release";
+    for options in OPTIONS {
+        let mut program = Program::load("Square", options, script)?;
+        assert_eq!(program.run(), Exit::Halt);
+
+        // the 30 pixel square ends at the bottom-right, from (480, 224)
+        let ram = program.emulator.ram();
+        for (x, y) in [(480, 224), (510, 224), (480, 254), (510, 254), (495, 239)] {
+            assert!(screen::pixel(ram, x, y), "({x}, {y}) is white");
+        }
+        for (x, y) in [(479, 224), (480, 223), (511, 254), (480, 255), (0, 0)] {
+            assert!(!screen::pixel(ram, x, y), "({x}, {y}) is black");
+        }
+    }
+    Ok(())
+}
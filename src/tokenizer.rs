@@ -16,6 +16,9 @@ pub enum TokenType {
 }
 
 impl TokenType {
+    // the tokenizer reads its tokens char by char, so only the tests classify
+    // whole tokens
+    #[allow(dead_code, clippy::self_named_constructors)]
     fn token_type(token: &str) -> Self {
         use TokenType::*;
         if TokenType::is_keyword(token) {
@@ -83,30 +86,28 @@ impl TokenType {
             || token == "~"
     }
 
+    #[allow(dead_code)]
     fn is_int_const(token: &str) -> bool {
-        if let Ok(_) = token.parse::<i32>() {
-            return true;
-        }
-        false
+        token.parse::<i32>().is_ok()
     }
 
+    #[allow(dead_code)]
     fn is_string_const(token: &str) -> bool {
         for (i, ch) in token.chars().enumerate() {
             if i == 0 || i == token.len() - 1 {
                 if ch != '"' {
                     return false;
                 }
-            } else {
-                if ch == '"' || ch == '\n' {
-                    return false;
-                }
+            } else if ch == '"' || ch == '\n' {
+                return false;
             }
         }
         true
     }
 
+    #[allow(dead_code)]
     fn is_identifier(token: &str) -> bool {
-        let first = token.chars().nth(0).unwrap();
+        let first = token.chars().next().unwrap();
         first.is_ascii_lowercase() || first.is_ascii_uppercase() || first == '_'
     }
 }
@@ -115,6 +116,9 @@ struct CharReader {
     next_char: Option<char>,
     reached_eof: bool,
     input_file_reader: BufReader<File>,
+    line: usize,
+    // column of `next_char`, 1-based
+    column: usize,
 }
 impl CharReader {
     fn new(file_path: &Path) -> io::Result<Self> {
@@ -124,6 +128,8 @@ impl CharReader {
             next_char: None,
             input_file_reader,
             reached_eof: false,
+            line: 1,
+            column: 0,
         };
 
         char_reader.read_next_char()?;
@@ -136,24 +142,55 @@ impl CharReader {
     }
 
     fn read_next_char(&mut self) -> io::Result<()> {
+        if self.next_char == Some('\n') {
+            self.line += 1;
+            self.column = 0;
+        }
         let mut buf = [0; 1];
         let read_count = self.input_file_reader.read(&mut buf)?;
         if read_count == 0 {
             self.reached_eof = true;
             self.next_char = None;
         } else {
-            self.next_char = Some(buf[0] as char)
+            self.next_char = Some(buf[0] as char);
+            self.column += 1;
         }
         Ok(())
     }
 }
 
+/// A `//`, `/* */` or `/** */` comment skipped by the tokenizer, kept with
+/// its full source text so tools can put it back.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Comment {
+    pub text: String,
+    pub line: usize,
+}
+
+impl Comment {
+    pub fn is_line_comment(&self) -> bool {
+        self.text.starts_with("//")
+    }
+
+    pub fn is_doc_comment(&self) -> bool {
+        self.text.starts_with("/**") && self.text != "/**/"
+    }
+
+    pub fn end_line(&self) -> usize {
+        self.line + self.text.matches('\n').count()
+    }
+}
+
 pub struct Tokenizer {
     next_token_type: Option<TokenType>,
     char_reader: CharReader,
     has_more_token: bool,
     symbol: char,
     identifier: String,
+    line: usize,
+    column: usize,
+    comments: Vec<Comment>,
+    errors: Vec<Diagnostic>,
 }
 impl Tokenizer {
     pub fn new(file_path: &Path) -> io::Result<Self> {
@@ -164,6 +201,10 @@ impl Tokenizer {
             has_more_token: true,
             symbol: ' ',
             identifier: "".to_string(),
+            line: 1,
+            column: 1,
+            comments: vec![],
+            errors: vec![],
         };
 
         tokenizer.advance()?;
@@ -177,6 +218,7 @@ impl Tokenizer {
 
     pub fn advance(&mut self) -> io::Result<()> {
         let mut ch;
+        self.comments.clear();
         loop {
             if !self.char_reader.has_more_char() {
                 self.has_more_token = false;
@@ -185,16 +227,20 @@ impl Tokenizer {
             }
 
             ch = self.char_reader.next_char.unwrap();
+            self.line = self.char_reader.line;
+            self.column = self.char_reader.column;
 
             // comments
             if ch == '/' {
-                ch = self._get_next_ch()?.unwrap();
+                let next = self._get_next_ch()?;
                 // `//` comment
-                if ch == '/' {
+                if next == Some('/') {
+                    let mut text = "//".to_string();
                     loop {
                         self.char_reader.read_next_char()?;
                         if !self.char_reader.has_more_char() {
                             // program end with `// ...(no newline)`
+                            self._push_comment(text);
                             self.has_more_token = false;
                             self.next_token_type = None;
                             return Ok(());
@@ -204,23 +250,28 @@ impl Tokenizer {
                             self.char_reader.read_next_char()?;
                             break;
                         }
+                        text.push(ch);
                     }
+                    self._push_comment(text);
                 }
                 // `/* */` comment
-                else if ch == '*' {
-                    ch = self._get_next_ch()?.unwrap();
+                else if next == Some('*') {
+                    let mut text = "/*".to_string();
+                    let mut next = self._get_next_ch()?;
                     loop {
-                        if ch == '*' {
-                            ch = self._get_next_ch()?.unwrap();
-
-                            if ch == '/' {
-                                self.char_reader.read_next_char()?;
-                                break;
-                            }
-                        } else {
-                            ch = self._get_next_ch()?.unwrap();
+                        let Some(ch) = next else {
+                            self._error("comment doesn't end");
+                            break;
+                        };
+                        text.push(ch);
+                        next = self._get_next_ch()?;
+                        if ch == '*' && next == Some('/') {
+                            text.push('/');
+                            self.char_reader.read_next_char()?;
+                            break;
                         }
                     }
+                    self._push_comment(text);
                 }
                 // symbol `/`
                 else {
@@ -253,58 +304,92 @@ impl Tokenizer {
             // identifier
             else if ch.is_ascii_lowercase() || ch.is_ascii_uppercase() || ch == '_' {
                 self.identifier = ch.to_string();
-                loop {
-                    ch = self._get_next_ch()?.unwrap();
+                while let Some(ch) = self._get_next_ch()? {
                     if !Tokenizer::_is_identifier_component(ch) {
-                        // is keyword?
-                        if TokenType::is_keyword(&self.identifier) {
-                            self.next_token_type = Some(TokenType::Keyword);
-                        } else {
-                            self.next_token_type = Some(TokenType::Identifier);
-                        }
-                        return Ok(());
+                        break;
                     }
-                    self.identifier += &ch.to_string();
+                    self.identifier.push(ch);
+                }
+                // is keyword?
+                if TokenType::is_keyword(&self.identifier) {
+                    self.next_token_type = Some(TokenType::Keyword);
+                } else {
+                    self.next_token_type = Some(TokenType::Identifier);
                 }
+                return Ok(());
             }
             // int const
             else if ch.is_ascii_digit() {
                 self.identifier = ch.to_string();
-                loop {
-                    ch = self._get_next_ch()?.unwrap();
+                while let Some(ch) = self._get_next_ch()? {
                     if !ch.is_ascii_digit() {
-                        self.next_token_type = Some(TokenType::IntConst);
-                        return Ok(());
+                        break;
                     }
-                    self.identifier += &ch.to_string();
+                    self.identifier.push(ch);
+                }
+                if !matches!(self.identifier.parse::<u16>(), Ok(0..=32767)) {
+                    self._error(&format!(
+                        "integer constant `{}` is out of range 0..32767",
+                        self.identifier
+                    ));
+                    self.identifier = "32767".to_string();
                 }
+                self.next_token_type = Some(TokenType::IntConst);
+                return Ok(());
             }
             // string const
             else if ch == '"' {
                 self.identifier = "".to_string();
+                self.next_token_type = Some(TokenType::StringConst);
                 loop {
-                    ch = self._get_next_ch()?.unwrap();
-                    if ch == '\n' {
-                        report_syntax_error("string shouldn't contains newline");
-                    } else if ch == '"' {
-                        self.next_token_type = Some(TokenType::StringConst);
-                        self.char_reader.read_next_char()?; // comsume close `"`
-                        return Ok(());
+                    match self._get_next_ch()? {
+                        // the string is taken to end with its line
+                        Some('\n') | None => {
+                            self._error("string doesn't end on its line");
+                            return Ok(());
+                        }
+                        Some('"') => {
+                            self.char_reader.read_next_char()?; // comsume close `"`
+                            return Ok(());
+                        }
+                        Some(ch) => self.identifier.push(ch),
                     }
-                    self.identifier += &ch.to_string();
                 }
             }
             // syntax error
             else {
-                report_syntax_error("unknow char");
+                self._error(&format!("unexpected character `{ch}`"));
+                self.char_reader.read_next_char()?;
             }
         }
     }
 
+    /// Lexical errors found so far. The character or comment at fault is
+    /// skipped, and a string missing its closing `"` ends with its line.
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.errors
+    }
+
     pub fn token_type(&self) -> Option<TokenType> {
         self.next_token_type
     }
 
+    /// Source line (1-based) of the current token.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Source column (1-based) of the current token.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Comments between the previous token and the current one (or the end
+    /// of file once `has_more_tokens` is false).
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
     pub fn symbol(&self) -> char {
         assert_eq!(self.token_type(), Some(TokenType::Symbol));
         self.symbol
@@ -330,20 +415,103 @@ impl Tokenizer {
         self.identifier.clone()
     }
 
+    /// The current token as an error message names it, like `` `let` `` or
+    /// `end of file`.
+    pub fn describe_token(&self) -> String {
+        match self.token_type() {
+            Some(TokenType::Keyword) => format!("`{}`", self.keyword()),
+            Some(TokenType::Symbol) => format!("`{}`", self.symbol()),
+            Some(TokenType::Identifier) => format!("`{}`", self.identifier()),
+            Some(TokenType::IntConst) => format!("`{}`", self.int_const()),
+            Some(TokenType::StringConst) => format!("`\"{}\"`", self.string_const()),
+            None => "end of file".to_string(),
+        }
+    }
+
+    /// The character after the current one, `None` at the end of the file.
     fn _get_next_ch(&mut self) -> io::Result<Option<char>> {
-        if !self.char_reader.has_more_char() {
-            report_syntax_error("bad ending");
+        if self.char_reader.has_more_char() {
+            self.char_reader.read_next_char()?;
         }
-        self.char_reader.read_next_char()?;
         Ok(self.char_reader.next_char)
     }
 
+    fn _error(&mut self, message: &str) {
+        self.errors.push(Diagnostic {
+            line: self.line,
+            message: message.to_string(),
+        });
+    }
+
+    fn _push_comment(&mut self, text: String) {
+        self.comments.push(Comment {
+            text: text.trim_end().to_string(),
+            line: self.line,
+        });
+    }
+
     fn _is_identifier_component(ch: char) -> bool {
         ch.is_ascii_uppercase() || ch.is_ascii_lowercase() || ch == '_' || ch.is_ascii_digit()
     }
 }
 
+/// The tokens of a `.jack` file as XML, one `<keyword>class</keyword>` like
+/// line a token, the way the `.my-token.xml` files are written.
+pub fn token_xml(file_path: &Path) -> io::Result<String> {
+    let mut buf = String::new();
+    buf += "<tokens>\n";
 
+    let mut tokenizer = Tokenizer::new(file_path)?;
+    while let Some(token_type) = tokenizer.token_type() {
+        use TokenType::*;
+        match token_type {
+            Keyword => {
+                buf += &format!("<{}>", XML_TAG_KEYWORD);
+                let keyword = tokenizer.keyword();
+                buf += &keyword;
+                buf += &format!("</{}>\n", XML_TAG_KEYWORD);
+            }
+            Symbol => {
+                buf += &format!("<{}>", XML_TAG_SYMBOL);
+                let symbol = tokenizer.symbol();
+                let mut symbol_string = symbol.to_string();
+                if symbol == '<' {
+                    symbol_string = "&lt;".to_string();
+                } else if symbol == '>' {
+                    symbol_string = "&gt;".to_string();
+                } else if symbol == '&' {
+                    symbol_string = "&amp;".to_string();
+                }
+                buf += &symbol_string;
+
+                buf += &format!("</{}>\n", XML_TAG_SYMBOL);
+            }
+            IntConst => {
+                buf += &format!("<{}>", XML_TAG_INT_CONST);
+                let int_const = tokenizer.int_const();
+                buf += &int_const.to_string();
+                buf += &format!("</{}>\n", XML_TAG_INT_CONST);
+            }
+            StringConst => {
+                buf += &format!("<{}>", XML_TAG_STRING_CONST);
+                let string_const = tokenizer.string_const();
+                buf += &string_const;
+                buf += &format!("</{}>\n", XML_TAG_STRING_CONST);
+            }
+            Identifier => {
+                buf += &format!("<{}>", XML_TAG_IDENTIFIER);
+                let identifier = tokenizer.identifier();
+                buf += &identifier;
+                buf += &format!("</{}>\n", XML_TAG_IDENTIFIER);
+            }
+        }
+        tokenizer.advance()?;
+    }
+
+    buf += r#"</tokens>"#;
+
+    Ok(buf)
+}
 
 #[cfg(test)]
 mod tests {
@@ -490,6 +658,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_lexical_errors() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line(r#"let s = "abc;"#)?;
+        test_file.add_line("x # 40000")?;
+        test_file.add_line("/* never closed")?;
+
+        let mut tokenizer = Tokenizer::new(Path::new(&test_file.path))?;
+        let mut tokens = vec![];
+        while tokenizer.has_more_tokens() {
+            tokens.push(match tokenizer.token_type() {
+                Some(TokenType::StringConst) => format!("\"{}\"", tokenizer.string_const()),
+                Some(TokenType::IntConst) => tokenizer.int_const().to_string(),
+                Some(TokenType::Symbol) => tokenizer.symbol().to_string(),
+                _ => tokenizer.identifier.clone(),
+            });
+            tokenizer.advance()?;
+        }
+        assert_eq!(tokens, ["let", "s", "=", "\"abc;\"", "x", "32767"]);
+
+        let errors: Vec<String> = tokenizer.errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            [
+                "1: string doesn't end on its line",
+                "2: unexpected character `#`",
+                "2: integer constant `40000` is out of range 0..32767",
+                "3: comment doesn't end",
+            ]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_has_more_token_empty() -> io::Result<()> {
         let mut test_file = TestFile::new()?;
@@ -587,6 +790,60 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_comments() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        test_file.clear()?;
+        test_file.add_line("/** doc")?;
+        test_file.add_line("  */ class // trailing")?;
+        test_file.add_line("/* block */")?;
+
+        let mut tokenizer = Tokenizer::new(Path::new(&test_file.path))?;
+        assert_eq!(tokenizer.keyword(), "class");
+        assert_eq!(tokenizer.line(), 2);
+        assert_eq!(tokenizer.column(), 6);
+        assert_eq!(tokenizer.comments().len(), 1);
+        assert_eq!(tokenizer.comments()[0].text, "/** doc\n  */");
+        assert_eq!(tokenizer.comments()[0].line, 1);
+        assert_eq!(tokenizer.comments()[0].end_line(), 2);
+        assert!(tokenizer.comments()[0].is_doc_comment());
+
+        tokenizer.advance()?;
+        assert!(!tokenizer.has_more_tokens());
+        assert_eq!(
+            tokenizer.comments(),
+            &[
+                Comment {
+                    text: "// trailing".to_string(),
+                    line: 2
+                },
+                Comment {
+                    text: "/* block */".to_string(),
+                    line: 3
+                },
+            ]
+        );
+        assert!(tokenizer.comments()[0].is_line_comment());
+        assert!(!tokenizer.comments()[1].is_line_comment());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_xml_without_tokens() -> io::Result<()> {
+        let mut test_file = TestFile::new()?;
+        for source in ["", "// only a comment", "#", "/** unterminated"] {
+            test_file.clear()?;
+            test_file.add_line(source)?;
+            assert_eq!(
+                token_xml(Path::new(&test_file.path))?,
+                "<tokens>\n</tokens>",
+                "{source}"
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_read_next_char_empty() -> io::Result<()> {
         let mut test_file = TestFile::new()?;
@@ -609,9 +866,11 @@ mod tests {
         let mut char_reader = CharReader::new(Path::new(&test_file.path))?;
 
         assert_eq!(char_reader.next_char, Some('h'));
+        assert_eq!(char_reader.column, 1);
 
         char_reader.read_next_char()?;
         assert_eq!(char_reader.next_char, Some('e'));
+        assert_eq!(char_reader.column, 2);
 
         char_reader.read_next_char()?;
         assert_eq!(char_reader.next_char, Some('l'));
@@ -0,0 +1,473 @@
+use crate::vm::{ArithmeticOp, Segment, VmInstruction};
+
+/// Translates linked VM code to Hack assembly, with the standard bootstrap
+/// and calling convention: SP starts at 256 and `Sys.init` is called.
+///
+/// Labels are made unique by prefixing them with their function name,
+/// `Function$label`, and statics with their class name, `Class.index`.
+pub struct Translator {
+    buf: String,
+    function_name: String,
+    class_name: String,
+    // numbers return addresses and comparison results
+    label_id: usize,
+}
+
+impl Translator {
+    pub fn translate(functions: &[Vec<VmInstruction>]) -> String {
+        let mut translator = Translator {
+            buf: String::new(),
+            function_name: "Bootstrap".to_string(),
+            class_name: "Bootstrap".to_string(),
+            label_id: 0,
+        };
+
+        translator.comment("bootstrap");
+        translator.lines(&["@256", "D=A", "@SP", "M=D"]);
+        translator.write(&VmInstruction::Call("Sys.init".to_string(), 0));
+
+        for instruction in functions.iter().flatten() {
+            translator.write(instruction);
+        }
+        translator.buf
+    }
+
+    fn write(&mut self, instruction: &VmInstruction) {
+        self.comment(&instruction.to_string());
+        match instruction {
+            VmInstruction::Push(segment, index) => self.write_push(*segment, *index),
+            VmInstruction::Pop(segment, index) => self.write_pop(*segment, *index),
+            VmInstruction::Arithmetic(op) => self.write_arithmetic(*op),
+            VmInstruction::Label(label) => {
+                let label = self.label(label);
+                self.line(&format!("({label})"));
+            }
+            VmInstruction::Goto(label) => {
+                let label = self.label(label);
+                self.lines(&[&format!("@{label}"), "0;JMP"]);
+            }
+            VmInstruction::IfGoto(label) => {
+                let label = self.label(label);
+                self.lines(&["@SP", "AM=M-1", "D=M", &format!("@{label}"), "D;JNE"]);
+            }
+            VmInstruction::Call(name, n_args) => self.write_call(name, *n_args),
+            VmInstruction::Function(name, n_vars) => {
+                self.function_name = name.clone();
+                self.class_name = name.split('.').next().unwrap().to_string();
+                self.line(&format!("({name})"));
+                for _ in 0..*n_vars {
+                    self.lines(&["@SP", "A=M", "M=0", "@SP", "M=M+1"]);
+                }
+            }
+            VmInstruction::Return => self.write_return(),
+        }
+    }
+
+    fn write_push(&mut self, segment: Segment, index: u16) {
+        match segment {
+            Segment::Constant => self.lines(&[&format!("@{index}"), "D=A"]),
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                let base = Translator::base(segment);
+                self.lines(&[&format!("@{index}"), "D=A", base, "A=D+M", "D=M"]);
+            }
+            _ => {
+                let address = self.address(segment, index);
+                self.lines(&[&address, "D=M"]);
+            }
+        }
+        self.push_d();
+    }
+
+    fn write_pop(&mut self, segment: Segment, index: u16) {
+        match segment {
+            Segment::Constant => panic!("can't pop to constant"),
+            Segment::Local | Segment::Argument | Segment::This | Segment::That => {
+                let base = Translator::base(segment);
+                self.lines(&[&format!("@{index}"), "D=A", base, "D=D+M", "@R13", "M=D"]);
+                self.pop_d();
+                self.lines(&["@R13", "A=M", "M=D"]);
+            }
+            _ => {
+                let address = self.address(segment, index);
+                self.pop_d();
+                self.lines(&[&address, "M=D"]);
+            }
+        }
+    }
+
+    fn write_arithmetic(&mut self, op: ArithmeticOp) {
+        match op {
+            ArithmeticOp::Neg => self.lines(&["@SP", "A=M-1", "M=-M"]),
+            ArithmeticOp::Not => self.lines(&["@SP", "A=M-1", "M=!M"]),
+            ArithmeticOp::Add => self.binary("M=D+M"),
+            ArithmeticOp::Sub => self.binary("M=M-D"),
+            ArithmeticOp::And => self.binary("M=D&M"),
+            ArithmeticOp::Or => self.binary("M=D|M"),
+            ArithmeticOp::Eq => self.compare("JEQ"),
+            ArithmeticOp::Gt => self.compare("JGT"),
+            ArithmeticOp::Lt => self.compare("JLT"),
+        }
+    }
+
+    /// x op y, with y in D and x in M
+    fn binary(&mut self, comp: &str) {
+        self.pop_d();
+        self.lines(&["A=A-1", comp]);
+    }
+
+    /// -1 when x - y jumps, 0 otherwise
+    fn compare(&mut self, jump: &str) {
+        self.label_id += 1;
+        let label = format!("{}$compare.{}", self.function_name, self.label_id);
+        self.pop_d();
+        if jump == "JEQ" {
+            self.lines(&["A=A-1", "D=M-D", "M=-1"]);
+        } else {
+            self.signed_difference(&label);
+            self.lines(&["@SP", "A=M-1", "M=-1"]);
+        }
+        self.lines(&[&format!("@{label}"), &format!("D;{jump}")]);
+        self.lines(&["@SP", "A=M-1", "M=0", &format!("({label})")]);
+    }
+
+    /// D = a number with the sign of x - y, with y in D and x on the stack.
+    /// x - y overflows when x and y are far apart, which they can only be
+    /// with different signs, so it is only computed when their signs match.
+    fn signed_difference(&mut self, label: &str) {
+        let (x_negative, subtract, done) = (
+            format!("{label}.x_negative"),
+            format!("{label}.subtract"),
+            format!("{label}.done"),
+        );
+        self.lines(&["@R13", "M=D", "@SP", "A=M-1", "D=M"]);
+        self.lines(&[&format!("@{x_negative}"), "D;JLT"]);
+        // x >= 0, so x > y when y < 0
+        self.lines(&["@R13", "D=M", &format!("@{subtract}"), "D;JGE"]);
+        self.lines(&["D=1", &format!("@{done}"), "0;JMP"]);
+        // x < 0, so x < y when y >= 0
+        self.lines(&[&format!("({x_negative})"), "@R13", "D=M"]);
+        self.lines(&[&format!("@{subtract}"), "D;JLT"]);
+        self.lines(&["D=-1", &format!("@{done}"), "0;JMP"]);
+        self.lines(&[
+            &format!("({subtract})"),
+            "@SP",
+            "A=M-1",
+            "D=M",
+            "@R13",
+            "D=D-M",
+        ]);
+        self.line(&format!("({done})"));
+    }
+
+    fn write_call(&mut self, name: &str, n_args: u16) {
+        self.label_id += 1;
+        let return_label = format!("{}$ret.{}", self.function_name, self.label_id);
+
+        self.lines(&[&format!("@{return_label}"), "D=A"]);
+        self.push_d();
+        for pointer in ["@LCL", "@ARG", "@THIS", "@THAT"] {
+            self.lines(&[pointer, "D=M"]);
+            self.push_d();
+        }
+        // ARG = SP - n_args - 5, LCL = SP
+        self.lines(&[
+            "@SP",
+            "D=M",
+            &format!("@{}", n_args + 5),
+            "D=D-A",
+            "@ARG",
+            "M=D",
+        ]);
+        self.lines(&["@SP", "D=M", "@LCL", "M=D"]);
+        self.lines(&[&format!("@{name}"), "0;JMP", &format!("({return_label})")]);
+    }
+
+    fn write_return(&mut self) {
+        // R13 = frame, R14 = return address
+        self.lines(&[
+            "@LCL", "D=M", "@R13", "M=D", "@5", "A=D-A", "D=M", "@R14", "M=D",
+        ]);
+        // *ARG = return value, SP = ARG + 1
+        self.pop_d();
+        self.lines(&["@ARG", "A=M", "M=D", "@ARG", "D=M+1", "@SP", "M=D"]);
+        for pointer in ["@THAT", "@THIS", "@ARG", "@LCL"] {
+            self.lines(&["@R13", "AM=M-1", "D=M", pointer, "M=D"]);
+        }
+        self.lines(&["@R14", "A=M", "0;JMP"]);
+    }
+
+    fn base(segment: Segment) -> &'static str {
+        match segment {
+            Segment::Local => "@LCL",
+            Segment::Argument => "@ARG",
+            Segment::This => "@THIS",
+            Segment::That => "@THAT",
+            _ => unreachable!("`{segment}` has no base address"),
+        }
+    }
+
+    /// A-instruction for a `pointer`, `temp` or `static` entry.
+    fn address(&self, segment: Segment, index: u16) -> String {
+        match segment {
+            Segment::Pointer => format!("@{}", 3 + index),
+            Segment::Temp => format!("@{}", 5 + index),
+            Segment::Static => format!("@{}.{}", self.class_name, index),
+            _ => unreachable!("`{segment}` is addressed through a base"),
+        }
+    }
+
+    fn label(&self, label: &str) -> String {
+        format!("{}${}", self.function_name, label)
+    }
+
+    fn push_d(&mut self) {
+        self.lines(&["@SP", "A=M", "M=D", "@SP", "M=M+1"]);
+    }
+
+    /// leaves A pointing at the popped entry
+    fn pop_d(&mut self) {
+        self.lines(&["@SP", "AM=M-1", "D=M"]);
+    }
+
+    fn comment(&mut self, text: &str) {
+        self.line(&format!("// {text}"));
+    }
+
+    fn lines(&mut self, lines: &[&str]) {
+        for line in lines {
+            self.line(line);
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.buf += line;
+        self.buf += "\n";
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::vm::{ArithmeticOp::*, Segment::*, VmInstruction::*};
+
+    /// Runs Hack assembly until it reaches `stop_label`, returning the RAM.
+    fn run(asm: &str, stop_label: &str) -> Vec<u16> {
+        let mut symbols = HashMap::from([
+            ("SP".to_string(), 0),
+            ("LCL".to_string(), 1),
+            ("ARG".to_string(), 2),
+            ("THIS".to_string(), 3),
+            ("THAT".to_string(), 4),
+        ]);
+        for i in 0..16 {
+            symbols.insert(format!("R{i}"), i);
+        }
+        let mut program = vec![];
+        for line in asm.lines().map(str::trim) {
+            if let Some(label) = line.strip_prefix('(') {
+                symbols.insert(
+                    label.trim_end_matches(')').to_string(),
+                    program.len() as u16,
+                );
+            } else if !line.is_empty() && !line.starts_with("//") {
+                program.push(line);
+            }
+        }
+
+        let mut ram = vec![0u16; 32768];
+        let (mut a, mut d, mut pc) = (0u16, 0u16, 0usize);
+        let mut next_variable = 16;
+        let stop = symbols[stop_label] as usize;
+        for _ in 0..1_000_000 {
+            if pc == stop {
+                return ram;
+            }
+            let instruction = program[pc];
+            pc += 1;
+
+            if let Some(value) = instruction.strip_prefix('@') {
+                a = value.parse().unwrap_or_else(|_| {
+                    *symbols.entry(value.to_string()).or_insert_with(|| {
+                        next_variable += 1;
+                        next_variable - 1
+                    })
+                });
+                continue;
+            }
+
+            let (dest, rest) = instruction.split_once('=').unwrap_or(("", instruction));
+            let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+            let m = ram[a as usize];
+            let value = match comp {
+                "0" => 0,
+                "1" => 1,
+                "-1" => u16::MAX,
+                "D" => d,
+                "A" => a,
+                "M" => m,
+                "!M" => !m,
+                "-M" => m.wrapping_neg(),
+                "D+1" => d.wrapping_add(1),
+                "M+1" => m.wrapping_add(1),
+                "M-1" => m.wrapping_sub(1),
+                "A-1" => a.wrapping_sub(1),
+                "D+A" => d.wrapping_add(a),
+                "D+M" => d.wrapping_add(m),
+                "D-A" => d.wrapping_sub(a),
+                "D-M" => d.wrapping_sub(m),
+                "M-D" => m.wrapping_sub(d),
+                "D&M" => d & m,
+                "D|M" => d | m,
+                _ => panic!("unknown comp `{comp}`"),
+            };
+            let address = a as usize;
+            if dest.contains('A') {
+                a = value;
+            }
+            if dest.contains('D') {
+                d = value;
+            }
+            if dest.contains('M') {
+                ram[address] = value;
+            }
+            let value = value as i16;
+            let taken = match jump {
+                "" => false,
+                "JMP" => true,
+                "JEQ" => value == 0,
+                "JNE" => value != 0,
+                "JGT" => value > 0,
+                "JGE" => value >= 0,
+                "JLT" => value < 0,
+                _ => panic!("unknown jump `{jump}`"),
+            };
+            if taken {
+                pc = a as usize;
+            }
+        }
+        panic!("`{stop_label}` not reached");
+    }
+
+    fn function(name: &str, n_vars: u16, body: &[VmInstruction]) -> Vec<VmInstruction> {
+        [&[Function(name.to_string(), n_vars)], body].concat()
+    }
+
+    #[test]
+    fn test_translate() {
+        let sys_init = function(
+            "Sys.init",
+            0,
+            &[
+                Call("Main.main".to_string(), 0),
+                Pop(Temp, 0),
+                Label("halt".to_string()),
+                Goto("halt".to_string()),
+            ],
+        );
+        // static 0 = mul(6, 7); static 1..3 = 6 < 7, 6 > 7, 6 = 6
+        let main = function(
+            "Main.main",
+            0,
+            &[
+                Push(Constant, 6),
+                Push(Constant, 7),
+                Call("Main.mul".to_string(), 2),
+                Pop(Static, 0),
+                Push(Constant, 6),
+                Push(Constant, 7),
+                Arithmetic(Lt),
+                Pop(Static, 1),
+                Push(Constant, 6),
+                Push(Constant, 7),
+                Arithmetic(Gt),
+                Pop(Static, 2),
+                Push(Constant, 6),
+                Push(Constant, 6),
+                Arithmetic(Eq),
+                Pop(Static, 3),
+                // `that` and `pointer`
+                Push(Constant, 3000),
+                Pop(Pointer, 1),
+                Push(Constant, 5),
+                Arithmetic(Neg),
+                Arithmetic(Not),
+                Pop(That, 2),
+                Push(Constant, 0),
+                Return,
+            ],
+        );
+        // sum = 0; while (~(b = 0)) { sum = sum + a; b = b - 1 } return sum
+        let mul = function(
+            "Main.mul",
+            1,
+            &[
+                Label("loop".to_string()),
+                Push(Argument, 1),
+                Push(Constant, 0),
+                Arithmetic(Eq),
+                IfGoto("end".to_string()),
+                Push(Local, 0),
+                Push(Argument, 0),
+                Arithmetic(Add),
+                Pop(Local, 0),
+                Push(Argument, 1),
+                Push(Constant, 1),
+                Arithmetic(Sub),
+                Pop(Argument, 1),
+                Goto("loop".to_string()),
+                Label("end".to_string()),
+                Push(Local, 0),
+                Return,
+            ],
+        );
+
+        let asm = Translator::translate(&[sys_init, main, mul]);
+        let ram = run(&asm, "Sys.init$halt");
+        assert!(crate::assembler::assemble(&asm).is_ok());
+
+        // statics are allocated from 16 in order of first use
+        assert_eq!(ram[16..20], [42, u16::MAX, 0, u16::MAX]);
+        assert_eq!(ram[3002], 4);
+        // the stack is back to `Sys.init`'s frame
+        assert_eq!(ram[0], 256 + 5);
+    }
+
+    #[test]
+    fn test_compare_far_apart() {
+        // x - y overflows for each pair, which can't change the result
+        let pairs: [(i16, i16); 6] = [
+            (-30000, 30000),
+            (30000, -30000),
+            (-32767, 1),
+            (32767, -2),
+            (0, -32767),
+            (-1, 0),
+        ];
+        let mut body = vec![];
+        for (i, &(x, y)) in pairs.iter().enumerate() {
+            for op in [Lt, Gt] {
+                for value in [x, y] {
+                    body.push(Push(Constant, value.unsigned_abs()));
+                    if value < 0 {
+                        body.push(Arithmetic(Neg));
+                    }
+                }
+                body.push(Arithmetic(op));
+                let index = 2 * i as u16 + if op == Lt { 0 } else { 1 };
+                body.push(Pop(Static, index));
+            }
+        }
+        body.extend([Label("halt".to_string()), Goto("halt".to_string())]);
+
+        let asm = Translator::translate(&[function("Sys.init", 0, &body)]);
+        let ram = run(&asm, "Sys.init$halt");
+        assert!(crate::assembler::assemble(&asm).is_ok());
+        let boolean = |b: bool| if b { u16::MAX } else { 0 };
+        for (i, &(x, y)) in pairs.iter().enumerate() {
+            assert_eq!(ram[16 + 2 * i], boolean(x < y), "{x} < {y}");
+            assert_eq!(ram[16 + 2 * i + 1], boolean(x > y), "{x} > {y}");
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use std::{collections::HashSet, fmt};
+
+use crate::symbol_table::Kind;
+
+/// A memory segment of the VM.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum Segment {
+    Constant,
+    Argument,
+    Local,
+    Static,
+    This,
+    That,
+    Pointer,
+    Temp,
+}
+
+impl From<Kind> for Segment {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Static => Segment::Static,
+            Kind::Field => Segment::This,
+            Kind::Arg => Segment::Argument,
+            Kind::Var => Segment::Local,
+        }
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Segment::Constant => "constant",
+            Segment::Argument => "argument",
+            Segment::Local => "local",
+            Segment::Static => "static",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// An arithmetic or logical command, working on the top of the stack.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+}
+
+impl fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ArithmeticOp::Add => "add",
+            ArithmeticOp::Sub => "sub",
+            ArithmeticOp::Neg => "neg",
+            ArithmeticOp::Eq => "eq",
+            ArithmeticOp::Gt => "gt",
+            ArithmeticOp::Lt => "lt",
+            ArithmeticOp::And => "and",
+            ArithmeticOp::Or => "or",
+            ArithmeticOp::Not => "not",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub enum VmInstruction {
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Arithmetic(ArithmeticOp),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    /// function name, number of arguments
+    Call(String, u16),
+    /// function name, number of local variables
+    Function(String, u16),
+    Return,
+}
+
+impl fmt::Display for VmInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmInstruction::Push(segment, index) => write!(f, "push {segment} {index}"),
+            VmInstruction::Pop(segment, index) => write!(f, "pop {segment} {index}"),
+            VmInstruction::Arithmetic(op) => write!(f, "{op}"),
+            VmInstruction::Label(label) => write!(f, "label {label}"),
+            VmInstruction::Goto(label) => write!(f, "goto {label}"),
+            VmInstruction::IfGoto(label) => write!(f, "if-goto {label}"),
+            VmInstruction::Call(name, n_args) => write!(f, "call {name} {n_args}"),
+            VmInstruction::Function(name, n_vars) => write!(f, "function {name} {n_vars}"),
+            VmInstruction::Return => write!(f, "return"),
+        }
+    }
+}
+
+/// Name of a function, from the `function` instruction it starts with.
+pub fn function_name(function: &[VmInstruction]) -> &str {
+    match function.first() {
+        Some(VmInstruction::Function(name, _)) => name,
+        _ => panic!("a function starts with a `function` instruction"),
+    }
+}
+
+/// VM code of functions, one instruction per line.
+pub fn to_vm_code(functions: &[Vec<VmInstruction>]) -> String {
+    let mut buf = String::new();
+    for instruction in functions.iter().flatten() {
+        buf += &format!("{instruction}\n");
+    }
+    buf
+}
+
+/// Parse VM code, one instruction a line with `//` comments, into its
+/// functions, checking it the way the VM emulator would load it: a segment
+/// index must be in range, a label must be defined once in its function,
+/// and a `goto` or `if-goto` must jump to a label of its own function.
+/// Every problem is returned as `line: message`.
+pub fn parse(code: &str) -> Result<Vec<Vec<VmInstruction>>, Vec<String>> {
+    let mut functions: Vec<Vec<VmInstruction>> = vec![];
+    let mut names = HashSet::new();
+    let mut errors = vec![];
+    // labels of the current function, and (line, label) of its jumps
+    let mut labels = HashSet::new();
+    let mut jumps = vec![];
+
+    for (i, line) in code.lines().enumerate() {
+        let line_number = i + 1;
+        let words = line
+            .split("//")
+            .next()
+            .unwrap()
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            continue;
+        }
+
+        let instruction = match parse_instruction(&words) {
+            Ok(instruction) => instruction,
+            Err(error) => {
+                errors.push((line_number, error));
+                continue;
+            }
+        };
+        match &instruction {
+            VmInstruction::Function(name, _) => {
+                check_jumps(&labels, &mut jumps, &mut errors);
+                labels.clear();
+                if !names.insert(name.clone()) {
+                    errors.push((line_number, format!("function `{name}` is already defined")));
+                }
+                functions.push(vec![]);
+            }
+            _ if functions.is_empty() => {
+                errors.push((
+                    line_number,
+                    format!("`{}` is outside of a function", words[0]),
+                ));
+                continue;
+            }
+            VmInstruction::Label(label) => {
+                let defined = !labels.insert(label.clone());
+                if defined {
+                    errors.push((line_number, format!("label `{label}` is already defined")));
+                }
+            }
+            VmInstruction::Goto(label) | VmInstruction::IfGoto(label) => {
+                jumps.push((line_number, label.clone()));
+            }
+            _ => {}
+        }
+        functions.last_mut().unwrap().push(instruction);
+    }
+    check_jumps(&labels, &mut jumps, &mut errors);
+
+    if errors.is_empty() {
+        Ok(functions)
+    } else {
+        errors.sort_by_key(|(line_number, _)| *line_number);
+        Err(errors
+            .into_iter()
+            .map(|(line_number, error)| format!("{line_number}: {error}"))
+            .collect())
+    }
+}
+
+/// The jumps of a function to labels it doesn't define are errors.
+fn check_jumps(
+    labels: &HashSet<String>,
+    jumps: &mut Vec<(usize, String)>,
+    errors: &mut Vec<(usize, String)>,
+) {
+    for (line_number, label) in jumps.drain(..) {
+        if !labels.contains(&label) {
+            errors.push((line_number, format!("label `{label}` is not defined")));
+        }
+    }
+}
+
+fn parse_instruction(words: &[&str]) -> Result<VmInstruction, String> {
+    let command = words[0];
+    let instruction = match (command, &words[1..]) {
+        ("push" | "pop", [segment, index]) => {
+            let segment = parse_segment(segment)?;
+            let index = parse_number(index)?;
+            let out_of_range = match segment {
+                Segment::Pointer => index > 1,
+                Segment::Temp => index > 7,
+                _ => false,
+            };
+            if out_of_range {
+                return Err(format!("`{segment} {index}` is out of range"));
+            }
+            if command == "push" {
+                VmInstruction::Push(segment, index)
+            } else if segment == Segment::Constant {
+                return Err("can't pop to `constant`".to_string());
+            } else {
+                VmInstruction::Pop(segment, index)
+            }
+        }
+        ("label" | "goto" | "if-goto", [label]) => {
+            if !is_symbol(label) {
+                return Err(format!("bad label `{label}`"));
+            }
+            let label = label.to_string();
+            match command {
+                "label" => VmInstruction::Label(label),
+                "goto" => VmInstruction::Goto(label),
+                _ => VmInstruction::IfGoto(label),
+            }
+        }
+        ("function" | "call", [name, n]) => {
+            if !is_symbol(name) {
+                return Err(format!("bad function name `{name}`"));
+            }
+            let n = parse_number(n)?;
+            match command {
+                "function" => VmInstruction::Function(name.to_string(), n),
+                _ => VmInstruction::Call(name.to_string(), n),
+            }
+        }
+        ("return", []) => VmInstruction::Return,
+        (op, []) => match parse_op(op) {
+            Some(op) => VmInstruction::Arithmetic(op),
+            None => return Err(format!("unknown command `{op}`")),
+        },
+        _ => {
+            let expected = match command {
+                "push" | "pop" => "a segment and an index",
+                "label" | "goto" | "if-goto" => "a label",
+                "function" => "a name and a number of local variables",
+                "call" => "a name and a number of arguments",
+                "return" => "nothing",
+                _ if parse_op(command).is_some() => "nothing",
+                _ => return Err(format!("unknown command `{command}`")),
+            };
+            return Err(format!("`{command}` takes {expected}"));
+        }
+    };
+    Ok(instruction)
+}
+
+fn parse_segment(segment: &str) -> Result<Segment, String> {
+    Ok(match segment {
+        "constant" => Segment::Constant,
+        "argument" => Segment::Argument,
+        "local" => Segment::Local,
+        "static" => Segment::Static,
+        "this" => Segment::This,
+        "that" => Segment::That,
+        "pointer" => Segment::Pointer,
+        "temp" => Segment::Temp,
+        _ => return Err(format!("unknown segment `{segment}`")),
+    })
+}
+
+fn parse_op(op: &str) -> Option<ArithmeticOp> {
+    Some(match op {
+        "add" => ArithmeticOp::Add,
+        "sub" => ArithmeticOp::Sub,
+        "neg" => ArithmeticOp::Neg,
+        "eq" => ArithmeticOp::Eq,
+        "gt" => ArithmeticOp::Gt,
+        "lt" => ArithmeticOp::Lt,
+        "and" => ArithmeticOp::And,
+        "or" => ArithmeticOp::Or,
+        "not" => ArithmeticOp::Not,
+        _ => return None,
+    })
+}
+
+/// An index or a count, a word of the Hack computer.
+fn parse_number(number: &str) -> Result<u16, String> {
+    match number.parse::<u16>() {
+        Ok(n) if n <= 0x7fff => Ok(n),
+        _ => Err(format!("`{number}` is not a number from 0 to 32767")),
+    }
+}
+
+/// A label or function name is letters, digits, `_`, `.`, `$` and `:`,
+/// not starting with a digit. Hack assembly symbols are the same, so the
+/// labels and functions of VM code carry over to them.
+pub fn is_symbol(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        use ArithmeticOp::*;
+        use Segment::*;
+        use VmInstruction::*;
+
+        let function = vec![
+            Function("Main.main".to_string(), 1),
+            Push(Constant, 7),
+            Pop(Local, 0),
+            Push(Kind::Field.into(), 2),
+            Arithmetic(Not),
+            IfGoto("L0".to_string()),
+            Label("L0".to_string()),
+            Goto("L0".to_string()),
+            Call("Math.multiply".to_string(), 2),
+            Return,
+        ];
+
+        assert_eq!(
+            to_vm_code(&[function]),
+            "function Main.main 1
+push constant 7
+pop local 0
+push this 2
+not
+if-goto L0
+label L0
+goto L0
+call Math.multiply 2
+return
+"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let code = "// a comment
+function Main.main 1
+    push constant 7   // trailing
+    pop local 0
+label LOOP
+    push this 2
+    not
+    if-goto END
+    call Math.multiply 2
+    goto LOOP
+label END
+    return
+function Main.f 0
+label LOOP
+    goto LOOP
+";
+        let functions = parse(code).unwrap();
+        assert_eq!(functions.len(), 2);
+        assert_eq!(function_name(&functions[1]), "Main.f");
+        // what `to_vm_code` writes, it parses back
+        assert_eq!(parse(&to_vm_code(&functions)), Ok(functions.clone()));
+        assert_eq!(
+            to_vm_code(&functions[..1]),
+            "function Main.main 1
+push constant 7
+pop local 0
+label LOOP
+push this 2
+not
+if-goto END
+call Math.multiply 2
+goto LOOP
+label END
+return
+"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse(
+                "push constant 1
+function Main.main 0
+pop constant 0
+push pointer 2
+pop temp 8
+push heap 0
+push local -1
+push local 32768
+jump
+add 1
+push local
+label 1abc
+label A
+label A
+goto B
+function Main.main 0
+if-goto A
+function Main.g x
+"
+            ),
+            Err([
+                "1: `push` is outside of a function",
+                "3: can't pop to `constant`",
+                "4: `pointer 2` is out of range",
+                "5: `temp 8` is out of range",
+                "6: unknown segment `heap`",
+                "7: `-1` is not a number from 0 to 32767",
+                "8: `32768` is not a number from 0 to 32767",
+                "9: unknown command `jump`",
+                "10: `add` takes nothing",
+                "11: `push` takes a segment and an index",
+                "12: bad label `1abc`",
+                "14: label `A` is already defined",
+                "15: label `B` is not defined",
+                "16: function `Main.main` is already defined",
+                "17: label `A` is not defined",
+                "18: `x` is not a number from 0 to 32767",
+            ]
+            .map(String::from)
+            .to_vec())
+        );
+    }
+}
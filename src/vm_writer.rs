@@ -1,76 +1,176 @@
-use std::{
-    fs::{File, OpenOptions},
-    io::{self, Write},
-    path::Path,
+use std::io::{self, Write};
+
+use crate::{
+    debug_info::ClassDebugInfo,
+    source_map,
+    vm::{self, ArithmeticOp, Segment, VmInstruction},
 };
 
-pub struct VmWriter {
-    file: File,
+/// Collects the instructions of every compiled function, and writes them to
+/// `out` on `finish`. The source line and column each instruction comes
+/// from are recorded along.
+pub struct VmWriter<W: Write> {
+    out: W,
+    functions: Vec<Vec<VmInstruction>>,
+    lines: Vec<Vec<usize>>,
+    columns: Vec<Vec<usize>>,
+    // (line, column) of the instructions written from now on
+    position: (usize, usize),
 }
 
-impl VmWriter {
-    pub fn new(filepath: &Path) -> io::Result<Self> {
-        let file = OpenOptions::new().write(true).create(true).open(filepath)?;
-        Ok(Self { file })
+impl<W: Write> VmWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            functions: vec![],
+            lines: vec![],
+            columns: vec![],
+            position: (0, 0),
+        }
     }
 
-    pub fn writePush(&mut self, segment: &str, index: i32) -> io::Result<()> {
-        let buf = format!("push {segment} {index}\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    /// Functions written so far, each one starting with its `function`
+    /// instruction.
+    pub fn functions(&self) -> &[Vec<VmInstruction>] {
+        &self.functions
     }
 
-    pub fn writePop(&mut self, segment: &str, index: i32) -> io::Result<()> {
-        let buf = format!("pop {segment} {index}\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn functions_mut(&mut self) -> &mut [Vec<VmInstruction>] {
+        &mut self.functions
     }
 
-    pub fn writeArithmetic(&mut self, command: &str) -> io::Result<()> {
-        let buf = format!("{command}\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    /// Source line of each instruction of `functions`, as long as they are
+    /// not changed through `functions_mut`.
+    pub fn lines(&self) -> &[Vec<usize>] {
+        &self.lines
     }
 
-    pub fn writeLabel(&mut self, label: &str) -> io::Result<()> {
-        let buf = format!("label {label}\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    /// Source column of each instruction of `functions`, like `lines`.
+    pub fn columns(&self) -> &[Vec<usize>] {
+        &self.columns
     }
 
-    pub fn writeGoto(&mut self, label: &str) -> io::Result<()> {
-        let buf = format!("goto {label}\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn position(&self) -> (usize, usize) {
+        self.position
     }
 
-    pub fn writeIf(&mut self, label: &str) -> io::Result<()> {
-        let buf = format!("if-goto {label}\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn set_position(&mut self, line: usize, column: usize) {
+        self.position = (line, column);
     }
 
-    pub fn writeCall(&mut self, name: &str, n_args: i32) -> io::Result<()> {
-        let buf = format!("call {name} {n_args}\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    /// Number of instructions of the function being written.
+    pub fn instruction_count(&self) -> usize {
+        self.functions.last().map_or(0, Vec::len)
     }
 
-    pub fn writeFunction(&mut self, name: &str, n_vars: i32) -> io::Result<()> {
-        let buf = format!("function {name} {n_vars}\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    /// Make the jumps to `label` of the function being written, from
+    /// instruction `start` on, jump to `new_label` instead.
+    pub fn retarget(&mut self, start: usize, label: &str, new_label: &str) {
+        let Some(function) = self.functions.last_mut() else {
+            return;
+        };
+        for instruction in function.iter_mut().skip(start) {
+            if let VmInstruction::Goto(target) | VmInstruction::IfGoto(target) = instruction {
+                if target == label {
+                    *target = new_label.to_string();
+                }
+            }
+        }
     }
 
-    pub fn writeReturn(&mut self) -> io::Result<()> {
-        let buf = format!("return\n");
-        self.file.write_all(buf.as_bytes())?;
-        Ok(())
+    pub fn write(&mut self, instruction: VmInstruction) {
+        if let VmInstruction::Function(..) = instruction {
+            self.functions.push(vec![]);
+            self.lines.push(vec![]);
+            self.columns.push(vec![]);
+        }
+        let Some(function) = self.functions.last_mut() else {
+            panic!("`{instruction}` written outside of a function");
+        };
+        function.push(instruction);
+        self.lines.last_mut().unwrap().push(self.position.0);
+        self.columns.last_mut().unwrap().push(self.position.1);
+    }
+
+    pub fn write_push(&mut self, segment: Segment, index: u16) {
+        self.write(VmInstruction::Push(segment, index));
+    }
+
+    pub fn write_pop(&mut self, segment: Segment, index: u16) {
+        self.write(VmInstruction::Pop(segment, index));
+    }
+
+    pub fn write_arithmetic(&mut self, op: ArithmeticOp) {
+        self.write(VmInstruction::Arithmetic(op));
+    }
+
+    pub fn write_label(&mut self, label: &str) {
+        self.write(VmInstruction::Label(label.to_string()));
     }
 
-    pub fn close(&mut self) -> io::Result<()> {
-        // file will auto close
-        // do nothing
+    pub fn write_goto(&mut self, label: &str) {
+        self.write(VmInstruction::Goto(label.to_string()));
+    }
+
+    pub fn write_if(&mut self, label: &str) {
+        self.write(VmInstruction::IfGoto(label.to_string()));
+    }
+
+    pub fn write_call(&mut self, name: &str, n_args: u16) {
+        self.write(VmInstruction::Call(name.to_string(), n_args));
+    }
+
+    pub fn write_function(&mut self, name: &str, n_vars: u16) {
+        self.write(VmInstruction::Function(name.to_string(), n_vars));
+    }
+
+    pub fn write_return(&mut self) {
+        self.write(VmInstruction::Return);
+    }
+
+    /// Write all collected functions to `out` and flush it.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out
+            .write_all(vm::to_vm_code(&self.functions).as_bytes())?;
+        self.out.flush()
+    }
+
+    /// Like `finish`, with the source lines of `classes` as comments in
+    /// front of their code.
+    pub fn finish_with_comments(&mut self, classes: &[ClassDebugInfo]) -> io::Result<()> {
+        self.out
+            .write_all(source_map::to_commented_vm_code(&self.functions, classes).as_bytes())?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finish() -> io::Result<()> {
+        let mut out = vec![];
+        let mut vm_writer = VmWriter::new(&mut out);
+        vm_writer.set_position(1, 1);
+        vm_writer.write_function("Main.main", 0);
+        vm_writer.set_position(2, 5);
+        vm_writer.write_push(Segment::Constant, 0);
+        vm_writer.write_return();
+        vm_writer.set_position(4, 1);
+        vm_writer.write_function("Main.run", 2);
+        vm_writer.write_arithmetic(ArithmeticOp::Neg);
+        assert_eq!(vm_writer.functions().len(), 2);
+        assert_eq!(vm_writer.lines(), [vec![1, 2, 2], vec![4, 4]]);
+        assert_eq!(vm_writer.columns(), [vec![1, 5, 5], vec![1, 1]]);
+
+        vm_writer.finish()?;
+        drop(vm_writer);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "function Main.main 0\npush constant 0\nreturn\nfunction Main.run 2\nneg\n"
+        );
+
         Ok(())
     }
 }